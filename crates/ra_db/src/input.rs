@@ -12,7 +12,7 @@ use ra_cfg::CfgOptions;
 use ra_syntax::SmolStr;
 use ra_tt::TokenExpander;
 use rustc_hash::{FxHashMap, FxHashSet};
-use vfs::file_set::FileSet;
+use vfs::{file_set::FileSet, VfsPath};
 
 pub use vfs::FileId;
 
@@ -46,6 +46,9 @@ impl SourceRoot {
     pub fn iter(&self) -> impl Iterator<Item = FileId> + '_ {
         self.file_set.iter()
     }
+    pub fn path_for_file(&self, file: &FileId) -> Option<&VfsPath> {
+        self.file_set.path_for_file(file)
+    }
 }
 
 /// `CrateGraph` is a bit of information which turns a set of text files into a
@@ -1253,6 +1253,42 @@ impl Type {
         )
     }
 
+    /// Checks whether values of this type take up no space. This is worked out from the structure
+    /// of the type rather than from its layout, so returns false if that's not enough to know, e.g.
+    /// for closures, or types that are yet to be inferred.
+    pub fn is_zero_sized(&self, db: &dyn HirDatabase) -> bool {
+        return go(db, &self.ty.value);
+
+        fn go(db: &dyn HirDatabase, ty: &Ty) -> bool {
+            let a_ty = match ty {
+                Ty::Apply(a_ty) => a_ty,
+                _ => return false,
+            };
+            let fields_zero_sized = |variant_id: hir_def::VariantId| {
+                db.field_types(variant_id)
+                    .iter()
+                    .all(|(_, ty)| go(db, &ty.clone().subst(&a_ty.parameters)))
+            };
+            match a_ty.ctor {
+                TypeCtor::Never | TypeCtor::FnDef(_) => true,
+                // An array of zero-sized elements is zero-sized whatever its length.
+                TypeCtor::Tuple { .. } | TypeCtor::Array => {
+                    a_ty.parameters.iter().all(|ty| go(db, ty))
+                }
+                TypeCtor::Adt(AdtId::StructId(s)) => fields_zero_sized(s.into()),
+                TypeCtor::Adt(AdtId::EnumId(e)) => {
+                    let enum_data = db.enum_data(e);
+                    // Only an enum with at most one variant has no need for a discriminant.
+                    enum_data.variants.len() <= 1
+                        && enum_data.variants.iter().all(|(local_id, _)| {
+                            fields_zero_sized(hir_def::EnumVariantId { parent: e, local_id }.into())
+                        })
+                }
+                _ => false,
+            }
+        }
+    }
+
     pub fn is_raw_ptr(&self) -> bool {
         matches!(&self.ty.value, Ty::Apply(ApplicationTy { ctor: TypeCtor::RawPtr(..), .. }))
     }
@@ -1436,6 +1472,12 @@ impl Type {
         }
     }
 
+    /// Returns whether `self` and `other` are the same type, regardless of the environments in
+    /// which they were found.
+    pub fn is_same_type(&self, other: &Type) -> bool {
+        self.ty.value == other.ty.value
+    }
+
     fn derived(&self, ty: Ty) -> Type {
         Type {
             krate: self.krate,
@@ -25,7 +25,8 @@ use crate::{
     semantics::source_to_def::{ChildContainer, SourceToDefCache, SourceToDefCtx},
     source_analyzer::{resolve_hir_path, resolve_hir_path_qualifier, SourceAnalyzer},
     AssocItem, Callable, Field, Function, HirFileId, ImplDef, InFile, Local, MacroDef, Module,
-    ModuleDef, Name, Origin, Path, ScopeDef, Trait, Type, TypeAlias, TypeParam, VariantDef,
+    ModuleDef, Name, Origin, Path, ScopeDef, Trait, Type, TypeAlias, TypeParam, TypeRef,
+    VariantDef,
 };
 use resolver::TypeNs;
 
@@ -663,6 +664,13 @@ impl<'a> SemanticsScope<'a> {
     pub fn resolve_hir_path_qualifier(&self, path: &Path) -> Option<PathResolution> {
         resolve_hir_path_qualifier(self.db, &self.resolver, path)
     }
+
+    /// Resolves the paths in `type_ref` as if it was written in this scope, giving the type that it
+    /// refers to.
+    pub fn resolve_hir_type(&self, type_ref: &TypeRef) -> Option<Type> {
+        let ctx = hir_ty::TyLoweringContext::new(self.db, &self.resolver);
+        Type::new_with_resolver(self.db, &self.resolver, hir_ty::Ty::from_hir(&ctx, type_ref))
+    }
 }
 
 // FIXME: Change `HasSource` trait to work with `Semantics` and remove this?
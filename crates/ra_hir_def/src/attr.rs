@@ -149,6 +149,10 @@ pub enum AttrInput {
 }
 
 impl Attr {
+    pub fn path(&self) -> &ModPath {
+        &self.path
+    }
+
     fn from_src(ast: ast::Attr, hygiene: &Hygiene) -> Option<Attr> {
         let path = ModPath::from_src(ast.path()?, hygiene)?;
         let input = if let Some(lit) = ast.literal() {
@@ -1,5 +1,6 @@
 //! HIR for references to types. Paths in these are not yet resolved. They can
 //! be directly created from an ast::TypeRef, without further queries.
+use hir_expand::hygiene::Hygiene;
 use ra_syntax::ast::{self};
 
 use crate::{body::LowerCtx, path::Path};
@@ -78,6 +79,12 @@ pub enum TypeBound {
 }
 
 impl TypeRef {
+    /// Converts an `ast::Type` that isn't in a body to a `TypeRef`, as `Path::from_src` does for
+    /// paths.
+    pub fn from_src(node: ast::Type, hygiene: &Hygiene) -> Self {
+        TypeRef::from_ast(&LowerCtx::with_hygiene(hygiene), node)
+    }
+
     /// Converts an `ast::TypeRef` to a `hir::TypeRef`.
     pub(crate) fn from_ast(ctx: &LowerCtx, node: ast::Type) -> Self {
         match node {
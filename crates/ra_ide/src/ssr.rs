@@ -10,6 +10,29 @@ use ra_ssr::{MatchFinder, SsrError, SsrRule};
 // The syntax for a structural search replace command is `<search_pattern> ==>> <replace_pattern>`.
 // A `$<name>` placeholder in the search pattern will match any AST node and `$<name>` will reference it in the replacement.
 // Within a macro call, a placeholder will match up until whatever token follows the placeholder.
+// A placeholder that is the only thing in a block, as in `async { $body }`, matches the whole body
+// of the block, including any statements. In the replacement, such a body is put in braces unless
+// it's the whole body of a block there too.
+// A placeholder directly after `else`, as in `if $c { $b } else $rest`, matches the whole else
+// branch, which may be a block or the rest of an `if` / `else if` chain.
+// If a placeholder appears more than once in the search pattern, then each occurrence must match
+// the same code. Paths are considered the same if they resolve to the same item.
+// A search pattern that's an impl with members matches impls that contain those members, in any
+// order. Only the matched members are replaced, so the replacement must be the same impl with the
+// same number of members.
+// A search pattern such as `Some($x)` matches both expressions and patterns. To match only one
+// kind of syntax, start the pattern with `expr:`, `pat:`, `type:`, `item:` or `path:`, e.g.
+// `pat: Some($x)`.
+// The last element of an array or tuple in the search pattern may be written `$<name>...`, as in
+// `[$first, $rest...]`. This matches all the remaining elements, possibly none, and `$<name>...`
+// in the replacement references them, along with their separating commas.
+// Alternative replacements may follow the first, separated by `|||`. The first replacement whose
+// placeholders all matched some code is used, or the last if none did. Since `$<name>...` may
+// match nothing, `run([$cmd, $args...]) ==>> spawn($cmd, &[$args...]) ||| spawn_bare($cmd)`
+// replaces `run(["ls"])` with `spawn_bare("ls")`.
+// A chain of `?`-terminated method calls can be matched with `$<name>...` as the method of a
+// field-like expression, as in `$e.$stages...?`. This matches `a.foo()?.bar()?`, binding
+// `$stages` to `foo()?.bar()` and `$e` to `a`.
 //
 // All paths in both the search pattern and the replacement template must resolve in the context
 // in which this command is invoked. Paths in the search pattern will then match the code if they
@@ -17,10 +40,47 @@ use ra_ssr::{MatchFinder, SsrError, SsrRule};
 // command in the module `foo` with a pattern of `Bar`, then code in the parent module that refers
 // to `foo::Bar` will match.
 //
+// Segments in the middle of a path in the search pattern may be `*`, which stands for any one
+// segment, or `**`, which stands for any number of them. Such paths aren't resolved. Instead, what
+// the code resolves to is written out in full, starting with its crate, and compared segment by
+// segment. So `std::*::spawn` matches `thread::spawn` after a `use std::thread`, but not
+// `spawn` from the current crate. Wildcards can't be used in the replacement.
+//
+// A search pattern that's a use declaration, such as `use $mod::*;`, matches whole use
+// declarations. A glob only matches another glob, so `use $mod::*; ==>> use $mod::{A, B};` turns
+// glob imports into explicit ones. Paths within braces, like `A` there, are relative to the path
+// before the braces, so are compared as written rather than resolved, though they may be in any
+// order. A placeholder at the end of a use path, as in `use old::$rest; ==>> use new::$rest;`,
+// matches everything after the part of the path before it, including braces, globs and `as`
+// renames. An item can be removed by replacing it with nothing, as in `extern crate $n; ==>>`.
+//
+// A placeholder can stand for the operator of a binary or prefix expression if it's constrained
+// to a list of operators, e.g. `$a ${op:one_of(==, !=)} $b ==>> ($b) $op ($a)`. The pattern is
+// parsed as if the placeholder were the first operator listed, so all the operators should have
+// the same precedence.
+//
+// Placeholders in generic arguments match const generic arguments too, so `Foo::<$n>` matches
+// `Foo::<5>`. An expression put in such a position by the replacement is wrapped in braces if it's
+// not a literal, block or path.
+//
+// When the matched code is the expression of an expression statement, the statement's `;` is added
+// or dropped as the replacement needs. So `if $c {} ==>> check($c)` adds one after the call, and
+// `check($c) ==>> if $c {}` drops it, since an `if` without a value doesn't need one.
+//
 // Paths in the replacement template will be rendered appropriately for the context in which the
 // replacement occurs. For example if our replacement template is `foo::Bar` and we match some
 // code in the `foo` module, we'll insert just `Bar`.
 //
+// The replacement template may introduce new variables by writing `${fresh(<name>)}`. This becomes
+// `<name>`, or `<name>1`, `<name>2` etc. if that's already in scope where the match was found.
+// Every occurrence within a single replacement uses the same name.
+//
+// String literals in the replacement template may contain `${match.module}`, `${match.file}` and
+// `${match.fn}`, which become the path of the module in which the match was found, the name of its
+// file without the extension, and the name of the enclosing function, or nothing outside of one.
+// For example `$e ==>> { trace!("${match.fn}"); $e }`. Values are escaped as needed within the
+// string.
+//
 // Method calls should generally be written in UFCS form. e.g. `foo::Bar::baz($s, $a)` will match
 // `$s.baz($a)`, provided the method call `baz` resolves to the method `foo::Bar::baz`.
 //
@@ -32,10 +92,32 @@ use ra_ssr::{MatchFinder, SsrError, SsrRule};
 // Supported constraints:
 //
 // |===
-// | Constraint    | Restricts placeholder
+// | Constraint                | Restricts placeholder
 //
-// | kind(literal) | Is a literal (e.g. `42` or `"forty two"`)
-// | not(a)        | Negates the constraint `a`
+// | kind(literal)             | Is a literal (e.g. `42` or `"forty two"`)
+// | kind(numeric_literal)     | Is an integer or float literal (e.g. `42` or `1.5f32`)
+// | kind(string_literal)      | Is a string literal, possibly raw (e.g. `"forty two"` or `r"42"`), but not a byte string
+// | kind(field)               | Is a field access (e.g. `s.a`, but not `s.a()` or `s.a[0]`)
+// | not(a)                    | Negates the constraint `a`
+// | is_const                  | Is an expression that can be evaluated at compile time
+// | redundant_cast            | Is the operand of a cast to the type that it already has
+// | named(x)                  | Is the identifier or keyword `x`, e.g. `self`
+// | zero_sized                | Is an expression whose type takes up no space
+// | in_loop                   | Is inside a loop in the same function, not counting closures
+// | in_loop(through_closures) | Is inside a loop in the same function, including via closures
+// | no_alloc                  | Doesn't call anything known to allocate, e.g. `Box::new` or `vec!` (heuristic)
+// | suffix(u8)                | Is a numeric literal with the type suffix `u8`, or with no suffix for `suffix(none)`
+// | in_const                  | Is evaluated at compile time, e.g. in a `const fn`, `static` or array length
+// | int_in(1..=5)             | Is an integer literal, possibly negated, within the range. Either bound may be left out
+// | no_locals                 | Doesn't refer to local variables or `self`, other than those it defines itself
+// | one_of(a, b)              | Is a path that resolves to the same item as one of the listed paths
+// | parent(condition)         | Is directly the condition of an `if` or `while`. Also `parent(argument)` and `parent(statement)`
+// | has_default               | Is a generic type parameter with a default, e.g. `T = i32`
+// | arity(n)                  | Is an array or tuple literal with exactly `n` elements, e.g. `arity(2)`
+// | has_attr(path)            | Refers to an item with the attribute, e.g. `has_attr(must_use)`. Attribute inputs are ignored
+// | is_async                  | Is a call of an `async fn`, or a path to one
+// | one_of(==, !=)            | Is one of the listed operators. The placeholder must be the operator of a binary or prefix expression, as in `$a ${op:one_of(==, !=)} $b`
+// | returns(bool)             | Is a function or method call whose result has the type. Paths within the type are resolved in the scope where the rule is resolved
 // |===
 //
 // Available via the command `rust-analyzer.ssr`.
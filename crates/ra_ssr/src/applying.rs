@@ -0,0 +1,130 @@
+//! Writing edits to files on disk all at once. Edits are computed from the text that the database
+//! had when the search ran, so before anything is written, each file is read again and checked to
+//! still have that text wherever it's to be edited. Only if every file passes are any written, and
+//! each is written to a temporary file that's then renamed over it, so that a failure part way
+//! through doesn't leave a file half written. If renaming one fails, those already renamed get
+//! their old text back.
+
+use crate::{errors::ApplyError, MatchFinder};
+use ra_db::FileId;
+use ra_syntax::{TextRange, TextSize};
+use ra_text_edit::TextEdit;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An edit to a file, together with the text that it replaces, which `apply_guarded_edits` checks
+/// the file still has before applying it.
+#[derive(Debug, Clone)]
+pub struct GuardedEdit {
+    pub file_id: FileId,
+    pub edit: TextEdit,
+    /// The range deleted by each indel of `edit`, or the line around one that only inserts, with
+    /// the text that it had.
+    pub(crate) guards: Vec<(TextRange, String)>,
+}
+
+impl<'db> MatchFinder<'db> {
+    /// Returns the edits that `edits` would make, each with the text that it replaces.
+    pub fn guarded_edits(&self) -> Vec<GuardedEdit> {
+        use ra_db::SourceDatabaseExt;
+        self.edits()
+            .into_iter()
+            .map(|edit| {
+                let text = self.sema.db.file_text(edit.file_id);
+                let guards = edit
+                    .edit
+                    .iter()
+                    .map(|indel| {
+                        let range = if indel.delete.is_empty() {
+                            line_around(&text, indel.delete.start())
+                        } else {
+                            indel.delete
+                        };
+                        (range, text[range].to_owned())
+                    })
+                    .collect();
+                GuardedEdit { file_id: edit.file_id, edit: edit.edit, guards }
+            })
+            .collect()
+    }
+}
+
+/// Applies `edits` to the files on disk, with `path_for_file` giving the path of each. Files that
+/// have no path are left alone. If any file can't be read, or no longer has the text that an edit
+/// replaces or the line that it inserts into, then nothing is written and all such files are
+/// reported.
+pub fn apply_guarded_edits(
+    edits: &[GuardedEdit],
+    path_for_file: impl Fn(FileId) -> Option<PathBuf>,
+) -> Result<(), ApplyError> {
+    let mut staged = Vec::new();
+    let mut stale_files = Vec::new();
+    for edit in edits {
+        let path = match path_for_file(edit.file_id) {
+            Some(path) => path,
+            None => continue,
+        };
+        match fs::read_to_string(&path) {
+            Ok(original) if is_unchanged(&original, &edit.guards) => {
+                let mut text = original.clone();
+                edit.edit.apply(&mut text);
+                staged.push((path, original, text));
+            }
+            _ => stale_files.push(path),
+        }
+    }
+    if !stale_files.is_empty() {
+        return Err(ApplyError::StaleFiles(stale_files));
+    }
+    let mut temp_paths: Vec<PathBuf> = Vec::new();
+    for (path, _, text) in &staged {
+        let temp_path = temp_path_for(path);
+        if let Err(err) = fs::write(&temp_path, text) {
+            for temp_path in temp_paths.iter().chain(std::iter::once(&temp_path)) {
+                let _ = fs::remove_file(temp_path);
+            }
+            return Err(ApplyError::Io(err));
+        }
+        temp_paths.push(temp_path);
+    }
+    for (i, (path, _, _)) in staged.iter().enumerate() {
+        if let Err(err) = fs::rename(&temp_paths[i], path) {
+            for temp_path in &temp_paths[i..] {
+                let _ = fs::remove_file(temp_path);
+            }
+            for (path, original, _) in &staged[..i] {
+                let temp_path = temp_path_for(path);
+                if fs::write(&temp_path, original)
+                    .and_then(|_| fs::rename(&temp_path, path))
+                    .is_err()
+                {
+                    let _ = fs::remove_file(&temp_path);
+                }
+            }
+            return Err(ApplyError::Io(err));
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `text` still has the text of each guard at its range.
+fn is_unchanged(text: &str, guards: &[(TextRange, String)]) -> bool {
+    guards.iter().all(|(range, expected)| {
+        text.get(usize::from(range.start())..usize::from(range.end())) == Some(expected.as_str())
+    })
+}
+
+/// Returns the range of the line of `text` that contains `offset`, not including its newline.
+fn line_around(text: &str, offset: TextSize) -> TextRange {
+    let offset = usize::from(offset);
+    let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+    TextRange::new(TextSize::from(start as u32), TextSize::from(end as u32))
+}
+
+/// The path to write the new text of the file at `path` to, before renaming it over the file. It's
+/// in the same directory, so that the rename doesn't cross file systems.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.ssr-tmp", file_name))
+}
@@ -0,0 +1,236 @@
+//! Finding matches for several rules at once, independently of each other. Each rule is searched
+//! for on a thread of its own, with its own snapshot of the database. Syntax trees can't be sent
+//! between threads, so each thread reports where it found matches, then we find those nodes in our
+//! own trees and match the rule against them again, which is cheap compared to the search.
+
+use crate::{
+    matching,
+    parsing::{ParsedRule, RawPattern},
+    resolving::{ResolutionScope, ResolvedRule, ScopeLocation},
+    search, Match, MatchFinder, OncePer, SsrError, SsrMatches, SsrRule, TrailingSemicolon,
+};
+use hir::Semantics;
+use ra_db::{salsa::ParallelDatabase, Edition, FileId, FileRange};
+use ra_ide_db::RootDatabase;
+use ra_syntax::{ast, AstNode, SyntaxNode, SyntaxNodePtr};
+use rayon::prelude::*;
+
+impl<'db> MatchFinder<'db> {
+    /// Finds matches for each of `rules` independently of each other and of any rules previously
+    /// added to this instance. Results are returned in the same order as `rules`. Unlike when rules
+    /// are added via `add_rule`, a match for one rule doesn't prevent a different rule from
+    /// matching the same code. Names are resolved in the same context as rules added to `self` and
+    /// `restrict_ranges` are respected. Rules are searched for in parallel.
+    pub fn matches_batch(&self, rules: Vec<SsrRule>) -> Result<Vec<SsrMatches>, SsrError> {
+        // Resolving each rule here first means that errors are reported before any searching.
+        let resolved_rules = rules
+            .iter()
+            .map(|rule| resolve_rule(rule.clone(), &self.resolution_scope, &self.allocating_paths))
+            .collect::<Result<Vec<_>, _>>()?;
+        let settings = self.batch_settings();
+        let locations: Vec<Vec<MatchLocation>> = rules
+            .into_iter()
+            .map(RuleSource::from)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map_with(Snap(self.sema.db.snapshot()), |snap, rule| {
+                settings.find_match_locations(&snap.0, rule)
+            })
+            .collect();
+        Ok(resolved_rules
+            .iter()
+            .zip(locations)
+            .map(|(resolved_rules, locations)| {
+                let mut matches = Vec::new();
+                for location in locations {
+                    if let Some(code) = location.find_node(&self.sema) {
+                        self.try_add_match(
+                            &resolved_rules[location.rule_index],
+                            &code,
+                            &location.restrict_range,
+                            &mut matches,
+                        );
+                    }
+                }
+                crate::nester::nest_and_remove_collisions(
+                    matches,
+                    resolved_rules,
+                    self.rule_priority,
+                    self.nested_search_depth,
+                    &self.sema,
+                )
+            })
+            .collect())
+    }
+
+    fn batch_settings(&self) -> BatchSettings {
+        BatchSettings {
+            scope_location: self.resolution_scope.location.clone(),
+            restrict_ranges: self.restrict_ranges.clone(),
+            expansion_depth_limit: self.expansion_depth_limit,
+            nested_search_depth: self.nested_search_depth,
+            match_options: self.match_options,
+            search_generated_code: self.search_generated_code,
+            test_modules_only: self.test_modules_only,
+            generated_file_markers: self.generated_file_markers.clone(),
+            allocating_paths: self.allocating_paths.clone(),
+        }
+    }
+}
+
+fn resolve_rule(
+    rule: SsrRule,
+    resolution_scope: &ResolutionScope,
+    allocating_paths: &[String],
+) -> Result<Vec<ResolvedRule>, SsrError> {
+    rule.into_parsed_rules(resolution_scope.edition)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, parsed_rule)| {
+            ResolvedRule::new(parsed_rule, resolution_scope, allocating_paths, index, 0)
+        })
+        .collect()
+}
+
+/// An `SsrRule` without its parsed patterns, which hold syntax trees, so that it can be sent to
+/// another thread and parsed again there.
+struct RuleSource {
+    pattern: RawPattern,
+    template: RawPattern,
+    alternative_templates: Vec<RawPattern>,
+    statement_boundary: bool,
+    heuristic_fallback: bool,
+    idempotency_check: bool,
+    once_per: Option<OncePer>,
+    trailing_semicolon: TrailingSemicolon,
+}
+
+impl From<SsrRule> for RuleSource {
+    fn from(rule: SsrRule) -> RuleSource {
+        RuleSource {
+            pattern: rule.pattern,
+            template: rule.template,
+            alternative_templates: rule.alternative_templates,
+            statement_boundary: rule.statement_boundary,
+            heuristic_fallback: rule.heuristic_fallback,
+            idempotency_check: rule.idempotency_check,
+            once_per: rule.once_per,
+            trailing_semicolon: rule.trailing_semicolon,
+        }
+    }
+}
+
+impl RuleSource {
+    fn into_rule(self, edition: Edition) -> Result<SsrRule, SsrError> {
+        let parsed_rules = ParsedRule::new(
+            &self.pattern,
+            Some(&self.template),
+            &self.alternative_templates,
+            edition,
+        )?;
+        Ok(SsrRule {
+            pattern: self.pattern,
+            template: self.template,
+            alternative_templates: self.alternative_templates,
+            parsed_rules,
+            edition,
+            statement_boundary: self.statement_boundary,
+            heuristic_fallback: self.heuristic_fallback,
+            idempotency_check: self.idempotency_check,
+            once_per: self.once_per,
+            trailing_semicolon: self.trailing_semicolon,
+        })
+    }
+}
+
+/// Needed so that each thread that `map_with` uses can have its own snapshot.
+struct Snap(ra_db::salsa::Snapshot<RootDatabase>);
+
+impl Clone for Snap {
+    fn clone(&self) -> Snap {
+        Snap(self.0.snapshot())
+    }
+}
+
+/// The settings of a `MatchFinder` that affect searching, so that each thread can make its own.
+struct BatchSettings {
+    scope_location: ScopeLocation,
+    restrict_ranges: Vec<FileRange>,
+    expansion_depth_limit: usize,
+    nested_search_depth: Option<usize>,
+    match_options: matching::MatchOptions,
+    search_generated_code: bool,
+    test_modules_only: bool,
+    generated_file_markers: Vec<String>,
+    allocating_paths: Vec<String>,
+}
+
+impl BatchSettings {
+    fn find_match_locations(&self, db: &RootDatabase, rule: RuleSource) -> Vec<MatchLocation> {
+        let sema = Semantics::new(db);
+        let resolution_scope = ResolutionScope::at_location(&sema, &self.scope_location);
+        let resolved_rules = match rule
+            .into_rule(resolution_scope.edition)
+            .and_then(|rule| resolve_rule(rule, &resolution_scope, &self.allocating_paths))
+        {
+            Ok(resolved_rules) => resolved_rules,
+            Err(_) => return Vec::new(),
+        };
+        let mut match_finder =
+            MatchFinder::with_resolution_scope(db, resolution_scope, self.restrict_ranges.clone());
+        match_finder.expansion_depth_limit = self.expansion_depth_limit;
+        match_finder.nested_search_depth = self.nested_search_depth;
+        match_finder.match_options = self.match_options;
+        match_finder.search_generated_code = self.search_generated_code;
+        match_finder.test_modules_only = self.test_modules_only;
+        match_finder.generated_file_markers = self.generated_file_markers.clone();
+        match_finder.allocating_paths = self.allocating_paths.clone();
+        let mut usage_cache = search::UsageCache::default();
+        let mut matches = Vec::new();
+        for rule in &resolved_rules {
+            match_finder.find_matches_for_rule(rule, None, &mut usage_cache, &mut matches);
+        }
+        matches.iter().map(|m| MatchLocation::of(m, &match_finder.sema)).collect()
+    }
+}
+
+/// Where a match was found, in a form that can be sent to another thread.
+struct MatchLocation {
+    file_id: FileId,
+    /// The macro calls that the matched node is within the expansion of, outermost first. Each is
+    /// in the expansion of the one before, or for the first, in the file.
+    macro_calls: Vec<SyntaxNodePtr>,
+    node: SyntaxNodePtr,
+    restrict_range: Option<FileRange>,
+    rule_index: usize,
+}
+
+impl MatchLocation {
+    fn of(m: &Match, sema: &Semantics<RootDatabase>) -> MatchLocation {
+        let mut macro_calls = Vec::new();
+        let mut previous: Option<SyntaxNode> = None;
+        for node in sema.ancestors_with_macros(m.matched_node.clone()) {
+            // After the root of a macro expansion comes the macro call.
+            if matches!(&previous, Some(previous) if previous.parent().is_none()) {
+                macro_calls.push(SyntaxNodePtr::new(&node));
+            }
+            previous = Some(node);
+        }
+        macro_calls.reverse();
+        MatchLocation {
+            file_id: m.range.file_id,
+            macro_calls,
+            node: SyntaxNodePtr::new(&m.matched_node),
+            restrict_range: m.restrict_range,
+            rule_index: m.rule_index,
+        }
+    }
+
+    fn find_node(&self, sema: &Semantics<RootDatabase>) -> Option<SyntaxNode> {
+        let mut root = sema.parse(self.file_id).syntax().clone();
+        for macro_call in &self.macro_calls {
+            root = sema.expand(&ast::MacroCall::cast(macro_call.to_node(&root))?)?;
+        }
+        Some(self.node.to_node(&root))
+    }
+}
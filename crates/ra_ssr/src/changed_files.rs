@@ -0,0 +1,60 @@
+//! Restricting the search to the files that git reports as changed since some commit, so that CI
+//! can check just the code that a change touches, rather than the whole workspace.
+
+use crate::{MatchFinder, SsrMatches};
+use ra_db::FileId;
+use rustc_hash::FxHashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+impl<'db> MatchFinder<'db> {
+    /// Returns matches for all added rules, like `matches`, but only searches files that have been
+    /// modified since `base_commit`, according to `git diff --name-only`. Git is run in the current
+    /// directory, which should be within the repository containing the workspace. Changed files
+    /// that aren't part of the workspace, such as deleted files, are ignored. Any range
+    /// restrictions still apply.
+    pub fn matches_in_changed_files(&self, base_commit: &str) -> Result<SsrMatches, io::Error> {
+        let top_level = run_git(&["rev-parse", "--show-toplevel"])?;
+        let changed = run_git(&["diff", "--name-only", base_commit])?;
+        let changed: Vec<&str> = changed.lines().filter(|line| !line.is_empty()).collect();
+        Ok(self.matches_in_files_at_paths(Path::new(top_level.trim_end()), &changed))
+    }
+
+    /// Returns matches for all added rules in just the files at `paths`, which are relative to
+    /// `root`.
+    pub(crate) fn matches_in_files_at_paths(&self, root: &Path, paths: &[&str]) -> SsrMatches {
+        let paths: FxHashSet<PathBuf> = paths.iter().map(|path| root.join(path)).collect();
+        self.matches_in(Some(&self.files_at_paths(&paths)))
+    }
+
+    /// Returns the files in the workspace whose paths are in `paths`.
+    pub(crate) fn files_at_paths(&self, paths: &FxHashSet<PathBuf>) -> FxHashSet<FileId> {
+        use ra_db::SourceDatabaseExt;
+        let db = self.sema.db;
+        let mut files = FxHashSet::default();
+        self.search_files_do(|file_id| {
+            let source_root = db.source_root(db.file_source_root(file_id));
+            if let Some(path) = source_root.path_for_file(&file_id) {
+                if paths.contains(Path::new(&path.to_string())) {
+                    files.insert(file_id);
+                }
+            }
+        });
+        files
+    }
+}
+
+/// Runs git with `args` and returns what it writes to stdout. If git fails, what it writes to
+/// stderr is returned as the error.
+fn run_git(args: &[&str]) -> Result<String, io::Error> {
+    let output = Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("git {} failed: {}", args.join(" "), stderr.trim_end()),
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,209 @@
+//! Checks whether the replacement produced by one rule could be matched by the pattern of another,
+//! or of the same rule. This is done structurally, without resolving paths or looking at any code,
+//! so that problems like rules that undo each other can be spotted before the rules are applied.
+//! Whether a rule really matches its own replacement is then checked by applying it to that
+//! replacement.
+
+use crate::matching::only_ident;
+use crate::parsing::{ParsedRule, Placeholder};
+use crate::{MatchFinder, SsrRule};
+use ra_syntax::{ast, AstNode, SmolStr, SyntaxElement, SyntaxNode, TextRange, TextSize};
+use rustc_hash::FxHashMap;
+
+/// Whether the replacement of one rule could produce code that another rule's pattern matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compatibility {
+    /// The replacement can never be matched by the pattern.
+    Independent,
+    /// The replacement might be matched by the pattern, depending on what its placeholders match.
+    MayFeed,
+}
+
+/// Returns whether the replacement of `a`, or any of its alternatives, could produce code that the
+/// pattern of `b` matches. Each new part of the replacement is compared with the pattern, treating
+/// placeholders on both sides as matching anything, regardless of constraints. Paths are compared
+/// as written. When `a` and `b` are the same rule, `MayFeed` means that applying it repeatedly
+/// might never stop.
+pub fn rules_compose(a: &SsrRule, b: &SsrRule) -> Compatibility {
+    for from in &a.parsed_rules {
+        for template in from.template.iter().chain(&from.alternative_templates) {
+            for to in &b.parsed_rules {
+                let produced = template
+                    .descendants()
+                    .filter(|node| !is_placeholder(&from.placeholders_by_stand_in, node));
+                for node in produced {
+                    if could_match(to, &to.pattern, from, &node) {
+                        return Compatibility::MayFeed;
+                    }
+                }
+            }
+        }
+    }
+    Compatibility::Independent
+}
+
+/// Returns whether `rule` matches anything in its own replacement, once that's instantiated with
+/// each placeholder standing for an identifier. The replacement is put on its own in a file, so
+/// paths are compared by name, as `SsrRule::with_heuristic_fallback` does. A replacement that
+/// can't be checked this way, e.g. because it doesn't parse on its own, is assumed to match.
+pub(crate) fn matches_own_replacement(rule: &SsrRule) -> bool {
+    let mut check = rule.clone();
+    check.with_idempotency_check(false);
+    check.with_heuristic_fallback(true);
+    rule.parsed_rules
+        .iter()
+        .flat_map(|parsed| parsed.template.iter().chain(&parsed.alternative_templates))
+        .any(|template| match replacement_in_file(template) {
+            Some((source, range)) => {
+                let (db, _) = crate::database_for_file_content(&source);
+                let mut match_finder = match MatchFinder::at_first_file(&db) {
+                    Ok(match_finder) => match_finder,
+                    Err(_) => return true,
+                };
+                if match_finder.add_rule(check.clone()).is_err() {
+                    return true;
+                }
+                match_finder.matches().flatten().iter().any(|m| range.contains_range(m.range.range))
+            }
+            None => true,
+        })
+}
+
+/// Returns the source of a file in which `template` can be parsed as what it is, e.g. as an
+/// expression, together with the range of `template` within it.
+fn replacement_in_file(template: &SyntaxNode) -> Option<(String, TextRange)> {
+    let kind = template.kind();
+    let (prefix, suffix) = if ast::Expr::can_cast(kind) || ast::Path::can_cast(kind) {
+        ("fn __ssr_check() { ", "; }")
+    } else if ast::Type::can_cast(kind) {
+        ("type __SsrCheck = ", ";")
+    } else if ast::Pat::can_cast(kind) {
+        ("fn __ssr_check() { match () { ", " => {} } }")
+    } else if ast::Item::can_cast(kind) {
+        ("", "")
+    } else {
+        return None;
+    };
+    let text = template.text().to_string();
+    let source = format!("{}{}{}", prefix, text, suffix);
+    if !ast::SourceFile::parse(&source).errors().is_empty() {
+        return None;
+    }
+    let start = TextSize::of(prefix);
+    Some((source, TextRange::at(start, TextSize::of(&text))))
+}
+
+/// Returns a warning for each rule whose replacement its own pattern might match, and for each
+/// cycle of rules that might feed each other. Either could stop repeated application of the rules
+/// from finishing.
+pub fn composition_warnings(rules: &[SsrRule]) -> Vec<String> {
+    let feeds: Vec<Vec<bool>> = rules
+        .iter()
+        .map(|a| rules.iter().map(|b| rules_compose(a, b) == Compatibility::MayFeed).collect())
+        .collect();
+    let mut warnings = Vec::new();
+    for (index, rule) in rules.iter().enumerate() {
+        if feeds[index][index] {
+            warnings.push(format!(
+                "The replacement of `{}` may be matched by its own pattern, so applying it \
+                 repeatedly might not finish",
+                rule.canonicalize()
+            ));
+        }
+    }
+    // Only cycles whose lowest rule is `start` are looked for from `start`, so that each cycle is
+    // reported once.
+    for start in 0..rules.len() {
+        if let Some(cycle) = find_cycle(&feeds, start) {
+            let rules: Vec<String> =
+                cycle.iter().map(|&index| format!("`{}`", rules[index].canonicalize())).collect();
+            warnings.push(format!(
+                "The rules {} may each produce code that the next matches, so applying them \
+                 repeatedly might not finish",
+                rules.join(" then ")
+            ));
+        }
+    }
+    warnings
+}
+
+/// Returns a cycle of two or more rules that starts at `start` and otherwise only goes through
+/// rules after it.
+fn find_cycle(feeds: &[Vec<bool>], start: usize) -> Option<Vec<usize>> {
+    let mut path = vec![start];
+    let mut visited = vec![false; feeds.len()];
+    if extend_to_cycle(feeds, start, &mut path, &mut visited) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn extend_to_cycle(
+    feeds: &[Vec<bool>],
+    start: usize,
+    path: &mut Vec<usize>,
+    visited: &mut [bool],
+) -> bool {
+    let current = *path.last().unwrap();
+    for next in start..feeds.len() {
+        if !feeds[current][next] {
+            continue;
+        }
+        if next == start {
+            if path.len() > 1 {
+                return true;
+            }
+        } else if !visited[next] {
+            visited[next] = true;
+            path.push(next);
+            if extend_to_cycle(feeds, start, path, visited) {
+                return true;
+            }
+            path.pop();
+        }
+    }
+    false
+}
+
+/// Returns whether `pattern` from the rule `to` could match `code`, which is part of the template
+/// of the rule `from`.
+fn could_match(
+    to: &ParsedRule,
+    pattern: &SyntaxNode,
+    from: &ParsedRule,
+    code: &SyntaxNode,
+) -> bool {
+    if is_placeholder(&to.placeholders_by_stand_in, pattern)
+        || is_placeholder(&from.placeholders_by_stand_in, code)
+    {
+        return true;
+    }
+    if pattern.kind() != code.kind() {
+        return false;
+    }
+    let non_trivia = |node: &SyntaxNode| -> Vec<SyntaxElement> {
+        node.children_with_tokens().filter(|element| !element.kind().is_trivia()).collect()
+    };
+    let (pattern_children, code_children) = (non_trivia(pattern), non_trivia(code));
+    if pattern_children.len() != code_children.len() {
+        return false;
+    }
+    pattern_children.iter().zip(&code_children).all(|(p, c)| match (p, c) {
+        (SyntaxElement::Node(p), SyntaxElement::Node(c)) => could_match(to, p, from, c),
+        (SyntaxElement::Token(p), SyntaxElement::Token(c)) => {
+            p.kind() == c.kind()
+                && (p.text() == c.text()
+                    || to.placeholders_by_stand_in.contains_key(p.text())
+                    || from.placeholders_by_stand_in.contains_key(c.text()))
+        }
+        _ => false,
+    })
+}
+
+fn is_placeholder(placeholders: &FxHashMap<SmolStr, Placeholder>, node: &SyntaxNode) -> bool {
+    matches!(
+        only_ident(SyntaxElement::Node(node.clone())),
+        Some(ident) if placeholders.contains_key(ident.text())
+    )
+}
@@ -0,0 +1,211 @@
+//! Formatting the edits that rules would make as a unified diff, so that they can be reviewed
+//! before being applied, or applied with `patch -p1`. We know exactly which ranges each edit
+//! changes, so rather than comparing the old and new text, each hunk consists of the lines that
+//! the edit touches, with three lines of context either side.
+
+use crate::MatchFinder;
+use ra_text_edit::{Indel, TextEdit};
+
+/// The number of unchanged lines shown before and after each change.
+const CONTEXT_LINES: usize = 3;
+
+impl<'db> MatchFinder<'db> {
+    /// Returns the edits that `edits` would make as a unified diff, with a section for each file
+    /// that would change. Files are named by their path, with `a/` and `b/` prefixes as `git diff`
+    /// uses, or by their ID if the database doesn't know their path.
+    pub fn preview_as_diff(&self) -> String {
+        use ra_db::SourceDatabaseExt;
+        let db = self.sema.db;
+        let mut edits = self.edits();
+        edits.sort_by_key(|edit| edit.file_id);
+        let mut out = String::new();
+        for edit in edits {
+            if edit.edit.is_empty() {
+                continue;
+            }
+            let source_root = db.source_root(db.file_source_root(edit.file_id));
+            let path = match source_root.path_for_file(&edit.file_id) {
+                Some(path) => path.to_string().trim_start_matches('/').to_owned(),
+                None => format!("file_{}", edit.file_id.0),
+            };
+            out.push_str(&format!("--- a/{}\n+++ b/{}\n", path, path));
+            out.push_str(&unified_diff_hunks(&db.file_text(edit.file_id), &edit.edit));
+        }
+        out
+    }
+}
+
+/// Returns the hunks of a unified diff between `text` and `text` with `edit` applied.
+fn unified_diff_hunks(text: &str, edit: &TextEdit) -> String {
+    let lines = Lines::new(text);
+    if lines.starts.is_empty() {
+        let mut new_text = String::new();
+        edit.apply(&mut new_text);
+        let added = split_lines(&new_text);
+        let mut out = format!("@@ -0,0 +1,{} @@\n", added.len());
+        push_lines(&mut out, '+', &added);
+        return out;
+    }
+    // The indels that change each run of lines, along with the first and last lines of the run.
+    // Indels that change the same line are in the same run.
+    let mut changes: Vec<Change> = Vec::new();
+    for indel in edit.iter() {
+        let (first, last) = lines.changed_lines(text, indel);
+        match changes.last_mut() {
+            Some(change) if first <= change.last => {
+                change.last = change.last.max(last);
+                change.indels.push(indel);
+            }
+            _ => changes.push(Change { first, last, indels: vec![indel] }),
+        }
+    }
+    // Changes are in the same hunk if the context lines between them would overlap.
+    let mut hunks: Vec<Vec<Change>> = Vec::new();
+    for change in changes {
+        match hunks.last_mut() {
+            Some(hunk) if change.first <= hunk[hunk.len() - 1].last + 2 * CONTEXT_LINES + 1 => {
+                hunk.push(change)
+            }
+            _ => hunks.push(vec![change]),
+        }
+    }
+    let mut out = String::new();
+    // How many more lines the new text has than the old, before the current hunk.
+    let mut line_delta: isize = 0;
+    for hunk in hunks {
+        let first = hunk[0].first;
+        let last = hunk[hunk.len() - 1].last;
+        let context_first = first.saturating_sub(CONTEXT_LINES);
+        let context_last = (last + CONTEXT_LINES).min(lines.starts.len() - 1);
+        let mut body = String::new();
+        let mut old_count = 0;
+        let mut new_count = 0;
+        let mut unchanged_start = lines.starts[context_first];
+        for change in &hunk {
+            let changed_start = lines.starts[change.first];
+            let changed_end = lines.end(text, change.last);
+            let unchanged = split_lines(&text[unchanged_start..changed_start]);
+            let removed = split_lines(&text[changed_start..changed_end]);
+            let new_text = change.apply(text, changed_start, changed_end);
+            let added = split_lines(&new_text);
+            push_lines(&mut body, ' ', &unchanged);
+            push_lines(&mut body, '-', &removed);
+            push_lines(&mut body, '+', &added);
+            old_count += unchanged.len() + removed.len();
+            new_count += unchanged.len() + added.len();
+            unchanged_start = changed_end;
+        }
+        let after = split_lines(&text[unchanged_start..lines.end(text, context_last)]);
+        push_lines(&mut body, ' ', &after);
+        old_count += after.len();
+        new_count += after.len();
+        let old_start = context_first + 1;
+        let new_start = (old_start as isize + line_delta) as usize;
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start,
+            old_count,
+            if new_count == 0 { new_start - 1 } else { new_start },
+            new_count
+        ));
+        out.push_str(&body);
+        line_delta += new_count as isize - old_count as isize;
+    }
+    out
+}
+
+/// A run of lines that some indels change.
+struct Change<'a> {
+    first: usize,
+    last: usize,
+    indels: Vec<&'a Indel>,
+}
+
+impl Change<'_> {
+    /// Returns `text[start..end]`, which should contain all of our indels, with them applied.
+    fn apply(&self, text: &str, start: usize, end: usize) -> String {
+        let mut new_text = String::new();
+        let mut offset = start;
+        for indel in &self.indels {
+            new_text.push_str(&text[offset..usize::from(indel.delete.start())]);
+            new_text.push_str(&indel.insert);
+            offset = indel.delete.end().into();
+        }
+        new_text.push_str(&text[offset..end]);
+        new_text
+    }
+}
+
+/// Appends each of `lines` to `out` with `prefix` before it. A line without a newline is the last
+/// in its file, which the diff marks as such.
+fn push_lines(out: &mut String, prefix: char, lines: &[&str]) {
+    for line in lines {
+        out.push(prefix);
+        out.push_str(line);
+        if !line.ends_with('\n') {
+            out.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// Splits `text` into lines, each including its newline, if it has one.
+fn split_lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (index, _) in text.match_indices('\n') {
+        lines.push(&text[start..=index]);
+        start = index + 1;
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// The offset at which each line of a text starts.
+struct Lines {
+    starts: Vec<usize>,
+}
+
+impl Lines {
+    fn new(text: &str) -> Lines {
+        let mut offset = 0;
+        let starts = split_lines(text)
+            .iter()
+            .map(|line| {
+                let start = offset;
+                offset += line.len();
+                start
+            })
+            .collect();
+        Lines { starts }
+    }
+
+    /// The line containing `offset`. An offset at the end of the text is on the last line.
+    fn line_of(&self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        }
+    }
+
+    /// The offset just after the end of `line`, including its newline.
+    fn end(&self, text: &str, line: usize) -> usize {
+        self.starts.get(line + 1).copied().unwrap_or(text.len())
+    }
+
+    /// The first and last lines that `indel` changes. If it replaces a newline with text that
+    /// doesn't end in one, then the following line is joined on, so changes too.
+    fn changed_lines(&self, text: &str, indel: &Indel) -> (usize, usize) {
+        let start = usize::from(indel.delete.start());
+        let end = usize::from(indel.delete.end());
+        let last = if end == start {
+            self.line_of(start)
+        } else if text[..end].ends_with('\n') && !indel.insert.ends_with('\n') {
+            self.line_of(end)
+        } else {
+            self.line_of(end - 1)
+        };
+        (self.line_of(start), last)
+    }
+}
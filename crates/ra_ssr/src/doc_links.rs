@@ -0,0 +1,165 @@
+//! Searching for paths within intra-doc links in doc comments, e.g. [`Foo`] or [a foo](Foo). These
+//! paths aren't part of the syntax tree, so regular search doesn't find them, but they refer to
+//! items just the same, so should be updated when those items are renamed.
+
+use crate::{
+    resolving::{ResolutionScope, ResolvedRule},
+    Match, MatchFinder, MatchOrigin, SsrMatches,
+};
+use ra_db::{FileId, FilePosition, FileRange};
+use ra_syntax::{ast, AstNode, AstToken, TextRange, TextSize};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+impl<'db> MatchFinder<'db> {
+    /// Adds to `matches` any links in doc comments that refer to the same thing as a rule whose
+    /// pattern is just a path. Links within code that's already been matched are skipped, since
+    /// that code will be replaced. If `files` is set, only those files are searched.
+    pub(crate) fn add_doc_link_matches(
+        &self,
+        matches: &mut SsrMatches,
+        files: Option<&FxHashSet<FileId>>,
+    ) {
+        let mut link_matches: Vec<Match> = Vec::new();
+        for rule in &self.rules {
+            self.find_doc_link_matches(rule, files, &mut link_matches);
+        }
+        for link_match in link_matches {
+            let is_covered = |m: &Match| {
+                m.range.file_id == link_match.range.file_id
+                    && m.range.range.contains_range(link_match.range.range)
+            };
+            if matches.matches.iter().any(is_covered) {
+                continue;
+            }
+            matches.matches.push(link_match);
+        }
+        matches.matches.sort_by(|a, b| {
+            a.range
+                .file_id
+                .cmp(&b.range.file_id)
+                .then_with(|| a.range.range.start().cmp(&b.range.range.start()))
+        });
+    }
+
+    fn find_doc_link_matches(
+        &self,
+        rule: &ResolvedRule,
+        files: Option<&FxHashSet<FileId>>,
+        matches_out: &mut Vec<Match>,
+    ) {
+        // Only rules that consist of a single path without placeholders can match links.
+        if rule.pattern.node.kind() != ra_syntax::SyntaxKind::PATH
+            || !rule.pattern.placeholders_by_stand_in.is_empty()
+        {
+            return;
+        }
+        let resolution = match rule.pattern.resolved_paths.get(&rule.pattern.node) {
+            Some(resolved_path) => &resolved_path.resolution,
+            None => return,
+        };
+        self.search_files_in_do(files, |file_id| {
+            let file = self.sema.parse(file_id);
+            for token in file.syntax().descendants_with_tokens().filter_map(|e| e.into_token()) {
+                let comment = match ast::Comment::cast(token) {
+                    Some(comment) if comment.kind().doc.is_some() => comment,
+                    _ => continue,
+                };
+                for target in link_targets(comment.text()) {
+                    let range = target + comment.syntax().text_range().start();
+                    if !self.restrict_ranges.is_empty()
+                        && !self.restrict_ranges.iter().any(|restrict_range| {
+                            restrict_range.file_id == file_id
+                                && restrict_range.range.contains_range(range)
+                        })
+                    {
+                        continue;
+                    }
+                    let path = match ast::Path::parse(&comment.text()[target]) {
+                        Ok(path) => path,
+                        Err(()) => continue,
+                    };
+                    // Links are resolved in the scope of the item that they document.
+                    let scope = ResolutionScope::new(
+                        &self.sema,
+                        FilePosition { file_id, offset: range.start() },
+                    );
+                    if scope.resolve_path(&path).as_ref() != Some(resolution) {
+                        continue;
+                    }
+                    let mut link_match = Match {
+                        range: FileRange { file_id, range },
+                        matched_node: path.syntax().clone(),
+                        placeholder_values: FxHashMap::default(),
+                        ignored_comments: Vec::new(),
+                        rule_index: rule.index,
+                        added_rule_index: rule.added_index,
+                        depth: 0,
+                        rendered_template_paths: FxHashMap::default(),
+                        matched_impl_members: Vec::new(),
+                        in_generated_code: self.is_generated_file(file_id),
+                        fresh_names: FxHashMap::default(),
+                        match_variables: FxHashMap::default(),
+                        inside_macro_expansion: false,
+                        origin: MatchOrigin::Direct,
+                        constraint_checks: Vec::new(),
+                        is_heuristic: false,
+                        restrict_range: None,
+                    };
+                    if let (Some(template), Some(module)) = (&rule.template, scope.module()) {
+                        if link_match
+                            .render_template_paths_for_module(template, module, &self.sema)
+                            .is_err()
+                        {
+                            continue;
+                        }
+                    }
+                    // If several rules match the same link, the first one wins.
+                    if !matches_out.iter().any(|m| m.range == link_match.range) {
+                        matches_out.push(link_match);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Returns the ranges within `text` of the targets of any links. i.e. `path` in both [`path`] and
+/// [text](path). Reference-style links, such as [text][ref], are ignored.
+fn link_targets(text: &str) -> Vec<TextRange> {
+    let range = |start: usize, end: usize| {
+        TextRange::new(TextSize::from(start as u32), TextSize::from(end as u32))
+    };
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while let Some(open) = text[offset..].find('[').map(|i| offset + i) {
+        let close = match text[open..].find(']') {
+            Some(i) => open + i,
+            None => break,
+        };
+        offset = close + 1;
+        let after = &text[offset..];
+        if after.starts_with('(') {
+            if let Some(close_paren) = after.find(')').map(|i| offset + i) {
+                let (start, end) = strip_backticks(text, offset + 1, close_paren);
+                targets.push(range(start, end));
+                offset = close_paren + 1;
+            }
+        } else if !after.starts_with('[') {
+            let (start, end) = strip_backticks(text, open + 1, close);
+            if start != open + 1 {
+                targets.push(range(start, end));
+            }
+        }
+    }
+    targets
+}
+
+/// Returns `start..end`, excluding any backticks that surround it.
+fn strip_backticks(text: &str, start: usize, end: usize) -> (usize, usize) {
+    let inner = &text[start..end];
+    if inner.len() >= 2 && inner.starts_with('`') && inner.ends_with('`') {
+        (start + 1, end - 1)
+    } else {
+        (start, end)
+    }
+}
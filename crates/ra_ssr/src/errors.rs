@@ -14,16 +14,101 @@ macro_rules! _bail {
 pub(crate) use _bail as bail;
 
 #[derive(Debug, PartialEq)]
-pub struct SsrError(pub(crate) String);
+pub struct SsrError {
+    pub(crate) message: String,
+    kind: SsrErrorKind,
+}
+
+/// What kind of problem an `SsrError` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsrErrorKind {
+    /// A rule or search pattern couldn't be parsed or resolved, or there was nothing to search.
+    Parse,
+    /// A rule with `SsrRule::with_idempotency_check` set matched its own replacement.
+    NonIdempotentRule,
+}
 
 impl std::fmt::Display for SsrError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Parse error: {}", self.0)
+        match self.kind {
+            SsrErrorKind::Parse => write!(f, "Parse error: {}", self.message),
+            SsrErrorKind::NonIdempotentRule => write!(f, "{}", self.message),
+        }
     }
 }
 
 impl SsrError {
     pub(crate) fn new(message: impl Into<String>) -> SsrError {
-        SsrError(message.into())
+        SsrError { message: message.into(), kind: SsrErrorKind::Parse }
+    }
+
+    pub(crate) fn non_idempotent_rule(message: impl Into<String>) -> SsrError {
+        SsrError { message: message.into(), kind: SsrErrorKind::NonIdempotentRule }
+    }
+
+    pub fn kind(&self) -> SsrErrorKind {
+        self.kind
+    }
+}
+
+/// Returned when the edits for a set of matches can't all be applied, since some of the matches
+/// overlap.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SsrConflictError {
+    pub conflicts: Vec<crate::EditConflict>,
+}
+
+impl std::fmt::Display for SsrConflictError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} conflicting matches found", self.conflicts.len())
+    }
+}
+
+/// Returned by `MatchFinder::assert_match_count` when a rule matched an unexpected number of times.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AssertionError {
+    /// The index of the rule, in the order in which rules were added.
+    pub rule_index: usize,
+    pub expected: std::ops::RangeInclusive<usize>,
+    /// The location of each match that was found. The number of matches is the length of this.
+    pub locations: Vec<ra_db::FileRange>,
+}
+
+impl std::fmt::Display for AssertionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Expected {}..={} matches of rule {}, but found {}",
+            self.expected.start(),
+            self.expected.end(),
+            self.rule_index,
+            self.locations.len()
+        )
+    }
+}
+
+/// Returned by `apply_guarded_edits` when the edits couldn't be applied. Stale files are detected
+/// before anything is written. If writing fails, files already written get their old text back,
+/// unless that fails too.
+#[derive(Debug)]
+pub enum ApplyError {
+    /// Files that couldn't be read, or that have changed where they were to be edited since the
+    /// edits were computed.
+    StaleFiles(Vec<std::path::PathBuf>),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ApplyError::StaleFiles(paths) => {
+                write!(f, "No files were edited, since these have changed since the search:")?;
+                for path in paths {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            }
+            ApplyError::Io(err) => write!(f, "Failed to write edits: {}", err),
+        }
     }
 }
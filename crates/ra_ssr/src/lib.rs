@@ -3,49 +3,189 @@
 //! Allows searching the AST for code that matches one or more patterns and then replacing that code
 //! based on a template.
 
+mod applying;
+mod batch;
+mod changed_files;
+mod composing;
+mod diff;
+mod doc_links;
+mod library;
+mod lint_stubs;
+#[cfg(feature = "lsp")]
+mod lsp;
 mod matching;
 mod nester;
 mod parsing;
+#[cfg(feature = "persist")]
+mod persist;
 mod replacing;
 mod resolving;
 mod search;
+#[cfg(feature = "testing")]
+mod testing;
 #[macro_use]
 mod errors;
 #[cfg(test)]
 mod tests;
 
+pub use crate::applying::{apply_guarded_edits, GuardedEdit};
+pub use crate::composing::{composition_warnings, rules_compose, Compatibility};
 use crate::errors::bail;
-pub use crate::errors::SsrError;
-pub use crate::matching::Match;
-use crate::matching::MatchFailureReason;
+pub use crate::errors::{ApplyError, AssertionError, SsrConflictError, SsrError, SsrErrorKind};
+pub use crate::library::{library, library_entry, LibraryEntry};
+use crate::matching::PlaceholderMatch;
+pub use crate::matching::{
+    ConstraintCheck, ConstraintOutcome, Match, MatchFailureReason, MatchOrigin,
+};
+#[cfg(feature = "persist")]
+pub use crate::persist::{load_rules, save_rules};
+pub use crate::search::CostEstimate;
+#[cfg(feature = "testing")]
+pub use crate::testing::test_rule;
 use hir::Semantics;
-use ra_db::{FileId, FilePosition, FileRange};
-use ra_ide_db::source_change::SourceFileEdit;
-use ra_syntax::{ast, AstNode, SyntaxNode, TextRange};
-use resolving::ResolvedRule;
-use rustc_hash::FxHashMap;
+use ra_db::{Edition, FileId, FilePosition, FileRange};
+use ra_ide_db::source_change::{SourceChange, SourceFileEdit};
+use ra_syntax::{ast, AstNode, SyntaxKind, SyntaxNode, TextRange};
+use resolving::{Inserter, NodeRewriter, ResolvedRule};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::rc::Rc;
 
 // A structured search replace rule. Create by calling `parse` on a str.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SsrRule {
     /// A structured pattern that we're searching for.
     pattern: parsing::RawPattern,
     /// What we'll replace it with.
     template: parsing::RawPattern,
+    /// Templates to use instead of `template` when it refers to placeholders that matched nothing,
+    /// written after it separated by `|||`.
+    alternative_templates: Vec<parsing::RawPattern>,
     parsed_rules: Vec<parsing::ParsedRule>,
+    /// The edition that `parsed_rules` were parsed for.
+    edition: Edition,
+    /// Whether the pattern only matches expressions that make up a whole statement. Such a match
+    /// includes the statement's trailing `;`, and the replacement is given one in its place,
+    /// unless `trailing_semicolon` finds that it's a block-like expression that needn't have one.
+    statement_boundary: bool,
+    /// Whether paths that fail to resolve are compared by name instead.
+    heuristic_fallback: bool,
+    /// Whether adding the rule fails if its pattern could match its own replacement.
+    idempotency_check: bool,
+    /// If set, only one match is kept in each function, file or module.
+    once_per: Option<OncePer>,
+    /// How a `;` after a match that's an expression statement is handled.
+    trailing_semicolon: TrailingSemicolon,
 }
 
 #[derive(Debug)]
 pub struct SsrPattern {
     raw: parsing::RawPattern,
     parsed_rules: Vec<parsing::ParsedRule>,
+    /// The edition that `parsed_rules` were parsed for.
+    edition: Edition,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct SsrMatches {
     pub matches: Vec<Match>,
+    /// The number of matches that were left out because they were in generated code.
+    pub num_skipped_in_generated_code: usize,
+    /// Why each candidate that was tried didn't match, if `MatchFinder::collect_all_fail_reasons`
+    /// was set. At most `MAX_FAIL_REASONS` are kept. These aren't affected by filtering.
+    pub fail_reasons: Vec<(FileRange, MatchFailureReason)>,
+}
+
+/// Matches with their text, and that of their placeholders, copied out of the database so that
+/// they can be used without it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SsrMatchesWithText {
+    pub matches: Vec<MatchWithText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchWithText {
+    pub range: FileRange,
+    pub text: String,
+    /// The index of the rule that produced the match, in the order in which rules were added.
+    pub rule_index: usize,
+    /// The text of each placeholder, keyed by placeholder name.
+    pub placeholders: FxHashMap<String, PlaceholderMatchWithText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaceholderMatchWithText {
+    pub text: String,
+    pub range: FileRange,
+}
+
+/// An owned copy of what a `Match` found, returned by `Match::snapshot`. A `Match` refers to the
+/// syntax tree that it was found in, so is for immediate use, while the database is unchanged.
+/// Keep a snapshot instead if the result of a search is needed after edits have been applied or
+/// files have otherwise changed. Offsets are those of the code that was searched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchSnapshot {
+    pub file_id: u32,
+    /// The path of the file, if the database knows it.
+    pub path: Option<String>,
+    pub start: u32,
+    pub end: u32,
+    pub text: String,
+    /// The index of the rule that produced the match, in the order in which rules were added.
+    pub rule_index: usize,
+    /// Each placeholder, keyed by placeholder name.
+    pub placeholders: FxHashMap<String, PlaceholderSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlaceholderSnapshot {
+    pub text: String,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The range of a match, as returned by `SsrMatches::with_positions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionedMatch {
+    pub range: PositionedRange,
+    /// The range of each placeholder, keyed by placeholder name.
+    pub placeholders: FxHashMap<String, PositionedRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionedRange {
+    pub range: FileRange,
+    pub start: LinePosition,
+    pub end: LinePosition,
+}
+
+/// A zero-based line and column. The column is given both in bytes and in UTF-16 code units,
+/// which is what LSP uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinePosition {
+    pub line: u32,
+    pub col_utf8: u32,
+    pub col_utf16: u32,
 }
 
+/// A match, together with an edit that replaces it according to the rule that it matched.
+#[derive(Debug)]
+pub struct MatchFix {
+    pub matched: Match,
+    /// A description of the fix, suitable for showing to the user.
+    pub title: String,
+    pub edit: SourceFileEdit,
+}
+
+/// The default for `MatchFinder::with_expansion_depth_limit`.
+const DEFAULT_EXPANSION_DEPTH_LIMIT: usize = 16;
+
+/// The most reasons for failed matches that `MatchFinder::collect_all_fail_reasons` keeps.
+pub const MAX_FAIL_REASONS: usize = 1000;
+
 /// Searches a crate for pattern matches and possibly replaces them with something else.
 pub struct MatchFinder<'db> {
     /// Our source of information about the user's code.
@@ -53,6 +193,129 @@ pub struct MatchFinder<'db> {
     rules: Vec<ResolvedRule>,
     resolution_scope: resolving::ResolutionScope<'db>,
     restrict_ranges: Vec<FileRange>,
+    /// The parsed form of each rule and search pattern that has been added, kept so that they can
+    /// be resolved again by `rebind`. Each of these may have been parsed as several kinds of thing,
+    /// so may correspond to more than one entry in `rules`.
+    parsed_rules: Vec<Vec<parsing::ParsedRule>>,
+    /// The rewriters for rules added by `add_node_rewrite_rule`, keyed by the index at which the
+    /// rule was added.
+    node_rewriters: FxHashMap<usize, Rc<NodeRewriter>>,
+    /// The inserters for rules added by `add_insertion_rule`, keyed by the index at which the rule
+    /// was added.
+    inserters: FxHashMap<usize, Rc<Inserter>>,
+    rule_priority: RulePriority,
+    /// How many levels of nested macro expansions to search within.
+    expansion_depth_limit: usize,
+    /// How many levels of matches within the placeholders of other matches to keep, if limited.
+    nested_search_depth: Option<usize>,
+    search_doc_links: bool,
+    match_options: matching::MatchOptions,
+    search_generated_code: bool,
+    /// Whether to only search within modules marked `#[cfg(test)]`.
+    test_modules_only: bool,
+    generated_file_markers: Vec<String>,
+    /// Paths to definitions that `no_alloc` placeholders treat as allocating, in addition to the
+    /// known ones.
+    allocating_paths: Vec<String>,
+    /// Whether each file that we've checked so far starts with a generated file marker.
+    generated_files: RefCell<FxHashMap<FileId, bool>>,
+    /// The reasons why candidates failed to match during the current search, if we're collecting
+    /// them.
+    fail_reasons: Option<RefCell<Vec<(FileRange, MatchFailureReason)>>>,
+}
+
+/// Two matches that can't both be applied. Only `kept` will be used by `edits`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditConflict {
+    pub kind: ConflictKind,
+    pub kept: ConflictingMatch,
+    pub discarded: ConflictingMatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictingMatch {
+    pub range: FileRange,
+    /// The index of the rule that produced the match, in the order in which rules were added.
+    pub rule_index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both matches are of the same code.
+    SameNode,
+    /// The discarded match is within the kept match, but not within one of its placeholders, so
+    /// would be overwritten by the kept match's replacement.
+    Nested,
+}
+
+/// Decides which rule wins when more than one rule matches the same code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RulePriority {
+    /// The rule that was added first wins.
+    AddedOrder,
+    /// The rule with the most specific pattern wins, where a pattern is more specific if it has
+    /// more tokens other than placeholders. If rules are equally specific, the rule that was added
+    /// first wins.
+    MostSpecific,
+}
+
+/// Where a rule added by `MatchFinder::add_insertion_rule` inserts its text, relative to each
+/// match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertPosition {
+    /// Directly before the matched code.
+    BeforeMatch,
+    /// Directly after the matched code.
+    AfterMatch,
+    /// On its own line before the innermost item that contains the match, or is the match,
+    /// indented like that item.
+    BeforeEnclosingItem,
+    /// On its own line after the innermost item that contains the match, or is the match, indented
+    /// like that item.
+    AfterEnclosingItem,
+}
+
+/// The parts of the code within which a rule set with `SsrRule::set_once_per` or
+/// `SsrRule::set_last_per` keeps only one match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub enum OncePerScope {
+    /// The innermost enclosing function. Matches that aren't within a function are all kept.
+    Fn,
+    File,
+    Module,
+}
+
+/// How replacing an expression that makes up an expression statement treats the statement's `;`.
+/// See `SsrRule::set_trailing_semicolon`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub enum TrailingSemicolon {
+    /// Adds a `;` where the replacement needs one to remain a statement, e.g. when an `if` without
+    /// one is replaced by a call, and drops the `;` when the replacement is a block-like
+    /// expression, such as an `if` or `match`, that's clearly of type `()`, so doesn't need it.
+    Auto,
+    /// Adds a `;` where the replacement needs one, but never drops one.
+    Add,
+    /// Leaves the code after the match as it is.
+    Keep,
+}
+
+/// Which single match of a rule to keep within each scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct OncePer {
+    pub(crate) scope: OncePerScope,
+    /// Whether to keep the last match, rather than the first.
+    pub(crate) keep_last: bool,
+}
+
+/// Identifies a function, file or module, for grouping matches by `OncePerScope`.
+#[derive(PartialEq, Eq, Hash)]
+enum OncePerKey {
+    Fn(FileId, TextRange),
+    File(FileId),
+    Module(hir::Module),
 }
 
 impl<'db> MatchFinder<'db> {
@@ -66,11 +329,53 @@ impl<'db> MatchFinder<'db> {
         restrict_ranges.retain(|range| !range.range.is_empty());
         let sema = Semantics::new(db);
         let resolution_scope = resolving::ResolutionScope::new(&sema, lookup_context);
+        MatchFinder::with_resolution_scope(db, resolution_scope, restrict_ranges)
+    }
+
+    /// Constructs a new instance where names will be looked up as if they appeared at the top level
+    /// of `module`.
+    pub fn in_module(db: &'db ra_ide_db::RootDatabase, module: hir::Module) -> MatchFinder<'db> {
+        let sema = Semantics::new(db);
+        let resolution_scope = resolving::ResolutionScope::in_module(&sema, module);
+        MatchFinder::with_resolution_scope(db, resolution_scope, Vec::new())
+    }
+
+    /// Constructs a new instance where names will be looked up as if they appeared at the top level
+    /// of the root module of `krate`.
+    pub fn in_crate_root(
+        db: &'db ra_ide_db::RootDatabase,
+        krate: ra_db::CrateId,
+    ) -> Result<MatchFinder<'db>, SsrError> {
+        match hir::Crate::from(krate).root_module(db) {
+            Some(module) => Ok(MatchFinder::in_module(db, module)),
+            None => bail!("Crate has no root module"),
+        }
+    }
+
+    fn with_resolution_scope(
+        db: &'db ra_ide_db::RootDatabase,
+        resolution_scope: resolving::ResolutionScope<'db>,
+        restrict_ranges: Vec<FileRange>,
+    ) -> MatchFinder<'db> {
         MatchFinder {
             sema: Semantics::new(db),
             rules: Vec::new(),
             resolution_scope,
             restrict_ranges,
+            parsed_rules: Vec::new(),
+            node_rewriters: FxHashMap::default(),
+            inserters: FxHashMap::default(),
+            rule_priority: RulePriority::AddedOrder,
+            expansion_depth_limit: DEFAULT_EXPANSION_DEPTH_LIMIT,
+            nested_search_depth: None,
+            search_doc_links: false,
+            match_options: matching::MatchOptions::default(),
+            search_generated_code: false,
+            test_modules_only: false,
+            generated_file_markers: vec!["@generated".to_owned()],
+            allocating_paths: Vec::new(),
+            generated_files: RefCell::new(FxHashMap::default()),
+            fail_reasons: None,
         }
     }
 
@@ -95,19 +400,159 @@ impl<'db> MatchFinder<'db> {
     }
 
     /// Adds a rule to be applied. The order in which rules are added matters. Earlier rules take
-    /// precedence. If a node is matched by an earlier rule, then later rules won't be permitted to
-    /// match to it.
+    /// precedence, unless a different `RulePriority` is set. If a node is matched by an earlier
+    /// rule, then later rules won't be permitted to match to it.
     pub fn add_rule(&mut self, rule: SsrRule) -> Result<(), SsrError> {
-        for parsed_rule in rule.parsed_rules {
-            self.rules.push(ResolvedRule::new(
-                parsed_rule,
-                &self.resolution_scope,
-                self.rules.len(),
-            )?);
+        if rule.idempotency_check
+            && rules_compose(&rule, &rule) == Compatibility::MayFeed
+            && composing::matches_own_replacement(&rule)
+        {
+            return Err(SsrError::non_idempotent_rule(format!(
+                "The replacement of `{}` is matched by its own pattern, so the rule isn't \
+                 idempotent",
+                rule.canonicalize()
+            )));
+        }
+        self.add_parsed_rules(rule.into_parsed_rules(self.resolution_scope.edition)?)
+    }
+
+    /// Returns the module in which paths in rules are resolved, if there is one. This is useful for
+    /// explaining why a path in a rule failed to resolve.
+    pub fn resolution_module(&self) -> Option<hir::Module> {
+        self.resolution_scope.module()
+    }
+
+    /// Sets how to choose between rules that match the same code. By default, the rule that was
+    /// added first wins.
+    pub fn set_rule_priority(&mut self, rule_priority: RulePriority) {
+        self.rule_priority = rule_priority;
+    }
+
+    /// Limits how many levels of nested macro expansions are searched, e.g. with a limit of 1, code
+    /// passed to a macro can match, but not code passed to a macro within that macro's expansion.
+    /// This stops macros that expand to calls of themselves from making the search recurse
+    /// without end. The default is 16.
+    pub fn with_expansion_depth_limit(&mut self, limit: usize) {
+        self.expansion_depth_limit = limit;
+    }
+
+    /// Limits how deeply matches can be nested within the placeholders of other matches. e.g. with
+    /// a depth of 1, a match within a placeholder of a top-level match is kept, but not a match
+    /// within one of its placeholders. Matches nested more deeply are dropped, so they're neither
+    /// returned nor replaced, and when scanning files for matches of a rule, code within that
+    /// many matches of the rule isn't searched at all. By default, there's no limit.
+    pub fn nested_search_depth(&mut self, depth: usize) {
+        self.nested_search_depth = Some(depth);
+    }
+
+    /// Sets whether to also search links in doc comments, such as [`Foo`] or [a foo](Foo). Only
+    /// rules where the search pattern is just a path, without placeholders, can match links. The
+    /// replacement then changes just the link's target.
+    pub fn set_search_doc_links(&mut self, search_doc_links: bool) {
+        self.search_doc_links = search_doc_links;
+    }
+
+    /// Sets whether a method call pattern with explicit type arguments, such as
+    /// `$a.collect::<$c>()`, can match calls where the type arguments are inferred, such as
+    /// `x.collect()`. Placeholders in the type arguments are then bound to the inferred types. This
+    /// is only possible when the type argument is the type of the method's return value or of one
+    /// of its parameters.
+    pub fn set_infer_type_args(&mut self, infer_type_args: bool) {
+        self.match_options.infer_type_args = infer_type_args;
+    }
+
+    /// Sets whether a pattern that calls a method without its `self` argument, such as
+    /// `S::foo($x)`, can match calls of the same method on `self`, whether written
+    /// `self.foo(x)` or `S::foo(self, x)`.
+    pub fn ignore_explicit_self(&mut self, ignore_explicit_self: bool) {
+        self.match_options.ignore_explicit_self = ignore_explicit_self;
+    }
+
+    /// Sets whether `matches` also returns why each candidate that it tried didn't match, in
+    /// `SsrMatches::fail_reasons`. A candidate is code of the same kind as the search pattern that
+    /// the search didn't rule out beforehand. This is much slower than an ordinary search, since
+    /// each failure has its reason formatted, and only the first `MAX_FAIL_REASONS` are kept. To
+    /// see why particular code didn't match, `debug_where_text_equal` is cheaper.
+    pub fn collect_all_fail_reasons(&mut self, collect: bool) {
+        self.fail_reasons = if collect { Some(RefCell::new(Vec::new())) } else { None };
+    }
+
+    /// Sets whether to include matches in generated code. By default, matches within items marked
+    /// `#[automatically_derived]`, or in files with a generated file marker comment near the top,
+    /// are left out and counted in `SsrMatches::num_skipped_in_generated_code`.
+    pub fn set_search_generated_code(&mut self, search_generated_code: bool) {
+        self.search_generated_code = search_generated_code;
+    }
+
+    /// Restricts the search to code within modules marked `#[cfg(test)]`, including modules in
+    /// other files that are declared that way, so that test-only code can be cleaned up without
+    /// touching production code.
+    pub fn matches_in_test_modules(mut self) -> Self {
+        self.test_modules_only = true;
+        self
+    }
+
+    /// Sets the text that identifies a file as generated when it appears in a comment within the
+    /// first few lines of the file. The default is `@generated`.
+    pub fn set_generated_file_markers(&mut self, markers: Vec<String>) {
+        self.generated_file_markers = markers;
+        self.generated_files.borrow_mut().clear();
+    }
+
+    /// Adds a definition that `no_alloc` placeholders should treat as allocating, in addition to the
+    /// known ones such as `Vec::new` and `format!`. The path is resolved in the same context as
+    /// rules and may name a function, including an associated one such as `Arena::alloc`, a
+    /// trait, all of whose methods then count as allocating, or a macro.
+    pub fn add_allocating_path(&mut self, path: &str) -> Result<(), SsrError> {
+        let resolution =
+            ast::Path::parse(path).ok().and_then(|p| self.resolution_scope.resolve_path(&p));
+        let resolution = match resolution {
+            Some(resolution) => resolution,
+            None => bail!("Failed to resolve allocating path `{}`", path),
+        };
+        let mut defs = resolving::AllocatingDefs::default();
+        if !defs.add(resolution.clone()) {
+            bail!("Allocating path `{}` isn't a function, trait or macro", path);
+        }
+        for rule in &mut self.rules {
+            rule.allocating_defs.add(resolution.clone());
         }
+        self.allocating_paths.push(path.to_owned());
         Ok(())
     }
 
+    /// Sets whether identifiers that aren't resolved as paths are compared after normalizing their
+    /// case convention, so that `my_func` matches `myFunc` and `MyFunc`. Other tokens, such as
+    /// keywords and literals, must still match exactly.
+    pub fn set_case_convention_insensitive(&mut self, case_convention_insensitive: bool) {
+        self.match_options.case_convention_insensitive = case_convention_insensitive;
+    }
+
+    /// Sets the maximum number of tokens that a placeholder within a macro call's token tree may
+    /// match. Without a limit, matching patterns against large macro calls can be slow, since each
+    /// placeholder may try to match up to the end of the token tree.
+    pub fn set_placeholder_max_tokens(&mut self, max: usize) {
+        self.match_options.placeholder_max_tokens = Some(max);
+    }
+
+    /// Sets whether code within a macro expansion can match even if some of it came from the
+    /// macro's definition rather than from the tokens passed to the macro, e.g. so that
+    /// `$w.write_fmt($a)` can find calls written as `write!(...)`. Such matches have the range of
+    /// the macro call and are flagged by `Match::inside_macro_expansion`. They're reported, but not
+    /// edited, since there's no code at the call site that corresponds to what they matched.
+    pub fn set_match_inside_macro_expansions(&mut self, match_inside_macro_expansions: bool) {
+        self.match_options.match_inside_macro_expansions = match_inside_macro_expansions;
+    }
+
+    /// Sets whether to record the outcome of checking each constraint of each placeholder, so that
+    /// it's possible to see which constraints were evaluated and which couldn't be, e.g. because a
+    /// type couldn't be inferred. The outcomes are available from `Match::constraint_checks` and,
+    /// for code that didn't match, from the reasons given by `near_misses` and
+    /// `debug_where_text_equal`.
+    pub fn set_explain_constraints(&mut self, explain_constraints: bool) {
+        self.match_options.explain_constraints = explain_constraints;
+    }
+
     /// Finds matches for all added rules and returns edits for all found matches.
     pub fn edits(&self) -> Vec<SourceFileEdit> {
         use ra_db::SourceDatabaseExt;
@@ -128,27 +573,458 @@ impl<'db> MatchFinder<'db> {
         edits
     }
 
+    /// Finds matches for all added rules and returns a change that replaces them all. Unlike
+    /// `edits`, which quietly discards matches that conflict with other matches, this fails if
+    /// there are any conflicts, so that the change is never only partly applied.
+    pub fn matches_and_apply(&self) -> Result<SourceChange, SsrConflictError> {
+        let conflicts = self.conflicts();
+        if !conflicts.is_empty() {
+            return Err(SsrConflictError { conflicts });
+        }
+        Ok(SourceChange::from_edits(self.edits(), Vec::new()))
+    }
+
+    /// Checks that the rule with index `rule_index`, in the order in which rules were added, has a
+    /// number of matches within `expected`. This allows rules to be used as lints, e.g. by
+    /// expecting `0..=0` matches of an anti-pattern. Matches nested within other matches are
+    /// counted.
+    pub fn assert_match_count(
+        &self,
+        rule_index: usize,
+        expected: std::ops::RangeInclusive<usize>,
+    ) -> Result<(), AssertionError> {
+        let locations: Vec<FileRange> = self
+            .matches()
+            .filter_rule(rule_index)
+            .flattened()
+            .matches
+            .iter()
+            .map(|m| m.range)
+            .collect();
+        if expected.contains(&locations.len()) {
+            return Ok(());
+        }
+        Err(AssertionError { rule_index, expected, locations })
+    }
+
+    /// Returns the indices, in the order in which rules were added, of the rules and search
+    /// patterns that have no matches in `matches`, which should have come from `self.matches()`.
+    /// Matches nested within other matches are counted.
+    pub fn unmatched_rules(&self, matches: &SsrMatches) -> Vec<usize> {
+        let mut matched = vec![false; self.parsed_rules.len()];
+        for m in matches.flatten() {
+            matched[m.added_rule_index] = true;
+        }
+        matched.iter().enumerate().filter(|(_, matched)| !**matched).map(|(i, _)| i).collect()
+    }
+
+    /// Finds matches for all added rules and pairs each with a fix that would replace it. The fixes
+    /// are protocol independent, but each corresponds to an LSP code action, with `title` as the
+    /// action's title. Matches of search patterns, which have no replacement, are omitted.
+    pub fn matches_with_fix_suggestions(&self) -> Vec<MatchFix> {
+        use ra_db::SourceDatabaseExt;
+        let mut fixes = Vec::new();
+        for m in self.matches().matches {
+            if m.inside_macro_expansion {
+                continue;
+            }
+            let rule = &self.rules[m.rule_index];
+            let title = if let Some(inserter) = &rule.inserter {
+                format!("Insert `{}`", (inserter.text_from)(&m).trim())
+            } else if let Some(rewriter) = &rule.node_rewriter {
+                match rewriter(&m) {
+                    Some(node) => format!("Replace with `{}`", node),
+                    None => continue,
+                }
+            } else if let Some(template) = rule.template_for(&m) {
+                format!("Replace with `{}`", rule.render_with_placeholder_names(template))
+            } else {
+                continue;
+            };
+            let file_id = m.range.file_id;
+            let mut matches = SsrMatches { matches: vec![m], ..SsrMatches::default() };
+            let edit =
+                replacing::matches_to_edit(&matches, &self.sema.db.file_text(file_id), &self.rules);
+            fixes.push(MatchFix {
+                matched: matches.matches.pop().unwrap(),
+                title,
+                edit: SourceFileEdit { file_id, edit },
+            });
+        }
+        fixes
+    }
+
     /// Adds a search pattern. For use if you intend to only call `find_matches_in_file`. If you
     /// intend to do replacement, use `add_rule` instead.
     pub fn add_search_pattern(&mut self, pattern: SsrPattern) -> Result<(), SsrError> {
-        for parsed_rule in pattern.parsed_rules {
+        self.add_parsed_rules(pattern.into_parsed_rules(self.resolution_scope.edition)?)
+    }
+
+    /// Adds a rule whose replacement is built by `rewrite` rather than from a template. `rewrite`
+    /// is called for each match of `pattern` and returns the node that should replace it, e.g. one
+    /// constructed with `ra_syntax::ast::make`, or `None` to leave the match as it is. Since the
+    /// replacement is a syntax node, it's always well formed, unlike text assembled by hand. Any
+    /// matches within the placeholders of a rewritten match are only applied if `rewrite` includes
+    /// them in its result.
+    pub fn add_node_rewrite_rule(
+        &mut self,
+        pattern: SsrPattern,
+        rewrite: impl Fn(&Match) -> Option<SyntaxNode> + 'static,
+    ) -> Result<(), SsrError> {
+        let added_index = self.parsed_rules.len();
+        self.add_parsed_rules(pattern.into_parsed_rules(self.resolution_scope.edition)?)?;
+        self.node_rewriters.insert(added_index, Rc::new(rewrite));
+        self.attach_rule_callbacks();
+        Ok(())
+    }
+
+    /// Adds a rule that leaves each match of `pattern` as it is, and instead inserts the text
+    /// returned by `text_from` for that match at `position`. Several insertions may be made in the
+    /// same file, or even at the same place, in which case they appear in the order of the matches
+    /// that produced them. Insertions that would land within code that another rule replaces are
+    /// dropped.
+    pub fn add_insertion_rule(
+        &mut self,
+        pattern: SsrPattern,
+        position: InsertPosition,
+        text_from: impl Fn(&Match) -> String + 'static,
+    ) -> Result<(), SsrError> {
+        let added_index = self.parsed_rules.len();
+        self.add_parsed_rules(pattern.into_parsed_rules(self.resolution_scope.edition)?)?;
+        self.inserters
+            .insert(added_index, Rc::new(Inserter { position, text_from: Box::new(text_from) }));
+        self.attach_rule_callbacks();
+        Ok(())
+    }
+
+    fn attach_rule_callbacks(&mut self) {
+        for rule in &mut self.rules {
+            rule.node_rewriter = self.node_rewriters.get(&rule.added_index).cloned();
+            rule.inserter = self.inserters.get(&rule.added_index).cloned();
+        }
+    }
+
+    fn add_parsed_rules(&mut self, parsed_rules: Vec<parsing::ParsedRule>) -> Result<(), SsrError> {
+        let added_index = self.parsed_rules.len();
+        for parsed_rule in &parsed_rules {
             self.rules.push(ResolvedRule::new(
-                parsed_rule,
+                parsed_rule.clone(),
                 &self.resolution_scope,
+                &self.allocating_paths,
                 self.rules.len(),
+                added_index,
             )?);
         }
+        self.parsed_rules.push(parsed_rules);
+        Ok(())
+    }
+
+    /// Switches to searching `db`, usually a newer snapshot of the database, with names looked up
+    /// as if they appeared at `lookup_context`. The rules that have been added are resolved again
+    /// without being parsed again. Any that no longer resolve are left out until a later call
+    /// resolves them, and are reported in the returned error.
+    pub fn rebind(
+        &mut self,
+        db: &'db ra_ide_db::RootDatabase,
+        lookup_context: FilePosition,
+        mut restrict_ranges: Vec<FileRange>,
+    ) -> Result<(), SsrError> {
+        restrict_ranges.retain(|range| !range.range.is_empty());
+        let sema = Semantics::new(db);
+        self.resolution_scope = resolving::ResolutionScope::new(&sema, lookup_context);
+        self.sema = sema;
+        self.restrict_ranges = restrict_ranges;
+        self.generated_files.borrow_mut().clear();
+        self.rules.clear();
+        let mut errors = Vec::new();
+        for (added_index, parsed_rules) in self.parsed_rules.iter().enumerate() {
+            let resolved: Result<Vec<ResolvedRule>, SsrError> = parsed_rules
+                .iter()
+                .enumerate()
+                .map(|(i, parsed_rule)| {
+                    ResolvedRule::new(
+                        parsed_rule.clone(),
+                        &self.resolution_scope,
+                        &self.allocating_paths,
+                        self.rules.len() + i,
+                        added_index,
+                    )
+                })
+                .collect();
+            match resolved {
+                Ok(resolved) => self.rules.extend(resolved),
+                Err(e) => errors.push(format!("rule {}: {}", added_index, e.message)),
+            }
+        }
+        self.attach_rule_callbacks();
+        if !errors.is_empty() {
+            bail!("Rules no longer resolve: {}", errors.join(", "));
+        }
         Ok(())
     }
 
+    /// Returns a rough estimate of how expensive it will be to search for the rule at `rule_index`,
+    /// where rules are indexed in the order in which they were added. This is computed from the
+    /// structure of the rule without performing any search, so can be used to decide which rules
+    /// to run, or in what order.
+    pub fn estimate_cost(&self, rule_index: usize) -> CostEstimate {
+        self.rules
+            .iter()
+            .filter(|rule| rule.added_index == rule_index)
+            .map(search::estimate_cost)
+            .fold(CostEstimate::default(), CostEstimate::combine)
+    }
+
     /// Returns matches for all added rules.
     pub fn matches(&self) -> SsrMatches {
+        self.matches_in(None)
+    }
+
+    /// Returns matches for all added rules, only searching `files` if it's set.
+    pub(crate) fn matches_in(&self, files: Option<&FxHashSet<FileId>>) -> SsrMatches {
+        if let Some(fail_reasons) = &self.fail_reasons {
+            fail_reasons.borrow_mut().clear();
+        }
         let mut matches = Vec::new();
         let mut usage_cache = search::UsageCache::default();
         for rule in &self.rules {
-            self.find_matches_for_rule(rule, &mut usage_cache, &mut matches);
+            self.find_matches_for_rule(rule, files, &mut usage_cache, &mut matches);
+        }
+        // Matches of insertion rules leave the code as it is, so don't collide with replacements.
+        let (insertion_matches, matches): (Vec<Match>, Vec<Match>) =
+            matches.into_iter().partition(|m| self.rules[m.rule_index].inserter.is_some());
+        let mut matches = nester::nest_and_remove_collisions(
+            matches,
+            &self.rules,
+            self.rule_priority,
+            self.nested_search_depth,
+            &self.sema,
+        );
+        if !insertion_matches.is_empty() {
+            let insertion_matches = nester::nest_and_remove_collisions(
+                insertion_matches,
+                &self.rules,
+                self.rule_priority,
+                self.nested_search_depth,
+                &self.sema,
+            );
+            matches.matches.extend(insertion_matches.matches);
+            matches.matches.sort_by(|a, b| {
+                a.range
+                    .file_id
+                    .cmp(&b.range.file_id)
+                    .then_with(|| a.range.range.start().cmp(&b.range.range.start()))
+            });
+        }
+        if self.search_doc_links {
+            self.add_doc_link_matches(&mut matches, files);
+        }
+        if !self.search_generated_code {
+            let num_matches = matches.matches.len();
+            matches.matches.retain(|m| !m.in_generated_code);
+            matches.num_skipped_in_generated_code = num_matches - matches.matches.len();
+        }
+        self.apply_once_per(&mut matches);
+        if let Some(fail_reasons) = &self.fail_reasons {
+            matches.fail_reasons = fail_reasons.take();
+        }
+        matches
+    }
+
+    /// Removes matches of rules set with `SsrRule::set_once_per` or `SsrRule::set_last_per` other
+    /// than the first or last in each scope. Each list of matches within a placeholder is filtered
+    /// separately, so matches nested within a kept match are kept too.
+    fn apply_once_per(&self, matches: &mut SsrMatches) {
+        if self.rules.iter().all(|rule| rule.once_per.is_none()) {
+            return;
+        }
+        // The indexes in `matches` of the matches of each rule within each scope, along with
+        // whether to keep the last of them.
+        let mut groups: FxHashMap<(usize, OncePerKey), (bool, Vec<usize>)> = FxHashMap::default();
+        for (index, m) in matches.matches.iter().enumerate() {
+            let once_per = match self.rules[m.rule_index].once_per {
+                Some(once_per) => once_per,
+                None => continue,
+            };
+            if let Some(key) = self.once_per_key(m, once_per.scope) {
+                groups
+                    .entry((m.added_rule_index, key))
+                    .or_insert_with(|| (once_per.keep_last, Vec::new()))
+                    .1
+                    .push(index);
+            }
+        }
+        let position = |index: &usize| {
+            let m = &matches.matches[*index];
+            (m.range.file_id, m.range.range.start())
+        };
+        let mut dropped: FxHashSet<usize> = FxHashSet::default();
+        for (keep_last, indexes) in groups.values() {
+            let kept = if *keep_last {
+                indexes.iter().max_by_key(|index| position(index))
+            } else {
+                indexes.iter().min_by_key(|index| position(index))
+            };
+            dropped.extend(indexes.iter().filter(|index| Some(*index) != kept));
         }
-        nester::nest_and_remove_collisions(matches, &self.sema)
+        let mut index = 0;
+        matches.matches.retain(|_| {
+            index += 1;
+            !dropped.contains(&(index - 1))
+        });
+        for m in &mut matches.matches {
+            for placeholder_value in m.placeholder_values.values_mut() {
+                self.apply_once_per(&mut placeholder_value.inner_matches);
+            }
+        }
+    }
+
+    fn once_per_key(&self, m: &Match, scope: OncePerScope) -> Option<OncePerKey> {
+        match scope {
+            OncePerScope::Fn => {
+                let function = self
+                    .sema
+                    .ancestors_with_macros(m.matched_node.clone())
+                    .find(|node| node.kind() == SyntaxKind::FN)?;
+                let range = self.sema.original_range(&function);
+                Some(OncePerKey::Fn(range.file_id, range.range))
+            }
+            OncePerScope::File => Some(OncePerKey::File(m.range.file_id)),
+            OncePerScope::Module => {
+                self.sema.scope(&m.matched_node).module().map(OncePerKey::Module)
+            }
+        }
+    }
+
+    /// Returns matches for all added rules, like `matches`, but including those of code that came
+    /// partly from a macro's definition, as if `set_match_inside_macro_expansions` were set. Each
+    /// match's `Match::origin` says whether it was found in a file or via a macro expansion, so
+    /// that the direct matches can be edited straight away while the others are just reported.
+    pub fn matches_with_origin(&mut self) -> SsrMatches {
+        let match_inside_macro_expansions = self.match_options.match_inside_macro_expansions;
+        self.match_options.match_inside_macro_expansions = true;
+        let matches = self.matches();
+        self.match_options.match_inside_macro_expansions = match_inside_macro_expansions;
+        matches
+    }
+
+    /// Returns the matches, from those returned by `matches`, that are only approximate, since they
+    /// compared some path by name because it didn't resolve. Nested matches are returned as
+    /// top-level matches. See `SsrRule::with_heuristic_fallback`.
+    pub fn matches_by_heuristic(&self) -> SsrMatches {
+        let mut matches = self.matches().flattened();
+        matches.matches.retain(|m| m.is_heuristic);
+        matches
+    }
+
+    /// Returns matches for all added rules, like `matches`, but with the text of each match and of
+    /// each of its placeholders included. Nested matches are returned as top-level matches.
+    pub fn matches_with_source_text(&self) -> SsrMatchesWithText {
+        use ra_db::SourceDatabaseExt;
+        let mut file_texts = FxHashMap::default();
+        let matches = self
+            .matches()
+            .flattened()
+            .matches
+            .into_iter()
+            .map(|m| {
+                let file_text = file_texts
+                    .entry(m.range.file_id)
+                    .or_insert_with(|| self.sema.db.file_text(m.range.file_id));
+                let placeholders = m
+                    .placeholder_values
+                    .iter()
+                    .map(|(var, placeholder_value)| {
+                        let text = match &placeholder_value.inferred_text {
+                            Some(text) => text.clone(),
+                            None => file_text[placeholder_value.range.range].to_owned(),
+                        };
+                        (
+                            var.0.clone(),
+                            PlaceholderMatchWithText { text, range: placeholder_value.range },
+                        )
+                    })
+                    .collect();
+                MatchWithText {
+                    range: m.range,
+                    text: m.matched_text(),
+                    rule_index: m.added_rule_index,
+                    placeholders,
+                }
+            })
+            .collect();
+        SsrMatchesWithText { matches }
+    }
+
+    /// Returns the matches that conflict with other matches, and so won't be applied by `edits`.
+    /// For each conflict, the match that will be applied is reported along with the one that
+    /// won't. Nothing is modified, so this can be used to warn about conflicting rules before
+    /// applying them.
+    pub fn conflicts(&self) -> Vec<EditConflict> {
+        let mut matches = Vec::new();
+        let mut usage_cache = search::UsageCache::default();
+        for rule in &self.rules {
+            self.find_matches_for_rule(rule, None, &mut usage_cache, &mut matches);
+        }
+        let conflicting_match = |range, rule_index: usize| ConflictingMatch {
+            range,
+            rule_index: self.rules[rule_index].added_index,
+        };
+        let (insertion_matches, matches): (Vec<Match>, Vec<Match>) =
+            matches.into_iter().partition(|m| self.rules[m.rule_index].inserter.is_some());
+        let find_collisions = |matches| {
+            nester::find_collisions(
+                matches,
+                &self.rules,
+                self.rule_priority,
+                self.nested_search_depth,
+                &self.sema,
+            )
+        };
+        let mut collisions = find_collisions(matches);
+        collisions.extend(find_collisions(insertion_matches));
+        collisions
+            .into_iter()
+            .map(|collision| EditConflict {
+                kind: collision.kind,
+                kept: conflicting_match(collision.kept_range, collision.kept_rule_index),
+                discarded: conflicting_match(
+                    collision.discarded_range,
+                    collision.discarded_rule_index,
+                ),
+            })
+            .collect()
+    }
+
+    /// Returns whether any of our rules could possibly match in `file_id`. This only does cheap
+    /// textual checks, without any semantic analysis, so is suitable for calling frequently. It may
+    /// return true for files that don't contain any matches, but will never return false for a file
+    /// that does.
+    pub fn could_match_in_file(&self, file_id: FileId) -> bool {
+        use ra_db::SourceDatabaseExt;
+        if !self.restrict_ranges.is_empty()
+            && !self.restrict_ranges.iter().any(|range| range.file_id == file_id)
+        {
+            return false;
+        }
+        let file_text = self.sema.db.file_text(file_id);
+        self.rules
+            .iter()
+            .any(|rule| search::could_match_in_text(rule, &file_text, self.match_options))
+    }
+
+    /// Returns the files that weren't searched because they're in a crate with an edition that some
+    /// rule can't be applied to. Rules are parsed for the edition of the crate in which they're
+    /// resolved. If that's the 2018 edition and the rule uses keywords that were introduced in the
+    /// 2018 edition, then we don't search 2015 edition crates.
+    pub fn files_skipped_due_to_edition(&self) -> Vec<FileId> {
+        let mut skipped = Vec::new();
+        self.search_files_do(|file_id| {
+            if self.rules.iter().any(|rule| !self.edition_permits_search(rule, file_id)) {
+                skipped.push(file_id);
+            }
+        });
+        skipped
     }
 
     /// Finds all nodes in `file_id` whose text is exactly equal to `snippet` and attempts to match
@@ -177,6 +1053,56 @@ impl<'db> MatchFinder<'db> {
         res
     }
 
+    /// Returns up to `limit` of the nodes that came closest to matching the rule or search pattern
+    /// with index `rule_index`, in the order in which rules were added, together with why each
+    /// failed to match. This is intended to help with fixing a rule that doesn't match anything. A
+    /// node's closeness is how many tokens and placeholders of the pattern it matched before
+    /// failing. Closer nodes come first. Nodes that don't match any of the pattern are left out and
+    /// code within macro calls isn't searched.
+    pub fn near_misses(
+        &self,
+        rule_index: usize,
+        limit: usize,
+    ) -> Vec<(FileRange, MatchFailureReason)> {
+        let mut misses = Vec::new();
+        self.search_files_do(|file_id| {
+            let file = self.sema.parse(file_id);
+            for node in file.syntax().descendants() {
+                let range = self.sema.original_range(&node);
+                if !self.restrict_ranges.is_empty()
+                    && !self.restrict_ranges.iter().any(|restrict_range| {
+                        restrict_range.file_id == file_id
+                            && restrict_range.range.contains_range(range.range)
+                    })
+                {
+                    continue;
+                }
+                for rule in self.rules.iter().filter(|rule| rule.added_index == rule_index) {
+                    if !is_debug_candidate(rule, &node) {
+                        continue;
+                    }
+                    let (result, trace) = matching::get_match_with_trace(
+                        rule,
+                        &node,
+                        &None,
+                        self.match_options,
+                        &self.sema,
+                    );
+                    let progress = trace.progress.get();
+                    if let Err(e) = result {
+                        if progress > 0 {
+                            misses.push((progress, range, failure_reason(e, trace)));
+                        }
+                    }
+                }
+            }
+        });
+        // Sorting is stable, so nodes that are equally close stay in the order in which they were
+        // found.
+        misses.sort_by_key(|(progress, ..)| std::cmp::Reverse(*progress));
+        misses.into_iter().take(limit).map(|(_, range, reason)| (range, reason)).collect()
+    }
+
     fn output_debug_for_nodes_at_range(
         &self,
         node: &SyntaxNode,
@@ -195,21 +1121,19 @@ impl<'db> MatchFinder<'db> {
                     // For now we ignore rules that have a different kind than our node, otherwise
                     // we get lots of noise. If at some point we add support for restricting rules
                     // to a particular kind of thing (e.g. only match type references), then we can
-                    // relax this. We special-case expressions, since function calls can match
-                    // method calls.
-                    if rule.pattern.node.kind() != node.kind()
-                        && !(ast::Expr::can_cast(rule.pattern.node.kind())
-                            && ast::Expr::can_cast(node.kind()))
-                    {
+                    // relax this.
+                    if !is_debug_candidate(rule, &node) {
                         continue;
                     }
+                    let (result, trace) = matching::get_match_with_trace(
+                        rule,
+                        &node,
+                        restrict_range,
+                        self.match_options,
+                        &self.sema,
+                    );
                     out.push(MatchDebugInfo {
-                        matched: matching::get_match(true, rule, &node, restrict_range, &self.sema)
-                            .map_err(|e| MatchFailureReason {
-                                reason: e.reason.unwrap_or_else(|| {
-                                    "Match failed, but no reason was given".to_owned()
-                                }),
-                            }),
+                        matched: result.map_err(|e| failure_reason(e, trace)),
                         pattern: rule.pattern.node.clone(),
                         node: node.clone(),
                     });
@@ -231,6 +1155,52 @@ impl<'db> MatchFinder<'db> {
     }
 }
 
+fn failure_reason(
+    failed: matching::MatchFailed,
+    trace: matching::MatchTrace,
+) -> MatchFailureReason {
+    MatchFailureReason {
+        reason: failed.reason.unwrap_or_else(|| "Match failed, but no reason was given".to_owned()),
+        constraint_checks: trace.constraint_checks.into_inner(),
+    }
+}
+
+/// Returns whether `node` is of a kind that's worth reporting why `rule` didn't match. We
+/// special-case expressions, since function calls can match method calls.
+fn is_debug_candidate(rule: &ResolvedRule, node: &SyntaxNode) -> bool {
+    rule.pattern.node.kind() == node.kind()
+        || (ast::Expr::can_cast(rule.pattern.node.kind()) && ast::Expr::can_cast(node.kind()))
+}
+
+/// Creates a database containing a single crate that consists of a single file with the contents
+/// `source`. This is useful for tools and benchmarks that want to search code that isn't part of a
+/// project. Create a `MatchFinder` for the returned database with `MatchFinder::at_first_file`.
+/// Since a `MatchFinder` borrows its database, the two can't be returned together.
+pub fn database_for_file_content(source: &str) -> (ra_ide_db::RootDatabase, FileId) {
+    use ra_db::{CrateGraph, Env, FileSet, SourceRoot, VfsPath};
+    use ra_ide_db::change::AnalysisChange;
+    use std::sync::Arc;
+    let file_id = FileId(0);
+    let mut file_set = FileSet::default();
+    file_set.insert(file_id, VfsPath::new_virtual_path("/main.rs".to_string()));
+    let mut change = AnalysisChange::new();
+    change.set_roots(vec![SourceRoot::new_local(file_set)]);
+    let mut crate_graph = CrateGraph::default();
+    crate_graph.add_crate_root(
+        file_id,
+        Edition::Edition2018,
+        None,
+        Default::default(),
+        Env::default(),
+        Default::default(),
+    );
+    change.change_file(file_id, Some(Arc::new(source.to_owned())));
+    change.set_crate_graph(crate_graph);
+    let mut db = ra_ide_db::RootDatabase::default();
+    db.apply_change(change);
+    (db, file_id)
+}
+
 pub struct MatchDebugInfo {
     node: SyntaxNode,
     /// Our search pattern parsed as an expression or item, etc
@@ -242,7 +1212,12 @@ impl std::fmt::Debug for MatchDebugInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.matched {
             Ok(_) => writeln!(f, "Node matched")?,
-            Err(reason) => writeln!(f, "Node failed to match because: {}", reason.reason)?,
+            Err(reason) => {
+                writeln!(f, "Node failed to match because: {}", reason.reason)?;
+                for check in &reason.constraint_checks {
+                    writeln!(f, "${}:{} {:?}", check.placeholder, check.constraint, check.outcome)?;
+                }
+            }
         }
         writeln!(
             f,
@@ -259,12 +1234,163 @@ impl std::fmt::Debug for MatchDebugInfo {
 
 impl SsrMatches {
     /// Returns `self` with any nested matches removed and made into top-level matches.
-    pub fn flattened(self) -> SsrMatches {
-        let mut out = SsrMatches::default();
+    pub fn flattened(mut self) -> SsrMatches {
+        let mut out = SsrMatches {
+            num_skipped_in_generated_code: self.num_skipped_in_generated_code,
+            fail_reasons: std::mem::take(&mut self.fail_reasons),
+            ..SsrMatches::default()
+        };
         self.flatten_into(&mut out);
         out
     }
 
+    /// Returns the matches that `flattened` would, without taking `self`, in document order.
+    /// Matches are ordered by file, then by where they start, with a match coming before any
+    /// shorter ones that start at the same place, such as those nested within it.
+    pub fn flatten(&self) -> Vec<&Match> {
+        let mut out = Vec::new();
+        self.flatten_refs_into(&mut out);
+        out.sort_by_key(|m| (m.range.file_id, m.range.range.start(), Reverse(m.range.range.end())));
+        out
+    }
+
+    /// Returns only the matches of the rule with index `rule_index`, in the order in which rules
+    /// were added. Matches of the rule that were nested within matches of other rules become
+    /// top-level matches.
+    pub fn filter_rule(self, rule_index: usize) -> SsrMatches {
+        self.filter(&|m| m.added_rule_index == rule_index)
+    }
+
+    /// Returns only the matches in `file_id`.
+    pub fn filter_file(self, file_id: FileId) -> SsrMatches {
+        self.filter(&|m| m.range.file_id == file_id)
+    }
+
+    fn filter(mut self, keep: &dyn Fn(&Match) -> bool) -> SsrMatches {
+        let mut out = SsrMatches {
+            num_skipped_in_generated_code: self.num_skipped_in_generated_code,
+            fail_reasons: std::mem::take(&mut self.fail_reasons),
+            ..SsrMatches::default()
+        };
+        self.filter_into(keep, &mut out);
+        out
+    }
+
+    fn filter_into(self, keep: &dyn Fn(&Match) -> bool, out: &mut SsrMatches) {
+        for mut m in self.matches {
+            if keep(&m) {
+                for placeholder_value in m.placeholder_values.values_mut() {
+                    let inner = std::mem::take(&mut placeholder_value.inner_matches);
+                    inner.filter_into(keep, &mut placeholder_value.inner_matches);
+                }
+                out.matches.push(m);
+            } else {
+                let mut placeholder_values: Vec<_> = m.placeholder_values.into_iter().collect();
+                placeholder_values.sort_by_key(|(_, p)| p.range.range.start());
+                for (_, placeholder_value) in placeholder_values {
+                    placeholder_value.inner_matches.filter_into(keep, out);
+                }
+            }
+        }
+    }
+
+    /// Combines `self` with `other`, e.g. the results of searching with two different
+    /// `MatchFinder`s. Matches of `other` that are within a placeholder of a match of `self`, or
+    /// the other way round, are nested within that placeholder. Matches of the same rule with the
+    /// same range are only kept once. Of any other matches that overlap, only the match of the rule
+    /// that was added first is kept, or the match from `self` if they're of the same rule.
+    pub fn merge(mut self, other: SsrMatches) -> SsrMatches {
+        self.num_skipped_in_generated_code += other.num_skipped_in_generated_code;
+        self.fail_reasons.extend(other.fail_reasons);
+        for m in other.matches {
+            self.insert(m);
+        }
+        self.matches.sort_by_key(|m| (m.range.file_id, m.range.range.start()));
+        self
+    }
+
+    fn insert(&mut self, mut m: Match) {
+        let contains = |placeholder_value: &PlaceholderMatch, m: &Match| {
+            placeholder_value.range.file_id == m.range.file_id
+                && placeholder_value.range.range.contains_range(m.range.range)
+        };
+        if self.matches.iter().any(|existing| {
+            existing.range == m.range && existing.added_rule_index == m.added_rule_index
+        }) {
+            return;
+        }
+        for existing in &mut self.matches {
+            if let Some(placeholder_value) =
+                existing.placeholder_values.values_mut().find(|p| contains(p, &m))
+            {
+                placeholder_value.inner_matches.insert(m);
+                return;
+            }
+        }
+        let overlaps = |existing: &Match, m: &Match| {
+            existing.range.file_id == m.range.file_id
+                && matches!(existing.range.range.intersect(m.range.range), Some(r) if !r.is_empty())
+                && !m.placeholder_values.values().any(|p| contains(p, existing))
+        };
+        if self.matches.iter().any(|existing| {
+            overlaps(existing, &m) && existing.added_rule_index <= m.added_rule_index
+        }) {
+            return;
+        }
+        for existing in std::mem::take(&mut self.matches) {
+            if overlaps(&existing, &m) {
+                continue;
+            }
+            match m.placeholder_values.values_mut().find(|p| contains(p, &existing)) {
+                Some(placeholder_value) => placeholder_value.inner_matches.insert(existing),
+                None => self.matches.push(existing),
+            }
+        }
+        self.matches.push(m);
+    }
+
+    /// Returns the line and column of the start and end of each match and of each of its
+    /// placeholders. Each file's line index is fetched only once, so this is much faster than
+    /// converting ranges one at a time when there are lots of matches. Nested matches aren't
+    /// included, so call `flattened` first if they're wanted.
+    pub fn with_positions(&self, db: &ra_ide_db::RootDatabase) -> Vec<PositionedMatch> {
+        use ra_ide_db::{line_index::LineIndex, LineIndexDatabase};
+        let mut line_indexes = FxHashMap::default();
+        let mut position = |file_range: FileRange| {
+            let line_index: &LineIndex = line_indexes
+                .entry(file_range.file_id)
+                .or_insert_with(|| db.line_index(file_range.file_id));
+            let line_position = |offset| {
+                let line_col = line_index.line_col(offset);
+                let line_start =
+                    line_index.offset(ra_ide_db::line_index::LineCol { col_utf16: 0, ..line_col });
+                LinePosition {
+                    line: line_col.line,
+                    col_utf8: u32::from(offset - line_start),
+                    col_utf16: line_col.col_utf16,
+                }
+            };
+            PositionedRange {
+                range: file_range,
+                start: line_position(file_range.range.start()),
+                end: line_position(file_range.range.end()),
+            }
+        };
+        self.matches
+            .iter()
+            .map(|m| PositionedMatch {
+                range: position(m.range),
+                placeholders: m
+                    .placeholder_values
+                    .iter()
+                    .map(|(var, placeholder_value)| {
+                        (var.0.clone(), position(placeholder_value.range))
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
     fn flatten_into(self, out: &mut SsrMatches) {
         for mut m in self.matches {
             for p in m.placeholder_values.values_mut() {
@@ -273,16 +1399,140 @@ impl SsrMatches {
             out.matches.push(m);
         }
     }
+
+    /// Like `flatten_into`, but adds references to the matches, in the same order.
+    fn flatten_refs_into<'a>(&'a self, out: &mut Vec<&'a Match>) {
+        for m in &self.matches {
+            for p in m.placeholder_values.values() {
+                p.inner_matches.flatten_refs_into(out);
+            }
+            out.push(m);
+        }
+    }
 }
 
 impl Match {
     pub fn matched_text(&self) -> String {
         self.matched_node.text().to_string()
     }
+
+    /// The code that was matched.
+    pub fn matched_node(&self) -> &SyntaxNode {
+        &self.matched_node
+    }
+
+    /// Returns an owned copy of the match, which stays meaningful after `db` changes. See
+    /// `MatchSnapshot`.
+    pub fn snapshot(&self, db: &ra_ide_db::RootDatabase) -> MatchSnapshot {
+        use ra_db::SourceDatabaseExt;
+        let file_id = self.range.file_id;
+        let file_text = db.file_text(file_id);
+        let source_root = db.source_root(db.file_source_root(file_id));
+        let placeholders = self
+            .placeholder_values
+            .iter()
+            .map(|(var, placeholder_value)| {
+                let range = placeholder_value.range.range;
+                let text = match &placeholder_value.inferred_text {
+                    Some(text) => text.clone(),
+                    None => file_text[range].to_owned(),
+                };
+                let snapshot = PlaceholderSnapshot {
+                    text,
+                    start: range.start().into(),
+                    end: range.end().into(),
+                };
+                (var.0.clone(), snapshot)
+            })
+            .collect();
+        MatchSnapshot {
+            file_id: file_id.0,
+            path: source_root.path_for_file(&file_id).map(|path| path.to_string()),
+            start: self.range.range.start().into(),
+            end: self.range.range.end().into(),
+            text: self.matched_text(),
+            rule_index: self.added_rule_index,
+            placeholders,
+        }
+    }
+
+    /// The node that the placeholder `name` matched, if it matched a whole node. Placeholders
+    /// within macro calls match tokens rather than nodes, so have no node.
+    pub fn placeholder_node(&self, name: &str) -> Option<&SyntaxNode> {
+        self.placeholder_values.get(&matching::Var(name.to_owned()))?.node.as_ref()
+    }
+
+    /// If the placeholder `name` was bound to the stages of a `?` chain, as `$stages` is in
+    /// `$e.$stages...?`, the range of each stage, in source order. Each range runs from the method
+    /// name to the end of its arguments, so excludes the `?`.
+    pub fn placeholder_stages(&self, name: &str) -> Vec<FileRange> {
+        self.placeholder_values
+            .get(&matching::Var(name.to_owned()))
+            .map(|placeholder_value| placeholder_value.stages.clone())
+            .unwrap_or_default()
+    }
+
+    /// The outcome of checking each constraint of each placeholder, if
+    /// `MatchFinder::set_explain_constraints` was set. Since the match succeeded, constraints
+    /// either passed or were within a `not(...)`.
+    pub fn constraint_checks(&self) -> &[ConstraintCheck] {
+        &self.constraint_checks
+    }
+
+    /// Whether the matched code came partly from a macro's definition. See
+    /// `MatchFinder::set_match_inside_macro_expansions`.
+    pub fn inside_macro_expansion(&self) -> bool {
+        self.inside_macro_expansion
+    }
+
+    /// Whether the matched code was found in the file itself or in a macro expansion. See
+    /// `MatchFinder::matches_with_origin`.
+    pub fn origin(&self) -> &MatchOrigin {
+        &self.origin
+    }
+
+    /// Whether some path was compared by name, because it didn't resolve. See
+    /// `SsrRule::with_heuristic_fallback`.
+    pub fn is_heuristic(&self) -> bool {
+        self.is_heuristic
+    }
+
+    /// Returns the inferred type of each placeholder that matched an expression, keyed by
+    /// placeholder name and rendered for display. Placeholders that matched something other than
+    /// an expression, or whose type couldn't be inferred, are omitted. So are placeholders within
+    /// macro calls, since `sema` won't know about the expansions in which they were matched.
+    pub fn placeholder_types(
+        &self,
+        sema: &Semantics<ra_ide_db::RootDatabase>,
+    ) -> FxHashMap<String, String> {
+        use hir::HirDisplay;
+        let file = sema.parse(self.range.file_id);
+        self.placeholder_values
+            .iter()
+            .filter_map(|(var, placeholder_value)| {
+                let node = placeholder_value.node.clone()?;
+                if node.ancestors().last().as_ref() != Some(file.syntax()) {
+                    return None;
+                }
+                let expr = ast::Expr::cast(node)?;
+                let ty = sema.type_of_expr(&expr)?;
+                if ty.contains_unknown() {
+                    return None;
+                }
+                Some((var.0.clone(), ty.display(sema.db).to_string()))
+            })
+            .collect()
+    }
 }
 
 impl std::error::Error for SsrError {}
 
+impl std::error::Error for SsrConflictError {}
+
+impl std::error::Error for AssertionError {}
+
+impl std::error::Error for ApplyError {}
+
 #[cfg(test)]
 impl MatchDebugInfo {
     pub(crate) fn match_failure_reason(&self) -> Option<&str> {
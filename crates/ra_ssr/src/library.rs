@@ -0,0 +1,45 @@
+//! Built-in rules for common rewrites. Equivalences that the matcher deliberately doesn't treat as
+//! equal, such as inclusive and exclusive ranges, are available here as ordinary rules, so that
+//! they only apply when asked for.
+
+use crate::SsrRule;
+
+/// A named group of built-in rules.
+pub struct LibraryEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    rules: &'static [&'static str],
+}
+
+impl LibraryEntry {
+    /// Returns the entry's rules, parsed and ready to be added to a `MatchFinder`.
+    pub fn rules(&self) -> Vec<SsrRule> {
+        self.rules
+            .iter()
+            .map(|rule| rule.parse().expect("Built-in rules are always valid"))
+            .collect()
+    }
+}
+
+const LIBRARY: &[LibraryEntry] = &[LibraryEntry {
+    name: "inclusive_range",
+    description: "Replaces exclusive ranges that end one past a value with inclusive ranges that \
+                  end at it. Only this direction is provided, since `$a..$b + 1` overflows when \
+                  `$b` is the largest value of its type, whereas `$a..=$b` doesn't.",
+    rules: &[
+        "$a..$b + 1 ==>> $a..=$b",
+        "$a..($b + 1) ==>> $a..=$b",
+        "..$b + 1 ==>> ..=$b",
+        "..($b + 1) ==>> ..=$b",
+    ],
+}];
+
+/// Returns all the entries in the built-in library.
+pub fn library() -> &'static [LibraryEntry] {
+    LIBRARY
+}
+
+/// Returns the entry in the built-in library called `name`, if there is one.
+pub fn library_entry(name: &str) -> Option<&'static LibraryEntry> {
+    LIBRARY.iter().find(|entry| entry.name == name)
+}
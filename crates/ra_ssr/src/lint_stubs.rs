@@ -0,0 +1,49 @@
+//! Generating Clippy lint declarations from rules, for users who want to turn their rules into
+//! lints. The generated file is meant to be added to the `clippy_lints` crate, where the macro is
+//! defined. Only the declarations are generated. The lint passes that find and fix the code still
+//! need to be written by hand.
+
+use crate::MatchFinder;
+
+impl<'db> MatchFinder<'db> {
+    /// Returns the source of a Rust file containing a `declare_clippy_lint!` invocation for each
+    /// rule and search pattern that has been added, in the order in which they were added. The
+    /// search pattern becomes the lint's description and the replacement, if any, its suggestion.
+    /// Lints are named `SSR_RULE_<index>` and placed in the `style` group, since there's nothing in
+    /// a rule to suggest otherwise.
+    pub fn export_as_clippy_lint_stubs(&self) -> String {
+        let mut out = String::from("// Generated from structured search replace rules.\n");
+        for added_index in 0..self.parsed_rules.len() {
+            let rule = match self.rules.iter().find(|rule| rule.added_index == added_index) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let pattern = rule.render_with_placeholder_names(&rule.pattern);
+            out.push_str("\ndeclare_clippy_lint! {\n");
+            out.push_str(&format!(
+                "    /// **What it does:** Finds code matching `{}`.\n",
+                pattern
+            ));
+            if rule.inserter.is_some() {
+                out.push_str(
+                    "    ///\n    /// **Suggestion:** Insert code computed by a custom inserter.\n",
+                );
+            } else if rule.node_rewriter.is_some() {
+                out.push_str("    ///\n    /// **Suggestion:** Computed by a custom rewriter.\n");
+            } else if let Some(template) = &rule.template {
+                // Which template is used depends on the match, so all of them are listed.
+                let templates: Vec<String> = std::iter::once(template)
+                    .chain(&rule.alternative_templates)
+                    .map(|template| format!("`{}`", rule.render_with_placeholder_names(template)))
+                    .collect();
+                out.push_str(&format!(
+                    "    ///\n    /// **Suggestion:** Replace with {}.\n",
+                    templates.join(", or else ")
+                ));
+            }
+            out.push_str(&format!("    pub SSR_RULE_{},\n    style,\n", added_index));
+            out.push_str(&format!("    {:?}\n}}\n", format!("matches `{}`", pattern)));
+        }
+        out
+    }
+}
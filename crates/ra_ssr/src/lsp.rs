@@ -0,0 +1,110 @@
+//! Converting matches to LSP locations, so that SSR can be used to provide results to an LSP client
+//! in the same way as, for example, find references, and fixes to LSP code actions. Also
+//! constructing a `MatchFinder` from an LSP position.
+
+use crate::MatchFinder;
+use ra_db::{FileId, FilePosition};
+use ra_syntax::TextSize;
+
+impl<'db> MatchFinder<'db> {
+    /// Constructs a new instance where names will be looked up as if they appeared at `position`
+    /// within the document `uri`. The database only knows files by ID, so `file_resolver` gives the
+    /// file for each URI. Returns `None` if it doesn't know `uri`, or if `position` is outside the
+    /// file. `position` uses UTF-16 columns, as sent by LSP clients.
+    pub fn for_lsp_position(
+        db: &'db ra_ide_db::RootDatabase,
+        uri: &lsp_types::Url,
+        position: lsp_types::Position,
+        file_resolver: impl Fn(&lsp_types::Url) -> Option<FileId>,
+    ) -> Option<MatchFinder<'db>> {
+        use ra_db::SourceDatabaseExt;
+        use ra_ide_db::{line_index::LineCol, LineIndexDatabase};
+        use std::convert::TryFrom;
+        let file_id = file_resolver(uri)?;
+        let line_index = db.line_index(file_id);
+        let file_len = TextSize::of(db.file_text(file_id).as_str());
+        let line_col = LineCol {
+            line: u32::try_from(position.line).ok()?,
+            col_utf16: u32::try_from(position.character).ok()?,
+        };
+        if line_col.line > line_index.line_col(file_len).line {
+            return None;
+        }
+        let offset = line_index.offset(line_col);
+        if offset > file_len {
+            return None;
+        }
+        Some(MatchFinder::in_context(db, FilePosition { file_id, offset }, Vec::new()))
+    }
+
+    /// Finds matches for all added rules, including nested matches, and returns the location of
+    /// each. `file_resolver` gives the URI of each file. Matches in files for which it returns
+    /// `None` are skipped. Ranges use UTF-16 columns, as required by LSP.
+    pub fn lsp_locations(
+        &self,
+        file_resolver: impl Fn(FileId) -> Option<lsp_types::Url>,
+    ) -> Vec<lsp_types::Location> {
+        let position = |line, character| lsp_types::Position::new(u64::from(line), character);
+        self.matches()
+            .flattened()
+            .with_positions(self.sema.db)
+            .into_iter()
+            .filter_map(|m| {
+                let uri = file_resolver(m.range.range.file_id)?;
+                let start = position(m.range.start.line, u64::from(m.range.start.col_utf16));
+                let end = position(m.range.end.line, u64::from(m.range.end.col_utf16));
+                Some(lsp_types::Location::new(uri, lsp_types::Range::new(start, end)))
+            })
+            .collect()
+    }
+
+    /// Returns a code action for each fix from `matches_with_fix_suggestions`. `file_resolver`
+    /// gives the URI of each file. Fixes in files for which it returns `None` are skipped. Ranges
+    /// use UTF-16 columns, as required by LSP.
+    pub fn lsp_code_actions(
+        &self,
+        file_resolver: impl Fn(FileId) -> Option<lsp_types::Url>,
+    ) -> Vec<lsp_types::CodeAction> {
+        use ra_ide_db::LineIndexDatabase;
+        self.matches_with_fix_suggestions()
+            .into_iter()
+            .filter_map(|fix| {
+                let file_id = fix.edit.file_id;
+                let uri = file_resolver(file_id)?;
+                let line_index = self.sema.db.line_index(file_id);
+                let position = |offset| {
+                    let line_col = line_index.line_col(offset);
+                    lsp_types::Position::new(
+                        u64::from(line_col.line),
+                        u64::from(line_col.col_utf16),
+                    )
+                };
+                let edits = fix
+                    .edit
+                    .edit
+                    .iter()
+                    .map(|indel| {
+                        let range = lsp_types::Range::new(
+                            position(indel.delete.start()),
+                            position(indel.delete.end()),
+                        );
+                        lsp_types::TextEdit::new(range, indel.insert.clone())
+                    })
+                    .collect();
+                let mut changes = std::collections::HashMap::new();
+                changes.insert(uri, edits);
+                Some(lsp_types::CodeAction {
+                    title: fix.title,
+                    kind: Some(lsp_types::CodeActionKind::REFACTOR_REWRITE),
+                    diagnostics: None,
+                    edit: Some(lsp_types::WorkspaceEdit {
+                        changes: Some(changes),
+                        document_changes: None,
+                    }),
+                    command: None,
+                    is_preferred: None,
+                })
+            })
+            .collect()
+    }
+}
@@ -2,16 +2,22 @@
 //! process of matching, placeholder values are recorded.
 
 use crate::{
-    parsing::{Constraint, NodeKind, Placeholder},
-    resolving::{ResolvedPattern, ResolvedRule},
+    parsing::{self, Constraint, NodeKind, ParentKind, Placeholder},
+    resolving::{PathPatternSegment, ResolvedPattern, ResolvedRule},
     SsrMatches,
 };
-use hir::Semantics;
+use hir::{HasAttrs, Semantics};
 use ra_db::FileRange;
 use ra_syntax::ast::{AstNode, AstToken};
-use ra_syntax::{ast, SyntaxElement, SyntaxElementChildren, SyntaxKind, SyntaxNode, SyntaxToken};
-use rustc_hash::FxHashMap;
-use std::{cell::Cell, iter::Peekable};
+use ra_syntax::{
+    ast, SmolStr, SyntaxElement, SyntaxElementChildren, SyntaxKind, SyntaxNode, SyntaxToken,
+    TextRange, TextSize, T,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    cell::{Cell, RefCell},
+    iter::Peekable,
+};
 use test_utils::mark;
 
 // Creates a match error. If we're currently attempting to match some code that we thought we were
@@ -43,17 +49,73 @@ macro_rules! fail_match {
 }
 
 /// Information about a match that was found.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Match {
     pub(crate) range: FileRange,
     pub(crate) matched_node: SyntaxNode,
     pub(crate) placeholder_values: FxHashMap<Var, PlaceholderMatch>,
     pub(crate) ignored_comments: Vec<ast::Comment>,
     pub(crate) rule_index: usize,
+    /// The index of the rule or search pattern that produced the match, in the order in which they
+    /// were added to the `MatchFinder`.
+    pub(crate) added_rule_index: usize,
     /// The depth of matched_node.
     pub(crate) depth: usize,
     // Each path in the template rendered for the module in which the match was found.
     pub(crate) rendered_template_paths: FxHashMap<SyntaxNode, hir::ModPath>,
+    /// If the search pattern was an impl with members, the range of the member that each pattern
+    /// member matched. Only these get replaced.
+    pub(crate) matched_impl_members: Vec<FileRange>,
+    /// Whether the match is within generated code, which we don't edit by default.
+    pub(crate) in_generated_code: bool,
+    /// The identifier chosen for each `${fresh(name)}` in the template, keyed by `name`.
+    pub(crate) fresh_names: FxHashMap<SmolStr, String>,
+    /// The value of each `${match.<name>}` in the template, keyed by `name`.
+    pub(crate) match_variables: FxHashMap<SmolStr, String>,
+    /// Whether the matched code came partly from the definition of a macro, rather than just from
+    /// the tokens passed to it. `range` is then the macro call, and the match isn't edited.
+    pub(crate) inside_macro_expansion: bool,
+    /// Whether the matched code was found in the file itself or in a macro expansion.
+    pub(crate) origin: MatchOrigin,
+    /// How each constraint of each placeholder fared. Only recorded when explaining constraints.
+    pub(crate) constraint_checks: Vec<ConstraintCheck>,
+    /// Whether some path was compared by name because it didn't resolve.
+    pub(crate) is_heuristic: bool,
+    /// The range that the matched code was required to come from, as passed to `get_match`. Kept
+    /// so that the match can be made again, see the `batch` module.
+    pub(crate) restrict_range: Option<FileRange>,
+}
+
+/// Where the code of a match was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchOrigin {
+    /// The code is in the syntax tree of a file.
+    Direct,
+    /// The code is in the expansion of the macro called at `call_site`, or of macros called within
+    /// it. If the code was all passed to the macro, the match can still be edited at the call site.
+    /// Otherwise it's flagged by `Match::inside_macro_expansion`.
+    MacroExpansion { call_site: FileRange },
+}
+
+/// The result of checking one of a placeholder's constraints, recorded when
+/// `MatchFinder::set_explain_constraints` is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstraintCheck {
+    /// The name of the placeholder, without the `$`.
+    pub placeholder: String,
+    /// The constraint as it would be written in a rule, e.g. `not(kind(literal))`.
+    pub constraint: String,
+    pub outcome: ConstraintOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConstraintOutcome {
+    Passed,
+    /// The constraint didn't hold, for the given reason.
+    Failed(String),
+    /// The constraint couldn't be evaluated, e.g. because a type couldn't be inferred. This is
+    /// treated as the constraint not holding, so within `not(...)` it lets the match succeed.
+    NotEvaluated(String),
 }
 
 /// Represents a `$var` in an SSR query.
@@ -61,19 +123,34 @@ pub struct Match {
 pub(crate) struct Var(pub String);
 
 /// Information about a placeholder bound in a match.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct PlaceholderMatch {
     /// The node that the placeholder matched to. If set, then we'll search for further matches
     /// within this node. It isn't set when we match tokens within a macro call's token tree.
     pub(crate) node: Option<SyntaxNode>,
     pub(crate) range: FileRange,
+    /// If `node` is a path, what it resolved to. Used to check that repeated occurrences of a
+    /// placeholder match equivalent paths.
+    pub(crate) resolution: Option<hir::PathResolution>,
+    /// If the placeholder was bound to something that was inferred rather than written in the
+    /// code, e.g. a type argument, the code for it. `range` is then empty.
+    pub(crate) inferred_text: Option<String>,
     /// More matches, found within `node`.
     pub(crate) inner_matches: SsrMatches,
+    /// If the placeholder was bound to the stages of a `?` chain, the range of each stage, from
+    /// the method name to the end of its arguments, in source order.
+    pub(crate) stages: Vec<FileRange>,
+    /// Whether the placeholder was bound to the whole body of a block, which may have statements.
+    /// See `attempt_match_block`.
+    pub(crate) is_block_body: bool,
 }
 
-#[derive(Debug)]
-pub(crate) struct MatchFailureReason {
-    pub(crate) reason: String,
+/// Why a node didn't match a rule's search pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchFailureReason {
+    pub reason: String,
+    /// The constraints that were checked before the match failed, if explaining constraints.
+    pub constraint_checks: Vec<ConstraintCheck>,
 }
 
 /// An "error" indicating that matching failed. Use the fail_match! macro to create and return this.
@@ -92,13 +169,62 @@ pub(crate) fn get_match(
     rule: &ResolvedRule,
     code: &SyntaxNode,
     restrict_range: &Option<FileRange>,
+    options: MatchOptions,
     sema: &Semantics<ra_ide_db::RootDatabase>,
 ) -> Result<Match, MatchFailed> {
     record_match_fails_reasons_scope(debug_active, || {
-        Matcher::try_match(rule, code, restrict_range, sema)
+        Matcher::try_match(rule, code, restrict_range, options, sema, &MatchTrace::default())
     })
 }
 
+/// Like `get_match` with `debug_active` set, but also returns what was learned along the way.
+pub(crate) fn get_match_with_trace(
+    rule: &ResolvedRule,
+    code: &SyntaxNode,
+    restrict_range: &Option<FileRange>,
+    options: MatchOptions,
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+) -> (Result<Match, MatchFailed>, MatchTrace) {
+    let trace = MatchTrace::default();
+    let result = record_match_fails_reasons_scope(true, || {
+        Matcher::try_match(rule, code, restrict_range, options, sema, &trace)
+    });
+    (result, trace)
+}
+
+/// What happened while attempting a match, other than whether it succeeded.
+#[derive(Default)]
+pub(crate) struct MatchTrace {
+    /// How many tokens and placeholders of the pattern were matched, before the match failed or
+    /// succeeded. Since matching backtracks in places and is done in two phases, this is only a
+    /// rough measure of how close the code came to matching.
+    pub(crate) progress: Cell<usize>,
+    /// The constraints that were checked, if we're explaining constraints. If the match failed in
+    /// its second phase, these are only the checks made in that phase.
+    pub(crate) constraint_checks: RefCell<Vec<ConstraintCheck>>,
+}
+
+/// Options that affect what code a pattern can match.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MatchOptions {
+    /// Whether explicit type arguments of method calls in the pattern can match calls where the
+    /// type arguments are inferred.
+    pub(crate) infer_type_args: bool,
+    /// Whether identifiers are compared after normalizing their case convention, so that e.g.
+    /// `my_func`, `myFunc` and `MyFunc` are all considered equal.
+    pub(crate) case_convention_insensitive: bool,
+    /// The maximum number of tokens that a placeholder within a token tree may match, if limited.
+    pub(crate) placeholder_max_tokens: Option<usize>,
+    /// Whether code within a macro expansion can match even if it didn't all come from the tokens
+    /// passed to the macro. Such matches are attributed to the macro call.
+    pub(crate) match_inside_macro_expansions: bool,
+    /// Whether to record the outcome of each constraint that's checked.
+    pub(crate) explain_constraints: bool,
+    /// Whether a call of a method that leaves out the `self` argument, such as `S::foo($x)`, can
+    /// match `self.foo(x)` or `S::foo(self, x)`.
+    pub(crate) ignore_explicit_self: bool,
+}
+
 /// Checks if our search pattern matches a particular node of the AST.
 struct Matcher<'db, 'sema> {
     sema: &'sema Semantics<'db, ra_ide_db::RootDatabase>,
@@ -106,6 +232,11 @@ struct Matcher<'db, 'sema> {
     /// rejected.
     restrict_range: Option<FileRange>,
     rule: &'sema ResolvedRule,
+    options: MatchOptions,
+    /// Whether we're matching code that's partly from a macro definition, in which case ranges
+    /// needn't be within `restrict_range`.
+    inside_macro_expansion: bool,
+    trace: &'sema MatchTrace,
 }
 
 /// Which phase of matching we're currently performing. We do two phases because most attempted
@@ -123,39 +254,91 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         rule: &ResolvedRule,
         code: &SyntaxNode,
         restrict_range: &Option<FileRange>,
+        options: MatchOptions,
         sema: &'sema Semantics<'db, ra_ide_db::RootDatabase>,
+        trace: &'sema MatchTrace,
     ) -> Result<Match, MatchFailed> {
-        let match_state = Matcher { sema, restrict_range: restrict_range.clone(), rule };
+        let mut match_state = Matcher {
+            sema,
+            restrict_range: restrict_range.clone(),
+            rule,
+            options,
+            inside_macro_expansion: false,
+            trace,
+        };
         // First pass at matching, where we check that node types and idents match.
         match_state.attempt_match_node(&mut Phase::First, &rule.pattern.node, code)?;
-        match_state.validate_range(&sema.original_range(code))?;
+        let mut range = matched_range(&rule.pattern.node, code, sema);
+        if rule.statement_boundary {
+            let stmt = code
+                .parent()
+                .and_then(ast::ExprStmt::cast)
+                .filter(|stmt| matches!(stmt.expr(), Some(expr) if expr.syntax() == code));
+            match stmt {
+                Some(stmt) => range = sema.original_range(stmt.syntax()),
+                None => fail_match!("Code '{}' isn't a whole statement", code.text()),
+            }
+        }
+        if let Err(e) = match_state.validate_range(&range) {
+            if !options.match_inside_macro_expansions {
+                return Err(e);
+            }
+            let macro_call = sema
+                .ancestors_with_macros(code.clone())
+                .find(|node| node.kind() == SyntaxKind::MACRO_CALL)
+                .ok_or(e)?;
+            range = sema.original_range(&macro_call);
+            match_state.inside_macro_expansion = true;
+        }
         let mut the_match = Match {
-            range: sema.original_range(code),
+            range,
             matched_node: code.clone(),
             placeholder_values: FxHashMap::default(),
             ignored_comments: Vec::new(),
             rule_index: rule.index,
+            added_rule_index: rule.added_index,
             depth: 0,
             rendered_template_paths: FxHashMap::default(),
+            matched_impl_members: Vec::new(),
+            in_generated_code: false,
+            fresh_names: FxHashMap::default(),
+            match_variables: FxHashMap::default(),
+            inside_macro_expansion: match_state.inside_macro_expansion,
+            origin: match_origin(sema, code, range.file_id),
+            constraint_checks: Vec::new(),
+            is_heuristic: false,
+            restrict_range: *restrict_range,
         };
         // Second matching pass, where we record placeholder matches, ignored comments and maybe do
-        // any other more expensive checks that we didn't want to do on the first pass.
+        // any other more expensive checks that we didn't want to do on the first pass. Constraints
+        // are all checked again, so we only keep those checks.
+        trace.constraint_checks.borrow_mut().clear();
         match_state.attempt_match_node(
             &mut Phase::Second(&mut the_match),
             &rule.pattern.node,
             code,
         )?;
         the_match.depth = sema.ancestors_with_macros(the_match.matched_node.clone()).count();
-        if let Some(template) = &rule.template {
+        the_match.constraint_checks = trace.constraint_checks.take();
+        if let Some(template) = rule.template_for(&the_match) {
             the_match.render_template_paths(template, sema)?;
+            the_match.choose_fresh_names(template, sema);
+            the_match.compute_match_variables(template, sema);
         }
         Ok(the_match)
     }
 
+    fn record_progress(&self) {
+        self.trace.progress.set(self.trace.progress.get() + 1);
+    }
+
     /// Checks that `range` is within the permitted range if any. This is applicable when we're
     /// processing a macro expansion and we want to fail the match if we're working with a node that
     /// didn't originate from the token tree of the macro call.
     fn validate_range(&self, range: &FileRange) -> Result<(), MatchFailed> {
+        if self.inside_macro_expansion {
+            return Ok(());
+        }
         if let Some(restrict_range) = &self.restrict_range {
             if restrict_range.file_id != range.file_id
                 || !restrict_range.range.contains_range(range.range)
@@ -172,29 +355,57 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         pattern: &SyntaxNode,
         code: &SyntaxNode,
     ) -> Result<(), MatchFailed> {
-        // Handle placeholders.
-        if let Some(placeholder) = self.get_placeholder(&SyntaxElement::Node(pattern.clone())) {
+        // Handle placeholders. A placeholder directly after `else` matches the whole else branch,
+        // which may be either a block or another `if` expression.
+        let placeholder =
+            self.get_placeholder(&SyntaxElement::Node(pattern.clone())).or_else(|| {
+                self.rule
+                    .else_branch_placeholder_token(pattern)
+                    .and_then(|token| self.rule.get_placeholder(&token))
+            });
+        if let Some(placeholder) = placeholder {
             for constraint in &placeholder.constraints {
-                self.check_constraint(constraint, code)?;
+                // Constraints that require semantic analysis are deferred to the second phase.
+                if matches!(phase, Phase::First) && constraint.is_semantic() {
+                    continue;
+                }
+                if self.options.explain_constraints {
+                    self.explain_constraint(placeholder, constraint, code)?;
+                } else {
+                    self.check_constraint(constraint, code)?;
+                }
             }
             if let Phase::Second(matches_out) = phase {
                 let original_range = self.sema.original_range(code);
                 // We validated the range for the node when we started the match, so the placeholder
                 // probably can't fail range validation, but just to be safe...
                 self.validate_range(&original_range)?;
-                matches_out.placeholder_values.insert(
+                let resolution = path_in_node(code).and_then(|path| self.sema.resolve_path(&path));
+                self.record_placeholder(
+                    matches_out,
                     Var(placeholder.ident.to_string()),
-                    PlaceholderMatch::new(code, original_range),
-                );
+                    PlaceholderMatch::new(code, original_range, resolution),
+                )?;
             }
+            self.record_progress();
             return Ok(());
         }
         // We allow a UFCS call to match a method call, provided they resolve to the same function.
         if let Some(pattern_function) = self.rule.pattern.ufcs_function_calls.get(pattern) {
-            if let (Some(pattern), Some(code)) =
-                (ast::CallExpr::cast(pattern.clone()), ast::MethodCallExpr::cast(code.clone()))
-            {
-                return self.attempt_match_ufcs(phase, &pattern, &code, *pattern_function);
+            if let Some(pattern_call) = ast::CallExpr::cast(pattern.clone()) {
+                if self.options.ignore_explicit_self
+                    && self.omits_self_arg(&pattern_call, *pattern_function)
+                {
+                    return self.attempt_match_implicit_self(
+                        phase,
+                        &pattern_call,
+                        code,
+                        *pattern_function,
+                    );
+                }
+                if let Some(code) = ast::MethodCallExpr::cast(code.clone()) {
+                    return self.attempt_match_ufcs(phase, &pattern_call, &code, *pattern_function);
+                }
             }
         }
         if pattern.kind() != code.kind() {
@@ -212,8 +423,32 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
             SyntaxKind::RECORD_EXPR_FIELD_LIST => {
                 self.attempt_match_record_field_list(phase, pattern, code)
             }
+            SyntaxKind::RECORD_FIELD_PAT_LIST => {
+                self.attempt_match_record_field_pat_list(phase, pattern, code)
+            }
+            SyntaxKind::ASSOC_ITEM_LIST => self.attempt_match_assoc_item_list(phase, pattern, code),
+            SyntaxKind::BLOCK_EXPR => self.attempt_match_block(phase, pattern, code),
+            SyntaxKind::METHOD_CALL_EXPR => self.attempt_match_method_call(phase, pattern, code),
+            SyntaxKind::MACRO_CALL => self.attempt_match_macro_call(phase, pattern, code),
+            SyntaxKind::FN_POINTER_TYPE => self.attempt_match_fn_pointer_type(phase, pattern, code),
+            SyntaxKind::IMPL_TRAIT_TYPE => self.attempt_match_impl_trait_type(phase, pattern, code),
+            SyntaxKind::LOOP_EXPR | SyntaxKind::WHILE_EXPR | SyntaxKind::FOR_EXPR => {
+                self.attempt_match_loop(phase, pattern, code)
+            }
             SyntaxKind::TOKEN_TREE => self.attempt_match_token_tree(phase, pattern, code),
+            SyntaxKind::MATCH_ARM => self.attempt_match_match_guard(phase, pattern, code),
+            SyntaxKind::BIND_PAT => self.attempt_match_bind_pat(phase, pattern, code),
+            SyntaxKind::RANGE_EXPR => self.attempt_match_range(phase, pattern, code),
             SyntaxKind::PATH => self.attempt_match_path(phase, pattern, code),
+            SyntaxKind::USE_TREE => self.attempt_match_use_tree(phase, pattern, code),
+            SyntaxKind::USE_TREE_LIST => self.attempt_match_use_tree_list(phase, pattern, code),
+            SyntaxKind::ARRAY_EXPR | SyntaxKind::TUPLE_EXPR => {
+                self.attempt_match_array_or_tuple(phase, pattern, code)
+            }
+            SyntaxKind::TRY_EXPR => self.attempt_match_try_chain(phase, pattern, code),
+            SyntaxKind::BIN_EXPR | SyntaxKind::PREFIX_EXPR => {
+                self.attempt_match_operator(phase, pattern, code)
+            }
             _ => self.attempt_match_node_children(phase, pattern, code),
         }
     }
@@ -231,6 +466,156 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         )
     }
 
+    /// Matches an array or tuple literal. If the last element of the pattern is a variadic
+    /// placeholder, such as `$rest...`, then it matches all the elements of the code that are left
+    /// after the other elements of the pattern have been matched, possibly none.
+    fn attempt_match_array_or_tuple(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_elements: Vec<SyntaxNode> = pattern.children().collect();
+        let variadic = pattern_elements
+            .last()
+            .and_then(|last| self.get_placeholder(&SyntaxElement::Node(last.clone())))
+            .filter(|placeholder| placeholder.is_variadic);
+        let variadic = match variadic {
+            Some(variadic) => variadic,
+            None => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        if code.children_with_tokens().any(|element| element.kind() == T![;]) {
+            fail_match!("Code is a repeat expression `{}`, which has no variadic elements", code);
+        }
+        let fixed = &pattern_elements[..pattern_elements.len() - 1];
+        let code_elements: Vec<SyntaxNode> = code.children().collect();
+        if code_elements.len() < fixed.len() {
+            fail_match!(
+                "Pattern needs at least {} elements, but code has {}",
+                fixed.len(),
+                code_elements.len()
+            );
+        }
+        for (p, c) in fixed.iter().zip(&code_elements) {
+            self.attempt_match_node(phase, p, c)?;
+        }
+        if let Phase::Second(matches_out) = phase {
+            let code_range = self.sema.original_range(code);
+            let rest = &code_elements[fixed.len()..];
+            let range = match (rest.first(), rest.last()) {
+                (Some(first), Some(last)) => TextRange::new(
+                    self.sema.original_range(first).range.start(),
+                    self.sema.original_range(last).range.end(),
+                ),
+                // Nothing is left, so the placeholder is the empty range before the closing
+                // bracket.
+                _ => TextRange::empty(code_range.range.end() - TextSize::of(']')),
+            };
+            let range = FileRange { file_id: code_range.file_id, range };
+            self.validate_range(&range)?;
+            self.record_placeholder(
+                matches_out,
+                Var(variadic.ident.to_string()),
+                PlaceholderMatch::from_range(range),
+            )?;
+        }
+        self.record_progress();
+        Ok(())
+    }
+
+    /// Matches a chain of `?`-terminated method calls, such as `a.foo()?.bar()?`, against a pattern
+    /// like `$e.$stages...?`. The variadic placeholder binds one or more of the outermost stages,
+    /// preferring as many as possible, and `$e` binds whatever the first of those was called on.
+    fn attempt_match_try_chain(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_field = ast::TryExpr::cast(pattern.clone())
+            .and_then(|try_expr| try_expr.expr())
+            .and_then(|expr| match expr {
+                ast::Expr::FieldExpr(field) => Some(field),
+                _ => None,
+            });
+        let variadic = pattern_field
+            .as_ref()
+            .and_then(|field| field.name_ref())
+            .and_then(|name_ref| {
+                self.get_placeholder(&SyntaxElement::Node(name_ref.syntax().clone()))
+            })
+            .filter(|placeholder| placeholder.is_variadic);
+        let (pattern_base, variadic) =
+            match (pattern_field.and_then(|field| field.expr()), variadic) {
+                (Some(base), Some(variadic)) => (base, variadic),
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            };
+        // The method calls in the chain, outermost first.
+        let mut stages: Vec<ast::MethodCallExpr> = Vec::new();
+        let mut try_expr = ast::TryExpr::cast(code.clone());
+        while let Some(ast::Expr::MethodCallExpr(call)) = try_expr.and_then(|t| t.expr()) {
+            try_expr = match call.expr() {
+                Some(ast::Expr::TryExpr(receiver)) => Some(receiver),
+                _ => None,
+            };
+            stages.push(call);
+        }
+        if stages.is_empty() {
+            fail_match!("Code `{}` isn't a `?` applied to a method call", code);
+        }
+        use ast::ArgListOwner;
+        let stage_range = |call: &ast::MethodCallExpr| -> Option<TextRange> {
+            let start = self.sema.original_range(call.name_ref()?.syntax()).range.start();
+            let end = self.sema.original_range(call.arg_list()?.syntax()).range.end();
+            Some(TextRange::new(start, end))
+        };
+        let mut last_failure = None;
+        for num_stages in (1..=stages.len()).rev() {
+            let base = match stages[num_stages - 1].expr() {
+                Some(base) => base,
+                None => continue,
+            };
+            if let Err(failure) =
+                self.attempt_match_node(&mut Phase::First, pattern_base.syntax(), base.syntax())
+            {
+                last_failure = Some(failure);
+                continue;
+            }
+            self.attempt_match_node(phase, pattern_base.syntax(), base.syntax())?;
+            if let Phase::Second(matches_out) = phase {
+                let file_id = self.sema.original_range(code).file_id;
+                let mut ranges = Vec::new();
+                for call in stages[..num_stages].iter().rev() {
+                    match stage_range(call) {
+                        Some(range) => ranges.push(FileRange { file_id, range }),
+                        None => fail_match!("Method call `{}` is incomplete", call.syntax()),
+                    }
+                }
+                let range = FileRange {
+                    file_id,
+                    range: TextRange::new(
+                        ranges[0].range.start(),
+                        ranges[ranges.len() - 1].range.end(),
+                    ),
+                };
+                self.validate_range(&range)?;
+                let mut placeholder_match = PlaceholderMatch::from_range(range);
+                placeholder_match.stages = ranges;
+                self.record_placeholder(
+                    matches_out,
+                    Var(variadic.ident.to_string()),
+                    placeholder_match,
+                )?;
+            }
+            self.record_progress();
+            return Ok(());
+        }
+        match last_failure {
+            Some(failure) => Err(failure),
+            None => fail_match!("Code `{}` has no receiver to match `{}`", code, pattern_base),
+        }
+    }
+
     fn attempt_match_sequences(
         &self,
         phase: &mut Phase,
@@ -285,8 +670,37 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         }
         // Consume an element from the pattern and make sure it matches.
         match pattern.next() {
+            Some(SyntaxElement::Token(p))
+                if p.kind() == SyntaxKind::LIFETIME && code.kind() == SyntaxKind::LIFETIME =>
+            {
+                // A placeholder used as a label.
+                if let Some(placeholder) = self.rule.get_placeholder(&p) {
+                    if let Phase::Second(match_out) = phase {
+                        let range = original_token_range(code, self.sema);
+                        self.validate_range(&range)?;
+                        self.record_placeholder(
+                            match_out,
+                            Var(placeholder.ident.to_string()),
+                            PlaceholderMatch::from_range(range),
+                        )?;
+                    }
+                } else if p.text() != code.text() {
+                    fail_match!(
+                        "Pattern wanted lifetime '{}', but code had '{}'",
+                        p.text(),
+                        code.text()
+                    );
+                }
+            }
             Some(SyntaxElement::Token(p)) => {
-                if p.kind() != code.kind() || p.text() != code.text() {
+                let same_text = if self.options.case_convention_insensitive
+                    && p.kind() == SyntaxKind::IDENT
+                {
+                    normalize_case_convention(p.text()) == normalize_case_convention(code.text())
+                } else {
+                    p.text() == code.text()
+                };
+                if p.kind() != code.kind() || !same_text {
                     fail_match!(
                         "Pattern wanted token '{}' ({:?}), but code had token '{}' ({:?})",
                         p.text(),
@@ -295,6 +709,7 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
                         code.kind()
                     )
                 }
+                self.record_progress();
             }
             Some(SyntaxElement::Node(p)) => {
                 // Not sure if this is actually reachable.
@@ -312,6 +727,53 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         Ok(())
     }
 
+    /// Checks `constraint` like `check_constraint`, while recording the outcome in our trace.
+    fn explain_constraint(
+        &self,
+        placeholder: &Placeholder,
+        constraint: &Constraint,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        // We want the reason for failure even if we're not otherwise recording reasons.
+        let result =
+            record_match_fails_reasons_scope(true, || self.check_constraint(constraint, code));
+        let outcome = match (self.unevaluated_reason(constraint, code), &result) {
+            (Some(reason), _) => ConstraintOutcome::NotEvaluated(reason),
+            (None, Ok(())) => ConstraintOutcome::Passed,
+            (None, Err(e)) => ConstraintOutcome::Failed(e.reason.clone().unwrap_or_default()),
+        };
+        self.trace.constraint_checks.borrow_mut().push(ConstraintCheck {
+            placeholder: placeholder.ident.to_string(),
+            constraint: constraint.to_string(),
+            outcome,
+        });
+        result
+    }
+
+    /// If `constraint` can't really be evaluated for `code`, e.g. because it needs the type of
+    /// `code` and that couldn't be inferred, returns why. `check_constraint` then reports the
+    /// constraint as not holding.
+    fn unevaluated_reason(&self, constraint: &Constraint, code: &SyntaxNode) -> Option<String> {
+        match constraint {
+            Constraint::Not(sub) => self.unevaluated_reason(sub, code),
+            Constraint::IsZeroSized | Constraint::Returns(_) => {
+                let expr = ast::Expr::cast(code.clone())?;
+                match self.sema.type_of_expr(&expr) {
+                    Some(ty) if !ty.contains_unknown() => None,
+                    _ => Some(format!("The type of '{}' couldn't be inferred", code.text())),
+                }
+            }
+            Constraint::OneOf(_) => {
+                let path = path_in_node(code)?;
+                if self.sema.resolve_path(&path).is_some() {
+                    return None;
+                }
+                Some(format!("Path '{}' couldn't be resolved", path))
+            }
+            _ => None,
+        }
+    }
+
     fn check_constraint(
         &self,
         constraint: &Constraint,
@@ -321,98 +783,1312 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
             Constraint::Kind(kind) => {
                 kind.matches(code)?;
             }
+            Constraint::Parent(kind) => {
+                kind.matches(code)?;
+            }
+            Constraint::HasDefaultValue => {
+                let has_default = matches!(
+                    ast::TypeParam::cast(code.clone()),
+                    Some(param) if param.default_type().is_some()
+                );
+                if !has_default {
+                    fail_match!("Code '{}' doesn't have a default value", code.text());
+                }
+            }
+            Constraint::Arity(arity) => {
+                let elements = match code.kind() {
+                    SyntaxKind::ARRAY_EXPR
+                        if !code.children_with_tokens().any(|element| element.kind() == T![;]) =>
+                    {
+                        Some(code.children().count())
+                    }
+                    SyntaxKind::TUPLE_EXPR => Some(code.children().count()),
+                    _ => None,
+                };
+                if elements != Some(*arity) {
+                    fail_match!(
+                        "Code '{}' isn't an array or tuple literal with {} elements",
+                        code.text(),
+                        arity
+                    );
+                }
+            }
             Constraint::Not(sub) => {
                 if self.check_constraint(&*sub, code).is_ok() {
                     fail_match!("Constraint {:?} failed for '{}'", constraint, code.text());
                 }
             }
+            Constraint::IsConst => {
+                if !matches!(ast::Expr::cast(code.clone()), Some(expr) if self.is_const_expr(&expr))
+                {
+                    fail_match!("Code '{}' isn't a constant expression", code.text());
+                }
+            }
+            Constraint::IsAsync => {
+                if !matches!(self.called_function(code), Some(f) if self.is_async_fn(f)) {
+                    fail_match!("Code '{}' isn't a call of an async function", code.text());
+                }
+            }
+            Constraint::Returns(ty) => {
+                let call_type = Some(code)
+                    .filter(|code| {
+                        matches!(code.kind(), SyntaxKind::CALL_EXPR | SyntaxKind::METHOD_CALL_EXPR)
+                    })
+                    .and_then(|code| ast::Expr::cast(code.clone()))
+                    .and_then(|call| self.sema.type_of_expr(&call));
+                let call_type = match call_type {
+                    Some(call_type) => call_type,
+                    None => fail_match!("Code '{}' isn't a function or method call", code.text()),
+                };
+                if !matches!(self.rule.returns_types.get(ty), Some(expected) if call_type.is_same_type(expected))
+                {
+                    use hir::HirDisplay;
+                    let actual = call_type.display(self.sema.db);
+                    fail_match!("Call '{}' returns `{}`, not `{}`", code.text(), actual, ty);
+                }
+            }
+            Constraint::RedundantCast => {
+                if !self.is_redundant_cast_operand(code) {
+                    fail_match!("Code '{}' isn't the operand of a redundant cast", code.text());
+                }
+            }
+            Constraint::IsZeroSized => {
+                let ty =
+                    ast::Expr::cast(code.clone()).and_then(|expr| self.sema.type_of_expr(&expr));
+                if !matches!(ty, Some(ty) if ty.is_zero_sized(self.sema.db)) {
+                    fail_match!("Code '{}' doesn't have a zero-sized type", code.text());
+                }
+            }
+            Constraint::NoLocals => {
+                if let Some(path) = self.find_outside_local(code) {
+                    fail_match!("Code '{}' refers to the local `{}`", code.text(), path);
+                }
+            }
+            Constraint::InConst => {
+                if !self.is_in_const(code) {
+                    fail_match!("Code '{}' isn't in a const context", code.text());
+                }
+            }
+            Constraint::IntInRange { min, max } => {
+                let value = match int_literal_value(code) {
+                    Some(value) => value,
+                    None => fail_match!(
+                        "Code '{}' isn't an integer literal. Only literals, possibly negated, are \
+                         evaluated",
+                        code.text()
+                    ),
+                };
+                if matches!(min, Some(min) if value < *min)
+                    || matches!(max, Some(max) if value > *max)
+                {
+                    fail_match!("Integer {} is out of range", value);
+                }
+            }
+            Constraint::InLoop { through_closures } => {
+                if !self.is_in_loop(code, *through_closures) {
+                    fail_match!("Code '{}' isn't inside a loop", code.text());
+                }
+            }
+            Constraint::NoAllocHeuristic => {
+                if let Some(allocation) = self.find_allocation(code) {
+                    fail_match!("Code '{}' may allocate in '{}'", code.text(), allocation.text());
+                }
+            }
+            Constraint::LiteralSuffix(suffix) => {
+                let actual = match as_literal(code).map(|literal| literal.kind()) {
+                    Some(ast::LiteralKind::IntNumber { suffix })
+                    | Some(ast::LiteralKind::FloatNumber { suffix }) => suffix,
+                    _ => fail_match!("Code '{}' isn't a numeric literal", code.text()),
+                };
+                if &actual != suffix {
+                    fail_match!("Literal '{}' doesn't have suffix {:?}", code.text(), suffix);
+                }
+            }
+            Constraint::OneOfOperators(_) => {
+                // Operators are tokens rather than nodes, so are checked by
+                // `attempt_match_operator` instead.
+                fail_match!("Code '{}' isn't an operator", code.text());
+            }
+            Constraint::OneOf(paths) => {
+                let resolution = path_in_node(code).and_then(|path| self.sema.resolve_path(&path));
+                let is_listed = matches!(resolution, Some(resolution) if paths
+                    .iter()
+                    .any(|path| self.rule.one_of_paths.get(path) == Some(&resolution)));
+                if !is_listed {
+                    fail_match!("Code '{}' isn't one of {}", code.text(), paths.join(", "));
+                }
+            }
+            Constraint::HasAttr(path) => {
+                let has_attr = matches!(self.resolved_attrs(code), Some(attrs) if attrs
+                    .iter()
+                    .any(|attr| attr.path().to_string() == path.as_str()));
+                if !has_attr {
+                    fail_match!("Code '{}' doesn't refer to an item with #[{}]", code.text(), path);
+                }
+            }
+            Constraint::IsNamed(name) => {
+                let is_named = matches!(
+                    only_name(SyntaxElement::Node(code.clone())),
+                    Some(t) if t.text() == name
+                );
+                if !is_named {
+                    fail_match!("Code '{}' isn't the name `{}`", code.text(), name);
+                }
+            }
         }
         Ok(())
     }
 
-    /// Paths are matched based on whether they refer to the same thing, even if they're written
-    /// differently.
-    fn attempt_match_path(
+    /// Returns the attributes of the definition that `code` refers to. `code` may be a path, or a
+    /// call of a function or method.
+    fn resolved_attrs(&self, code: &SyntaxNode) -> Option<hir::Attrs> {
+        if let Some(call) = ast::MethodCallExpr::cast(code.clone()) {
+            return Some(self.sema.resolve_method_call(&call)?.attrs(self.sema.db));
+        }
+        let def: hir::AttrDef = match self.sema.resolve_path(&callee_path(code)?)? {
+            hir::PathResolution::Def(hir::ModuleDef::Module(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::Function(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::Adt(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::EnumVariant(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::Const(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::Static(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::Trait(it)) => it.into(),
+            hir::PathResolution::Def(hir::ModuleDef::TypeAlias(it)) => it.into(),
+            hir::PathResolution::AssocItem(hir::AssocItem::Function(it)) => it.into(),
+            hir::PathResolution::AssocItem(hir::AssocItem::Const(it)) => it.into(),
+            hir::PathResolution::AssocItem(hir::AssocItem::TypeAlias(it)) => it.into(),
+            hir::PathResolution::Macro(it) => it.into(),
+            _ => return None,
+        };
+        Some(def.attrs(self.sema.db))
+    }
+
+    /// Returns the first path within `code` that refers to a local variable or `self` that's
+    /// defined outside of `code`.
+    fn find_outside_local(&self, code: &SyntaxNode) -> Option<ast::Path> {
+        let code_range = self.sema.original_range(code);
+        code.descendants().filter_map(ast::PathExpr::cast).filter_map(|e| e.path()).find(|path| {
+            let local = match self.sema.resolve_path(path) {
+                Some(hir::PathResolution::Local(local)) => local,
+                _ => return false,
+            };
+            let source = local.source(self.sema.db);
+            let definition = source.value.as_ref().either(|pat| pat.syntax(), |p| p.syntax());
+            let definition_range = hir::original_range(self.sema.db, source.with_value(definition));
+            definition_range.file_id != code_range.file_id
+                || !code_range.range.contains_range(definition_range.range)
+        })
+    }
+
+    /// Returns the first call within `code` to a function or macro that's known to allocate.
+    fn find_allocation(&self, code: &SyntaxNode) -> Option<SyntaxNode> {
+        use crate::resolving::{ALLOCATING_MACRO_CRATES, KNOWN_ALLOCATING_MACROS};
+        use hir::AsAssocItem;
+        let defs = &self.rule.allocating_defs;
+        let db = self.sema.db;
+        code.descendants().find(|node| {
+            if matches!(self.called_function(node), Some(f) if defs.functions.contains(&f)) {
+                return true;
+            }
+            if let Some(call) = ast::CallExpr::cast(node.clone()) {
+                let path = match call.expr() {
+                    Some(ast::Expr::PathExpr(path_expr)) => path_expr.path(),
+                    _ => None,
+                };
+                let function_name = path.as_ref().and_then(|p| p.segment()?.name_ref());
+                let qualifier = path.and_then(|p| p.qualifier());
+                if let (Some(function_name), Some(qualifier)) = (function_name, qualifier) {
+                    if let Some(hir::PathResolution::Def(hir::ModuleDef::Adt(adt))) =
+                        self.sema.resolve_path(&qualifier)
+                    {
+                        let functions = defs.types.get(&adt).copied().unwrap_or_default();
+                        return functions.contains(&function_name.text().as_str());
+                    }
+                }
+            } else if let Some(call) = ast::MethodCallExpr::cast(node.clone()) {
+                let function = self.sema.resolve_method_call(&call);
+                let container = function.and_then(|f| f.as_assoc_item(db)).map(|i| i.container(db));
+                if let Some(hir::AssocItemContainer::Trait(t)) = container {
+                    return defs.traits.contains(&t);
+                }
+            } else if let Some(macro_call) = ast::MacroCall::cast(node.clone()) {
+                if let Some(mac) = self.sema.resolve_macro_call(&macro_call) {
+                    if defs.macros.contains(&mac) {
+                        return true;
+                    }
+                    let name = mac.name(db).map(|name| name.to_string()).unwrap_or_default();
+                    let krate = mac.module(db).and_then(|m| m.krate().display_name(db));
+                    return KNOWN_ALLOCATING_MACROS.contains(&name.as_str())
+                        && ALLOCATING_MACRO_CRATES.contains(&krate.unwrap_or_default().as_str());
+                }
+            }
+            false
+        })
+    }
+
+    /// Returns whether `code` is inside a loop within the same function. The iterable of a `for`
+    /// loop is only evaluated once, so doesn't count as being inside the loop.
+    fn is_in_loop(&self, code: &SyntaxNode, through_closures: bool) -> bool {
+        let in_loop = self.find_in_ancestors(code, |ancestor, child| match ancestor.kind() {
+            SyntaxKind::LOOP_EXPR | SyntaxKind::WHILE_EXPR => Some(true),
+            SyntaxKind::FOR_EXPR => {
+                let is_iterable = matches!(
+                    ast::ForExpr::cast(ancestor.clone()).and_then(|f| f.iterable()),
+                    Some(iterable) if iterable.syntax() == child
+                );
+                if is_iterable {
+                    None
+                } else {
+                    Some(true)
+                }
+            }
+            SyntaxKind::LAMBDA_EXPR if !through_closures => Some(false),
+            kind if ast::Item::can_cast(kind) => Some(false),
+            _ => None,
+        });
+        in_loop.unwrap_or(false)
+    }
+
+    /// Returns whether `code` is evaluated at compile time, because it's within a `const fn`, the
+    /// initializer of a `const` or `static`, an array length or an enum discriminant.
+    fn is_in_const(&self, code: &SyntaxNode) -> bool {
+        let in_const = self.find_in_ancestors(code, |ancestor, child| {
+            let is_child =
+                |expr: &Option<ast::Expr>| matches!(expr, Some(e) if e.syntax() == child);
+            match ancestor.kind() {
+                SyntaxKind::CONST | SyntaxKind::STATIC => Some(true),
+                SyntaxKind::FN => Some(ast::Fn::cast(ancestor.clone())?.const_token().is_some()),
+                SyntaxKind::ARRAY_TYPE
+                    if is_child(&ast::ArrayType::cast(ancestor.clone())?.expr()) =>
+                {
+                    Some(true)
+                }
+                SyntaxKind::ARRAY_EXPR => match ast::ArrayExpr::cast(ancestor.clone())?.kind() {
+                    ast::ArrayExprKind::Repeat { repeat, .. } if is_child(&repeat) => Some(true),
+                    _ => None,
+                },
+                SyntaxKind::VARIANT if is_child(&ast::Variant::cast(ancestor.clone())?.expr()) => {
+                    Some(true)
+                }
+                kind if ast::Item::can_cast(kind) => Some(false),
+                _ => None,
+            }
+        });
+        in_const.unwrap_or(false)
+    }
+
+    /// Walks up the ancestors of `code`, including through macro calls, calling `f` with each
+    /// ancestor and the child of it that the walk came from. Stops at the first result from `f`.
+    fn find_in_ancestors<T>(
         &self,
-        phase: &mut Phase,
-        pattern: &SyntaxNode,
         code: &SyntaxNode,
-    ) -> Result<(), MatchFailed> {
-        if let Some(pattern_resolved) = self.rule.pattern.resolved_paths.get(pattern) {
-            let pattern_path = ast::Path::cast(pattern.clone()).unwrap();
-            let code_path = ast::Path::cast(code.clone()).unwrap();
-            if let (Some(pattern_segment), Some(code_segment)) =
-                (pattern_path.segment(), code_path.segment())
-            {
-                // Match everything within the segment except for the name-ref, which is handled
-                // separately via comparing what the path resolves to below.
-                self.attempt_match_opt(
-                    phase,
-                    pattern_segment.type_arg_list(),
-                    code_segment.type_arg_list(),
-                )?;
-                self.attempt_match_opt(
-                    phase,
-                    pattern_segment.param_list(),
-                    code_segment.param_list(),
-                )?;
+        mut f: impl FnMut(&SyntaxNode, &SyntaxNode) -> Option<T>,
+    ) -> Option<T> {
+        let mut child = code.clone();
+        for ancestor in self.sema.ancestors_with_macros(code.clone()).skip(1) {
+            if let Some(result) = f(&ancestor, &child) {
+                return Some(result);
             }
-            if matches!(phase, Phase::Second(_)) {
-                let resolution = self
-                    .sema
-                    .resolve_path(&code_path)
-                    .ok_or_else(|| match_error!("Failed to resolve path `{}`", code.text()))?;
-                if pattern_resolved.resolution != resolution {
-                    fail_match!("Pattern had path `{}` code had `{}`", pattern.text(), code.text());
-                }
+            child = ancestor;
+        }
+        None
+    }
+
+    /// Returns whether `code` is being cast to the type that it already has.
+    fn is_redundant_cast_operand(&self, code: &SyntaxNode) -> bool {
+        let cast = match code.parent().and_then(ast::CastExpr::cast) {
+            Some(cast) => cast,
+            None => return false,
+        };
+        let operand = match cast.expr() {
+            Some(operand) if operand.syntax() == code => operand,
+            _ => return false,
+        };
+        match (self.sema.type_of_expr(&operand), self.sema.type_of_expr(&cast.into())) {
+            (Some(from), Some(to)) => !from.contains_unknown() && from == to,
+            _ => false,
+        }
+    }
+
+    /// Returns whether `expr` could be evaluated at compile time. This is approximate. We accept
+    /// literals, references to constants, calls to const functions and tuple struct or variant
+    /// constructors and operators applied to any of these.
+    fn is_const_expr(&self, expr: &ast::Expr) -> bool {
+        use ast::ArgListOwner;
+        let is_const = |expr: Option<ast::Expr>| matches!(expr, Some(e) if self.is_const_expr(&e));
+        let all_const =
+            |mut exprs: ast::AstChildren<ast::Expr>| exprs.all(|e| self.is_const_expr(&e));
+        let args_const =
+            |args: Option<ast::ArgList>| matches!(args, Some(a) if all_const(a.args()));
+        match expr {
+            ast::Expr::Literal(_) => true,
+            ast::Expr::ParenExpr(e) => is_const(e.expr()),
+            ast::Expr::PrefixExpr(e) => {
+                matches!(e.op_kind(), Some(op) if op != ast::PrefixOp::Deref) && is_const(e.expr())
             }
-        } else {
-            return self.attempt_match_node_children(phase, pattern, code);
+            ast::Expr::BinExpr(e) => {
+                matches!(e.op_kind(), Some(op) if !op.is_assignment())
+                    && is_const(e.lhs())
+                    && is_const(e.rhs())
+            }
+            ast::Expr::TupleExpr(e) => all_const(e.exprs()),
+            ast::Expr::ArrayExpr(e) => all_const(e.exprs()),
+            ast::Expr::PathExpr(e) => matches!(
+                e.path().and_then(|path| self.sema.resolve_path(&path)),
+                Some(hir::PathResolution::Def(hir::ModuleDef::Const(_)))
+                    | Some(hir::PathResolution::AssocItem(hir::AssocItem::Const(_)))
+                    | Some(hir::PathResolution::Def(hir::ModuleDef::EnumVariant(_)))
+                    | Some(hir::PathResolution::Def(hir::ModuleDef::Adt(hir::Adt::Struct(_))))
+            ),
+            ast::Expr::CallExpr(call) => {
+                let callee = match call.expr() {
+                    Some(ast::Expr::PathExpr(callee)) => callee.path(),
+                    _ => None,
+                };
+                let callee_is_const = match callee.and_then(|path| self.sema.resolve_path(&path)) {
+                    Some(hir::PathResolution::Def(hir::ModuleDef::Function(f)))
+                    | Some(hir::PathResolution::AssocItem(hir::AssocItem::Function(f))) => {
+                        self.is_const_fn(f)
+                    }
+                    Some(hir::PathResolution::Def(hir::ModuleDef::EnumVariant(_)))
+                    | Some(hir::PathResolution::Def(hir::ModuleDef::Adt(_))) => true,
+                    _ => false,
+                };
+                callee_is_const && args_const(call.arg_list())
+            }
+            ast::Expr::MethodCallExpr(call) => {
+                matches!(self.sema.resolve_method_call(call), Some(f) if self.is_const_fn(f))
+                    && is_const(call.expr())
+                    && args_const(call.arg_list())
+            }
+            _ => false,
         }
-        Ok(())
     }
 
-    fn attempt_match_opt<T: AstNode>(
+    /// Returns the function that `code` calls, or that it refers to if it's a path.
+    fn called_function(&self, code: &SyntaxNode) -> Option<hir::Function> {
+        if let Some(call) = ast::MethodCallExpr::cast(code.clone()) {
+            return self.sema.resolve_method_call(&call);
+        }
+        match self.sema.resolve_path(&callee_path(code)?)? {
+            hir::PathResolution::Def(hir::ModuleDef::Function(function))
+            | hir::PathResolution::AssocItem(hir::AssocItem::Function(function)) => Some(function),
+            _ => None,
+        }
+    }
+
+    fn is_const_fn(&self, function: hir::Function) -> bool {
+        use hir::HasSource;
+        function.source(self.sema.db).value.const_token().is_some()
+    }
+
+    fn is_async_fn(&self, function: hir::Function) -> bool {
+        use hir::HasSource;
+        function.source(self.sema.db).value.async_token().is_some()
+    }
+
+    /// Matches loops. A pattern without a label can match a loop with a label, provided that the
+    /// label isn't used within the loop, since then it can be dropped.
+    fn attempt_match_loop(
         &self,
         phase: &mut Phase,
-        pattern: Option<T>,
-        code: Option<T>,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
     ) -> Result<(), MatchFailed> {
-        match (pattern, code) {
-            (Some(p), Some(c)) => self.attempt_match_node(phase, &p.syntax(), &c.syntax()),
-            (None, None) => Ok(()),
-            (Some(p), None) => fail_match!("Pattern `{}` had nothing to match", p.syntax().text()),
-            (None, Some(c)) => {
-                fail_match!("Nothing in pattern to match code `{}`", c.syntax().text())
+        let label = |node: &SyntaxNode| node.children().find(|c| c.kind() == SyntaxKind::LABEL);
+        let mut code_children = code.children_with_tokens();
+        if let (None, Some(code_label)) = (label(pattern), label(code)) {
+            let label_name = code_label
+                .children_with_tokens()
+                .find(|e| e.kind() == SyntaxKind::LIFETIME)
+                .map(|e| e.to_string());
+            let is_used = code.descendants_with_tokens().any(|e| {
+                e.kind() == SyntaxKind::LIFETIME
+                    && matches!(
+                        e.parent().map(|p| p.kind()),
+                        Some(SyntaxKind::BREAK_EXPR) | Some(SyntaxKind::CONTINUE_EXPR)
+                    )
+                    && Some(e.to_string()) == label_name
+            });
+            if is_used {
+                fail_match!("Pattern has no label, but code's label '{}' is used", code_label);
             }
+            code_children.find(|e| e.kind() == SyntaxKind::LABEL);
         }
+        self.attempt_match_sequences(phase, PatternIterator::new(pattern), code_children)
     }
 
-    /// We want to allow the records to match in any order, so we have special matching logic for
-    /// them.
-    fn attempt_match_record_field_list(
+    /// Matches a match arm, which may have a guard, as in `$p if $guard => $body`. If the pattern
+    /// has a guard, then so must the code. A pattern without a guard can match an arm with one,
+    /// except when replacing, since the guard would then be lost.
+    fn attempt_match_match_guard(
         &self,
         phase: &mut Phase,
         pattern: &SyntaxNode,
         code: &SyntaxNode,
     ) -> Result<(), MatchFailed> {
-        // Build a map keyed by field name.
-        let mut fields_by_name = FxHashMap::default();
-        for child in code.children() {
-            if let Some(record) = ast::RecordExprField::cast(child.clone()) {
-                if let Some(name) = record.field_name() {
-                    fields_by_name.insert(name.text().clone(), child.clone());
+        let (pattern_arm, code_arm) =
+            match (ast::MatchArm::cast(pattern.clone()), ast::MatchArm::cast(code.clone())) {
+                (Some(p), Some(c)) => (p, c),
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            };
+        match (pattern_arm.guard(), code_arm.guard()) {
+            (Some(_), None) => {
+                fail_match!("Pattern has a guard, but match arm `{}` doesn't", code.text())
+            }
+            (None, Some(code_guard)) => {
+                if self.rule.template.is_some() {
+                    fail_match!(
+                        "Pattern has no guard, so replacing `{}` would drop `{}`",
+                        code.text(),
+                        code_guard.syntax().text()
+                    );
                 }
+                self.attempt_match_opt(phase, pattern_arm.pat(), code_arm.pat())?;
+                self.attempt_match_opt(phase, pattern_arm.expr(), code_arm.expr())
             }
+            _ => self.attempt_match_node_children(phase, pattern, code),
         }
-        for p in pattern.children_with_tokens() {
-            if let SyntaxElement::Node(p) = p {
-                if let Some(name_element) = p.first_child_or_token() {
-                    if self.get_placeholder(&name_element).is_some() {
-                        // If the pattern is using placeholders for field names then order
-                        // independence doesn't make sense. Fall back to regular ordered
-                        // matching.
-                        return self.attempt_match_node_children(phase, pattern, code);
+    }
+
+    /// Matches range expressions, which may be missing either operand or both, as in `..b`, `a..`
+    /// or `..`. The operands are matched by which side of the operator they're on, so a
+    /// placeholder never matches a missing operand, and `..` only matches `..`, not `..=`.
+    fn attempt_match_range(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let (pattern_range, code_range) =
+            match (ast::RangeExpr::cast(pattern.clone()), ast::RangeExpr::cast(code.clone())) {
+                (Some(p), Some(c)) => (p, c),
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            };
+        let op_text = |range: &ast::RangeExpr| range.op_token().map(|op| op.text().clone());
+        if op_text(&pattern_range) != op_text(&code_range) {
+            fail_match!(
+                "Pattern `{}` and code `{}` have different range operators",
+                pattern.text(),
+                code.text()
+            );
+        }
+        let sides = [
+            ("start", pattern_range.start(), code_range.start()),
+            ("end", pattern_range.end(), code_range.end()),
+        ];
+        for (side, pattern_operand, code_operand) in sides.iter() {
+            match (pattern_operand, code_operand) {
+                (Some(p), Some(c)) => self.attempt_match_node(phase, p.syntax(), c.syntax())?,
+                (None, None) => {}
+                (Some(_), None) => fail_match!("Code `{}` has no {} to match", code.text(), side),
+                (None, Some(_)) => {
+                    fail_match!("Pattern has no {}, but code `{}` does", side, code.text())
+                }
+            }
+        }
+        self.record_progress();
+        Ok(())
+    }
+
+    /// Matches `impl Trait` types. If the pattern is `impl $T` and the code has just one bound,
+    /// which is a trait, then `$T` is bound to the trait's path, together with what it resolves to.
+    /// So repeated occurrences of `$T` match the same trait, even if it's written differently.
+    /// Otherwise, such as when the code has several bounds, `$T` is bound to all of them.
+    fn attempt_match_impl_trait_type(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let bound_list = |node: &SyntaxNode| {
+            ast::ImplTraitType::cast(node.clone()).and_then(|ty| ty.type_bound_list())
+        };
+        let pattern_bounds = match bound_list(pattern) {
+            Some(bounds)
+                if self
+                    .get_placeholder(&SyntaxElement::Node(bounds.syntax().clone()))
+                    .is_some() =>
+            {
+                bounds
+            }
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        let mut code_bounds = match bound_list(code) {
+            Some(bounds) => bounds.bounds(),
+            None => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        let trait_path_type = match (code_bounds.next(), code_bounds.next()) {
+            (Some(bound), None) if bound.question_mark_token().is_none() => match bound.kind() {
+                ast::TypeBoundKind::PathType(path_type) => path_type,
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            },
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        if matches!(phase, Phase::Second(_)) {
+            let resolution = trait_path_type.path().and_then(|path| self.sema.resolve_path(&path));
+            if !matches!(resolution, Some(hir::PathResolution::Def(hir::ModuleDef::Trait(_)))) {
+                fail_match!("Failed to resolve trait `{}`", trait_path_type.syntax().text());
+            }
+        }
+        self.attempt_match_node(phase, pattern_bounds.syntax(), trait_path_type.syntax())
+    }
+
+    /// Paths are matched based on whether they refer to the same thing, even if they're written
+    /// differently.
+    fn attempt_match_path(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        if let Some(segments) = self.rule.pattern.wildcard_paths.get(pattern) {
+            return self.attempt_match_wildcard_path(phase, segments, pattern, code);
+        }
+        if self.rule.pattern.unresolved_paths.contains(pattern) {
+            // The path in the rule doesn't resolve, so all we can do is compare it as written.
+            self.attempt_match_node_children(phase, pattern, code)?;
+            if let Phase::Second(match_out) = phase {
+                match_out.is_heuristic = true;
+            }
+        } else if let Some(pattern_resolved) = self.rule.pattern.resolved_paths.get(pattern) {
+            let pattern_path = ast::Path::cast(pattern.clone()).unwrap();
+            let code_path = ast::Path::cast(code.clone()).unwrap();
+            if let (Some(pattern_segment), Some(code_segment)) =
+                (pattern_path.segment(), code_path.segment())
+            {
+                // Match everything within the segment except for the name-ref, which is handled
+                // separately via comparing what the path resolves to below.
+                self.attempt_match_opt(
+                    phase,
+                    pattern_segment.type_arg_list(),
+                    code_segment.type_arg_list(),
+                )?;
+                self.attempt_match_opt(
+                    phase,
+                    pattern_segment.param_list(),
+                    code_segment.param_list(),
+                )?;
+            }
+            if let Phase::Second(match_out) = phase {
+                match self.sema.resolve_path(&code_path) {
+                    Some(resolution) => {
+                        if pattern_resolved.resolution != resolution {
+                            fail_match!(
+                                "Pattern had path `{}` code had `{}`",
+                                pattern.text(),
+                                code.text()
+                            );
+                        }
+                    }
+                    None if self.rule.heuristic_fallback => {
+                        let name = |path: &ast::Path| {
+                            path.segment()
+                                .and_then(|segment| segment.name_ref())
+                                .map(|name_ref| name_ref.text().clone())
+                        };
+                        if name(&pattern_path).is_none() || name(&pattern_path) != name(&code_path)
+                        {
+                            fail_match!(
+                                "Code path `{}` doesn't resolve and isn't named like `{}`",
+                                code.text(),
+                                pattern.text()
+                            );
+                        }
+                        match_out.is_heuristic = true;
+                    }
+                    None => fail_match!("Failed to resolve path `{}`", code.text()),
+                }
+            }
+        } else {
+            return self.attempt_match_node_children(phase, pattern, code);
+        }
+        Ok(())
+    }
+
+    /// Matches a path containing `*` or `**` wildcards, such as `std::*::spawn`, against the full
+    /// path of the item that the code resolves to, starting with the name of its crate. How the
+    /// code wrote the path, e.g. via a `use`, doesn't matter. Type arguments of the last segment
+    /// are matched as usual.
+    fn attempt_match_wildcard_path(
+        &self,
+        phase: &mut Phase,
+        segments: &[PathPatternSegment],
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_path = ast::Path::cast(pattern.clone()).unwrap();
+        let code_path = ast::Path::cast(code.clone()).unwrap();
+        if let (Some(pattern_segment), Some(code_segment)) =
+            (pattern_path.segment(), code_path.segment())
+        {
+            self.attempt_match_opt(
+                phase,
+                pattern_segment.type_arg_list(),
+                code_segment.type_arg_list(),
+            )?;
+        }
+        if let Phase::Second(match_out) = phase {
+            let code_segments = match self.sema.resolve_path(&code_path) {
+                Some(resolution) => match definition_path(self.sema.db, &resolution) {
+                    Some(code_segments) => code_segments,
+                    None => fail_match!("Code path `{}` doesn't resolve to an item", code.text()),
+                },
+                None if self.rule.heuristic_fallback => {
+                    match_out.is_heuristic = true;
+                    written_path(&code_path)
+                }
+                None => fail_match!("Failed to resolve path `{}`", code.text()),
+            };
+            if !wildcard_path_matches(segments, &code_segments) {
+                fail_match!(
+                    "Pattern had path `{}` code had `{}`",
+                    wildcard_path_text(segments),
+                    code_segments.join("::")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches a binary or prefix expression whose operator in the pattern is a placeholder, as in
+    /// `$a ${op:one_of(==, !=)} $b`. The placeholder is bound to the code's operator, which must be
+    /// one of those listed. Expressions with ordinary operators are matched as usual.
+    fn attempt_match_operator(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let op_token = |node: &SyntaxNode| match ast::BinExpr::cast(node.clone()) {
+            Some(bin_expr) => bin_expr.op_token(),
+            None => ast::PrefixExpr::cast(node.clone()).and_then(|prefix| prefix.op_token()),
+        };
+        let (pattern_op, code_op) = match (op_token(pattern), op_token(code)) {
+            (Some(pattern_op), Some(code_op)) => (pattern_op, code_op),
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        let placeholder = match self.rule.get_placeholder(&pattern_op) {
+            Some(placeholder) => placeholder,
+            None => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        for constraint in &placeholder.constraints {
+            if let Constraint::OneOfOperators(operators) = constraint {
+                if !operators.iter().any(|op| op == code_op.text()) {
+                    fail_match!(
+                        "Operator `{}` isn't one of {}",
+                        code_op.text(),
+                        operators.join(", ")
+                    );
+                }
+            }
+        }
+        if let (Some(pattern_bin), Some(code_bin)) =
+            (ast::BinExpr::cast(pattern.clone()), ast::BinExpr::cast(code.clone()))
+        {
+            self.attempt_match_opt(phase, pattern_bin.lhs(), code_bin.lhs())?;
+            self.attempt_match_opt(phase, pattern_bin.rhs(), code_bin.rhs())?;
+        } else if let (Some(pattern_prefix), Some(code_prefix)) =
+            (ast::PrefixExpr::cast(pattern.clone()), ast::PrefixExpr::cast(code.clone()))
+        {
+            self.attempt_match_opt(phase, pattern_prefix.expr(), code_prefix.expr())?;
+        }
+        if let Phase::Second(match_out) = phase {
+            // The operator is a token, which we can't map out of a macro expansion directly, so
+            // we find it relative to the expression, provided that was copied unchanged.
+            let range = self.sema.original_range(code);
+            if range.range.len() != code.text_range().len() {
+                fail_match!("Can't bind operator `{}` within a macro expansion", code_op.text());
+            }
+            let op_start =
+                range.range.start() + (code_op.text_range().start() - code.text_range().start());
+            let op_range = FileRange {
+                file_id: range.file_id,
+                range: TextRange::at(op_start, code_op.text_range().len()),
+            };
+            self.record_placeholder(
+                match_out,
+                Var(placeholder.ident.to_string()),
+                PlaceholderMatch::from_range(op_range),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Matches a use tree. A glob import, as in `use $m::*;`, only matches other glob imports. The
+    /// path that the glob imports from is matched as usual, so `$m` is bound to it. A placeholder
+    /// at the end of the pattern's path, as in `use old::$rest;`, is bound to whatever follows the
+    /// part of the code's path that matches the rest of the pattern, which may be a nested tree, a
+    /// glob or a renamed import. Other use trees are matched as usual.
+    fn attempt_match_use_tree(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_tree = ast::UseTree::cast(pattern.clone()).unwrap();
+        let code_tree = ast::UseTree::cast(code.clone()).unwrap();
+        if let Some((prefix, placeholder)) =
+            use_tree_rest_placeholder(&self.rule.pattern, &pattern_tree)
+        {
+            return self.attempt_match_use_tree_rest(phase, &prefix, placeholder, &code_tree);
+        }
+        match (pattern_tree.star_token(), code_tree.star_token()) {
+            (Some(_), Some(_)) => {
+                self.attempt_match_opt(phase, pattern_tree.path(), code_tree.path())
+            }
+            (Some(_), None) => {
+                fail_match!("Pattern is a glob import, but code `{}` isn't", code.text())
+            }
+            (None, Some(_)) => {
+                fail_match!("Code `{}` is a glob import, but the pattern isn't", code.text())
+            }
+            (None, None) => self.attempt_match_node_children(phase, pattern, code),
+        }
+    }
+
+    fn attempt_match_use_tree_rest(
+        &self,
+        phase: &mut Phase,
+        pattern_prefix: &ast::Path,
+        placeholder: &Placeholder,
+        code_tree: &ast::UseTree,
+    ) -> Result<(), MatchFailed> {
+        // The candidates for what `pattern_prefix` matches are the qualifiers of the code's path,
+        // shortest first, and the whole path if something follows it.
+        let mut code_prefixes = Vec::new();
+        if let Some(path) = code_tree.path() {
+            if code_tree.star_token().is_some() || code_tree.use_tree_list().is_some() {
+                code_prefixes.push(path.clone());
+            }
+            code_prefixes
+                .extend(std::iter::successors(path.qualifier(), |qualifier| qualifier.qualifier()));
+        }
+        let code_prefix = code_prefixes
+            .into_iter()
+            .rev()
+            .find(|code_prefix| {
+                self.attempt_match_node(
+                    &mut Phase::First,
+                    pattern_prefix.syntax(),
+                    code_prefix.syntax(),
+                )
+                .is_ok()
+            })
+            .ok_or_else(|| {
+                match_error!(
+                    "Use tree `{}` doesn't start with `{}`",
+                    code_tree.syntax().text(),
+                    pattern_prefix.syntax().text()
+                )
+            })?;
+        self.attempt_match_node(phase, pattern_prefix.syntax(), code_prefix.syntax())?;
+        if let Phase::Second(match_out) = phase {
+            // The rest starts after the `::` that follows the prefix.
+            let mut after_prefix = code_prefix
+                .syntax()
+                .siblings_with_tokens(ra_syntax::Direction::Next)
+                .skip(1)
+                .filter(|element| !element.kind().is_trivia());
+            let rest_start = match (after_prefix.next(), after_prefix.next()) {
+                (Some(separator), Some(rest)) if separator.kind() == T![::] => {
+                    rest.text_range().start()
+                }
+                _ => fail_match!("Use tree `{}` is incomplete", code_tree.syntax()),
+            };
+            let tree_range = self.sema.original_range(code_tree.syntax());
+            if tree_range.range.len() != code_tree.syntax().text_range().len() {
+                fail_match!("Use tree `{}` came partly from a macro", code_tree.syntax());
+            }
+            let offset = rest_start - code_tree.syntax().text_range().start();
+            let range = FileRange {
+                file_id: tree_range.file_id,
+                range: TextRange::new(tree_range.range.start() + offset, tree_range.range.end()),
+            };
+            self.record_placeholder(
+                match_out,
+                Var(placeholder.ident.to_string()),
+                PlaceholderMatch::from_range(range),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The trees within braces in a use declaration can be in any order, so each tree in the
+    /// pattern matches whichever tree in the code it can. If the pattern has a placeholder for a
+    /// whole tree, we fall back to ordered matching, since that placeholder would match anything.
+    fn attempt_match_use_tree_list(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_trees: Vec<_> = pattern.children().filter_map(ast::UseTree::cast).collect();
+        let code_trees: Vec<_> = code.children().filter_map(ast::UseTree::cast).collect();
+        if pattern_trees.len() != code_trees.len() {
+            fail_match!(
+                "Pattern has {} use trees within braces, but code `{}` has {}",
+                pattern_trees.len(),
+                code.text(),
+                code_trees.len()
+            );
+        }
+        if pattern_trees
+            .iter()
+            .any(|tree| self.get_placeholder(&SyntaxElement::Node(tree.syntax().clone())).is_some())
+        {
+            return self.attempt_match_node_children(phase, pattern, code);
+        }
+        let pattern_trees: Vec<_> =
+            pattern_trees.iter().map(|tree| tree.syntax().clone()).collect();
+        let code_trees: Vec<_> = code_trees.iter().map(|tree| tree.syntax().clone()).collect();
+        self.attempt_match_unordered(phase, &pattern_trees, &code_trees, &mut Vec::new(), &|tree| {
+            match_error!("No use tree in `{}` matched `{}`", code.text(), tree.text())
+        })
+    }
+
+    fn attempt_match_opt<T: AstNode>(
+        &self,
+        phase: &mut Phase,
+        pattern: Option<T>,
+        code: Option<T>,
+    ) -> Result<(), MatchFailed> {
+        match (pattern, code) {
+            (Some(p), Some(c)) => self.attempt_match_node(phase, &p.syntax(), &c.syntax()),
+            (None, None) => Ok(()),
+            (Some(p), None) => fail_match!("Pattern `{}` had nothing to match", p.syntax().text()),
+            (None, Some(c)) => {
+                fail_match!("Nothing in pattern to match code `{}`", c.syntax().text())
+            }
+        }
+    }
+
+    /// Matches a token that may be left out, such as `mut` or `unsafe`. If the pattern has the
+    /// token, then so must the code. If the pattern doesn't, then neither may the code, unless
+    /// `code_may_add` is set.
+    fn attempt_match_opt_token(
+        &self,
+        pattern: Option<SyntaxToken>,
+        code: Option<SyntaxToken>,
+        code_may_add: bool,
+    ) -> Result<(), MatchFailed> {
+        match (pattern, code) {
+            (Some(p), None) => fail_match!("Pattern has `{}`, but the code doesn't", p.text()),
+            (None, Some(c)) if !code_may_add => {
+                fail_match!("Code has `{}`, but the pattern doesn't", c.text())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Matches a binding in a pattern, such as `ref mut x`. A pattern without `ref` or `mut` can
+    /// match a binding with them, except when replacing, since they would then be lost.
+    fn attempt_match_bind_pat(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        use ast::NameOwner;
+        let (pattern_pat, code_pat) =
+            match (ast::BindPat::cast(pattern.clone()), ast::BindPat::cast(code.clone())) {
+                (Some(p), Some(c)) => (p, c),
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            };
+        let code_may_add = self.rule.template.is_none();
+        self.attempt_match_opt_token(pattern_pat.ref_token(), code_pat.ref_token(), code_may_add)?;
+        self.attempt_match_opt_token(pattern_pat.mut_token(), code_pat.mut_token(), code_may_add)?;
+        self.attempt_match_opt(phase, pattern_pat.name(), code_pat.name())?;
+        self.attempt_match_opt(phase, pattern_pat.pat(), code_pat.pat())
+    }
+
+    /// Matches a macro call. A macro call in item position includes its trailing `;`, whereas one
+    /// in expression position doesn't, so we compare just the path and the token tree. That way a
+    /// pattern like `m!($x)` matches the call regardless of where it's used.
+    fn attempt_match_macro_call(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        use ast::NameOwner;
+        let (pattern_call, code_call) =
+            match (ast::MacroCall::cast(pattern.clone()), ast::MacroCall::cast(code.clone())) {
+                // Macro definitions, such as `macro_rules! foo {}`, have a name and get regular
+                // matching.
+                (Some(p), Some(c)) if p.name().is_none() && c.name().is_none() => (p, c),
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            };
+        self.attempt_match_opt(phase, pattern_call.path(), code_call.path())?;
+        self.attempt_match_opt(phase, pattern_call.token_tree(), code_call.token_tree())
+    }
+
+    /// Matches a function pointer type, such as `fn(x: i32) -> u8`. Parameter names are optional in
+    /// these types and don't affect what the type is, so a parameter with a name matches one
+    /// without. If the pattern names a parameter with a placeholder and the code doesn't name it,
+    /// then the placeholder is bound to `_`.
+    fn attempt_match_fn_pointer_type(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let (pattern_fn, code_fn) = match (
+            ast::FnPointerType::cast(pattern.clone()),
+            ast::FnPointerType::cast(code.clone()),
+        ) {
+            (Some(p), Some(c)) => (p, c),
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        self.attempt_match_opt_token(pattern_fn.unsafe_token(), code_fn.unsafe_token(), false)?;
+        self.attempt_match_opt(phase, pattern_fn.abi(), code_fn.abi())?;
+        let pattern_params: Vec<ast::Param> =
+            pattern_fn.param_list().map(|list| list.params().collect()).unwrap_or_default();
+        let code_params: Vec<ast::Param> =
+            code_fn.param_list().map(|list| list.params().collect()).unwrap_or_default();
+        if pattern_params.len() != code_params.len() {
+            fail_match!(
+                "Pattern has {} parameter(s), code has {}",
+                pattern_params.len(),
+                code_params.len()
+            );
+        }
+        for (pattern_param, code_param) in pattern_params.iter().zip(&code_params) {
+            self.attempt_match_opt_token(
+                pattern_param.dotdotdot_token(),
+                code_param.dotdotdot_token(),
+                false,
+            )?;
+            match (pattern_param.pat(), code_param.pat()) {
+                (Some(pattern_pat), None) => {
+                    let placeholder = self
+                        .get_placeholder(&SyntaxElement::Node(pattern_pat.syntax().clone()))
+                        .filter(|placeholder| placeholder.constraints.is_empty());
+                    match placeholder {
+                        Some(placeholder) => {
+                            if let Phase::Second(match_out) = phase {
+                                let range = FileRange {
+                                    file_id: self.sema.original_range(code).file_id,
+                                    range: TextRange::empty(
+                                        code_param.syntax().text_range().start(),
+                                    ),
+                                };
+                                self.record_placeholder(
+                                    match_out,
+                                    Var(placeholder.ident.to_string()),
+                                    PlaceholderMatch::from_inferred_text(range, "_".to_owned()),
+                                )?;
+                            }
+                        }
+                        None if matches!(pattern_pat, ast::Pat::PlaceholderPat(_)) => {}
+                        None => fail_match!(
+                            "Pattern names parameter `{}`, but code doesn't name it",
+                            pattern_pat.syntax().text()
+                        ),
+                    }
+                }
+                (pattern_pat, code_pat) if pattern_pat.is_some() => {
+                    self.attempt_match_opt(phase, pattern_pat, code_pat)?
+                }
+                // The pattern doesn't name the parameter, so any name is fine.
+                _ => {}
+            }
+            self.attempt_match_opt(phase, pattern_param.ty(), code_param.ty())?;
+        }
+        self.attempt_match_opt(phase, pattern_fn.ret_type(), code_fn.ret_type())
+    }
+
+    /// Matches a method call. If enabled, type arguments in the pattern can match a call where they
+    /// were inferred, in which case placeholders in the type arguments are bound to the inferred
+    /// types.
+    fn attempt_match_method_call(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        use ast::ArgListOwner;
+        let (pattern_call, code_call) = match (
+            ast::MethodCallExpr::cast(pattern.clone()),
+            ast::MethodCallExpr::cast(code.clone()),
+        ) {
+            (Some(p), Some(c)) => (p, c),
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        let type_args = match pattern_call.type_arg_list() {
+            Some(type_args)
+                if self.options.infer_type_args && code_call.type_arg_list().is_none() =>
+            {
+                type_args
+            }
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        self.attempt_match_opt(phase, pattern_call.expr(), code_call.expr())?;
+        self.attempt_match_opt(phase, pattern_call.name_ref(), code_call.name_ref())?;
+        self.attempt_match_opt(phase, pattern_call.arg_list(), code_call.arg_list())?;
+        if let Phase::Second(match_out) = phase {
+            let inferred = self.infer_type_args(&code_call)?;
+            if inferred.len() != type_args.type_args().count() {
+                fail_match!(
+                    "Pattern has a different number of type arguments to `{}`",
+                    code.text()
+                );
+            }
+            // Placeholders are bound to an empty range where the type arguments would go.
+            let offset = code_call
+                .name_ref()
+                .map_or(code.text_range().end(), |name_ref| name_ref.syntax().text_range().end());
+            let range = FileRange {
+                file_id: self.sema.original_range(code).file_id,
+                range: TextRange::empty(offset),
+            };
+            for (type_arg, inferred_type) in type_args.type_args().zip(inferred) {
+                let placeholder = type_arg
+                    .ty()
+                    .and_then(|ty| self.get_placeholder(&SyntaxElement::Node(ty.syntax().clone())))
+                    .ok_or_else(|| {
+                        match_error!("Type argument `{}` isn't a placeholder", type_arg.syntax())
+                    })?;
+                let inferred_type = inferred_type.ok_or_else(|| {
+                    match_error!("Couldn't infer type argument `{}`", type_arg.syntax())
+                })?;
+                self.record_placeholder(
+                    match_out,
+                    Var(placeholder.ident.to_string()),
+                    PlaceholderMatch::from_inferred_text(range, inferred_type),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the type arguments of the method called by `call`, rendered as code. A type argument
+    /// can only be inferred if it's the method's return type or the type of one of its parameters,
+    /// otherwise it's None.
+    fn infer_type_args(
+        &self,
+        call: &ast::MethodCallExpr,
+    ) -> Result<Vec<Option<String>>, MatchFailed> {
+        use ast::{ArgListOwner, GenericParamsOwner};
+        use hir::{HasSource, HirDisplay};
+        let function = self
+            .sema
+            .resolve_method_call(call)
+            .ok_or_else(|| match_error!("Failed to resolve method `{}`", call.syntax()))?;
+        let module = self
+            .sema
+            .scope(call.syntax())
+            .module()
+            .ok_or_else(|| match_error!("Method call isn't in a module"))?;
+        let source = function.source(self.sema.db).value;
+        let type_params: Vec<String> = source
+            .generic_param_list()
+            .map(|params| params.type_params().map(|param| param.syntax().text().to_string()))
+            .into_iter()
+            .flatten()
+            .collect();
+        let render = |ty: hir::Type| -> Option<String> {
+            if ty.contains_unknown() {
+                return None;
+            }
+            ty.display_source_code(self.sema.db, module.into()).ok()
+        };
+        let is_param = |ty: Option<ast::Type>, name: &str| -> bool {
+            matches!(ty, Some(ast::Type::PathType(path)) if path.syntax().text() == name)
+        };
+        let params: Vec<ast::Param> =
+            source.param_list().map(|list| list.params().collect()).unwrap_or_default();
+        let args: Vec<ast::Expr> =
+            call.arg_list().map(|list| list.args().collect()).unwrap_or_default();
+        Ok(type_params
+            .iter()
+            .map(|name| {
+                if is_param(source.ret_type().and_then(|ret| ret.ty()), name) {
+                    return self.sema.type_of_expr(&ast::Expr::from(call.clone())).and_then(render);
+                }
+                params
+                    .iter()
+                    .zip(&args)
+                    .find(|(param, _)| is_param(param.ty(), name))
+                    .and_then(|(_, arg)| self.sema.type_of_expr(arg))
+                    .and_then(render)
+            })
+            .collect())
+    }
+
+    /// Matches a block. If the pattern block contains only a placeholder, then it binds to the
+    /// statements and tail expression of the code block, however many there are. e.g.
+    /// `async { $body }` will match any async block. Matches within the body are nested within the
+    /// placeholder, like those within any other placeholder.
+    fn attempt_match_block(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let (pattern_block, code_block) =
+            match (ast::BlockExpr::cast(pattern.clone()), ast::BlockExpr::cast(code.clone())) {
+                (Some(p), Some(c)) => (p, c),
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            };
+        let placeholder = match pattern_block.expr() {
+            Some(expr) if pattern_block.statements().next().is_none() => {
+                self.get_placeholder(&SyntaxElement::Node(expr.syntax().clone()))
+            }
+            _ => None,
+        };
+        let is_single_expr =
+            code_block.statements().next().is_none() && code_block.expr().is_some();
+        // Within a macro expansion, the range of the block's body wouldn't correspond to a range in
+        // the file, so we only use regular matching there.
+        let placeholder = match placeholder {
+            Some(placeholder) if !is_single_expr && self.restrict_range.is_none() => placeholder,
+            _ => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        if !placeholder.constraints.is_empty() {
+            fail_match!(
+                "Placeholder `${}` has constraints, so can't match multiple statements",
+                placeholder.ident
+            );
+        }
+        if let Phase::Second(match_out) = phase {
+            let body: Vec<SyntaxNode> = code_block
+                .statements()
+                .map(|stmt| stmt.syntax().clone())
+                .chain(code_block.expr().map(|expr| expr.syntax().clone()))
+                .collect();
+            let start = match body.first() {
+                Some(first) => first.text_range().start(),
+                None => code_block
+                    .l_curly_token()
+                    .map_or(code.text_range().start(), |curly| curly.text_range().end()),
+            };
+            let end = body.last().map_or(start, |last| last.text_range().end());
+            let file_id = self.sema.original_range(code).file_id;
+            let range = FileRange { file_id, range: TextRange::new(start, end) };
+            self.validate_range(&range)?;
+            self.record_placeholder(
+                match_out,
+                Var(placeholder.ident.to_string()),
+                PlaceholderMatch { is_block_body: true, ..PlaceholderMatch::from_range(range) },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Matches the members of an impl independently of order. The code may have additional members
+    /// that aren't mentioned in the pattern.
+    fn attempt_match_assoc_item_list(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_members = match pattern.parent().and_then(|impl_| parsing::impl_members(&impl_))
+        {
+            Some(members) => members,
+            None => return self.attempt_match_node_children(phase, pattern, code),
+        };
+        let code_members: Vec<_> =
+            code.children().filter(|member| ast::AssocItem::can_cast(member.kind())).collect();
+        let mut used = Vec::new();
+        self.attempt_match_unordered(
+            phase,
+            &pattern_members,
+            &code_members,
+            &mut used,
+            &|pattern_member| {
+                match_error!("No member of the impl matched `{}`", pattern_member.text())
+            },
+        )?;
+        if let Phase::Second(match_out) = phase {
+            for index in used {
+                match_out.matched_impl_members.push(self.sema.original_range(&code_members[index]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches each of `patterns` with a different one of `code`, in any order. If a pairing fails,
+    /// even in the second phase, or leaves nothing for a later pattern, then other pairings are
+    /// tried. On success, the index in `code` that each pattern was paired with is appended to
+    /// `used`, which is otherwise left as it was. If nothing passes the first phase for some
+    /// pattern, `no_match` gives the reason.
+    fn attempt_match_unordered(
+        &self,
+        phase: &mut Phase,
+        patterns: &[SyntaxNode],
+        code: &[SyntaxNode],
+        used: &mut Vec<usize>,
+        no_match: &dyn Fn(&SyntaxNode) -> MatchFailed,
+    ) -> Result<(), MatchFailed> {
+        let (pattern, rest) = match patterns.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let mut failure = None;
+        for (index, candidate) in code.iter().enumerate() {
+            // Try the cheap checks first, so that we don't record anything for code that doesn't
+            // match.
+            if used.contains(&index)
+                || self.attempt_match_node(&mut Phase::First, pattern, candidate).is_err()
+            {
+                continue;
+            }
+            used.push(index);
+            let result = match phase {
+                Phase::First => self.attempt_match_unordered(phase, rest, code, used, no_match),
+                // What the pairing records is only kept if the rest can be paired too.
+                Phase::Second(match_out) => {
+                    let mut trial = (**match_out).clone();
+                    let result = self
+                        .attempt_match_node(&mut Phase::Second(&mut trial), pattern, candidate)
+                        .and_then(|()| {
+                            let mut phase = Phase::Second(&mut trial);
+                            self.attempt_match_unordered(&mut phase, rest, code, used, no_match)
+                        });
+                    if result.is_ok() {
+                        **match_out = trial;
+                    }
+                    result
+                }
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    used.pop();
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| no_match(pattern)))
+    }
+
+    /// We want to allow the records to match in any order, so we have special matching logic for
+    /// them.
+    fn attempt_match_record_field_list(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        // Build a map keyed by field name.
+        let mut fields_by_name = FxHashMap::default();
+        for child in code.children() {
+            if let Some(record) = ast::RecordExprField::cast(child.clone()) {
+                if let Some(name) = record.field_name() {
+                    fields_by_name.insert(name.text().clone(), child.clone());
+                }
+            }
+        }
+        for p in pattern.children_with_tokens() {
+            if let SyntaxElement::Node(p) = p {
+                if let Some(name_element) = p.first_child_or_token() {
+                    if self.get_placeholder(&name_element).is_some() {
+                        // If the pattern is using placeholders for field names then order
+                        // independence doesn't make sense. Fall back to regular ordered
+                        // matching.
+                        return self.attempt_match_node_children(phase, pattern, code);
                     }
                     if let Some(ident) = only_ident(name_element) {
                         let code_record = fields_by_name.remove(ident.text()).ok_or_else(|| {
@@ -436,6 +2112,79 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         Ok(())
     }
 
+    /// Record patterns, e.g. in destructured function parameters, can also have their fields in any
+    /// order. A field written in shorthand form, `x`, is equivalent to `x: x`, so in either the
+    /// pattern or the code, it's matched as if it were written that way. This lets `P { x: $a }`
+    /// bind `$a` to the `x` in `P { x }`.
+    fn attempt_match_record_field_pat_list(
+        &self,
+        phase: &mut Phase,
+        pattern: &SyntaxNode,
+        code: &SyntaxNode,
+    ) -> Result<(), MatchFailed> {
+        let pattern_list = ast::RecordFieldPatList::cast(pattern.clone()).unwrap();
+        let code_list = ast::RecordFieldPatList::cast(code.clone()).unwrap();
+        self.attempt_match_opt_token(pattern_list.dotdot_token(), code_list.dotdot_token(), false)?;
+        let mut pattern_fields = Vec::new();
+        for field in record_pat_fields(&pattern_list) {
+            match field {
+                Some((name, pat))
+                    if self.get_placeholder(&SyntaxElement::Node(name.clone())).is_none() =>
+                {
+                    pattern_fields.push((name, pat))
+                }
+                // If the pattern is using placeholders for field names then order independence
+                // doesn't make sense. Fall back to regular ordered matching.
+                _ => return self.attempt_match_node_children(phase, pattern, code),
+            }
+        }
+        let mut code_fields_by_name = FxHashMap::default();
+        for (name, pat) in record_pat_fields(&code_list).flatten() {
+            code_fields_by_name.insert(name.text().to_string(), pat);
+        }
+        for (name, pattern_pat) in pattern_fields {
+            let code_pat =
+                code_fields_by_name.remove(&name.text().to_string()).ok_or_else(|| {
+                    match_error!(
+                        "Pattern has record pattern field '{}', but code doesn't",
+                        name.text()
+                    )
+                })?;
+            self.attempt_match_node(phase, &pattern_pat, &code_pat)?;
+        }
+        if let Some(unmatched_field) = code_fields_by_name.keys().next() {
+            fail_match!(
+                "{} field(s) of a record pattern failed to match, starting with {}",
+                code_fields_by_name.len(),
+                unmatched_field
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds the tokens in `element` to the count of those that a placeholder within a token tree
+    /// has matched, failing if that exceeds `MatchOptions::placeholder_max_tokens`.
+    fn consume_placeholder_tokens(
+        &self,
+        consumed_tokens: &mut usize,
+        element: &SyntaxElement,
+    ) -> Result<(), MatchFailed> {
+        if let Some(max) = self.options.placeholder_max_tokens {
+            *consumed_tokens += match element {
+                SyntaxElement::Token(token) => !token.kind().is_trivia() as usize,
+                SyntaxElement::Node(node) => node
+                    .descendants_with_tokens()
+                    .filter_map(|e| e.into_token())
+                    .filter(|t| !t.kind().is_trivia())
+                    .count(),
+            };
+            if *consumed_tokens > max {
+                fail_match!("Placeholder would match more than {} tokens", max);
+            }
+        }
+        Ok(())
+    }
+
     /// Outside of token trees, a placeholder can only match a single AST node, whereas in a token
     /// tree it can match a sequence of tokens. Note, that this code will only be used when the
     /// pattern matches the macro invocation. For matches within the macro call, we'll already have
@@ -458,6 +2207,8 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
                         SyntaxElement::Node(n) => n.first_token(),
                     })
                     .map(|p| p.text().to_string());
+                let mut consumed_tokens = 0;
+                self.consume_placeholder_tokens(&mut consumed_tokens, &child)?;
                 let first_matched_token = child.clone();
                 let mut last_matched_token = child;
                 // Read code tokens util we reach one equal to the next token from our pattern
@@ -482,10 +2233,12 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
                             }
                         }
                     };
+                    self.consume_placeholder_tokens(&mut consumed_tokens, &next)?;
                     last_matched_token = next;
                 }
                 if let Phase::Second(match_out) = phase {
-                    match_out.placeholder_values.insert(
+                    self.record_placeholder(
+                        match_out,
                         Var(placeholder.ident.to_string()),
                         PlaceholderMatch::from_range(FileRange {
                             file_id: self.sema.original_range(code).file_id,
@@ -493,7 +2246,7 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
                                 .text_range()
                                 .cover(last_matched_token.text_range()),
                         }),
-                    );
+                    )?;
                 }
                 continue;
             }
@@ -552,12 +2305,218 @@ impl<'db, 'sema> Matcher<'db, 'sema> {
         }
     }
 
+    /// Returns whether `call` passes one argument fewer than `function` takes, leaving out `self`.
+    fn omits_self_arg(&self, call: &ast::CallExpr, function: hir::Function) -> bool {
+        use ast::ArgListOwner;
+        let num_args = call.arg_list().map_or(0, |arg_list| arg_list.args().count());
+        function.has_self_param(self.sema.db) && num_args + 1 == function.params(self.sema.db).len()
+    }
+
+    /// Matches a call that leaves out the `self` argument, such as `S::foo($x)`, against either
+    /// `self.foo(x)` or `S::foo(self, x)`, provided that the code calls the same function.
+    fn attempt_match_implicit_self(
+        &self,
+        phase: &mut Phase,
+        pattern: &ast::CallExpr,
+        code: &SyntaxNode,
+        pattern_function: hir::Function,
+    ) -> Result<(), MatchFailed> {
+        use ast::ArgListOwner;
+        let (code_function, receiver, mut code_args) =
+            if let Some(method_call) = ast::MethodCallExpr::cast(code.clone()) {
+                let function = self.sema.resolve_method_call(&method_call);
+                let args = method_call.arg_list().map(|arg_list| arg_list.args());
+                (function, method_call.expr(), args)
+            } else if let Some(call) = ast::CallExpr::cast(code.clone()) {
+                let function = match call.expr() {
+                    Some(ast::Expr::PathExpr(path_expr)) => {
+                        match path_expr.path().and_then(|path| self.sema.resolve_path(&path)) {
+                            Some(hir::PathResolution::AssocItem(hir::AssocItem::Function(f))) => {
+                                Some(f)
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                let mut args = call.arg_list().map(|arg_list| arg_list.args());
+                let receiver = args.as_mut().and_then(|args| args.next());
+                (function, receiver, args)
+            } else {
+                fail_match!("Code `{}` isn't a call", code.text());
+            };
+        if code_function != Some(pattern_function) {
+            fail_match!("Code `{}` doesn't call the same function as the pattern", code.text());
+        }
+        if !matches!(&receiver, Some(receiver) if receiver.syntax().text() == "self") {
+            fail_match!("Code `{}` doesn't call the function on `self`", code.text());
+        }
+        let mut pattern_args = pattern.arg_list().map(|arg_list| arg_list.args());
+        loop {
+            let p = pattern_args.as_mut().and_then(|args| args.next());
+            let c = code_args.as_mut().and_then(|args| args.next());
+            match (p, c) {
+                (None, None) => return Ok(()),
+                (p, c) => self.attempt_match_opt(phase, p, c)?,
+            }
+        }
+    }
+
+    /// Records what a placeholder matched. If the same placeholder already matched something else,
+    /// then the two must be consistent, as checked by `check_placeholder_consistency`.
+    fn record_placeholder(
+        &self,
+        match_out: &mut Match,
+        var: Var,
+        value: PlaceholderMatch,
+    ) -> Result<(), MatchFailed> {
+        if let Some(previous) = match_out.placeholder_values.get(&var) {
+            return self.check_placeholder_consistency(&var, previous, &value);
+        }
+        match_out.placeholder_values.insert(var, value);
+        Ok(())
+    }
+
+    /// Checks that two occurrences of the placeholder `var` bound equivalent code. Paths are
+    /// equivalent if they resolve to the same thing, even if they're written differently. Anything
+    /// else must be the same, ignoring whitespace and comments.
+    fn check_placeholder_consistency(
+        &self,
+        var: &Var,
+        previous: &PlaceholderMatch,
+        value: &PlaceholderMatch,
+    ) -> Result<(), MatchFailed> {
+        if let (Some(previous_resolution), Some(resolution)) =
+            (&previous.resolution, &value.resolution)
+        {
+            if previous_resolution != resolution {
+                fail_match!("Paths bound to `${}` resolve to different things", var.0);
+            }
+            return Ok(());
+        }
+        let same = match (&previous.node, &value.node) {
+            (Some(previous_node), Some(node)) => {
+                non_trivia_tokens(previous_node).eq(non_trivia_tokens(node))
+            }
+            _ => self.binding_text(previous) == self.binding_text(value),
+        };
+        if !same {
+            fail_match!("Code bound to `${}` differs between occurrences", var.0);
+        }
+        Ok(())
+    }
+
+    /// Returns the text that `value` is bound to, without whitespace.
+    fn binding_text(&self, value: &PlaceholderMatch) -> String {
+        use ra_db::SourceDatabaseExt;
+        let text = match &value.inferred_text {
+            Some(text) => text.clone(),
+            None => self.sema.db.file_text(value.range.file_id)[value.range.range].to_owned(),
+        };
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
     fn get_placeholder(&self, element: &SyntaxElement) -> Option<&Placeholder> {
         only_ident(element.clone()).and_then(|ident| self.rule.get_placeholder(&ident))
     }
 }
 
 impl Match {
+    /// Picks a name for each fresh identifier in `template`. The name is the one given, followed by
+    /// the lowest number, if any, that makes it different from every name in scope at the match.
+    fn choose_fresh_names(
+        &mut self,
+        template: &ResolvedPattern,
+        sema: &Semantics<ra_ide_db::RootDatabase>,
+    ) {
+        let mut bases: Vec<&SmolStr> = template
+            .placeholders_by_stand_in
+            .values()
+            .filter(|p| p.is_fresh)
+            .map(|p| &p.ident)
+            .collect();
+        if bases.is_empty() {
+            return;
+        }
+        bases.sort();
+        bases.dedup();
+        let mut taken = FxHashSet::default();
+        sema.scope(&self.matched_node).process_all_names(&mut |name, _| {
+            taken.insert(name.to_string());
+        });
+        for base in bases {
+            let mut candidate = base.to_string();
+            let mut suffix = 1;
+            while taken.contains(&candidate) {
+                candidate = format!("{}{}", base, suffix);
+                suffix += 1;
+            }
+            taken.insert(candidate.clone());
+            self.fresh_names.insert(base.clone(), candidate);
+        }
+    }
+
+    /// Works out the value of each `${match.<name>}` within string literals in `template`.
+    fn compute_match_variables(
+        &mut self,
+        template: &ResolvedPattern,
+        sema: &Semantics<ra_ide_db::RootDatabase>,
+    ) {
+        use ast::NameOwner;
+        let strings = template
+            .node
+            .descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+            .filter(|token| matches!(token.kind(), SyntaxKind::STRING | SyntaxKind::RAW_STRING));
+        for token in strings {
+            for (_, name) in parsing::match_variables(token.text()) {
+                if self.match_variables.contains_key(name) {
+                    continue;
+                }
+                let value = match name {
+                    "module" => self.module_path(sema),
+                    "file" => self.file_stem(sema.db),
+                    "fn" => sema
+                        .ancestors_with_macros(self.matched_node.clone())
+                        .skip(1)
+                        .find_map(ast::Fn::cast)
+                        .and_then(|function| function.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_default(),
+                    _ => continue,
+                };
+                self.match_variables.insert(SmolStr::new(name), value);
+            }
+        }
+    }
+
+    /// Returns the path of the module containing the match, starting with the name of its crate,
+    /// as `module_path!()` would give.
+    fn module_path(&self, sema: &Semantics<ra_ide_db::RootDatabase>) -> String {
+        let module = match sema.scope(&self.matched_node).module() {
+            Some(module) => module,
+            None => return String::new(),
+        };
+        let crate_name = module.krate().display_name(sema.db).unwrap_or_else(|| "crate".to_owned());
+        let mut path = vec![crate_name];
+        let modules = module.path_to_root(sema.db);
+        path.extend(modules.iter().rev().filter_map(|m| m.name(sema.db)).map(|n| n.to_string()));
+        path.join("::")
+    }
+
+    /// Returns the name of the file containing the match, without its extension.
+    fn file_stem(&self, db: &ra_ide_db::RootDatabase) -> String {
+        use ra_db::SourceDatabaseExt;
+        let file_id = self.range.file_id;
+        let source_root = db.source_root(db.file_source_root(file_id));
+        let path = match source_root.path_for_file(&file_id) {
+            Some(path) => path.to_string(),
+            None => return String::new(),
+        };
+        let stem = std::path::Path::new(&path).file_stem().map(|stem| stem.to_string_lossy());
+        stem.map(|stem| stem.into_owned()).unwrap_or_default()
+    }
+
     fn render_template_paths(
         &mut self,
         template: &ResolvedPattern,
@@ -567,6 +2526,55 @@ impl Match {
             .scope(&self.matched_node)
             .module()
             .ok_or_else(|| match_error!("Matched node isn't in a module"))?;
+        self.render_template_paths_for_module(template, module, sema)?;
+        self.check_impl_trait_replacement(template, sema)
+    }
+
+    /// Checks that the replacement would still type check if the template is a call to a function
+    /// that returns `impl Trait`. The type of the replacement is then opaque, so it can't be used
+    /// where the surrounding code requires a particular type, e.g. an argument to a function whose
+    /// parameter isn't generic. An opaque type can't be named, so adding a type annotation wouldn't
+    /// help. Instead we reject the match, giving the reason.
+    fn check_impl_trait_replacement(
+        &self,
+        template: &ResolvedPattern,
+        sema: &Semantics<ra_ide_db::RootDatabase>,
+    ) -> Result<(), MatchFailed> {
+        let call = match ast::CallExpr::cast(template.node.clone()) {
+            Some(call) => call,
+            None => return Ok(()),
+        };
+        let function = match call.expr() {
+            Some(ast::Expr::PathExpr(callee)) => callee
+                .path()
+                .and_then(|path| template.resolved_paths.get(path.syntax()))
+                .and_then(|resolved_path| match resolved_path.resolution {
+                    hir::PathResolution::Def(hir::ModuleDef::Function(f)) => Some(f),
+                    _ => None,
+                }),
+            _ => None,
+        };
+        let function = match function {
+            Some(function) if returns_impl_trait(function, sema) => function,
+            _ => return Ok(()),
+        };
+        if let Some(required_type) = type_required_by_context(&self.matched_node, sema) {
+            fail_match!(
+                "Replacement calls `{}`, which returns `impl Trait`, but `{}` is required here",
+                function.name(sema.db),
+                required_type
+            );
+        }
+        Ok(())
+    }
+
+    /// Renders each path in `template` as it would need to be written in `module`.
+    pub(crate) fn render_template_paths_for_module(
+        &mut self,
+        template: &ResolvedPattern,
+        module: hir::Module,
+        sema: &Semantics<ra_ide_db::RootDatabase>,
+    ) -> Result<(), MatchFailed> {
         for (path, resolved_path) in &template.resolved_paths {
             if let hir::PathResolution::Def(module_def) = resolved_path.resolution {
                 let mod_path = module.find_use_path(sema.db, module_def).ok_or_else(|| {
@@ -579,6 +2587,83 @@ impl Match {
     }
 }
 
+fn returns_impl_trait(function: hir::Function, sema: &Semantics<ra_ide_db::RootDatabase>) -> bool {
+    use hir::HasSource;
+    let source = function.source(sema.db).value;
+    matches!(source.ret_type().and_then(|ret| ret.ty()), Some(ast::Type::ImplTraitType(_)))
+}
+
+/// Returns the type that the surrounding code requires `code` to have, if it requires a particular
+/// type. We only look at the simple cases of a `let` statement with a type, an argument to a
+/// function and the tail expression of a function. A type that contains `impl Trait` or mentions a
+/// type parameter of the function doesn't count, since those accept any suitable type.
+fn type_required_by_context(
+    code: &SyntaxNode,
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+) -> Option<String> {
+    use ast::{GenericParamsOwner, NameOwner};
+    use hir::HasSource;
+    let parent = code.parent()?;
+    // Type parameters of the function's impl or trait count too.
+    let is_generic = |ty: &ast::Type, function: &ast::Fn| {
+        let type_params: Vec<SmolStr> = function
+            .syntax()
+            .ancestors()
+            .filter_map(|node| {
+                ast::Fn::cast(node.clone())
+                    .and_then(|it| it.generic_param_list())
+                    .or_else(|| ast::Impl::cast(node.clone())?.generic_param_list())
+                    .or_else(|| ast::Trait::cast(node)?.generic_param_list())
+            })
+            .flat_map(|params| params.type_params())
+            .filter_map(|param| param.name())
+            .map(|name| name.text().clone())
+            .collect();
+        ty.syntax().descendants().any(|node| match ast::NameRef::cast(node.clone()) {
+            Some(name_ref) => type_params.contains(name_ref.text()),
+            None => node.kind() == SyntaxKind::IMPL_TRAIT_TYPE,
+        })
+    };
+    if let Some(let_stmt) = ast::LetStmt::cast(parent.clone()) {
+        return match let_stmt.ty() {
+            Some(ast::Type::ImplTraitType(_)) | None => None,
+            Some(ty) => Some(ty.syntax().text().to_string()),
+        };
+    }
+    if let Some(arg_list) = ast::ArgList::cast(parent.clone()) {
+        let index = arg_list.args().position(|arg| arg.syntax() == code)?;
+        let function = match ast::Expr::cast(arg_list.syntax().parent()?)? {
+            ast::Expr::CallExpr(call) => {
+                match sema.resolve_path(&path_in_node(call.expr()?.syntax())?)? {
+                    hir::PathResolution::Def(hir::ModuleDef::Function(f)) => f,
+                    hir::PathResolution::AssocItem(hir::AssocItem::Function(f)) => f,
+                    _ => return None,
+                }
+            }
+            ast::Expr::MethodCallExpr(call) => sema.resolve_method_call(&call)?,
+            _ => return None,
+        };
+        let source = function.source(sema.db).value;
+        let ty = source.param_list()?.params().nth(index)?.ty()?;
+        if is_generic(&ty, &source) {
+            return None;
+        }
+        return Some(ty.syntax().text().to_string());
+    }
+    if let Some(block) = ast::BlockExpr::cast(parent) {
+        if block.expr()?.syntax() != code {
+            return None;
+        }
+        let function = ast::Fn::cast(block.syntax().parent()?)?;
+        let ty = function.ret_type()?.ty()?;
+        if is_generic(&ty, &function) {
+            return None;
+        }
+        return Some(ty.syntax().text().to_string());
+    }
+    None
+}
+
 impl Phase<'_> {
     fn next_non_trivial(&mut self, code_it: &mut SyntaxElementChildren) -> Option<SyntaxElement> {
         loop {
@@ -612,9 +2697,9 @@ pub(crate) fn record_match_fails_reasons_scope<F, T>(debug_active: bool, f: F) -
 where
     F: Fn() -> T,
 {
-    RECORDING_MATCH_FAIL_REASONS.with(|c| c.set(debug_active));
+    let was_active = RECORDING_MATCH_FAIL_REASONS.with(|c| c.replace(debug_active));
     let res = f();
-    RECORDING_MATCH_FAIL_REASONS.with(|c| c.set(false));
+    RECORDING_MATCH_FAIL_REASONS.with(|c| c.set(was_active));
     res
 }
 
@@ -631,12 +2716,32 @@ fn recording_match_fail_reasons() -> bool {
 }
 
 impl PlaceholderMatch {
-    fn new(node: &SyntaxNode, range: FileRange) -> Self {
-        Self { node: Some(node.clone()), range, inner_matches: SsrMatches::default() }
+    fn new(node: &SyntaxNode, range: FileRange, resolution: Option<hir::PathResolution>) -> Self {
+        Self {
+            node: Some(node.clone()),
+            range,
+            resolution,
+            inferred_text: None,
+            inner_matches: SsrMatches::default(),
+            stages: Vec::new(),
+            is_block_body: false,
+        }
+    }
+
+    pub(crate) fn from_range(range: FileRange) -> Self {
+        Self {
+            node: None,
+            range,
+            resolution: None,
+            inferred_text: None,
+            inner_matches: SsrMatches::default(),
+            stages: Vec::new(),
+            is_block_body: false,
+        }
     }
 
-    fn from_range(range: FileRange) -> Self {
-        Self { node: None, range, inner_matches: SsrMatches::default() }
+    fn from_inferred_text(range: FileRange, text: String) -> Self {
+        Self { inferred_text: Some(text), ..Self::from_range(range) }
     }
 }
 
@@ -645,8 +2750,18 @@ impl NodeKind {
         let ok = match self {
             Self::Literal => {
                 mark::hit!(literal_constraint);
-                ast::Literal::can_cast(node.kind())
+                as_literal(node).is_some()
             }
+            Self::NumericLiteral => matches!(
+                as_literal(node).map(|literal| literal.kind()),
+                Some(ast::LiteralKind::IntNumber { .. })
+                    | Some(ast::LiteralKind::FloatNumber { .. })
+            ),
+            Self::StringLiteral => matches!(
+                as_literal(node).map(|literal| literal.kind()),
+                Some(ast::LiteralKind::String)
+            ),
+            Self::FieldAccess => ast::FieldExpr::can_cast(node.kind()),
         };
         if !ok {
             fail_match!("Code '{}' isn't of kind {:?}", node.text(), self);
@@ -655,8 +2770,215 @@ impl NodeKind {
     }
 }
 
+impl ParentKind {
+    fn matches(&self, node: &SyntaxNode) -> Result<(), MatchFailed> {
+        let parent_kind = node.parent().map(|parent| parent.kind());
+        let ok = match self {
+            Self::Condition => parent_kind == Some(SyntaxKind::CONDITION),
+            Self::Argument => parent_kind == Some(SyntaxKind::ARG_LIST),
+            Self::Statement => parent_kind == Some(SyntaxKind::EXPR_STMT),
+        };
+        if !ok {
+            fail_match!("Code '{}' isn't directly within a {:?}", node.text(), self);
+        }
+        Ok(())
+    }
+}
+
+/// Returns `code` as a literal. That includes a const generic argument that's a literal, such as
+/// the `5` in `Foo::<5>`, which is a bare token rather than a literal node, so is parsed again.
+fn as_literal(code: &SyntaxNode) -> Option<ast::Literal> {
+    if code.kind() != SyntaxKind::CONST_ARG {
+        return ast::Literal::cast(code.clone());
+    }
+    let token = code.first_token().filter(|token| token.kind().is_literal())?;
+    match ast::Expr::parse(token.text()) {
+        Ok(ast::Expr::Literal(literal)) => Some(literal),
+        _ => None,
+    }
+}
+
+/// Returns the value of `code` if it's an integer literal, or a negated integer literal.
+fn int_literal_value(code: &SyntaxNode) -> Option<i128> {
+    if let Some(prefix_expr) = ast::PrefixExpr::cast(code.clone()) {
+        if prefix_expr.op_kind()? != ast::PrefixOp::Neg {
+            return None;
+        }
+        return int_literal_value(prefix_expr.expr()?.syntax())?.checked_neg();
+    }
+    let literal = as_literal(code)?;
+    let suffix = match literal.kind() {
+        ast::LiteralKind::IntNumber { suffix } => suffix,
+        _ => return None,
+    };
+    let token = literal.token();
+    let text = token.text();
+    let text = text[..text.len() - suffix.map_or(0, |suffix| suffix.len())].replace('_', "");
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") => (&text[2..], 16),
+        Some("0o") => (&text[2..], 8),
+        Some("0b") => (&text[2..], 2),
+        _ => (text.as_str(), 10),
+    };
+    i128::from_str_radix(digits, radix).ok()
+}
+
+/// Returns the name and pattern of each field of a record pattern. For shorthand fields, such as
+/// `x` or `ref mut x`, the pattern is the binding, `x` is the name. Fields that we can't make sense
+/// of are `None`.
+fn record_pat_fields(
+    list: &ast::RecordFieldPatList,
+) -> impl Iterator<Item = Option<(SyntaxNode, SyntaxNode)>> {
+    use ast::NameOwner;
+    list.syntax().children().filter_map(|child| {
+        let (name_ref, pat) = match ast::RecordFieldPat::cast(child.clone()) {
+            Some(field) => (field.name_ref(), field.pat()?),
+            None => (None, ast::Pat::cast(child)?),
+        };
+        if let Some(name_ref) = name_ref {
+            return Some(Some((name_ref.syntax().clone(), pat.syntax().clone())));
+        }
+        match pat {
+            ast::Pat::BindPat(bind_pat) => {
+                Some(bind_pat.name().map(|name| (name.syntax().clone(), bind_pat.syntax().clone())))
+            }
+            _ => Some(None),
+        }
+    })
+}
+
+/// Returns the path of the function that `node` calls, if it's a call, otherwise the path that
+/// `node` consists of, if any.
+fn callee_path(node: &SyntaxNode) -> Option<ast::Path> {
+    match ast::CallExpr::cast(node.clone()) {
+        Some(call) => path_in_node(call.expr()?.syntax()),
+        None => path_in_node(node),
+    }
+}
+
+/// Returns the path that makes up `node`, if it's a path, or a path expression or type.
+fn path_in_node(node: &SyntaxNode) -> Option<ast::Path> {
+    if let Some(path) = ast::Path::cast(node.clone()) {
+        return Some(path);
+    }
+    if let Some(path_expr) = ast::PathExpr::cast(node.clone()) {
+        return path_expr.path();
+    }
+    ast::PathType::cast(node.clone())?.path()
+}
+
+/// Returns the full path of the item that `resolution` refers to: the name of its crate, followed
+/// by the modules from the crate root down, then the item itself. Items other than modules and
+/// module-level definitions, such as locals and associated items, don't have such a path.
+fn definition_path(
+    db: &ra_ide_db::RootDatabase,
+    resolution: &hir::PathResolution,
+) -> Option<Vec<String>> {
+    let def = match resolution {
+        hir::PathResolution::Def(def) => *def,
+        _ => return None,
+    };
+    let module = match def {
+        hir::ModuleDef::Module(module) => module,
+        _ => def.module(db)?,
+    };
+    let mut path = vec![module.krate().display_name(db)?];
+    let modules = module.path_to_root(db);
+    path.extend(modules.iter().rev().filter_map(|m| m.name(db)).map(|name| name.to_string()));
+    if !matches!(def, hir::ModuleDef::Module(_)) {
+        path.push(def.name(db)?.to_string());
+    }
+    Some(path)
+}
+
+/// Returns the names of the segments of `path` as written, for when it can't be resolved.
+fn written_path(path: &ast::Path) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut current = Some(path.clone());
+    while let Some(path) = current {
+        let name_ref = path.segment().and_then(|segment| segment.name_ref());
+        names.push(name_ref.map(|name_ref| name_ref.text().to_string()).unwrap_or_default());
+        current = path.qualifier();
+    }
+    names.reverse();
+    names
+}
+
+/// Returns whether `names` matches `segments`, where `*` can be any one name and `**` can be any
+/// number of names.
+fn wildcard_path_matches(segments: &[PathPatternSegment], names: &[String]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        Some((PathPatternSegment::AnyNumber, rest)) => {
+            (0..=names.len()).any(|skip| wildcard_path_matches(rest, &names[skip..]))
+        }
+        Some((segment, rest)) => match names.split_first() {
+            Some((name, names_rest)) => {
+                let segment_matches = match segment {
+                    PathPatternSegment::Name(expected) => expected == name,
+                    _ => true,
+                };
+                segment_matches && wildcard_path_matches(rest, names_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Returns `segments` as the user wrote them, e.g. `std::*::spawn`.
+fn wildcard_path_text(segments: &[PathPatternSegment]) -> String {
+    let names: Vec<&str> = segments
+        .iter()
+        .map(|segment| match segment {
+            PathPatternSegment::Name(name) => name.as_str(),
+            PathPatternSegment::Any => "*",
+            PathPatternSegment::AnyNumber => "**",
+        })
+        .collect();
+    names.join("::")
+}
+
+/// Returns `ident` with its words lowercased and separated by underscores, e.g. `my_func` for each
+/// of `my_func`, `myFunc`, `MyFunc` and `MY_FUNC`. A run of capitals is treated as one word, so
+/// `HTTPServer` becomes `http_server`. Leading underscores are kept, since they're significant.
+pub(crate) fn normalize_case_convention(ident: &str) -> String {
+    let body = ident.trim_start_matches('_');
+    let mut out = ident[..ident.len() - body.len()].to_owned();
+    let chars: Vec<char> = body.chars().collect();
+    let mut at_word_start = true;
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '_' {
+            at_word_start = true;
+            continue;
+        }
+        let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+        let next = chars.get(i + 1).copied();
+        let starts_word = c.is_uppercase()
+            && match prev {
+                Some(prev) if prev.is_lowercase() || prev.is_ascii_digit() => true,
+                Some(prev) if prev.is_uppercase() => {
+                    matches!(next, Some(next) if next.is_lowercase())
+                }
+                _ => false,
+            };
+        if (at_word_start || starts_word) && out.len() > ident.len() - body.len() {
+            out.push('_');
+        }
+        at_word_start = false;
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+fn non_trivia_tokens(node: &SyntaxNode) -> impl Iterator<Item = String> {
+    node.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| !token.kind().is_trivia())
+        .map(|token| token.text().to_string())
+}
+
 // If `node` contains nothing but an ident then return it, otherwise return None.
-fn only_ident(element: SyntaxElement) -> Option<SyntaxToken> {
+pub(crate) fn only_ident(element: SyntaxElement) -> Option<SyntaxToken> {
     match element {
         SyntaxElement::Token(t) => {
             if t.kind() == SyntaxKind::IDENT {
@@ -673,6 +2995,122 @@ fn only_ident(element: SyntaxElement) -> Option<SyntaxToken> {
     None
 }
 
+/// Returns whether `code`, which is within `file_id` or a macro expansion there, is in the syntax
+/// tree of the file itself and if not, which macro call it came from.
+fn match_origin(
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+    code: &SyntaxNode,
+    file_id: ra_db::FileId,
+) -> MatchOrigin {
+    if code.ancestors().last().as_ref() == Some(sema.parse(file_id).syntax()) {
+        return MatchOrigin::Direct;
+    }
+    // Going up from a macro expansion leads to the call. The last call is the one in the file.
+    match sema
+        .ancestors_with_macros(code.clone())
+        .filter(|n| n.kind() == SyntaxKind::MACRO_CALL)
+        .last()
+    {
+        Some(call) => MatchOrigin::MacroExpansion { call_site: sema.original_range(&call) },
+        None => MatchOrigin::Direct,
+    }
+}
+
+/// If `tree` is just a path whose last segment is a placeholder, as in `use old::$rest;`, returns
+/// the path before that segment, along with the placeholder.
+pub(crate) fn use_tree_rest_placeholder<'a>(
+    pattern: &'a ResolvedPattern,
+    tree: &ast::UseTree,
+) -> Option<(ast::Path, &'a Placeholder)> {
+    if tree.star_token().is_some() || tree.use_tree_list().is_some() || tree.rename().is_some() {
+        return None;
+    }
+    let path = tree.path()?;
+    let ident = only_ident(SyntaxElement::Node(path.segment()?.syntax().clone()))?;
+    let placeholder = pattern.placeholders_by_stand_in.get(ident.text())?;
+    Some((path.qualifier()?, placeholder))
+}
+
+/// Like `only_ident`, but also accepts keywords that can be used as names, such as `self`.
+fn only_name(element: SyntaxElement) -> Option<SyntaxToken> {
+    match element {
+        SyntaxElement::Token(t) => {
+            if matches!(t.kind(), SyntaxKind::IDENT | T![self] | T![super] | T![crate]) {
+                return Some(t);
+            }
+        }
+        SyntaxElement::Node(n) => {
+            let mut children = n.children_with_tokens();
+            if let (Some(only_child), None) = (children.next(), children.next()) {
+                return only_name(only_child);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the range of the file that `code` matched. This is usually the original range of the
+/// node, but if the pattern is a macro call without a trailing `;` and the code is a macro call in
+/// item position with one, we leave the `;` out of the range so that replacing the match keeps it.
+fn matched_range(
+    pattern: &SyntaxNode,
+    code: &SyntaxNode,
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+) -> FileRange {
+    let mut range = sema.original_range(code);
+    // Lifetimes don't map out of macro expansions, so the range of a labeled loop would start
+    // after its label.
+    if let Some(label) = code.first_token().filter(|token| token.kind() == SyntaxKind::LIFETIME) {
+        let label_range = original_token_range(&label, sema);
+        if label_range.file_id == range.file_id && range.range != code.text_range() {
+            range.range = range.range.cover(label_range.range);
+        }
+    }
+    let trailing_semicolon =
+        |node: &SyntaxNode| node.last_token().filter(|token| token.kind() == SyntaxKind::SEMICOLON);
+    if pattern.kind() == SyntaxKind::MACRO_CALL && trailing_semicolon(pattern).is_none() {
+        if let Some(semicolon) = trailing_semicolon(code) {
+            // Only adjust the range if it's the node's own range, i.e. not within a macro
+            // expansion.
+            if range.range == code.text_range() {
+                range.range = TextRange::new(range.range.start(), semicolon.text_range().start());
+            }
+        }
+    }
+    range
+}
+
+/// Returns the range of the file that `token` came from. Semantics only maps nodes out of macro
+/// expansions, so we map the token's parent and then look for a token with the same text within
+/// that range. For lifetimes the parent often maps to the whole macro call, so the token we find
+/// may be another occurrence of the same label, but its text is all that a placeholder needs.
+fn original_token_range(
+    token: &SyntaxToken,
+    sema: &Semantics<ra_ide_db::RootDatabase>,
+) -> FileRange {
+    let parent = token.parent();
+    let parent_range = sema.original_range(&parent);
+    if parent_range.range == parent.text_range() {
+        return FileRange { file_id: parent_range.file_id, range: token.text_range() };
+    }
+    let file = sema.parse(parent_range.file_id);
+    let original_token = file
+        .syntax()
+        .covering_element(parent_range.range)
+        .as_node()
+        .into_iter()
+        .flat_map(|node| node.descendants_with_tokens())
+        .filter_map(|element| element.into_token())
+        .filter(|t| parent_range.range.contains_range(t.text_range()))
+        .find(|t| t.kind() == token.kind() && t.text() == token.text());
+    match original_token {
+        Some(original_token) => {
+            FileRange { file_id: parent_range.file_id, range: original_token.text_range() }
+        }
+        None => parent_range,
+    }
+}
+
 struct PatternIterator {
     iter: SyntaxElementChildren,
 }
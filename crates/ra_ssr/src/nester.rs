@@ -6,23 +6,73 @@
 //! For example, if our search pattern is `foo(foo($a))` and the code had `foo(foo(foo(foo(42))))`,
 //! then we'll get 3 matches, however only the outermost and innermost matches can be accepted. The
 //! middle match would take the second `foo` from the outer match.
+//!
+//! Matches can be nested within matches that are themselves nested, to any depth, unless a maximum
+//! depth is given, in which case matches nested any deeper are dropped.
 
-use crate::{Match, SsrMatches};
+use crate::{resolving::ResolvedRule, ConflictKind, Match, RulePriority, SsrMatches};
+use ra_db::FileRange;
 use ra_syntax::SyntaxNode;
 use rustc_hash::FxHashMap;
 
+/// A match that was discarded because it collided with a match that was kept.
+pub(crate) struct Collision {
+    pub(crate) kind: ConflictKind,
+    pub(crate) kept_range: FileRange,
+    pub(crate) kept_rule_index: usize,
+    pub(crate) discarded_range: FileRange,
+    pub(crate) discarded_rule_index: usize,
+}
+
 pub(crate) fn nest_and_remove_collisions(
+    matches: Vec<Match>,
+    rules: &[ResolvedRule],
+    priority: RulePriority,
+    max_nesting: Option<usize>,
+    sema: &hir::Semantics<ra_ide_db::RootDatabase>,
+) -> SsrMatches {
+    nest(matches, rules, priority, max_nesting, sema, &mut Vec::new())
+}
+
+/// Returns the collisions that `nest_and_remove_collisions` would resolve by discarding matches.
+pub(crate) fn find_collisions(
+    matches: Vec<Match>,
+    rules: &[ResolvedRule],
+    priority: RulePriority,
+    max_nesting: Option<usize>,
+    sema: &hir::Semantics<ra_ide_db::RootDatabase>,
+) -> Vec<Collision> {
+    let mut collisions = Vec::new();
+    nest(matches, rules, priority, max_nesting, sema, &mut collisions);
+    collisions
+}
+
+fn nest(
     mut matches: Vec<Match>,
+    rules: &[ResolvedRule],
+    priority: RulePriority,
+    max_nesting: Option<usize>,
     sema: &hir::Semantics<ra_ide_db::RootDatabase>,
+    collisions: &mut Vec<Collision>,
 ) -> SsrMatches {
-    // We sort the matches by depth then by rule index. Sorting by depth means that by the time we
-    // see a match, any parent matches or conflicting matches will have already been seen. Sorting
-    // by rule_index means that if there are two matches for the same node, the rule added first
-    // will take precedence.
-    matches.sort_by(|a, b| a.depth.cmp(&b.depth).then_with(|| a.rule_index.cmp(&b.rule_index)));
-    let mut collector = MatchCollector::default();
+    // We sort the matches by depth then by rule priority. Sorting by depth means that by the time
+    // we see a match, any parent matches or conflicting matches will have already been seen.
+    // Sorting by priority means that if there are two matches for the same node, the rule with the
+    // highest priority will take precedence. Ties are broken by rule_index, so that the rule added
+    // first wins.
+    let specificity = |m: &Match| match priority {
+        RulePriority::AddedOrder => 0,
+        RulePriority::MostSpecific => rules[m.rule_index].specificity,
+    };
+    matches.sort_by(|a, b| {
+        a.depth
+            .cmp(&b.depth)
+            .then_with(|| specificity(b).cmp(&specificity(a)))
+            .then_with(|| a.rule_index.cmp(&b.rule_index))
+    });
+    let mut collector = MatchCollector { max_nesting, ..MatchCollector::default() };
     for m in matches {
-        collector.add_match(m, sema);
+        collector.add_match(m, sema, collisions);
     }
     collector.into()
 }
@@ -30,21 +80,29 @@ pub(crate) fn nest_and_remove_collisions(
 #[derive(Default)]
 struct MatchCollector {
     matches_by_node: FxHashMap<SyntaxNode, Match>,
+    /// How many placeholders the matches that we collect are nested within.
+    nesting: usize,
+    max_nesting: Option<usize>,
 }
 
 impl MatchCollector {
     /// Attempts to add `m` to matches. If it conflicts with an existing match, it is discarded. If
     /// it is entirely within the a placeholder of an existing match, then it is added as a child
     /// match of the existing match.
-    fn add_match(&mut self, m: Match, sema: &hir::Semantics<ra_ide_db::RootDatabase>) {
+    fn add_match(
+        &mut self,
+        m: Match,
+        sema: &hir::Semantics<ra_ide_db::RootDatabase>,
+        collisions: &mut Vec<Collision>,
+    ) {
         let matched_node = m.matched_node.clone();
         if let Some(existing) = self.matches_by_node.get_mut(&matched_node) {
-            try_add_sub_match(m, existing, sema);
+            try_add_sub_match(m, existing, self.nesting, self.max_nesting, sema, collisions);
             return;
         }
         for ancestor in sema.ancestors_with_macros(m.matched_node.clone()) {
             if let Some(existing) = self.matches_by_node.get_mut(&ancestor) {
-                try_add_sub_match(m, existing, sema);
+                try_add_sub_match(m, existing, self.nesting, self.max_nesting, sema, collisions);
                 return;
             }
         }
@@ -52,31 +110,61 @@ impl MatchCollector {
     }
 }
 
-/// Attempts to add `m` as a sub-match of `existing`.
+/// Attempts to add `m` as a sub-match of `existing`, which is nested within `nesting` placeholders.
+/// If it isn't within one of the placeholders of `existing`, it's discarded and recorded in
+/// `collisions`. If it would be nested too deeply, it's discarded without being recorded.
 fn try_add_sub_match(
     m: Match,
     existing: &mut Match,
+    nesting: usize,
+    max_nesting: Option<usize>,
     sema: &hir::Semantics<ra_ide_db::RootDatabase>,
+    collisions: &mut Vec<Collision>,
 ) {
+    // A pattern that's just a placeholder has a placeholder that covers the whole match, but a
+    // node can't be matched within itself.
+    if m.matched_node == existing.matched_node {
+        collisions.push(Collision {
+            kind: ConflictKind::SameNode,
+            kept_range: existing.range,
+            kept_rule_index: existing.rule_index,
+            discarded_range: m.range,
+            discarded_rule_index: m.rule_index,
+        });
+        return;
+    }
     for p in existing.placeholder_values.values_mut() {
         // Note, no need to check if p.range.file is equal to m.range.file, since we
         // already know we're within `existing`.
         if p.range.range.contains_range(m.range.range) {
+            if let Some(max_nesting) = max_nesting {
+                if nesting >= max_nesting {
+                    return;
+                }
+            }
             // Convert the inner matches in `p` into a temporary MatchCollector. When
             // we're done, we then convert it back into an SsrMatches. If we expected
             // lots of inner matches, it might be worthwhile keeping a MatchCollector
             // around for each placeholder match. However we expect most placeholder
             // will have 0 and a few will have 1. More than that should hopefully be
             // exceptional.
-            let mut collector = MatchCollector::default();
+            let mut collector =
+                MatchCollector { nesting: nesting + 1, max_nesting, ..MatchCollector::default() };
             for m in std::mem::replace(&mut p.inner_matches.matches, Vec::new()) {
                 collector.matches_by_node.insert(m.matched_node.clone(), m);
             }
-            collector.add_match(m, sema);
+            collector.add_match(m, sema, collisions);
             p.inner_matches = collector.into();
-            break;
+            return;
         }
     }
+    collisions.push(Collision {
+        kind: ConflictKind::Nested,
+        kept_range: existing.range,
+        kept_rule_index: existing.rule_index,
+        discarded_range: m.range,
+        discarded_rule_index: m.rule_index,
+    });
 }
 
 impl From<MatchCollector> for SsrMatches {
@@ -6,22 +6,53 @@
 //! e.g. expressions, type references etc.
 
 use crate::errors::bail;
-use crate::{SsrError, SsrPattern, SsrRule};
-use ra_syntax::{ast, AstNode, SmolStr, SyntaxKind, SyntaxNode, T};
+use crate::{OncePer, OncePerScope, SsrError, SsrPattern, SsrRule, TrailingSemicolon};
+use ra_db::Edition;
+use ra_syntax::{
+    ast, AstNode, NodeOrToken, SmolStr, SyntaxKind, SyntaxNode, SyntaxToken, TextSize, WalkEvent, T,
+};
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::str::FromStr;
 use test_utils::mark;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct ParsedRule {
     pub(crate) placeholders_by_stand_in: FxHashMap<SmolStr, Placeholder>,
     pub(crate) pattern: SyntaxNode,
     pub(crate) template: Option<SyntaxNode>,
+    /// Templates written after `template`, separated by `|||`. See `ResolvedRule::template_for`.
+    pub(crate) alternative_templates: Vec<SyntaxNode>,
+    /// Whether the pattern must match a whole expression statement.
+    pub(crate) statement_boundary: bool,
+    /// Whether paths that don't resolve, in either the rule or the code, are compared by name.
+    pub(crate) heuristic_fallback: bool,
+    /// If set, only one match is kept in each function, file or module.
+    pub(crate) once_per: Option<OncePer>,
+    /// How a `;` after a match that's an expression statement is handled.
+    pub(crate) trailing_semicolon: TrailingSemicolon,
+    /// The operator tokens, in the pattern and templates, that are placeholders, along with the
+    /// stand-in names of those placeholders.
+    pub(crate) operator_placeholders: FxHashMap<SyntaxToken, SmolStr>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct RawPattern {
-    tokens: Vec<PatternElement>,
+    pub(crate) tokens: Vec<PatternElement>,
+    /// The kind of syntax that the pattern was restricted to with a prefix such as `pat:`, if any.
+    pub(crate) position: Option<PatternPosition>,
+}
+
+/// A kind of syntax that a pattern can be restricted to matching, by starting it with the prefix
+/// returned by `prefix`. Without a prefix, a pattern such as `Some($x)` is parsed as every kind
+/// that it's valid as, so matches both expressions and patterns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum PatternPosition {
+    Expr,
+    Type,
+    Item,
+    Path,
+    Pat,
 }
 
 // Part of a search or replace pattern.
@@ -32,23 +63,95 @@ pub(crate) enum PatternElement {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct Placeholder {
     /// The name of this placeholder. e.g. for "$a", this would be "a"
     pub(crate) ident: SmolStr,
     /// A unique name used in place of this placeholder when we parse the pattern as Rust code.
-    stand_in_name: String,
+    pub(crate) stand_in_name: String,
     pub(crate) constraints: Vec<Constraint>,
+    /// Whether this is a `${fresh(name)}` placeholder in a template. These stand for a new
+    /// identifier, based on `ident`, that isn't already in scope where the match was found.
+    pub(crate) is_fresh: bool,
+    /// Whether this is written `$name...`, meaning that it matches any number of elements at the
+    /// end of an array or tuple.
+    pub(crate) is_variadic: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum Constraint {
     Kind(NodeKind),
     Not(Box<Constraint>),
+    IsConst,
+    RedundantCast,
+    IsNamed(SmolStr),
+    IsZeroSized,
+    /// Inside the body or condition of a loop. If `through_closures` is false, then the search
+    /// stops at the enclosing closure.
+    InLoop {
+        through_closures: bool,
+    },
+    /// Doesn't contain calls to functions or macros that are known to allocate. This is only a
+    /// heuristic, since it doesn't look inside other functions that are called.
+    NoAllocHeuristic,
+    /// Evaluated at compile time, e.g. in a `const fn` or the initializer of a `const`.
+    InConst,
+    /// Doesn't refer to any local variables, or `self`, other than those it defines itself.
+    NoLocals,
+    /// An integer literal, possibly negated, whose value is within the inclusive bounds. A bound
+    /// of `None` means that there's no limit in that direction.
+    IntInRange {
+        min: Option<i128>,
+        max: Option<i128>,
+    },
+    /// A numeric literal with the given type suffix, or without a suffix if `None`.
+    LiteralSuffix(Option<SmolStr>),
+    /// A path that resolves to the same thing as one of these paths. The paths are resolved along
+    /// with the rule.
+    OneOf(Vec<SmolStr>),
+    /// One of these operators. A placeholder with this constraint stands for the operator of a
+    /// binary or prefix expression, and is parsed as the first of them.
+    OneOfOperators(Vec<SmolStr>),
+    /// Directly within a particular position in its parent.
+    Parent(ParentKind),
+    /// A declaration with a default value. Fields and function parameters can't have defaults, and
+    /// we don't parse defaults of const generic parameters, so this is a type parameter written
+    /// with `= default`.
+    HasDefaultValue,
+    /// An array or tuple literal with exactly this many elements. Repeat expressions such as
+    /// `[0; 4]` never match, since their length may not be known.
+    Arity(usize),
+    /// Resolves to a definition with an attribute that has this path, e.g. `must_use`. Any input
+    /// to the attribute is ignored.
+    HasAttr(SmolStr),
+    /// A call of an `async fn`, or a path to one.
+    IsAsync,
+    /// A call of a function or method whose result has this type, as written in the rule. Paths in
+    /// the type are resolved in the rule's scope.
+    Returns(SmolStr),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum NodeKind {
     Literal,
+    /// An integer or float literal.
+    NumericLiteral,
+    /// A string literal, which may be raw, but not a byte string.
+    StringLiteral,
+    FieldAccess,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum ParentKind {
+    /// The condition of an `if` or `while`.
+    Condition,
+    /// An argument of a function or method call.
+    Argument,
+    /// An expression statement.
+    Statement,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -57,23 +160,85 @@ pub(crate) struct Token {
     pub(crate) text: SmolStr,
 }
 
+/// The start of the stand-in name of a placeholder that's directly after `else`. This differs from
+/// the start of other stand-in names so that we can tell that the block that the stand-in is put in
+/// came from the placeholder rather than having been written by the user.
+pub(crate) const ELSE_BRANCH_STAND_IN_PREFIX: &str = "__else_branch_placeholder_";
+
+/// The start of the stand-in name of a placeholder that's used as a loop label. This is a lifetime,
+/// since that's what the parser expects a label to be.
+const LABEL_STAND_IN_PREFIX: &str = "'__label_placeholder_";
+
+/// The stand-in identifiers for `*` and `**` path wildcards, as in `std::*::spawn`. These aren't
+/// placeholders, since they don't capture anything, but they need to be identifiers so that the
+/// pattern still parses as a path.
+pub(crate) const WILDCARD_SEGMENT_STAND_IN: &str = "__ssr_wildcard_segment";
+pub(crate) const WILDCARD_SEGMENTS_STAND_IN: &str = "__ssr_wildcard_segments";
+
+/// The operators that may be listed in a `one_of` constraint, as in `$a ${op:one_of(==, !=)} $b`.
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<", "<=", ">", ">=", "+", "-", "*", "/", "%", "&&", "||", "&", "|", "^", "<<",
+    ">>", "!", "=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>=",
+];
+
+/// The names that can be used as `${match.<name>}` within string literals in the replacement, to
+/// embed the module, file name or function in which the match was found.
+pub(crate) const MATCH_VARIABLES: &[&str] = &["module", "file", "fn"];
+
+/// The type suffixes that integer and float literals can have.
+const NUMERIC_LITERAL_SUFFIXES: &[&str] = &[
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "f32",
+    "f64",
+];
+
+/// Keywords that were introduced in the 2018 edition. In the 2015 edition, these are ordinary
+/// identifiers.
+const EDITION_2018_KEYWORDS: &[SyntaxKind] = &[T![async], T![await], T![try]];
+
 impl ParsedRule {
-    fn new(
+    pub(crate) fn new(
         pattern: &RawPattern,
         template: Option<&RawPattern>,
+        alternative_templates: &[RawPattern],
+        edition: Edition,
     ) -> Result<Vec<ParsedRule>, SsrError> {
-        let raw_pattern = pattern.as_rust_code();
-        let raw_template = template.map(|t| t.as_rust_code());
-        let raw_template = raw_template.as_ref().map(|s| s.as_str());
-        let mut builder = RuleBuilder {
-            placeholders_by_stand_in: pattern.placeholders_by_stand_in(),
-            rules: Vec::new(),
-        };
-        builder.try_add(ast::Expr::parse(&raw_pattern), raw_template.map(ast::Expr::parse));
-        builder.try_add(ast::Type::parse(&raw_pattern), raw_template.map(ast::Type::parse));
-        builder.try_add(ast::Item::parse(&raw_pattern), raw_template.map(ast::Item::parse));
-        builder.try_add(ast::Path::parse(&raw_pattern), raw_template.map(ast::Path::parse));
-        builder.try_add(ast::Pat::parse(&raw_pattern), raw_template.map(ast::Pat::parse));
+        let labels = pattern.label_placeholders();
+        let operators = pattern.operator_placeholders();
+        let raw_pattern = pattern.as_rust_code(edition, &labels, &operators);
+        let raw_template = template.map(|t| t.as_rust_code(edition, &labels, &operators));
+        let raw_template = raw_template.as_ref();
+        let raw_alternatives: Vec<RustCode> = alternative_templates
+            .iter()
+            .map(|t| t.as_rust_code(edition, &labels, &operators))
+            .collect();
+        let mut placeholders_by_stand_in = pattern.placeholders_by_stand_in();
+        // Fresh identifiers in the template are placeholders too, so that they aren't resolved as
+        // paths and get rendered as the chosen name.
+        for template in template.into_iter().chain(alternative_templates) {
+            placeholders_by_stand_in.extend(
+                template.placeholders_by_stand_in().into_iter().filter(|(_, p)| p.is_fresh),
+            );
+        }
+        let mut builder = RuleBuilder { placeholders_by_stand_in, rules: Vec::new() };
+        let allowed = |position| pattern.position.is_none() || pattern.position == Some(position);
+        if allowed(PatternPosition::Expr) {
+            builder.try_add(ast::Expr::parse, &raw_pattern, raw_template, &raw_alternatives);
+        }
+        if allowed(PatternPosition::Type) {
+            builder.try_add(ast::Type::parse, &raw_pattern, raw_template, &raw_alternatives);
+        }
+        if allowed(PatternPosition::Item) {
+            builder.try_add(ast::Item::parse, &raw_pattern, raw_template, &raw_alternatives);
+        }
+        if allowed(PatternPosition::Path) {
+            builder.try_add(ast::Path::parse, &raw_pattern, raw_template, &raw_alternatives);
+        }
+        if allowed(PatternPosition::Pat) {
+            builder.try_add(ast::Pat::parse, &raw_pattern, raw_template, &raw_alternatives);
+        }
+        if let (Some(position), true) = (pattern.position, builder.rules.is_empty()) {
+            bail!("Not a valid Rust {}", position.description());
+        }
         builder.build()
     }
 }
@@ -84,19 +249,56 @@ struct RuleBuilder {
 }
 
 impl RuleBuilder {
-    fn try_add<T: AstNode>(&mut self, pattern: Result<T, ()>, template: Option<Result<T, ()>>) {
-        match (pattern, template) {
-            (Ok(pattern), Some(Ok(template))) => self.rules.push(ParsedRule {
-                placeholders_by_stand_in: self.placeholders_by_stand_in.clone(),
-                pattern: pattern.syntax().clone(),
-                template: Some(template.syntax().clone()),
-            }),
-            (Ok(pattern), None) => self.rules.push(ParsedRule {
+    /// Adds a rule if `pattern` and all the templates can be parsed by `parse`, with any operator
+    /// placeholders ending up as operators.
+    fn try_add<T: AstNode>(
+        &mut self,
+        parse: fn(&str) -> Result<T, ()>,
+        pattern: &RustCode,
+        template: Option<&RustCode>,
+        alternative_templates: &[RustCode],
+    ) {
+        let mut operator_placeholders = FxHashMap::default();
+        let mut parse_code = |code: &RustCode| {
+            let node = parse(&code.text)?.syntax().clone();
+            if code.add_operator_placeholders(&node, &mut operator_placeholders) {
+                Ok(node)
+            } else {
+                Err(())
+            }
+        };
+        let alternative_templates: Vec<SyntaxNode> =
+            match alternative_templates.iter().map(&mut parse_code).collect() {
+                Ok(templates) => templates,
+                Err(()) => return,
+            };
+        let template = match template {
+            // An item may be replaced with nothing, which removes it.
+            Some(code) if code.text.trim().is_empty() => {
+                Some(ast::SourceFile::parse("").tree().syntax().clone())
+            }
+            Some(code) => match parse_code(code) {
+                Ok(template) => Some(template),
+                Err(()) => return,
+            },
+            None => None,
+        };
+        if let Ok(pattern) = parse_code(pattern) {
+            let removes = template.as_ref().map_or(false, |t| t.kind() == SyntaxKind::SOURCE_FILE);
+            if removes && !ast::Item::can_cast(pattern.kind()) {
+                return;
+            }
+            self.rules.push(ParsedRule {
                 placeholders_by_stand_in: self.placeholders_by_stand_in.clone(),
-                pattern: pattern.syntax().clone(),
-                template: None,
-            }),
-            _ => {}
+                pattern,
+                template,
+                alternative_templates,
+                statement_boundary: false,
+                heuristic_fallback: false,
+                once_per: None,
+                trailing_semicolon: TrailingSemicolon::Auto,
+                operator_placeholders,
+            });
         }
     }
 
@@ -104,6 +306,19 @@ impl RuleBuilder {
         if self.rules.is_empty() {
             bail!("Not a valid Rust expression, type, item, path or pattern");
         }
+        for rule in &self.rules {
+            validate_impl_member_rule(rule)?;
+        }
+        // A variadic placeholder parses as an ordinary element of whatever the pattern parsed as,
+        // but we only know how to match it as the last element of an array or tuple expression.
+        let num_rules = self.rules.len();
+        self.rules.retain(variadic_placeholders_supported);
+        if self.rules.is_empty() && num_rules > 0 {
+            bail!(
+                "Variadic placeholders, such as `$rest...`, can only be the last element of an \
+                 array or tuple expression, or the stages of a `?` chain, as in `$e.$stages...?`"
+            );
+        }
         // If any rules contain paths, then we reject any rules that don't contain paths. Allowing a
         // mix leads to strange semantics, since the path-based rules only match things where the
         // path refers to semantically the same thing, whereas the non-path-based rules could match
@@ -123,6 +338,121 @@ impl RuleBuilder {
     }
 }
 
+/// If `node` is an impl with at least one member, returns its members. Such an impl in a search
+/// pattern will match impls that have those members, in any order, plus possibly others.
+pub(crate) fn impl_members(node: &SyntaxNode) -> Option<Vec<SyntaxNode>> {
+    let item_list = ast::Impl::cast(node.clone())?.assoc_item_list()?;
+    let members: Vec<_> = item_list.assoc_items().map(|item| item.syntax().clone()).collect();
+    if members.is_empty() {
+        None
+    } else {
+        Some(members)
+    }
+}
+
+/// When a search pattern is an impl with members, only the matched members get replaced. So the
+/// template must list the same number of members, and otherwise be the same as the pattern.
+fn validate_impl_member_rule(rule: &ParsedRule) -> Result<(), SsrError> {
+    for template in rule.template.iter().chain(&rule.alternative_templates) {
+        validate_impl_member_template(rule, template)?;
+    }
+    Ok(())
+}
+
+fn validate_impl_member_template(rule: &ParsedRule, template: &SyntaxNode) -> Result<(), SsrError> {
+    let pattern_members = match impl_members(&rule.pattern) {
+        Some(members) => members,
+        None => return Ok(()),
+    };
+    let template_len = impl_members(template).map_or(0, |members| members.len());
+    if template_len != pattern_members.len() {
+        bail!(
+            "Search pattern has {} impl member(s), but the replacement has {}",
+            pattern_members.len(),
+            template_len
+        );
+    }
+    let header_tokens = |node: &SyntaxNode| -> Vec<String> {
+        node.descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+            .filter(|token| {
+                !token.kind().is_trivia()
+                    && !token.parent().ancestors().any(|n| n.kind() == SyntaxKind::ASSOC_ITEM_LIST)
+            })
+            .map(|token| token.text().to_string())
+            .collect()
+    };
+    if header_tokens(&rule.pattern) != header_tokens(template) {
+        bail!("Only the members of an impl can be replaced, not the impl itself");
+    }
+    Ok(())
+}
+
+/// Returns whether every variadic placeholder in `rule`'s pattern is either the last element of an
+/// array or tuple expression, or the stages of a `?` chain, as in `$e.$stages...?`.
+fn variadic_placeholders_supported(rule: &ParsedRule) -> bool {
+    rule.pattern.descendants_with_tokens().filter_map(|element| element.into_token()).all(|token| {
+        if !matches!(rule.placeholders_by_stand_in.get(token.text()), Some(p) if p.is_variadic) {
+            return true;
+        }
+        if is_try_chain_stages(&token.parent()) {
+            return true;
+        }
+        let element = token.parent().ancestors().find_map(|node| {
+            let parent = node.parent()?;
+            match parent.kind() {
+                SyntaxKind::ARRAY_EXPR | SyntaxKind::TUPLE_EXPR => Some((node, parent)),
+                _ => None,
+            }
+        });
+        match element {
+            Some((element, parent)) => {
+                element.text_range() == token.text_range()
+                    && element.next_sibling().is_none()
+                    && parent.children_with_tokens().all(|child| child.kind() != T![;])
+            }
+            None => false,
+        }
+    })
+}
+
+/// Returns whether `node` is the field name of a field expression that `?` is applied to, as
+/// `$stages` is in `$e.$stages...?`.
+pub(crate) fn is_try_chain_stages(node: &SyntaxNode) -> bool {
+    node.kind() == SyntaxKind::NAME_REF
+        && matches!(node.parent().and_then(ast::FieldExpr::cast), Some(field_expr)
+            if matches!(field_expr.syntax().parent(), Some(p) if p.kind() == SyntaxKind::TRY_EXPR))
+}
+
+/// Appends to `out` a line for each node and token within `node`, indented by depth.
+fn debug_tree(
+    node: &SyntaxNode,
+    placeholders_by_stand_in: &FxHashMap<SmolStr, Placeholder>,
+    out: &mut String,
+) {
+    let mut depth = 1;
+    for event in node.preorder_with_tokens() {
+        match event {
+            WalkEvent::Enter(element) => {
+                out.push_str(&"  ".repeat(depth));
+                out.push_str(&format!("{:?}@{:?}", element.kind(), element.text_range()));
+                match element {
+                    NodeOrToken::Node(_) => depth += 1,
+                    NodeOrToken::Token(token) => {
+                        out.push_str(&format!(" {:?}", token.text().as_str()));
+                        if let Some(placeholder) = placeholders_by_stand_in.get(token.text()) {
+                            out.push_str(&format!(" ${}", placeholder.ident));
+                        }
+                    }
+                }
+                out.push('\n');
+            }
+            WalkEvent::Leave(NodeOrToken::Node(_)) => depth -= 1,
+            WalkEvent::Leave(NodeOrToken::Token(_)) => {}
+        }
+    }
+}
+
 /// Returns whether there are any paths in `node`.
 fn contains_path(node: &SyntaxNode) -> bool {
     node.kind() == SyntaxKind::PATH
@@ -137,60 +467,506 @@ impl FromStr for SsrRule {
         let pattern = it.next().expect("at least empty string").trim();
         let template = it
             .next()
-            .ok_or_else(|| SsrError("Cannot find delimiter `==>>`".into()))?
+            .ok_or_else(|| SsrError::new("Cannot find delimiter `==>>`"))?
             .trim()
             .to_string();
         if it.next().is_some() {
-            return Err(SsrError("More than one delimiter found".into()));
+            return Err(SsrError::new("More than one delimiter found"));
+        }
+        let raw_pattern: RawPattern = pattern.parse()?;
+        let mut templates = template.split("|||");
+        let raw_template: RawPattern =
+            templates.next().expect("at least empty string").trim().parse()?;
+        let alternative_templates: Vec<RawPattern> =
+            templates.map(|t| t.trim().parse()).collect::<Result<_, _>>()?;
+        for template in std::iter::once(&raw_template).chain(&alternative_templates) {
+            if template.position.is_some() && template.position != raw_pattern.position {
+                bail!(
+                    "The replacement can only have the same position prefix as the search pattern"
+                );
+            }
         }
-        let raw_pattern = pattern.parse()?;
-        let raw_template = template.parse()?;
-        let parsed_rules = ParsedRule::new(&raw_pattern, Some(&raw_template))?;
-        let rule = SsrRule { pattern: raw_pattern, template: raw_template, parsed_rules };
+        let (parsed_rules, edition) = parse_for_any_edition(|edition| {
+            ParsedRule::new(&raw_pattern, Some(&raw_template), &alternative_templates, edition)
+        })?;
+        let rule = SsrRule {
+            pattern: raw_pattern,
+            template: raw_template,
+            alternative_templates,
+            parsed_rules,
+            edition,
+            statement_boundary: false,
+            heuristic_fallback: false,
+            idempotency_check: false,
+            once_per: None,
+            trailing_semicolon: TrailingSemicolon::Auto,
+        };
         validate_rule(&rule)?;
         Ok(rule)
     }
 }
 
+impl SsrRule {
+    /// Returns our parsed rules, parsing them again if they were parsed for a different edition.
+    pub(crate) fn into_parsed_rules(self, edition: Edition) -> Result<Vec<ParsedRule>, SsrError> {
+        let mut parsed_rules = if self.edition == edition {
+            self.parsed_rules
+        } else {
+            ParsedRule::new(
+                &self.pattern,
+                Some(&self.template),
+                &self.alternative_templates,
+                edition,
+            )?
+        };
+        for parsed_rule in &mut parsed_rules {
+            parsed_rule.statement_boundary = self.statement_boundary;
+            parsed_rule.heuristic_fallback = self.heuristic_fallback;
+            parsed_rule.once_per = self.once_per;
+            parsed_rule.trailing_semicolon = self.trailing_semicolon;
+        }
+        Ok(parsed_rules)
+    }
+
+    /// Returns the rule in a normalized form, so that rules that differ only in formatting or in
+    /// the names of their placeholders give the same string. Comments are dropped, there's a
+    /// single space only where one is needed between tokens and placeholders are renamed `$p0`,
+    /// `$p1` etc. in the order in which they first appear. The result can be parsed as a rule.
+    pub fn canonicalize(&self) -> String {
+        let mut placeholder_indexes = FxHashMap::default();
+        let mut pattern = self.pattern.canonicalize(&mut placeholder_indexes);
+        if let Some(position) = self.pattern.position {
+            pattern = format!("{}: {}", position.prefix(), pattern);
+        }
+        let mut template = self.template.canonicalize(&mut placeholder_indexes);
+        for alternative in &self.alternative_templates {
+            template.push_str(" ||| ");
+            template.push_str(&alternative.canonicalize(&mut placeholder_indexes));
+        }
+        format!("{} ==>> {}", pattern, template)
+    }
+
+    /// Returns warnings about probable mistakes in the rule that don't stop it from being used,
+    /// such as two placeholders in the search pattern whose names are so similar that one is
+    /// likely a misspelling of the other.
+    pub fn warnings(&self) -> Vec<String> {
+        self.pattern.similar_placeholder_warnings()
+    }
+
+    /// Sets whether the rule only matches expressions that make up a whole statement. The match
+    /// then includes the statement's trailing `;` and so does the replacement.
+    pub fn set_statement_boundary(&mut self, statement_boundary: bool) {
+        self.statement_boundary = statement_boundary;
+    }
+
+    /// Sets how to treat the `;` of an expression statement when the expression is replaced. By
+    /// default, it's added or dropped as the replacement needs, so that `foo($a) ==>> if $a {}`
+    /// turns `foo(x);` into `if x {}` and `if $a {} ==>> foo($a)` turns `if x {}` into `foo(x);`.
+    pub fn set_trailing_semicolon(&mut self, trailing_semicolon: TrailingSemicolon) {
+        self.trailing_semicolon = trailing_semicolon;
+    }
+
+    /// Sets whether to fall back to comparing paths by name when they don't resolve, such as when
+    /// the database is incomplete or the code is generated. A path in the rule that doesn't resolve
+    /// then matches code with the same text, rather than being an error, and a path in the code
+    /// that doesn't resolve matches a path in the rule whose last segment has the same name. Such
+    /// matches are only approximate, so are reported by `MatchFinder::matches_by_heuristic`.
+    pub fn with_heuristic_fallback(&mut self, heuristic_fallback: bool) {
+        self.heuristic_fallback = heuristic_fallback;
+    }
+
+    /// Sets whether `MatchFinder::add_rule` should reject the rule if its own pattern matches code
+    /// that its replacement produces, such as `x ==>> x + 0`. Applying such a rule repeatedly
+    /// might never stop. The rule is applied to its replacement, with placeholders standing for
+    /// identifiers, and rejected with an error of kind `SsrErrorKind::NonIdempotentRule` if it
+    /// matches anything there.
+    pub fn with_idempotency_check(&mut self, idempotency_check: bool) {
+        self.idempotency_check = idempotency_check;
+    }
+
+    /// Sets the rule to only apply to the first of its matches within each function, file or
+    /// module, for example to insert a call before the first use of an API in each function. Other
+    /// matches of the rule within the same scope are dropped, but matches nested within the kept
+    /// match's placeholders aren't affected. `None` applies the rule to every match.
+    pub fn set_once_per(&mut self, scope: Option<OncePerScope>) {
+        self.once_per = scope.map(|scope| OncePer { scope, keep_last: false });
+    }
+
+    /// Like `set_once_per`, but keeps the last match within each scope rather than the first.
+    pub fn set_last_per(&mut self, scope: Option<OncePerScope>) {
+        self.once_per = scope.map(|scope| OncePer { scope, keep_last: true });
+    }
+
+    /// Returns the syntax trees that the pattern and replacement were parsed as, for debugging
+    /// rules that don't match what was expected. A rule is parsed as each kind of syntax that it's
+    /// valid as, so there's a pair of trees for each. Placeholders are shown by name, e.g. `$a`,
+    /// after the identifier that stands in for them.
+    pub fn debug_tree(&self) -> String {
+        let mut out = String::new();
+        for rule in &self.parsed_rules {
+            out.push_str(&format!("Parsed as {:?}\n", rule.pattern.kind()));
+            out.push_str("pattern:\n");
+            debug_tree(&rule.pattern, &rule.placeholders_by_stand_in, &mut out);
+            for template in rule.template.iter().chain(&rule.alternative_templates) {
+                out.push_str("template:\n");
+                debug_tree(template, &rule.placeholders_by_stand_in, &mut out);
+            }
+        }
+        out
+    }
+}
+
+impl SsrPattern {
+    /// Returns warnings about probable mistakes in the pattern. See `SsrRule::warnings`.
+    pub fn warnings(&self) -> Vec<String> {
+        self.raw.similar_placeholder_warnings()
+    }
+
+    /// Returns our parsed rules, parsing them again if they were parsed for a different edition.
+    pub(crate) fn into_parsed_rules(self, edition: Edition) -> Result<Vec<ParsedRule>, SsrError> {
+        if self.edition == edition {
+            return Ok(self.parsed_rules);
+        }
+        ParsedRule::new(&self.raw, None, &[], edition)
+    }
+}
+
+/// When parsing a rule, we don't yet know which edition it will be used with. We parse it as 2018
+/// edition code if we can, otherwise as 2015 edition code. When the rule is added to a
+/// `MatchFinder`, it'll be parsed again if the edition of the resolution scope is different.
+fn parse_for_any_edition(
+    parse: impl Fn(Edition) -> Result<Vec<ParsedRule>, SsrError>,
+) -> Result<(Vec<ParsedRule>, Edition), SsrError> {
+    match parse(Edition::Edition2018) {
+        Ok(parsed_rules) => Ok((parsed_rules, Edition::Edition2018)),
+        Err(error) => match parse(Edition::Edition2015) {
+            Ok(parsed_rules) => Ok((parsed_rules, Edition::Edition2015)),
+            Err(_) => Err(error),
+        },
+    }
+}
+
+/// Returns whether `node` contains any keywords that only exist in the 2018 edition.
+pub(crate) fn uses_edition_2018_keywords(node: &SyntaxNode) -> bool {
+    node.descendants_with_tokens().any(|element| EDITION_2018_KEYWORDS.contains(&element.kind()))
+}
+
 impl FromStr for RawPattern {
     type Err = SsrError;
 
     fn from_str(pattern_str: &str) -> Result<RawPattern, SsrError> {
-        Ok(RawPattern { tokens: parse_pattern(pattern_str)? })
+        let (position, pattern_str) = PatternPosition::split_prefix(pattern_str);
+        Ok(RawPattern { tokens: parse_pattern(pattern_str)?, position })
+    }
+}
+
+impl PatternPosition {
+    const ALL: [PatternPosition; 5] = [
+        PatternPosition::Expr,
+        PatternPosition::Type,
+        PatternPosition::Item,
+        PatternPosition::Path,
+        PatternPosition::Pat,
+    ];
+
+    pub(crate) fn prefix(self) -> &'static str {
+        match self {
+            PatternPosition::Expr => "expr",
+            PatternPosition::Type => "type",
+            PatternPosition::Item => "item",
+            PatternPosition::Path => "path",
+            PatternPosition::Pat => "pat",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            PatternPosition::Expr => "expression",
+            PatternPosition::Type => "type",
+            PatternPosition::Item => "item",
+            PatternPosition::Path => "path",
+            PatternPosition::Pat => "pattern",
+        }
+    }
+
+    /// Splits a prefix such as `pat:` from the start of `pattern_str`. A prefix is followed by a
+    /// single colon, so `path::to::x` doesn't have one.
+    fn split_prefix(pattern_str: &str) -> (Option<PatternPosition>, &str) {
+        let trimmed = pattern_str.trim_start();
+        for position in PatternPosition::ALL.iter() {
+            let rest = match trimmed.strip_prefix(position.prefix()) {
+                Some(rest) => rest.trim_start(),
+                None => continue,
+            };
+            if let Some(rest) = rest.strip_prefix(':') {
+                if !rest.starts_with(':') {
+                    return (Some(*position), rest);
+                }
+            }
+        }
+        (None, pattern_str)
     }
 }
 
 impl RawPattern {
-    /// Returns this search pattern as Rust source code that we can feed to the Rust parser.
-    fn as_rust_code(&self) -> String {
+    /// Returns the pattern as text for `SsrRule::canonicalize`. Placeholders are renamed according
+    /// to `placeholder_indexes`, to which any new placeholders are added. Words, such as
+    /// identifiers, keywords and literals, are separated by a space. Punctuation is only separated
+    /// from other punctuation if it was in the original pattern, since joining it might change
+    /// its meaning, e.g. `- -x` vs `--x`.
+    fn canonicalize(&self, placeholder_indexes: &mut FxHashMap<SmolStr, usize>) -> String {
+        let mut out = String::new();
+        let mut previous_is_word = false;
+        let mut after_trivia = false;
+        for element in &self.tokens {
+            let (text, is_word) = match element {
+                PatternElement::Token(token) if token.kind.is_trivia() => {
+                    after_trivia = true;
+                    continue;
+                }
+                PatternElement::Token(token) if token.text == WILDCARD_SEGMENT_STAND_IN => {
+                    ("*".to_owned(), false)
+                }
+                PatternElement::Token(token) if token.text == WILDCARD_SEGMENTS_STAND_IN => {
+                    ("**".to_owned(), false)
+                }
+                PatternElement::Token(token) => {
+                    let kind = token.kind;
+                    let is_word = kind == SyntaxKind::IDENT
+                        || kind == SyntaxKind::LIFETIME
+                        || kind.is_keyword()
+                        || kind.is_literal();
+                    (token.text.to_string(), is_word)
+                }
+                PatternElement::Placeholder(placeholder) if placeholder.is_fresh => {
+                    (format!("${{fresh({})}}", placeholder.ident), true)
+                }
+                PatternElement::Placeholder(placeholder) => {
+                    let next_index = placeholder_indexes.len();
+                    let index =
+                        *placeholder_indexes.entry(placeholder.ident.clone()).or_insert(next_index);
+                    let text = if placeholder.is_variadic {
+                        format!("$p{}...", index)
+                    } else if placeholder.constraints.is_empty() {
+                        format!("$p{}", index)
+                    } else {
+                        let constraints: Vec<String> =
+                            placeholder.constraints.iter().map(|c| c.to_string()).collect();
+                        format!("${{p{}:{}}}", index, constraints.join(":"))
+                    };
+                    (text, true)
+                }
+            };
+            let needs_space =
+                if is_word { previous_is_word } else { !previous_is_word && after_trivia };
+            if needs_space && !out.is_empty() {
+                out.push(' ');
+            }
+            out.push_str(&text);
+            previous_is_word = is_word;
+            after_trivia = false;
+        }
+        out
+    }
+
+    /// Returns a warning for each pair of placeholders that occur only once each and whose names
+    /// differ by a single character, since they were probably meant to be the same placeholder.
+    /// Short names and names that differ only in their digits, such as `$a` and `$b` or `$arg1`
+    /// and `$arg2`, are commonly used for distinct placeholders, so aren't reported.
+    fn similar_placeholder_warnings(&self) -> Vec<String> {
+        let mut counts: Vec<(&SmolStr, usize)> = Vec::new();
+        for element in &self.tokens {
+            if let PatternElement::Placeholder(placeholder) = element {
+                match counts.iter_mut().find(|(ident, _)| **ident == placeholder.ident) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((&placeholder.ident, 1)),
+                }
+            }
+        }
+        let singles: Vec<&SmolStr> =
+            counts.into_iter().filter(|(_, count)| *count == 1).map(|(ident, _)| ident).collect();
+        let without_digits =
+            |name: &str| -> String { name.chars().filter(|c| !c.is_ascii_digit()).collect() };
+        let mut warnings = Vec::new();
+        for (i, a) in singles.iter().enumerate() {
+            for b in &singles[i + 1..] {
+                if a.len().min(b.len()) >= 4
+                    && edit_distance(a, b) == 1
+                    && without_digits(a) != without_digits(b)
+                {
+                    warnings.push(format!(
+                        "Placeholders `${}` and `${}` each occur once and have similar names. \
+                        If they should match the same code, give them the same name.",
+                        a, b
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Returns the names of placeholders that are used as loop labels, i.e. that are followed by a
+    /// `:` and then a loop, as in `$l: loop {}`.
+    fn label_placeholders(&self) -> FxHashSet<SmolStr> {
+        let non_trivia: Vec<&PatternElement> = self
+            .tokens
+            .iter()
+            .filter(|e| !matches!(e, PatternElement::Token(t) if t.kind.is_trivia()))
+            .collect();
+        let is_kind = |e: Option<&&PatternElement>, kinds: &[SyntaxKind]| match e {
+            Some(PatternElement::Token(t)) => kinds.contains(&t.kind),
+            _ => false,
+        };
+        let mut labels = FxHashSet::default();
+        for (i, element) in non_trivia.iter().enumerate() {
+            if let PatternElement::Placeholder(placeholder) = element {
+                if is_kind(non_trivia.get(i + 1), &[T![:]])
+                    && is_kind(non_trivia.get(i + 2), &[T![loop], T![while], T![for]])
+                {
+                    labels.insert(placeholder.ident.clone());
+                }
+            }
+        }
+        labels
+    }
+
+    /// Returns the operator that each placeholder that stands for an operator is parsed as, keyed
+    /// by the placeholder's name.
+    fn operator_placeholders(&self) -> FxHashMap<SmolStr, SmolStr> {
+        let mut operators = FxHashMap::default();
+        for element in &self.tokens {
+            if let PatternElement::Placeholder(placeholder) = element {
+                if let Some(operator) = placeholder.operator_stand_in() {
+                    operators.entry(placeholder.ident.clone()).or_insert_with(|| operator.clone());
+                }
+            }
+        }
+        operators
+    }
+
+    /// Returns this search pattern as Rust source code that we can feed to the Rust parser. Our
+    /// parser always treats 2018 edition keywords as keywords, so for the 2015 edition, we emit
+    /// them as raw identifiers. Placeholders in `labels` are emitted as lifetimes and those in
+    /// `operators` as the given operator.
+    fn as_rust_code(
+        &self,
+        edition: Edition,
+        labels: &FxHashSet<SmolStr>,
+        operators: &FxHashMap<SmolStr, SmolStr>,
+    ) -> RustCode {
         let mut res = String::new();
-        for t in &self.tokens {
-            res.push_str(match t {
-                PatternElement::Token(token) => token.text.as_str(),
-                PatternElement::Placeholder(placeholder) => placeholder.stand_in_name.as_str(),
-            });
+        let mut operator_offsets = Vec::new();
+        let mut after_else = false;
+        for (i, t) in self.tokens.iter().enumerate() {
+            match t {
+                PatternElement::Token(token) => {
+                    if edition == Edition::Edition2015
+                        && EDITION_2018_KEYWORDS.contains(&token.kind)
+                    {
+                        res.push_str("r#");
+                    }
+                    res.push_str(token.text.as_str());
+                    if !token.kind.is_trivia() {
+                        after_else = token.kind == T![else];
+                    }
+                }
+                PatternElement::Placeholder(placeholder) => {
+                    // Only a block or an `if` can follow `else`, so a placeholder there is put in a
+                    // block of its own. It then stands for the whole else branch.
+                    if after_else {
+                        res.push('{');
+                        res.push_str(&placeholder.else_branch_stand_in_name());
+                        res.push('}');
+                    } else if labels.contains(&placeholder.ident) {
+                        res.push_str(&placeholder.label_stand_in_name());
+                    } else if let Some(operator) = operators.get(&placeholder.ident) {
+                        // Spaces stop the operator being joined with neighbouring punctuation.
+                        let is_whitespace = |e: Option<&PatternElement>| {
+                            matches!(
+                                e,
+                                Some(PatternElement::Token(t)) if t.kind == SyntaxKind::WHITESPACE
+                            )
+                        };
+                        if i > 0 && !is_whitespace(self.tokens.get(i - 1)) {
+                            res.push(' ');
+                        }
+                        operator_offsets
+                            .push((TextSize::of(&res), SmolStr::new(&placeholder.stand_in_name)));
+                        res.push_str(operator);
+                        if !is_whitespace(self.tokens.get(i + 1)) {
+                            res.push(' ');
+                        }
+                    } else {
+                        res.push_str(placeholder.stand_in_name.as_str());
+                    }
+                    after_else = false;
+                }
+            }
         }
-        res
+        RustCode { text: res, operator_offsets }
     }
 
     pub(crate) fn placeholders_by_stand_in(&self) -> FxHashMap<SmolStr, Placeholder> {
         let mut res = FxHashMap::default();
         for t in &self.tokens {
             if let PatternElement::Placeholder(placeholder) = t {
-                res.insert(SmolStr::new(placeholder.stand_in_name.clone()), placeholder.clone());
+                res.entry(SmolStr::new(placeholder.stand_in_name.clone()))
+                    .or_insert_with(|| placeholder.clone());
+                res.entry(SmolStr::new(placeholder.else_branch_stand_in_name()))
+                    .or_insert_with(|| placeholder.clone());
+                res.entry(SmolStr::new(placeholder.label_stand_in_name()))
+                    .or_insert_with(|| placeholder.clone());
             }
         }
         res
     }
 }
 
+/// A pattern written as Rust source code by `RawPattern::as_rust_code`.
+struct RustCode {
+    text: String,
+    /// Where each operator that stands for a placeholder starts, along with the placeholder's
+    /// stand-in name.
+    operator_offsets: Vec<(TextSize, SmolStr)>,
+}
+
+impl RustCode {
+    /// Adds to `out` the token of each operator placeholder in `node`, which should have been
+    /// parsed from us. Returns false if any didn't end up as the operator of a binary or prefix
+    /// expression, since that's the only place that we can match them.
+    fn add_operator_placeholders(
+        &self,
+        node: &SyntaxNode,
+        out: &mut FxHashMap<SyntaxToken, SmolStr>,
+    ) -> bool {
+        for (offset, stand_in) in &self.operator_offsets {
+            if !node.text_range().contains(*offset) {
+                return false;
+            }
+            match node.token_at_offset(*offset).find(|t| t.text_range().start() == *offset) {
+                Some(token)
+                    if matches!(
+                        token.parent().kind(),
+                        SyntaxKind::BIN_EXPR | SyntaxKind::PREFIX_EXPR
+                    ) =>
+                {
+                    out.insert(token, stand_in.clone());
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
 impl FromStr for SsrPattern {
     type Err = SsrError;
 
     fn from_str(pattern_str: &str) -> Result<SsrPattern, SsrError> {
         let raw_pattern = pattern_str.parse()?;
-        let parsed_rules = ParsedRule::new(&raw_pattern, None)?;
-        Ok(SsrPattern { raw: raw_pattern, parsed_rules })
+        let (parsed_rules, edition) =
+            parse_for_any_edition(|edition| ParsedRule::new(&raw_pattern, None, &[], edition))?;
+        Ok(SsrPattern { raw: raw_pattern, parsed_rules, edition })
     }
 }
 
@@ -203,11 +979,41 @@ fn parse_pattern(pattern_str: &str) -> Result<Vec<PatternElement>, SsrError> {
     let mut tokens = tokenize(pattern_str)?.into_iter();
     while let Some(token) = tokens.next() {
         if token.kind == T![$] {
-            let placeholder = parse_placeholder(&mut tokens)?;
-            if !placeholder_names.insert(placeholder.ident.clone()) {
-                bail!("Name `{}` repeats more than once", placeholder.ident);
+            let mut placeholder = parse_placeholder(&mut tokens)?;
+            if is_ellipsis(tokens.as_slice()) {
+                tokens.nth(2);
+                if !placeholder.constraints.is_empty() || placeholder.is_fresh {
+                    bail!("Variadic placeholders can't have constraints");
+                }
+                placeholder.is_variadic = true;
+            }
+            if !placeholder_names.insert(placeholder.ident.clone())
+                && !placeholder.constraints.is_empty()
+            {
+                bail!(
+                    "Constraints for `{}` must be given where it first appears",
+                    placeholder.ident
+                );
             }
             res.push(PatternElement::Placeholder(placeholder));
+        } else if token.kind == T![*] && ends_with_path_separator(&res) {
+            // A `*` or `**` between two `::`s is a wildcard for path segments. Anywhere else, such
+            // as at the end of a glob import, it's left as it is.
+            let is_double = tokens.as_slice().first().map(|t| t.kind) == Some(T![*]);
+            let after = &tokens.as_slice()[if is_double { 1 } else { 0 }..];
+            if !(after.len() >= 2 && after[..2].iter().all(|t| t.kind == T![:])) {
+                res.push(PatternElement::Token(token));
+                continue;
+            }
+            if is_double {
+                tokens.next();
+            }
+            let stand_in =
+                if is_double { WILDCARD_SEGMENTS_STAND_IN } else { WILDCARD_SEGMENT_STAND_IN };
+            res.push(PatternElement::Token(Token {
+                kind: SyntaxKind::IDENT,
+                text: SmolStr::new(stand_in),
+            }));
         } else {
             res.push(PatternElement::Token(token));
         }
@@ -215,20 +1021,62 @@ fn parse_pattern(pattern_str: &str) -> Result<Vec<PatternElement>, SsrError> {
     Ok(res)
 }
 
+/// Returns whether the last two elements of `elements` are the `:`s of a `::`.
+fn ends_with_path_separator(elements: &[PatternElement]) -> bool {
+    elements.len() >= 2
+        && elements[elements.len() - 2..]
+            .iter()
+            .all(|e| matches!(e, PatternElement::Token(t) if t.kind == T![:]))
+}
+
+/// Returns whether `text` is the stand-in for a `*` or `**` path wildcard.
+pub(crate) fn is_wildcard_stand_in(text: &str) -> bool {
+    text == WILDCARD_SEGMENT_STAND_IN || text == WILDCARD_SEGMENTS_STAND_IN
+}
+
 /// Checks for errors in a rule. e.g. the replace pattern referencing placeholders that the search
 /// pattern didn't define.
 fn validate_rule(rule: &SsrRule) -> Result<(), SsrError> {
     let mut defined_placeholders = FxHashSet::default();
     for p in &rule.pattern.tokens {
         if let PatternElement::Placeholder(placeholder) = p {
+            if placeholder.is_fresh {
+                bail!("Fresh identifiers can only be used in the replacement");
+            }
             defined_placeholders.insert(&placeholder.ident);
         }
     }
     let mut undefined = Vec::new();
-    for p in &rule.template.tokens {
+    let templates = std::iter::once(&rule.template).chain(&rule.alternative_templates);
+    for p in templates.flat_map(|template| &template.tokens) {
+        if let PatternElement::Token(token) = p {
+            if is_wildcard_stand_in(&token.text) {
+                bail!("Path wildcards can only be used in the search pattern");
+            }
+            if matches!(token.kind, SyntaxKind::STRING | SyntaxKind::RAW_STRING) {
+                for (_, name) in match_variables(&token.text) {
+                    if !MATCH_VARIABLES.contains(&name) {
+                        bail!(
+                            "Unknown variable `${{match.{}}}`, expected one of: {}",
+                            name,
+                            MATCH_VARIABLES.join(", ")
+                        );
+                    }
+                }
+            }
+        }
         if let PatternElement::Placeholder(placeholder) = p {
-            if !defined_placeholders.contains(&placeholder.ident) {
-                undefined.push(format!("${}", placeholder.ident));
+            if !placeholder.is_fresh && !defined_placeholders.contains(&placeholder.ident) {
+                let suggestion = defined_placeholders
+                    .iter()
+                    .map(|defined| (edit_distance(defined, &placeholder.ident), *defined))
+                    .filter(|(distance, _)| *distance <= 2 && *distance < placeholder.ident.len())
+                    .min();
+                match suggestion {
+                    Some((_, defined)) => undefined
+                        .push(format!("${} (did you mean `${}`?)", placeholder.ident, defined)),
+                    None => undefined.push(format!("${}", placeholder.ident)),
+                }
             }
             if !placeholder.constraints.is_empty() {
                 bail!("Replacement placeholders cannot have constraints");
@@ -241,7 +1089,50 @@ fn validate_rule(rule: &SsrRule) -> Result<(), SsrError> {
     Ok(())
 }
 
-fn tokenize(source: &str) -> Result<Vec<Token>, SsrError> {
+/// Returns the range and name of each `${match.<name>}` in `text`, which is a string literal.
+pub(crate) fn match_variables(text: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    const START: &str = "${match.";
+    let mut variables = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find(START).map(|i| offset + i) {
+        let name_start = start + START.len();
+        let end = match text[name_start..].find('}') {
+            Some(i) => name_start + i,
+            None => break,
+        };
+        variables.push((start..end + 1, &text[name_start..end]));
+        offset = end + 1;
+    }
+    variables
+}
+
+/// Returns the number of single character insertions, deletions, substitutions and swaps of
+/// adjacent characters needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between the first i characters of `a` and the first j of `b`.
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() {
+        for j in 0..=b.len() {
+            distances[i][j] = if i == 0 || j == 0 {
+                i + j
+            } else {
+                let substitution_cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+                let mut distance = (distances[i - 1][j] + 1)
+                    .min(distances[i][j - 1] + 1)
+                    .min(distances[i - 1][j - 1] + substitution_cost);
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    distance = distance.min(distances[i - 2][j - 2] + 1);
+                }
+                distance
+            };
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+pub(crate) fn tokenize(source: &str) -> Result<Vec<Token>, SsrError> {
     let mut start = 0;
     let (raw_tokens, errors) = ra_syntax::tokenize(source);
     if let Some(first_error) = errors.first() {
@@ -259,18 +1150,31 @@ fn tokenize(source: &str) -> Result<Vec<Token>, SsrError> {
     Ok(tokens)
 }
 
+/// Returns whether `tokens` starts with `...`. The tokenizer produces a separate token for each
+/// `.`.
+fn is_ellipsis(tokens: &[Token]) -> bool {
+    tokens.len() >= 3 && tokens[..3].iter().all(|t| t.kind == T![.])
+}
+
 fn parse_placeholder(tokens: &mut std::vec::IntoIter<Token>) -> Result<Placeholder, SsrError> {
     let mut name = None;
     let mut constraints = Vec::new();
     if let Some(token) = tokens.next() {
         match token.kind {
-            SyntaxKind::IDENT => {
+            // Keywords are allowed as names too, since names like `$mod` and `$type` read well and
+            // the placeholder's stand-in name is never a keyword.
+            kind if kind == SyntaxKind::IDENT || kind.is_keyword() => {
                 name = Some(token.text);
             }
             T!['{'] => {
                 let token =
                     tokens.next().ok_or_else(|| SsrError::new("Unexpected end of placeholder"))?;
                 if token.kind == SyntaxKind::IDENT {
+                    if token.text == "fresh"
+                        && tokens.as_slice().first().map(|t| t.kind) == Some(T!['('])
+                    {
+                        return parse_fresh_placeholder(tokens);
+                    }
                     name = Some(token.text);
                 }
                 loop {
@@ -295,6 +1199,26 @@ fn parse_placeholder(tokens: &mut std::vec::IntoIter<Token>) -> Result<Placehold
     Ok(Placeholder::new(name, constraints))
 }
 
+/// Parses the rest of `${fresh(name)}`, after `fresh`.
+fn parse_fresh_placeholder(
+    tokens: &mut std::vec::IntoIter<Token>,
+) -> Result<Placeholder, SsrError> {
+    expect_token(tokens, "(")?;
+    let name = match tokens.next() {
+        Some(token) if token.kind == SyntaxKind::IDENT => token.text,
+        _ => bail!("Expected an identifier in `${{fresh(...)}}`"),
+    };
+    expect_token(tokens, ")")?;
+    expect_token(tokens, "}")?;
+    Ok(Placeholder {
+        stand_in_name: format!("__fresh_{}", name),
+        constraints: Vec::new(),
+        ident: name,
+        is_fresh: true,
+        is_variadic: false,
+    })
+}
+
 fn parse_constraint(tokens: &mut std::vec::IntoIter<Token>) -> Result<Constraint, SsrError> {
     let constraint_type = tokens
         .next()
@@ -313,16 +1237,195 @@ fn parse_constraint(tokens: &mut std::vec::IntoIter<Token>) -> Result<Constraint
             expect_token(tokens, ")")?;
             Ok(Constraint::Kind(NodeKind::from(&t.text)?))
         }
+        "parent" => {
+            expect_token(tokens, "(")?;
+            let t = tokens.next().ok_or_else(|| {
+                SsrError::new("Unexpected end of constraint while looking for parent kind")
+            })?;
+            expect_token(tokens, ")")?;
+            Ok(Constraint::Parent(ParentKind::from(&t.text)?))
+        }
         "not" => {
             expect_token(tokens, "(")?;
             let sub = parse_constraint(tokens)?;
             expect_token(tokens, ")")?;
             Ok(Constraint::Not(Box::new(sub)))
         }
+        "named" => {
+            expect_token(tokens, "(")?;
+            let t = tokens.next().ok_or_else(|| {
+                SsrError::new("Unexpected end of constraint while looking for name")
+            })?;
+            if t.kind != SyntaxKind::IDENT && !t.kind.is_keyword() {
+                bail!("Expected name, found {:?} while parsing named constraint", t.kind);
+            }
+            expect_token(tokens, ")")?;
+            Ok(Constraint::IsNamed(t.text))
+        }
+        "is_const" => Ok(Constraint::IsConst),
+        "is_async" => Ok(Constraint::IsAsync),
+        "redundant_cast" => Ok(Constraint::RedundantCast),
+        "zero_sized" => Ok(Constraint::IsZeroSized),
+        "no_alloc" => Ok(Constraint::NoAllocHeuristic),
+        "in_const" => Ok(Constraint::InConst),
+        "no_locals" => Ok(Constraint::NoLocals),
+        "has_default" => Ok(Constraint::HasDefaultValue),
+        "arity" => {
+            expect_token(tokens, "(")?;
+            let t = tokens.next().ok_or_else(|| {
+                SsrError::new("Unexpected end of constraint while looking for arity")
+            })?;
+            let arity = match t.kind {
+                SyntaxKind::INT_NUMBER => t.text.parse::<usize>().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| SsrError::new(format!("Invalid arity `{}`", t.text)))?;
+            expect_token(tokens, ")")?;
+            Ok(Constraint::Arity(arity))
+        }
+        "int_in" => {
+            expect_token(tokens, "(")?;
+            let mut range = String::new();
+            loop {
+                let t = tokens.next().ok_or_else(|| {
+                    SsrError::new("Unexpected end of constraint while looking for range")
+                })?;
+                match t.kind {
+                    T![')'] => break,
+                    kind if kind.is_trivia() => {}
+                    _ => range.push_str(&t.text),
+                }
+            }
+            parse_int_range(&range)
+        }
+        "suffix" => {
+            expect_token(tokens, "(")?;
+            let t = tokens.next().ok_or_else(|| {
+                SsrError::new("Unexpected end of constraint while looking for suffix")
+            })?;
+            expect_token(tokens, ")")?;
+            if t.text == "none" {
+                return Ok(Constraint::LiteralSuffix(None));
+            }
+            if !NUMERIC_LITERAL_SUFFIXES.contains(&t.text.as_str()) {
+                bail!("Expected a numeric type or `none`, found '{}' in suffix constraint", t.text);
+            }
+            Ok(Constraint::LiteralSuffix(Some(t.text)))
+        }
+        "one_of" => {
+            expect_token(tokens, "(")?;
+            let mut paths = vec![String::new()];
+            loop {
+                let t = tokens.next().ok_or_else(|| {
+                    SsrError::new("Unexpected end of constraint while looking for paths")
+                })?;
+                match t.kind {
+                    T![')'] => break,
+                    T![,] => paths.push(String::new()),
+                    kind if kind.is_trivia() => {}
+                    _ => paths.last_mut().unwrap().push_str(&t.text),
+                }
+            }
+            let num_operators = paths.iter().filter(|p| OPERATORS.contains(&p.as_str())).count();
+            if num_operators == paths.len() {
+                return Ok(Constraint::OneOfOperators(
+                    paths.into_iter().map(SmolStr::new).collect(),
+                ));
+            }
+            if num_operators > 0 {
+                bail!("A one_of constraint can list either operators or paths, but not both");
+            }
+            for path in &paths {
+                if ast::Path::parse(path).is_err() {
+                    bail!("Invalid path '{}' in one_of constraint", path);
+                }
+            }
+            Ok(Constraint::OneOf(paths.into_iter().map(SmolStr::new).collect()))
+        }
+        "returns" => {
+            expect_token(tokens, "(")?;
+            let mut ty = String::new();
+            let mut depth = 0;
+            loop {
+                let t = tokens.next().ok_or_else(|| {
+                    SsrError::new("Unexpected end of constraint while looking for return type")
+                })?;
+                match t.kind {
+                    T![')'] if depth == 0 => break,
+                    T![')'] => depth -= 1,
+                    T!['('] => depth += 1,
+                    _ => {}
+                }
+                ty.push_str(if t.kind.is_trivia() { " " } else { &t.text });
+            }
+            let ty = ty.trim();
+            if ast::Type::parse(ty).is_err() {
+                bail!("Expected a type in returns constraint, found `{}`", ty);
+            }
+            Ok(Constraint::Returns(SmolStr::new(ty)))
+        }
+        "has_attr" => {
+            expect_token(tokens, "(")?;
+            let mut path = String::new();
+            let mut depth = 0;
+            loop {
+                let t = tokens.next().ok_or_else(|| {
+                    SsrError::new("Unexpected end of constraint while looking for attribute")
+                })?;
+                match t.kind {
+                    T![')'] if depth == 0 => break,
+                    T![')'] => depth -= 1,
+                    T!['('] => depth += 1,
+                    kind if kind.is_trivia() => {}
+                    // Only the attribute's path is compared, so its input is skipped.
+                    _ if depth > 0 => {}
+                    _ => path.push_str(&t.text),
+                }
+            }
+            if ast::Path::parse(&path).is_err() {
+                bail!("Invalid attribute '{}' in has_attr constraint", path);
+            }
+            Ok(Constraint::HasAttr(SmolStr::new(path)))
+        }
+        "in_loop" => {
+            let mut through_closures = false;
+            if tokens.as_slice().first().map(|t| t.text.as_str()) == Some("(") {
+                expect_token(tokens, "(")?;
+                expect_token(tokens, "through_closures")?;
+                expect_token(tokens, ")")?;
+                through_closures = true;
+            }
+            Ok(Constraint::InLoop { through_closures })
+        }
         x => bail!("Unsupported constraint type '{}'", x),
     }
 }
 
+/// Parses a range such as `1..`, `..=5` or `-3..3` for the `int_in` constraint.
+fn parse_int_range(range: &str) -> Result<Constraint, SsrError> {
+    let invalid = || SsrError::new(format!("Invalid range '{}' in int_in constraint", range));
+    let dots = range.find("..").ok_or_else(invalid)?;
+    let (start, end) = (&range[..dots], &range[dots + 2..]);
+    let (end, inclusive) = match end.strip_prefix('=') {
+        Some(end) => (end, true),
+        None => (end, false),
+    };
+    let parse_bound = |bound: &str| -> Result<Option<i128>, SsrError> {
+        if bound.is_empty() {
+            return Ok(None);
+        }
+        bound.parse().map(Some).map_err(|_| invalid())
+    };
+    let min = parse_bound(start)?;
+    let max = match (parse_bound(end)?, inclusive) {
+        (Some(end), true) => Some(end),
+        (Some(end), false) => Some(end.checked_sub(1).ok_or_else(invalid)?),
+        (None, true) => return Err(invalid()),
+        (None, false) => None,
+    };
+    Ok(Constraint::IntInRange { min, max })
+}
+
 fn expect_token(tokens: &mut std::vec::IntoIter<Token>, expected: &str) -> Result<(), SsrError> {
     if let Some(t) = tokens.next() {
         if t.text == expected {
@@ -333,18 +1436,125 @@ fn expect_token(tokens: &mut std::vec::IntoIter<Token>, expected: &str) -> Resul
     bail!("Expected {} found end of stream", expected);
 }
 
+impl Constraint {
+    /// Returns whether checking this constraint requires semantic analysis, such as resolving paths
+    /// or inferring types. Such constraints are comparatively expensive to check.
+    pub(crate) fn is_semantic(&self) -> bool {
+        match self {
+            Constraint::Kind(_)
+            | Constraint::IsNamed(_)
+            | Constraint::InLoop { .. }
+            | Constraint::InConst
+            | Constraint::IntInRange { .. }
+            | Constraint::LiteralSuffix(_)
+            | Constraint::OneOfOperators(_)
+            | Constraint::Parent(_)
+            | Constraint::HasDefaultValue
+            | Constraint::Arity(_) => false,
+            Constraint::Not(sub) => sub.is_semantic(),
+            Constraint::IsConst
+            | Constraint::RedundantCast
+            | Constraint::IsZeroSized
+            | Constraint::NoAllocHeuristic
+            | Constraint::NoLocals
+            | Constraint::OneOf(_)
+            | Constraint::HasAttr(_)
+            | Constraint::IsAsync
+            | Constraint::Returns(_) => true,
+        }
+    }
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Kind(NodeKind::Literal) => write!(f, "kind(literal)"),
+            Constraint::Kind(NodeKind::NumericLiteral) => write!(f, "kind(numeric_literal)"),
+            Constraint::Kind(NodeKind::StringLiteral) => write!(f, "kind(string_literal)"),
+            Constraint::Kind(NodeKind::FieldAccess) => write!(f, "kind(field)"),
+            Constraint::Not(sub) => write!(f, "not({})", sub),
+            Constraint::IsConst => write!(f, "is_const"),
+            Constraint::IsAsync => write!(f, "is_async"),
+            Constraint::Returns(ty) => write!(f, "returns({})", ty),
+            Constraint::RedundantCast => write!(f, "redundant_cast"),
+            Constraint::IsNamed(name) => write!(f, "named({})", name),
+            Constraint::IsZeroSized => write!(f, "zero_sized"),
+            Constraint::InLoop { through_closures: false } => write!(f, "in_loop"),
+            Constraint::InLoop { through_closures: true } => write!(f, "in_loop(through_closures)"),
+            Constraint::NoAllocHeuristic => write!(f, "no_alloc"),
+            Constraint::InConst => write!(f, "in_const"),
+            Constraint::NoLocals => write!(f, "no_locals"),
+            Constraint::HasDefaultValue => write!(f, "has_default"),
+            Constraint::Arity(arity) => write!(f, "arity({})", arity),
+            Constraint::LiteralSuffix(None) => write!(f, "suffix(none)"),
+            Constraint::LiteralSuffix(Some(suffix)) => write!(f, "suffix({})", suffix),
+            Constraint::OneOf(paths) => write!(f, "one_of({})", paths.join(", ")),
+            Constraint::OneOfOperators(operators) => write!(f, "one_of({})", operators.join(", ")),
+            Constraint::HasAttr(path) => write!(f, "has_attr({})", path),
+            Constraint::Parent(ParentKind::Condition) => write!(f, "parent(condition)"),
+            Constraint::Parent(ParentKind::Argument) => write!(f, "parent(argument)"),
+            Constraint::Parent(ParentKind::Statement) => write!(f, "parent(statement)"),
+            Constraint::IntInRange { min, max } => {
+                let bound = |b: &Option<i128>| b.map(|b| b.to_string()).unwrap_or_default();
+                match max {
+                    Some(_) => write!(f, "int_in({}..={})", bound(min), bound(max)),
+                    None => write!(f, "int_in({}..)", bound(min)),
+                }
+            }
+        }
+    }
+}
+
 impl NodeKind {
     fn from(name: &SmolStr) -> Result<NodeKind, SsrError> {
         Ok(match name.as_str() {
             "literal" => NodeKind::Literal,
+            "numeric_literal" => NodeKind::NumericLiteral,
+            "string_literal" => NodeKind::StringLiteral,
+            "field" => NodeKind::FieldAccess,
             _ => bail!("Unknown node kind '{}'", name),
         })
     }
 }
 
+impl ParentKind {
+    fn from(name: &SmolStr) -> Result<ParentKind, SsrError> {
+        Ok(match name.as_str() {
+            "condition" => ParentKind::Condition,
+            "argument" => ParentKind::Argument,
+            "statement" => ParentKind::Statement,
+            _ => bail!("Unknown parent kind '{}'", name),
+        })
+    }
+}
+
 impl Placeholder {
     fn new(name: SmolStr, constraints: Vec<Constraint>) -> Self {
-        Self { stand_in_name: format!("__placeholder_{}", name), constraints, ident: name }
+        Self {
+            stand_in_name: format!("__placeholder_{}", name),
+            constraints,
+            ident: name,
+            is_fresh: false,
+            is_variadic: false,
+        }
+    }
+
+    /// If this placeholder stands for an operator, the operator that it's parsed as.
+    fn operator_stand_in(&self) -> Option<&SmolStr> {
+        self.constraints.iter().find_map(|constraint| match constraint {
+            Constraint::OneOfOperators(operators) => operators.first(),
+            _ => None,
+        })
+    }
+
+    /// The name used in place of this placeholder when it's directly after `else`.
+    pub(crate) fn else_branch_stand_in_name(&self) -> String {
+        format!("{}{}", ELSE_BRANCH_STAND_IN_PREFIX, self.ident)
+    }
+
+    /// The name used in place of this placeholder when it's a loop label.
+    pub(crate) fn label_stand_in_name(&self) -> String {
+        format!("{}{}", LABEL_STAND_IN_PREFIX, self.ident)
     }
 }
 
@@ -0,0 +1,152 @@
+//! Saving and loading sets of rules. Parsing a large number of rules from text each time they're
+//! used can be slow, so instead they can be saved once they've been parsed and validated, then
+//! loaded again later. Rules are saved before resolution, since that depends on the code that
+//! they're applied to.
+
+use crate::errors::bail;
+use crate::parsing::{self, ParsedRule, PatternElement, PatternPosition, Placeholder, RawPattern};
+use crate::{OncePer, SsrError, SsrRule, TrailingSemicolon};
+use ra_db::Edition;
+use ra_syntax::SmolStr;
+use serde::{Deserialize, Serialize};
+
+/// The version of the format in which rules are saved. This should be incremented whenever the
+/// format changes, including when constraints are added or changed.
+const FORMAT_VERSION: u32 = 24;
+
+#[derive(Serialize, Deserialize)]
+struct SavedRules {
+    version: u32,
+    rules: Vec<SavedRule>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRule {
+    pattern: Vec<SavedElement>,
+    pattern_position: Option<PatternPosition>,
+    template: Vec<SavedElement>,
+    alternative_templates: Vec<Vec<SavedElement>>,
+    edition_2018: bool,
+    statement_boundary: bool,
+    heuristic_fallback: bool,
+    idempotency_check: bool,
+    once_per: Option<OncePer>,
+    trailing_semicolon: TrailingSemicolon,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SavedElement {
+    /// The kind of a token isn't saved, since it's just a number that may change between versions.
+    /// Instead we lex the text again when loading.
+    Token(SmolStr),
+    Placeholder(Placeholder),
+}
+
+/// Returns `rules` in a form that can be passed to `load_rules`.
+pub fn save_rules(rules: &[SsrRule]) -> String {
+    let saved = SavedRules {
+        version: FORMAT_VERSION,
+        rules: rules
+            .iter()
+            .map(|rule| SavedRule {
+                pattern: save_pattern(&rule.pattern),
+                pattern_position: rule.pattern.position,
+                template: save_pattern(&rule.template),
+                alternative_templates: rule
+                    .alternative_templates
+                    .iter()
+                    .map(save_pattern)
+                    .collect(),
+                edition_2018: rule.edition == Edition::Edition2018,
+                statement_boundary: rule.statement_boundary,
+                heuristic_fallback: rule.heuristic_fallback,
+                idempotency_check: rule.idempotency_check,
+                once_per: rule.once_per,
+                trailing_semicolon: rule.trailing_semicolon,
+            })
+            .collect(),
+    };
+    serde_json::to_string(&saved).expect("Saved rules can always be serialized")
+}
+
+/// Loads rules that were saved by `save_rules`. The rules were validated before they were saved,
+/// so aren't validated again.
+pub fn load_rules(saved: &str) -> Result<Vec<SsrRule>, SsrError> {
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+    // We check the version before anything else, since a different version may not have the same
+    // structure.
+    match serde_json::from_str::<Version>(saved) {
+        Ok(Version { version: FORMAT_VERSION }) => {}
+        Ok(Version { version }) => bail!(
+            "Saved rules have format version {}, but only version {} is supported",
+            version,
+            FORMAT_VERSION
+        ),
+        Err(e) => bail!("Failed to load saved rules: {}", e),
+    }
+    let saved: SavedRules = serde_json::from_str(saved)
+        .map_err(|e| SsrError::new(format!("Failed to load saved rules: {}", e)))?;
+    saved
+        .rules
+        .into_iter()
+        .map(|rule| {
+            let mut pattern = load_pattern(rule.pattern)?;
+            pattern.position = rule.pattern_position;
+            let template = load_pattern(rule.template)?;
+            let alternative_templates = rule
+                .alternative_templates
+                .into_iter()
+                .map(load_pattern)
+                .collect::<Result<Vec<_>, _>>()?;
+            let edition =
+                if rule.edition_2018 { Edition::Edition2018 } else { Edition::Edition2015 };
+            let parsed_rules =
+                ParsedRule::new(&pattern, Some(&template), &alternative_templates, edition)?;
+            Ok(SsrRule {
+                pattern,
+                template,
+                alternative_templates,
+                parsed_rules,
+                edition,
+                statement_boundary: rule.statement_boundary,
+                heuristic_fallback: rule.heuristic_fallback,
+                idempotency_check: rule.idempotency_check,
+                once_per: rule.once_per,
+                trailing_semicolon: rule.trailing_semicolon,
+            })
+        })
+        .collect()
+}
+
+fn save_pattern(pattern: &RawPattern) -> Vec<SavedElement> {
+    pattern
+        .tokens
+        .iter()
+        .map(|element| match element {
+            PatternElement::Token(token) => SavedElement::Token(token.text.clone()),
+            PatternElement::Placeholder(placeholder) => {
+                SavedElement::Placeholder(placeholder.clone())
+            }
+        })
+        .collect()
+}
+
+fn load_pattern(saved: Vec<SavedElement>) -> Result<RawPattern, SsrError> {
+    let mut tokens = Vec::with_capacity(saved.len());
+    for element in saved {
+        tokens.push(match element {
+            SavedElement::Token(text) => {
+                let mut lexed = parsing::tokenize(&text)?;
+                if lexed.len() != 1 {
+                    bail!("Failed to load saved rules: `{}` isn't a single token", text);
+                }
+                PatternElement::Token(lexed.pop().unwrap())
+            }
+            SavedElement::Placeholder(placeholder) => PatternElement::Placeholder(placeholder),
+        });
+    }
+    Ok(RawPattern { tokens, position: None })
+}
@@ -1,21 +1,89 @@
 //! Code for applying replacement templates for matches that have previously been found.
 
-use crate::matching::Var;
-use crate::{resolving::ResolvedRule, Match, SsrMatches};
-use ra_syntax::ast::{self, AstToken};
-use ra_syntax::{SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken, TextRange, TextSize};
+use crate::matching::{self, Var};
+use crate::{
+    parsing,
+    resolving::{ResolvedPattern, ResolvedRule},
+    InsertPosition, Match, MatchOrigin, SsrMatches, TrailingSemicolon,
+};
+use ra_syntax::ast::{self, edit::IndentLevel, AstNode, AstToken};
+use ra_syntax::{
+    algo, Direction, SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken, TextRange, TextSize,
+};
 use ra_text_edit::TextEdit;
 use rustc_hash::{FxHashMap, FxHashSet};
 
 /// Returns a text edit that will replace each match in `matches` with its corresponding replacement
 /// template. Placeholders in the template will have been substituted with whatever they matched to
-/// in the original code.
+/// in the original code. Matches of insertion rules instead have their text inserted nearby.
 pub(crate) fn matches_to_edit(
     matches: &SsrMatches,
     file_src: &str,
     rules: &[ResolvedRule],
 ) -> TextEdit {
-    matches_to_edit_at_offset(matches, file_src, 0.into(), rules)
+    let mut edit = matches_to_edit_at_offset(matches, file_src, 0.into(), rules);
+    let insertions = insertions_edit(matches, &edit, rules);
+    edit.union(insertions).expect("Insertions within replaced code should have been dropped");
+    edit
+}
+
+/// Adds to `out` those of `matches` that can be replaced. Matches that came partly from a macro's
+/// definition can't be, but code that they matched in their placeholders might have matches that
+/// can be. The same goes for matches of insertion rules, which leave the code as it is.
+fn editable_matches<'a>(matches: &'a [Match], rules: &[ResolvedRule], out: &mut Vec<&'a Match>) {
+    for m in matches {
+        if m.inside_macro_expansion || rules[m.rule_index].inserter.is_some() {
+            for placeholder_value in m.placeholder_values.values() {
+                editable_matches(&placeholder_value.inner_matches.matches, rules, out);
+            }
+        } else {
+            out.push(m);
+        }
+    }
+}
+
+/// Returns an edit that makes the insertions for all matches of insertion rules in `matches`,
+/// including nested ones. Any that would be within code that `replacements` changes are left out,
+/// since that code won't be there any more. Since all the insertions are relative to the original
+/// text, several in the same file don't affect each other's positions.
+fn insertions_edit(
+    matches: &SsrMatches,
+    replacements: &TextEdit,
+    rules: &[ResolvedRule],
+) -> TextEdit {
+    let mut edit_builder = ra_text_edit::TextEditBuilder::default();
+    for m in matches.flatten() {
+        let inserter = match &rules[m.rule_index].inserter {
+            Some(inserter) if !m.inside_macro_expansion => inserter,
+            _ => continue,
+        };
+        let text = (inserter.text_from)(m);
+        let (offset, text) = match inserter.position {
+            InsertPosition::BeforeMatch => (m.range.range.start(), text),
+            InsertPosition::AfterMatch => (m.range.range.end(), text),
+            InsertPosition::BeforeEnclosingItem | InsertPosition::AfterEnclosingItem => {
+                let item = match m.matched_node.ancestors().find(|n| ast::Item::can_cast(n.kind()))
+                {
+                    Some(item) => item,
+                    None => continue,
+                };
+                let indent = IndentLevel::from_node(&item);
+                if inserter.position == InsertPosition::BeforeEnclosingItem {
+                    (item.text_range().start(), format!("{}\n{}", text, indent))
+                } else {
+                    (item.text_range().end(), format!("\n{}{}", indent, text))
+                }
+            }
+        };
+        if replacements
+            .iter()
+            .any(|indel| indel.delete.start() < offset && offset < indel.delete.end())
+        {
+            continue;
+        }
+        edit_builder.insert(offset, text);
+    }
+    edit_builder.finish()
 }
 
 fn matches_to_edit_at_offset(
@@ -25,15 +93,170 @@ fn matches_to_edit_at_offset(
     rules: &[ResolvedRule],
 ) -> TextEdit {
     let mut edit_builder = ra_text_edit::TextEditBuilder::default();
-    for m in &matches.matches {
-        edit_builder.replace(
-            m.range.range.checked_sub(relative_start).unwrap(),
-            render_replace(m, file_src, rules),
-        );
+    let mut editable = Vec::new();
+    editable_matches(&matches.matches, rules, &mut editable);
+    for m in editable {
+        if let Some(rewriter) = &rules[m.rule_index].node_rewriter {
+            if let Some(node) = rewriter(m) {
+                let mut replacement = node.to_string();
+                push_ignored_comments(m, &mut replacement);
+                edit_builder
+                    .replace(m.range.range.checked_sub(relative_start).unwrap(), replacement);
+            }
+            continue;
+        }
+        let template = template_for_match(m, rules);
+        if m.matched_impl_members.is_empty() {
+            let mut replacement =
+                render_replace(m, &template.node, m.range.range.start(), file_src, rules);
+            let mut range = m.range.range;
+            match semicolon_change(m, &rules[m.rule_index], &replacement) {
+                SemicolonChange::Add => replacement.push(';'),
+                // The `;` is deleted separately, so that the match still ends where it did, which
+                // is where insertions after it are made.
+                SemicolonChange::Drop(semicolon) => edit_builder.delete(
+                    TextRange::new(range.end(), semicolon.end())
+                        .checked_sub(relative_start)
+                        .unwrap(),
+                ),
+                SemicolonChange::None => {}
+            }
+            push_ignored_comments(m, &mut replacement);
+            if replacement.is_empty() && ast::Item::can_cast(m.matched_node.kind()) {
+                range = extend_over_whole_line(range, file_src);
+            }
+            edit_builder.replace(range.checked_sub(relative_start).unwrap(), replacement);
+            continue;
+        }
+        // The search pattern was an impl with members. Each member of the template replaces the
+        // code that the corresponding member of the pattern matched. Any other members of the impl
+        // are left alone.
+        let template_members = parsing::impl_members(&template.node).unwrap_or_default();
+        let num_members = m.matched_impl_members.len();
+        for (i, (member_range, template_member)) in
+            m.matched_impl_members.iter().zip(&template_members).enumerate()
+        {
+            let mut replacement =
+                render_replace(m, template_member, member_range.range.start(), file_src, rules);
+            if i + 1 == num_members {
+                push_ignored_comments(m, &mut replacement);
+            }
+            edit_builder
+                .replace(member_range.range.checked_sub(relative_start).unwrap(), replacement);
+        }
     }
     edit_builder.finish()
 }
 
+/// What to do with the `;` of the expression statement that a match is the expression of.
+enum SemicolonChange {
+    Add,
+    /// Drop the `;` at this range.
+    Drop(TextRange),
+    None,
+}
+
+/// Decides, according to the rule's `TrailingSemicolon`, whether replacing `m` with `replacement`
+/// should add or drop a `;`. If `m` is a whole expression statement, which the rule's
+/// `statement_boundary` requires, its `;` is part of the match, so is already dropped and only
+/// needs adding back.
+fn semicolon_change(m: &Match, rule: &ResolvedRule, replacement: &str) -> SemicolonChange {
+    let keeps_unit_value = || {
+        rule.trailing_semicolon == TrailingSemicolon::Auto
+            && ast::Expr::parse(replacement.trim()).map_or(false, |expr| is_unit_block_like(&expr))
+    };
+    if rule.statement_boundary {
+        return if keeps_unit_value() { SemicolonChange::None } else { SemicolonChange::Add };
+    }
+    if rule.trailing_semicolon == TrailingSemicolon::Keep || m.origin != MatchOrigin::Direct {
+        return SemicolonChange::None;
+    }
+    let stmt = match m.matched_node.parent().and_then(ast::ExprStmt::cast) {
+        Some(stmt) if stmt.expr().map_or(false, |expr| expr.syntax() == &m.matched_node) => stmt,
+        _ => return SemicolonChange::None,
+    };
+    match stmt.semicolon_token() {
+        // Only a block-like expression can be a statement without a `;`, unless it's the last
+        // thing in its block, in which case it isn't an `ExprStmt`.
+        None => {
+            let is_block_like =
+                ast::Expr::parse(replacement.trim()).map_or(false, |expr| expr.is_block_like());
+            if is_block_like {
+                SemicolonChange::None
+            } else {
+                SemicolonChange::Add
+            }
+        }
+        Some(semicolon) if keeps_unit_value() => SemicolonChange::Drop(semicolon.text_range()),
+        Some(_) => SemicolonChange::None,
+    }
+}
+
+/// Returns whether `expr` is a block-like expression whose value is clearly `()`, so that it can be
+/// a statement without a `;`. A `loop` could break with a value, so isn't counted.
+fn is_unit_block_like(expr: &ast::Expr) -> bool {
+    let is_unit_block = |block: Option<ast::BlockExpr>| {
+        block.map_or(false, |block| block.expr().map_or(true, |tail| is_unit_block_like(&tail)))
+    };
+    match expr {
+        ast::Expr::BlockExpr(block) => is_unit_block(Some(block.clone())),
+        ast::Expr::EffectExpr(effect) => {
+            effect.unsafe_token().is_some() && is_unit_block(effect.block_expr())
+        }
+        ast::Expr::IfExpr(if_expr) => {
+            is_unit_block(if_expr.then_branch())
+                && match if_expr.else_branch() {
+                    None => true,
+                    Some(ast::ElseBranch::Block(block)) => is_unit_block(Some(block)),
+                    Some(ast::ElseBranch::IfExpr(else_if)) => {
+                        is_unit_block_like(&ast::Expr::IfExpr(else_if))
+                    }
+                }
+        }
+        ast::Expr::MatchExpr(match_expr) => match_expr.match_arm_list().map_or(false, |arms| {
+            arms.arms().all(|arm| match arm.expr() {
+                Some(ast::Expr::BlockExpr(block)) => is_unit_block(Some(block)),
+                _ => false,
+            })
+        }),
+        ast::Expr::WhileExpr(_) | ast::Expr::ForExpr(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns `range` extended to cover the whole of its line, including the newline, if there's
+/// nothing else on that line. This way, removing an item doesn't leave a blank line behind.
+fn extend_over_whole_line(range: TextRange, file_src: &str) -> TextRange {
+    let is_space = |c: char| c == ' ' || c == '\t';
+    let before = &file_src[..usize::from(range.start())];
+    let after = &file_src[usize::from(range.end())..];
+    let indent = before.len() - before.trim_end_matches(is_space).len();
+    let trailing = after.len() - after.trim_start_matches(is_space).len();
+    let line_start = before.len() - indent;
+    if (line_start == 0 || before[..line_start].ends_with('\n'))
+        && after[trailing..].starts_with('\n')
+    {
+        TextRange::new(
+            TextSize::from(line_start as u32),
+            range.end() + TextSize::from(trailing as u32 + 1),
+        )
+    } else {
+        range
+    }
+}
+
+fn template_for_match<'a>(match_info: &Match, rules: &'a [ResolvedRule]) -> &'a ResolvedPattern {
+    rules[match_info.rule_index]
+        .template_for(match_info)
+        .expect("You called MatchFinder::edits after calling MatchFinder::add_search_pattern")
+}
+
+fn push_ignored_comments(match_info: &Match, out: &mut String) {
+    for comment in &match_info.ignored_comments {
+        out.push_str(&comment.syntax().to_string());
+    }
+}
+
 struct ReplacementRenderer<'a> {
     match_info: &'a Match,
     file_src: &'a str,
@@ -48,28 +271,41 @@ struct ReplacementRenderer<'a> {
     // is parsed, placeholders don't get split. e.g. if a template of `$a.to_string()` results in `1
     // + 2.to_string()` then the placeholder value `1 + 2` was split and needs parenthesis.
     placeholder_tokens_requiring_parenthesis: FxHashSet<SyntaxToken>,
+    // Set after a variadic placeholder that matched nothing, so that the comma that would have
+    // separated it from the next element is skipped.
+    skip_separator: bool,
+    // Set after the empty body of a block, so that the whitespace between it and the `}` is skipped.
+    skip_whitespace: bool,
+    // The indentation of the line on which the replaced code starts. Each line after the first in
+    // the template is indented by this much more than it was written, so that a template split
+    // across several lines lines up with the code around it.
+    indent: &'a str,
 }
 
-fn render_replace(match_info: &Match, file_src: &str, rules: &[ResolvedRule]) -> String {
+/// Renders `template`, which is either the whole template of the rule that produced `match_info`,
+/// or a part of it. `start` is the offset in `file_src` of the code that the result will replace.
+fn render_replace(
+    match_info: &Match,
+    template: &SyntaxNode,
+    start: TextSize,
+    file_src: &str,
+    rules: &[ResolvedRule],
+) -> String {
     let rule = &rules[match_info.rule_index];
-    let template = rule
-        .template
-        .as_ref()
-        .expect("You called MatchFinder::edits after calling MatchFinder::add_search_pattern");
     let mut renderer = ReplacementRenderer {
         match_info,
         file_src,
         rules,
         rule,
+        indent: line_indent(file_src, usize::from(start)),
         out: String::new(),
         placeholder_tokens_requiring_parenthesis: FxHashSet::default(),
         placeholder_tokens_by_range: FxHashMap::default(),
+        skip_separator: false,
+        skip_whitespace: false,
     };
-    renderer.render_node(&template.node);
-    renderer.maybe_rerender_with_extra_parenthesis(&template.node);
-    for comment in &match_info.ignored_comments {
-        renderer.out.push_str(&comment.syntax().to_string());
-    }
+    renderer.render_node(template);
+    renderer.maybe_rerender_with_extra_parenthesis(template);
     renderer.out
 }
 
@@ -93,7 +329,10 @@ impl ReplacementRenderer<'_> {
 
     fn render_node(&mut self, node: &SyntaxNode) {
         use ra_syntax::ast::AstNode;
-        if let Some(mod_path) = self.match_info.rendered_template_paths.get(&node) {
+        // The placeholder's value is the whole else branch, so already has braces if it needs them.
+        if let Some(token) = self.rule.else_branch_placeholder_token(node) {
+            self.render_token(&token);
+        } else if let Some(mod_path) = self.match_info.rendered_template_paths.get(&node) {
             self.out.push_str(&mod_path.to_string());
             // Emit everything except for the segment's name-ref, since we already effectively
             // emitted that as part of `mod_path`.
@@ -106,19 +345,42 @@ impl ReplacementRenderer<'_> {
                     }
                 }
             }
+        } else if node.kind() == SyntaxKind::TUPLE_EXPR {
+            self.render_tuple(node);
         } else {
             self.render_node_children(&node);
         }
     }
 
     fn render_token(&mut self, token: &SyntaxToken) {
+        if self.skip_separator {
+            if matches!(token.kind(), SyntaxKind::COMMA | SyntaxKind::WHITESPACE) {
+                return;
+            }
+            self.skip_separator = false;
+        }
+        if self.skip_whitespace {
+            self.skip_whitespace = false;
+            if token.kind() == SyntaxKind::WHITESPACE {
+                return;
+            }
+        }
         if let Some(placeholder) = self.rule.get_placeholder(&token) {
+            if placeholder.is_fresh {
+                if let Some(name) = self.match_info.fresh_names.get(&placeholder.ident) {
+                    self.out.push_str(name);
+                    return;
+                }
+            }
             if let Some(placeholder_value) =
                 self.match_info.placeholder_values.get(&Var(placeholder.ident.to_string()))
             {
                 let range = &placeholder_value.range.range;
-                let mut matched_text =
-                    self.file_src[usize::from(range.start())..usize::from(range.end())].to_owned();
+                let mut matched_text = match &placeholder_value.inferred_text {
+                    Some(text) => text.clone(),
+                    None => self.file_src[usize::from(range.start())..usize::from(range.end())]
+                        .to_owned(),
+                };
                 let edit = matches_to_edit_at_offset(
                     &placeholder_value.inner_matches,
                     self.file_src,
@@ -128,20 +390,76 @@ impl ReplacementRenderer<'_> {
                 let needs_parenthesis =
                     self.placeholder_tokens_requiring_parenthesis.contains(token);
                 edit.apply(&mut matched_text);
+                // The lines of a value that spans several lines are indented relative to the line
+                // it started on. Keep them so when it's moved to a line that's indented
+                // differently.
+                let old_indent = line_indent(self.file_src, usize::from(range.start()));
+                let new_indent = if self.out.contains('\n') {
+                    line_indent(&self.out, self.out.len())
+                } else {
+                    self.indent
+                };
+                if matched_text.contains('\n') && old_indent != new_indent {
+                    matched_text = reindent(&matched_text, old_indent, new_indent);
+                }
+                // The body of a block may have statements, so is only an expression if it's put back
+                // in braces. If it's the body of a block in the template too, and is empty, then we
+                // drop the whitespace around it, so that `{ $body }` becomes `{}`.
+                if placeholder_value.is_block_body {
+                    if !is_whole_block_body(token) {
+                        matched_text = if matched_text.is_empty() {
+                            "{}".to_owned()
+                        } else {
+                            format!("{{ {} }}", matched_text)
+                        };
+                    } else if matched_text.is_empty() {
+                        self.out.truncate(self.out.trim_end().len());
+                        self.skip_whitespace = true;
+                    }
+                    self.out.push_str(&matched_text);
+                    return;
+                }
+                // A variadic placeholder is a comma-separated list of elements, not a single node,
+                // so is never wrapped in parenthesis.
+                if placeholder.is_variadic {
+                    self.render_variadic(token, &matched_text);
+                    return;
+                }
+                // An expression used as a const generic argument must be a literal or be in braces.
+                let needs_braces = is_generic_arg(token)
+                    && placeholder_value.node.as_ref().map_or(false, needs_braces_as_generic_arg);
+                if needs_braces {
+                    self.out.push_str("{ ");
+                }
                 if needs_parenthesis {
                     self.out.push('(');
                 }
-                self.placeholder_tokens_by_range.insert(
-                    TextRange::new(
-                        TextSize::of(&self.out),
-                        TextSize::of(&self.out) + TextSize::of(&matched_text),
-                    ),
-                    token.clone(),
-                );
+                // The whole body of a block can't be split by operator precedence, and may not be
+                // an expression at all, so doesn't need checking. Neither do macro arguments, which
+                // are just tokens until the macro is expanded. Labels and operators are single
+                // tokens. Use trees don't have operators, and what follows a path within one, as in
+                // `use foo::$rest;`, needn't be a single node.
+                if !is_whole_block_body(token)
+                    && token.parent().kind() != SyntaxKind::TOKEN_TREE
+                    && !token.parent().ancestors().any(|node| node.kind() == SyntaxKind::USE_TREE)
+                    && token.kind() != SyntaxKind::LIFETIME
+                    && !self.rule.operator_placeholders.contains_key(token)
+                {
+                    self.placeholder_tokens_by_range.insert(
+                        TextRange::new(
+                            TextSize::of(&self.out),
+                            TextSize::of(&self.out) + TextSize::of(&matched_text),
+                        ),
+                        token.clone(),
+                    );
+                }
                 self.out.push_str(&matched_text);
                 if needs_parenthesis {
                     self.out.push(')');
                 }
+                if needs_braces {
+                    self.out.push_str(" }");
+                }
             } else {
                 // We validated that all placeholder references were valid before we
                 // started, so this shouldn't happen.
@@ -150,11 +468,92 @@ impl ReplacementRenderer<'_> {
                     placeholder.ident
                 );
             }
+        } else if matches!(token.kind(), SyntaxKind::STRING | SyntaxKind::RAW_STRING)
+            && !self.match_info.match_variables.is_empty()
+        {
+            let text = self.render_match_variables(token);
+            self.out.push_str(&text);
+        } else if token.kind() == SyntaxKind::WHITESPACE && token.text().contains('\n') {
+            self.out.push_str(&token.text().replace('\n', &format!("\n{}", self.indent)));
         } else {
             self.out.push_str(token.text().as_str());
         }
     }
 
+    /// Returns the text of the string literal `token` with each `${match.<name>}` replaced by its
+    /// value. Values are escaped, other than in raw strings, which don't have escapes.
+    fn render_match_variables(&self, token: &SyntaxToken) -> String {
+        let text = token.text().as_str();
+        let mut out = String::new();
+        let mut offset = 0;
+        for (range, name) in parsing::match_variables(text) {
+            out.push_str(&text[offset..range.start]);
+            let value =
+                self.match_info.match_variables.get(name).map_or("", |value| value.as_str());
+            if token.kind() == SyntaxKind::STRING {
+                out.push_str(&value.replace('\\', "\\\\").replace('"', "\\\""));
+            } else {
+                out.push_str(value);
+            }
+            offset = range.end;
+        }
+        out.push_str(&text[offset..]);
+        out
+    }
+
+    fn render_variadic(&mut self, token: &SyntaxToken, matched_text: &str) {
+        if !matched_text.is_empty() {
+            self.out.push_str(matched_text);
+            return;
+        }
+        let next =
+            token.next_token().and_then(|next| algo::skip_trivia_token(next, Direction::Next));
+        let is_last = match next {
+            Some(next) => {
+                matches!(
+                    next.kind(),
+                    SyntaxKind::R_PAREN | SyntaxKind::R_BRACK | SyntaxKind::R_CURLY
+                )
+            }
+            None => true,
+        };
+        if !is_last {
+            self.skip_separator = true;
+            return;
+        }
+        // Drop the separator before the placeholder.
+        let trimmed = self.out.trim_end().trim_end_matches(',').trim_end();
+        self.out.truncate(trimmed.len());
+    }
+
+    /// Renders a tuple from the template. If variadic placeholders that matched nothing leave it
+    /// with a single element, then it needs a trailing comma to still be a tuple.
+    fn render_tuple(&mut self, node: &SyntaxNode) {
+        let num_elements = node.children().filter(|child| !self.is_empty_variadic(child)).count();
+        self.render_node_children(node);
+        if num_elements == 1 && self.out.ends_with(')') {
+            let before_paren = self.out[..self.out.len() - 1].trim_end();
+            if !before_paren.ends_with(',') {
+                let offset = before_paren.len();
+                self.out.insert(offset, ',');
+            }
+        }
+    }
+
+    fn is_empty_variadic(&self, node: &SyntaxNode) -> bool {
+        let token = match node.first_token() {
+            Some(token) if token.text_range() == node.text_range() => token,
+            _ => return false,
+        };
+        match self.rule.get_placeholder(&token) {
+            Some(placeholder) if placeholder.is_variadic => matches!(
+                self.match_info.placeholder_values.get(&Var(placeholder.ident.to_string())),
+                Some(value) if value.range.range.is_empty()
+            ),
+            _ => false,
+        }
+    }
+
     // Checks if the resulting code, when parsed doesn't split any placeholders due to different
     // order of operations between the search pattern and the replacement template. If any do, then
     // we rerender the template and wrap the problematic placeholders with parenthesis.
@@ -179,6 +578,65 @@ impl ReplacementRenderer<'_> {
     }
 }
 
+/// Returns the whitespace at the start of the line in `text` that contains `offset`.
+fn line_indent(text: &str, offset: usize) -> &str {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = &text[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Replaces `old_indent` with `new_indent` at the start of each line of `text` after the first.
+/// Lines that don't start with `old_indent` are left alone.
+fn reindent(text: &str, old_indent: &str, new_indent: &str) -> String {
+    let mut lines = text.split('\n');
+    let mut out = lines.next().unwrap_or_default().to_owned();
+    for line in lines {
+        out.push('\n');
+        match line.strip_prefix(old_indent) {
+            Some(rest) => {
+                out.push_str(new_indent);
+                out.push_str(rest);
+            }
+            None => out.push_str(line),
+        }
+    }
+    out
+}
+
+/// Returns whether `token` is the whole of a generic argument, such as the `T` in `Foo::<T>`.
+fn is_generic_arg(token: &SyntaxToken) -> bool {
+    token
+        .parent()
+        .ancestors()
+        .find(|node| node.kind() == SyntaxKind::TYPE_ARG)
+        .and_then(|type_arg| matching::only_ident(type_arg.into()))
+        .as_ref()
+        == Some(token)
+}
+
+/// Returns whether `node` is an expression that needs braces around it to be a const generic
+/// argument. Literals, blocks and paths, such as the name of a const, don't.
+fn needs_braces_as_generic_arg(node: &SyntaxNode) -> bool {
+    ast::Expr::can_cast(node.kind())
+        && !matches!(
+            node.kind(),
+            SyntaxKind::LITERAL | SyntaxKind::BLOCK_EXPR | SyntaxKind::PATH_EXPR
+        )
+}
+
+/// Returns whether `token` is the only thing in a block.
+fn is_whole_block_body(token: &SyntaxToken) -> bool {
+    use ra_syntax::ast::AstNode;
+    let path_expr = match token.parent().ancestors().find_map(ast::PathExpr::cast) {
+        Some(path_expr) => path_expr,
+        None => return false,
+    };
+    match path_expr.syntax().parent().and_then(ast::BlockExpr::cast) {
+        Some(block) => block.statements().next().is_none(),
+        None => false,
+    }
+}
+
 fn parse_as_kind(code: &str, kind: SyntaxKind) -> Option<SyntaxNode> {
     use ra_syntax::ast::AstNode;
     if ast::Expr::can_cast(kind) {
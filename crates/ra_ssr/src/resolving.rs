@@ -1,32 +1,156 @@
 //! This module is responsible for resolving paths within rules.
 
 use crate::errors::error;
-use crate::{parsing, SsrError};
+use crate::matching::Var;
+use crate::{parsing, Match, SsrError};
 use parsing::Placeholder;
-use ra_db::FilePosition;
-use ra_syntax::{ast, SmolStr, SyntaxKind, SyntaxNode, SyntaxToken};
+use ra_db::{Edition, FileId, FilePosition};
+use ra_syntax::{ast, NodeOrToken, SmolStr, SyntaxKind, SyntaxNode, SyntaxNodePtr, SyntaxToken};
 use rustc_hash::{FxHashMap, FxHashSet};
+use std::rc::Rc;
 use test_utils::mark;
 
 pub(crate) struct ResolutionScope<'db> {
     scope: hir::SemanticsScope<'db>,
     hygiene: hir::Hygiene,
+    /// The edition of the crate in which we're resolving. Rules are parsed for this edition.
+    pub(crate) edition: Edition,
+    pub(crate) location: ScopeLocation,
+}
+
+/// The node at which a `ResolutionScope` resolves paths, in a form that can be sent to another
+/// thread and used there to build the same scope.
+#[derive(Debug, Clone)]
+pub(crate) struct ScopeLocation {
+    file_id: FileId,
+    node: SyntaxNodePtr,
 }
 
 pub(crate) struct ResolvedRule {
     pub(crate) pattern: ResolvedPattern,
     pub(crate) template: Option<ResolvedPattern>,
+    /// Used instead of `template` when it refers to placeholders that matched nothing. See
+    /// `template_for`.
+    pub(crate) alternative_templates: Vec<ResolvedPattern>,
     pub(crate) index: usize,
+    /// The index of the rule or search pattern that this was resolved from, in the order in which
+    /// they were added to the `MatchFinder`.
+    pub(crate) added_index: usize,
+    /// Whether the pattern uses keywords that were introduced in the 2018 edition, meaning that it
+    /// shouldn't be used to search code in 2015 edition crates.
+    pub(crate) requires_edition_2018: bool,
+    /// The number of tokens in the pattern, excluding placeholders and trivia. Patterns with more
+    /// tokens are more specific.
+    pub(crate) specificity: usize,
+    /// Whether the pattern must match a whole expression statement, including its `;`.
+    pub(crate) statement_boundary: bool,
+    /// Whether paths that don't resolve are compared by name. See
+    /// `SsrRule::with_heuristic_fallback`.
+    pub(crate) heuristic_fallback: bool,
+    /// Which match to keep in each scope, if only one is. See `SsrRule::set_once_per`.
+    pub(crate) once_per: Option<crate::OncePer>,
+    /// How a `;` after a match is handled. See `SsrRule::set_trailing_semicolon`.
+    pub(crate) trailing_semicolon: crate::TrailingSemicolon,
+    /// Definitions used by the `no_alloc` constraint. Only resolved if the pattern uses it.
+    pub(crate) allocating_defs: AllocatingDefs,
+    /// What each path in a `one_of` constraint resolved to, keyed by the path as written.
+    pub(crate) one_of_paths: FxHashMap<SmolStr, hir::PathResolution>,
+    /// What each type in a `returns` constraint resolved to, keyed by the type as written.
+    pub(crate) returns_types: FxHashMap<SmolStr, hir::Type>,
+    /// If set, produces the replacement for each match instead of `template`.
+    pub(crate) node_rewriter: Option<Rc<NodeRewriter>>,
+    /// If set, text is inserted near each match instead of the match being replaced.
+    pub(crate) inserter: Option<Rc<Inserter>>,
+    /// The operator tokens, in the pattern and templates, that are placeholders, along with the
+    /// stand-in names of those placeholders.
+    pub(crate) operator_placeholders: FxHashMap<SyntaxToken, SmolStr>,
+}
+
+/// Builds the code that should replace a match, or returns `None` to leave the match alone.
+pub(crate) type NodeRewriter = dyn Fn(&Match) -> Option<SyntaxNode>;
+
+/// Builds the text to insert near each match of a rule added by `MatchFinder::add_insertion_rule`.
+pub(crate) struct Inserter {
+    pub(crate) position: crate::InsertPosition,
+    pub(crate) text_from: Box<dyn Fn(&Match) -> String>,
+}
+
+/// Definitions that allocate, resolved from `KNOWN_ALLOCATING_ASSOC_FUNCTIONS`,
+/// `KNOWN_ALLOCATING_TRAITS` and any paths added with `MatchFinder::add_allocating_path`.
+#[derive(Default)]
+pub(crate) struct AllocatingDefs {
+    /// For each type, the names of its associated functions that allocate.
+    pub(crate) types: FxHashMap<hir::Adt, &'static [&'static str]>,
+    /// Traits whose methods allocate.
+    pub(crate) traits: FxHashSet<hir::Trait>,
+    pub(crate) functions: FxHashSet<hir::Function>,
+    pub(crate) macros: FxHashSet<hir::MacroDef>,
+}
+
+impl AllocatingDefs {
+    /// Adds the function, trait or macro that `resolution` refers to. Returns false if it's
+    /// something else.
+    pub(crate) fn add(&mut self, resolution: hir::PathResolution) -> bool {
+        match resolution {
+            hir::PathResolution::Def(hir::ModuleDef::Function(function))
+            | hir::PathResolution::AssocItem(hir::AssocItem::Function(function)) => {
+                self.functions.insert(function);
+            }
+            hir::PathResolution::Def(hir::ModuleDef::Trait(t)) => {
+                self.traits.insert(t);
+            }
+            hir::PathResolution::Macro(mac) => {
+                self.macros.insert(mac);
+            }
+            _ => return false,
+        }
+        true
+    }
 }
 
+/// Types, together with the names of those of their associated functions that allocate. Paths that
+/// don't resolve, e.g. in crates that don't depend on `std`, are ignored.
+const KNOWN_ALLOCATING_ASSOC_FUNCTIONS: &[(&str, &[&str])] = &[
+    ("std::string::String", &["from", "with_capacity"]),
+    ("std::vec::Vec", &["with_capacity"]),
+    ("std::boxed::Box", &["new"]),
+    ("alloc::string::String", &["from", "with_capacity"]),
+    ("alloc::vec::Vec", &["with_capacity"]),
+    ("alloc::boxed::Box", &["new"]),
+];
+
+/// Traits all of whose methods allocate.
+const KNOWN_ALLOCATING_TRAITS: &[&str] = &["std::string::ToString", "alloc::string::ToString"];
+
+/// Macros that allocate, if they're defined in one of `ALLOCATING_MACRO_CRATES`. Macros are
+/// recognized by name, since paths like `std::vec` resolve to a module rather than the macro.
+pub(crate) const KNOWN_ALLOCATING_MACROS: &[&str] = &["vec", "format"];
+pub(crate) const ALLOCATING_MACRO_CRATES: &[&str] = &["std", "alloc"];
+
 pub(crate) struct ResolvedPattern {
     pub(crate) placeholders_by_stand_in: FxHashMap<SmolStr, parsing::Placeholder>,
     pub(crate) node: SyntaxNode,
     // Paths in `node` that we've resolved.
     pub(crate) resolved_paths: FxHashMap<SyntaxNode, ResolvedPath>,
+    /// Paths in `node` that failed to resolve, which are compared by text. Only rules with a
+    /// heuristic fallback can have these.
+    pub(crate) unresolved_paths: FxHashSet<SyntaxNode>,
+    /// Paths in `node` that contain `*` or `**` wildcards, which are compared with the full path of
+    /// whatever the code resolves to, rather than being resolved themselves.
+    pub(crate) wildcard_paths: FxHashMap<SyntaxNode, Vec<PathPatternSegment>>,
     pub(crate) ufcs_function_calls: FxHashMap<SyntaxNode, hir::Function>,
 }
 
+/// A segment of a path that contains wildcards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PathPatternSegment {
+    Name(SmolStr),
+    /// `*`, which matches any one segment.
+    Any,
+    /// `**`, which matches any number of segments, including none.
+    AnyNumber,
+}
+
 pub(crate) struct ResolvedPath {
     pub(crate) resolution: hir::PathResolution,
     /// The depth of the ast::Path that was resolved within the pattern.
@@ -37,39 +161,168 @@ impl ResolvedRule {
     pub(crate) fn new(
         rule: parsing::ParsedRule,
         resolution_scope: &ResolutionScope,
+        allocating_paths: &[String],
         index: usize,
+        added_index: usize,
     ) -> Result<ResolvedRule, SsrError> {
-        let resolver =
-            Resolver { resolution_scope, placeholders_by_stand_in: rule.placeholders_by_stand_in };
+        let statement_boundary = rule.statement_boundary;
+        let heuristic_fallback = rule.heuristic_fallback;
+        let once_per = rule.once_per;
+        let trailing_semicolon = rule.trailing_semicolon;
+        let operator_placeholders = rule.operator_placeholders;
+        let allocating_defs = if uses_no_alloc(&rule.placeholders_by_stand_in) {
+            resolution_scope.resolve_allocating_defs(allocating_paths)
+        } else {
+            AllocatingDefs::default()
+        };
+        let one_of_paths = resolution_scope.resolve_one_of_paths(&rule.placeholders_by_stand_in)?;
+        let returns_types =
+            resolution_scope.resolve_returns_types(&rule.placeholders_by_stand_in)?;
+        let resolver = Resolver {
+            resolution_scope,
+            placeholders_by_stand_in: rule.placeholders_by_stand_in,
+            heuristic_fallback,
+        };
         let resolved_template = if let Some(template) = rule.template {
             Some(resolver.resolve_pattern_tree(template)?)
         } else {
             None
         };
+        let alternative_templates = rule
+            .alternative_templates
+            .into_iter()
+            .map(|template| resolver.resolve_pattern_tree(template))
+            .collect::<Result<_, _>>()?;
+        let pattern = resolver.resolve_pattern_tree(rule.pattern.clone())?;
+        let specificity = pattern
+            .node
+            .descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+            .filter(|token| {
+                !token.kind().is_trivia()
+                    && !pattern.placeholders_by_stand_in.contains_key(token.text())
+                    && !operator_placeholders.contains_key(token)
+            })
+            .count();
         Ok(ResolvedRule {
-            pattern: resolver.resolve_pattern_tree(rule.pattern)?,
+            pattern,
+            requires_edition_2018: parsing::uses_edition_2018_keywords(&rule.pattern),
+            specificity,
+            statement_boundary,
+            heuristic_fallback,
+            once_per,
+            trailing_semicolon,
+            allocating_defs,
+            one_of_paths,
+            returns_types,
             template: resolved_template,
+            alternative_templates,
             index,
+            added_index,
+            node_rewriter: None,
+            inserter: None,
+            operator_placeholders,
         })
     }
 
     pub(crate) fn get_placeholder(&self, token: &SyntaxToken) -> Option<&Placeholder> {
-        if token.kind() != SyntaxKind::IDENT {
+        if let Some(stand_in) = self.operator_placeholders.get(token) {
+            return self.pattern.placeholders_by_stand_in.get(stand_in);
+        }
+        // Placeholders used as labels are lifetimes.
+        if !matches!(token.kind(), SyntaxKind::IDENT | SyntaxKind::LIFETIME) {
             return None;
         }
         self.pattern.placeholders_by_stand_in.get(token.text())
     }
+
+    /// Returns the template to use for `m`, if the rule has one. That's the first template, in the
+    /// order written, every placeholder of which matched some code, or the last template if none
+    /// does. A placeholder matches no code if it's variadic and there were no elements left for
+    /// it, so a rule such as `f([$a, $rest...]) ==>> g($a, [$rest...]) ||| h($a)` can use a
+    /// different replacement depending on whether the optional part was present.
+    pub(crate) fn template_for(&self, m: &Match) -> Option<&ResolvedPattern> {
+        let template = self.template.as_ref()?;
+        let is_bound = |token: &SyntaxToken| match self.get_placeholder(token) {
+            Some(placeholder) if !placeholder.is_fresh => {
+                matches!(m.placeholder_values.get(&Var(placeholder.ident.to_string())),
+                    Some(value) if value.inferred_text.is_some() || !value.range.range.is_empty())
+            }
+            _ => true,
+        };
+        let all_bound = |template: &&ResolvedPattern| {
+            template
+                .node
+                .descendants_with_tokens()
+                .filter_map(|element| element.into_token())
+                .all(|token| is_bound(&token))
+        };
+        std::iter::once(template)
+            .chain(&self.alternative_templates)
+            .find(all_bound)
+            .or_else(|| Some(self.alternative_templates.last().unwrap_or(template)))
+    }
+
+    /// If `node` is the block that stands for a placeholder directly after `else`, returns the
+    /// placeholder's token within it.
+    pub(crate) fn else_branch_placeholder_token(&self, node: &SyntaxNode) -> Option<SyntaxToken> {
+        use ra_syntax::AstNode;
+        let block = ast::BlockExpr::cast(node.clone())?;
+        if block.statements().next().is_some() {
+            return None;
+        }
+        let expr = block.expr()?;
+        let token = expr.syntax().first_token()?;
+        if expr.syntax().text() != token.text().as_str()
+            || !token.text().starts_with(parsing::ELSE_BRANCH_STAND_IN_PREFIX)
+        {
+            return None;
+        }
+        self.get_placeholder(&token)?;
+        Some(token)
+    }
+
+    /// Returns the text of `pattern`, which should be part of this rule, with placeholders shown as
+    /// they were written by the user, rather than as their stand-in names.
+    pub(crate) fn render_with_placeholder_names(&self, pattern: &ResolvedPattern) -> String {
+        let mut out = String::new();
+        for token in pattern.node.descendants_with_tokens().filter_map(|e| e.into_token()) {
+            match self.get_placeholder(&token) {
+                Some(placeholder) => {
+                    out.push('$');
+                    out.push_str(&placeholder.ident);
+                    if placeholder.is_variadic {
+                        out.push_str("...");
+                    }
+                }
+                None if token.text() == parsing::WILDCARD_SEGMENT_STAND_IN => out.push('*'),
+                None if token.text() == parsing::WILDCARD_SEGMENTS_STAND_IN => out.push_str("**"),
+                None => out.push_str(token.text()),
+            }
+        }
+        out
+    }
 }
 
 struct Resolver<'a, 'db> {
     resolution_scope: &'a ResolutionScope<'db>,
     placeholders_by_stand_in: FxHashMap<SmolStr, parsing::Placeholder>,
+    /// Whether paths that don't resolve are recorded as unresolved, rather than being an error.
+    heuristic_fallback: bool,
 }
 
 impl Resolver<'_, '_> {
     fn resolve_pattern_tree(&self, pattern: SyntaxNode) -> Result<ResolvedPattern, SsrError> {
         let mut resolved_paths = FxHashMap::default();
-        self.resolve(pattern.clone(), 0, &mut resolved_paths)?;
+        let mut unresolved_paths = FxHashSet::default();
+        let mut wildcard_paths = FxHashMap::default();
+        self.resolve(
+            pattern.clone(),
+            0,
+            &mut resolved_paths,
+            &mut unresolved_paths,
+            &mut wildcard_paths,
+        )?;
         let ufcs_function_calls = resolved_paths
             .iter()
             .filter_map(|(path_node, resolved)| {
@@ -88,6 +341,8 @@ impl Resolver<'_, '_> {
         Ok(ResolvedPattern {
             node: pattern,
             resolved_paths,
+            unresolved_paths,
+            wildcard_paths,
             placeholders_by_stand_in: self.placeholders_by_stand_in.clone(),
             ufcs_function_calls,
         })
@@ -98,25 +353,52 @@ impl Resolver<'_, '_> {
         node: SyntaxNode,
         depth: u32,
         resolved_paths: &mut FxHashMap<SyntaxNode, ResolvedPath>,
+        unresolved_paths: &mut FxHashSet<SyntaxNode>,
+        wildcard_paths: &mut FxHashMap<SyntaxNode, Vec<PathPatternSegment>>,
     ) -> Result<(), SsrError> {
         use ra_syntax::ast::AstNode;
         if let Some(path) = ast::Path::cast(node.clone()) {
+            // A path with wildcards can't be resolved, since it doesn't name anything in
+            // particular. Only the type arguments of its last segment are resolved, since they're
+            // matched in the usual way.
+            if let Some(segments) = wildcard_path_segments(&path) {
+                let type_args = path.segment().and_then(|segment| segment.type_arg_list());
+                for node in type_args.iter().flat_map(|args| args.syntax().children()) {
+                    self.resolve(
+                        node,
+                        depth + 1,
+                        resolved_paths,
+                        unresolved_paths,
+                        wildcard_paths,
+                    )?;
+                }
+                wildcard_paths.insert(node, segments);
+                return Ok(());
+            }
             // Check if this is an appropriate place in the path to resolve. If the path is
             // something like `a::B::<i32>::c` then we want to resolve `a::B`. If the path contains
             // a placeholder. e.g. `a::$b::c` then we want to resolve `a`.
+            // Paths within the braces of a use tree, e.g. `B` in `use a::{B}`, are relative to
+            // the path before the braces, so don't resolve by themselves, and are compared as
+            // written.
             if !path_contains_type_arguments(path.qualifier())
                 && !self.path_contains_placeholder(&path)
+                && !path.syntax().ancestors().any(|n| n.kind() == SyntaxKind::USE_TREE_LIST)
             {
-                let resolution = self
-                    .resolution_scope
-                    .resolve_path(&path)
-                    .ok_or_else(|| error!("Failed to resolve path `{}`", node.text()))?;
-                resolved_paths.insert(node, ResolvedPath { resolution, depth });
+                match self.resolution_scope.resolve_path(&path) {
+                    Some(resolution) => {
+                        resolved_paths.insert(node, ResolvedPath { resolution, depth });
+                    }
+                    None if self.heuristic_fallback => {
+                        unresolved_paths.insert(node);
+                    }
+                    None => return Err(error!("Failed to resolve path `{}`", node.text())),
+                }
                 return Ok(());
             }
         }
         for node in node.children() {
-            self.resolve(node, depth + 1, resolved_paths)?;
+            self.resolve(node, depth + 1, resolved_paths, unresolved_paths, wildcard_paths)?;
         }
         Ok(())
     }
@@ -138,6 +420,27 @@ impl Resolver<'_, '_> {
     }
 }
 
+/// Returns the segments of `path`, if it contains any wildcards. Wildcards within type arguments
+/// don't count, since they belong to some other path.
+fn wildcard_path_segments(path: &ast::Path) -> Option<Vec<PathPatternSegment>> {
+    let mut segments = Vec::new();
+    let mut current = Some(path.clone());
+    while let Some(path) = current {
+        let name = path.segment()?.name_ref()?.text().clone();
+        segments.push(match name.as_str() {
+            parsing::WILDCARD_SEGMENT_STAND_IN => PathPatternSegment::Any,
+            parsing::WILDCARD_SEGMENTS_STAND_IN => PathPatternSegment::AnyNumber,
+            _ => PathPatternSegment::Name(name),
+        });
+        current = path.qualifier();
+    }
+    if segments.iter().all(|segment| matches!(segment, PathPatternSegment::Name(_))) {
+        return None;
+    }
+    segments.reverse();
+    Some(segments)
+}
+
 impl<'db> ResolutionScope<'db> {
     pub(crate) fn new(
         sema: &hir::Semantics<'db, ra_ide_db::RootDatabase>,
@@ -153,14 +456,148 @@ impl<'db> ResolutionScope<'db> {
             .map(|token| token.parent())
             .unwrap_or_else(|| file.syntax().clone());
         let node = pick_node_for_resolution(node);
-        let scope = sema.scope(&node);
+        ResolutionScope::at_node(sema, &node, resolve_context.file_id)
+    }
+
+    /// Constructs a scope in which paths resolve as if they were written at the top level of
+    /// `module`, outside of any item.
+    pub(crate) fn in_module(
+        sema: &hir::Semantics<'db, ra_ide_db::RootDatabase>,
+        module: hir::Module,
+    ) -> ResolutionScope<'db> {
+        use ra_syntax::ast::AstNode;
+        let source = module.definition_source(sema.db);
+        let file_id = source.file_id.original_file(sema.db);
+        let file = sema.parse(file_id);
+        let item_list = match &source.value {
+            hir::ModuleSource::SourceFile(_) => None,
+            hir::ModuleSource::Module(module_node) => module_node.item_list(),
+        };
+        let node = match item_list {
+            Some(item_list) => {
+                // The item list is from the database's parse of the file, which `sema` doesn't know
+                // about, so we find the same node in the parse that it does know about.
+                let range =
+                    hir::original_range(sema.db, source.with_value(item_list.syntax())).range;
+                match file.syntax().covering_element(range) {
+                    NodeOrToken::Node(node) => node,
+                    NodeOrToken::Token(token) => token.parent(),
+                }
+            }
+            None => file.syntax().clone(),
+        };
+        ResolutionScope::at_node(sema, &node, file_id)
+    }
+
+    /// Constructs the scope found at `location` by an earlier call of one of the other
+    /// constructors, possibly with a different snapshot of the database.
+    pub(crate) fn at_location(
+        sema: &hir::Semantics<'db, ra_ide_db::RootDatabase>,
+        location: &ScopeLocation,
+    ) -> ResolutionScope<'db> {
+        use ra_syntax::ast::AstNode;
+        let file = sema.parse(location.file_id);
+        ResolutionScope::at_node(sema, &location.node.to_node(file.syntax()), location.file_id)
+    }
+
+    fn at_node(
+        sema: &hir::Semantics<'db, ra_ide_db::RootDatabase>,
+        node: &SyntaxNode,
+        file_id: FileId,
+    ) -> ResolutionScope<'db> {
+        let scope = sema.scope(node);
+        let edition =
+            scope.module().map_or(Edition::Edition2018, |module| module.krate().edition(sema.db));
         ResolutionScope {
             scope,
-            hygiene: hir::Hygiene::new(sema.db, resolve_context.file_id.into()),
+            hygiene: hir::Hygiene::new(sema.db, file_id.into()),
+            edition,
+            location: ScopeLocation { file_id, node: SyntaxNodePtr::new(node) },
+        }
+    }
+
+    /// Resolves the known allocating definitions and `extra_paths`. Paths that don't resolve are
+    /// ignored.
+    fn resolve_allocating_defs(&self, extra_paths: &[String]) -> AllocatingDefs {
+        let resolve = |path: &str| ast::Path::parse(path).ok().and_then(|p| self.resolve_path(&p));
+        let mut defs = AllocatingDefs::default();
+        for (path, functions) in KNOWN_ALLOCATING_ASSOC_FUNCTIONS {
+            if let Some(hir::PathResolution::Def(hir::ModuleDef::Adt(adt))) = resolve(path) {
+                defs.types.insert(adt, functions);
+            }
+        }
+        for path in KNOWN_ALLOCATING_TRAITS {
+            if let Some(hir::PathResolution::Def(hir::ModuleDef::Trait(t))) = resolve(path) {
+                defs.traits.insert(t);
+            }
+        }
+        for resolution in extra_paths.iter().filter_map(|path| resolve(path)) {
+            defs.add(resolution);
+        }
+        defs
+    }
+
+    fn resolve_one_of_paths(
+        &self,
+        placeholders: &FxHashMap<SmolStr, Placeholder>,
+    ) -> Result<FxHashMap<SmolStr, hir::PathResolution>, SsrError> {
+        fn add_paths<'a>(constraint: &'a parsing::Constraint, out: &mut Vec<&'a SmolStr>) {
+            match constraint {
+                parsing::Constraint::OneOf(paths) => out.extend(paths),
+                parsing::Constraint::Not(sub) => add_paths(sub, out),
+                _ => {}
+            }
+        }
+        let mut paths = Vec::new();
+        for constraint in placeholders.values().flat_map(|p| &p.constraints) {
+            add_paths(constraint, &mut paths);
+        }
+        let mut resolved = FxHashMap::default();
+        for path in paths {
+            let resolution = ast::Path::parse(path)
+                .ok()
+                .and_then(|p| self.resolve_path(&p))
+                .ok_or_else(|| error!("Failed to resolve path `{}` in one_of constraint", path))?;
+            resolved.insert(path.clone(), resolution);
+        }
+        Ok(resolved)
+    }
+
+    fn resolve_returns_types(
+        &self,
+        placeholders: &FxHashMap<SmolStr, Placeholder>,
+    ) -> Result<FxHashMap<SmolStr, hir::Type>, SsrError> {
+        fn add_types<'a>(constraint: &'a parsing::Constraint, out: &mut Vec<&'a SmolStr>) {
+            match constraint {
+                parsing::Constraint::Returns(ty) => out.push(ty),
+                parsing::Constraint::Not(sub) => add_types(sub, out),
+                _ => {}
+            }
+        }
+        let mut types = Vec::new();
+        for constraint in placeholders.values().flat_map(|p| &p.constraints) {
+            add_types(constraint, &mut types);
+        }
+        let mut resolved = FxHashMap::default();
+        for ty in types {
+            let resolution = ast::Type::parse(ty)
+                .ok()
+                .and_then(|t| {
+                    self.scope.resolve_hir_type(&hir::TypeRef::from_src(t, &self.hygiene))
+                })
+                .filter(|t| !t.contains_unknown())
+                .ok_or_else(|| error!("Failed to resolve type `{}` in returns constraint", ty))?;
+            resolved.insert(ty.clone(), resolution);
         }
+        Ok(resolved)
     }
 
-    fn resolve_path(&self, path: &ast::Path) -> Option<hir::PathResolution> {
+    /// Returns the module in which we're resolving, if any.
+    pub(crate) fn module(&self) -> Option<hir::Module> {
+        self.scope.module()
+    }
+
+    pub(crate) fn resolve_path(&self, path: &ast::Path) -> Option<hir::PathResolution> {
         let hir_path = hir::Path::from_src(path.clone(), &self.hygiene)?;
         // First try resolving the whole path. This will work for things like
         // `std::collections::HashMap`, but will fail for things like
@@ -186,6 +623,17 @@ impl<'db> ResolutionScope<'db> {
     }
 }
 
+fn uses_no_alloc(placeholders: &FxHashMap<SmolStr, Placeholder>) -> bool {
+    fn is_no_alloc(constraint: &parsing::Constraint) -> bool {
+        match constraint {
+            parsing::Constraint::NoAllocHeuristic => true,
+            parsing::Constraint::Not(sub) => is_no_alloc(sub),
+            _ => false,
+        }
+    }
+    placeholders.values().flat_map(|p| &p.constraints).any(is_no_alloc)
+}
+
 /// Returns a suitable node for resolving paths in the current scope. If we create a scope based on
 /// a statement node, then we can't resolve local variables that were defined in the current scope
 /// (only in parent scopes). So we find another node, ideally a child of the statement where local
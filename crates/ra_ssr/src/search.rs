@@ -3,15 +3,16 @@
 use crate::{
     matching,
     resolving::{ResolvedPath, ResolvedPattern, ResolvedRule},
-    Match, MatchFinder,
+    Match, MatchFailureReason, MatchFinder,
 };
-use ra_db::{FileId, FileRange};
+use ra_db::{Edition, FileId, FileRange};
 use ra_ide_db::{
     defs::Definition,
     search::{Reference, SearchScope},
 };
-use ra_syntax::{ast, AstNode, SyntaxKind, SyntaxNode};
+use ra_syntax::{ast, match_ast, AstNode, SyntaxElement, SyntaxKind, SyntaxNode};
 use rustc_hash::FxHashSet;
+use std::cell::RefCell;
 use test_utils::mark;
 
 /// A cache for the results of find_usages. This is for when we have multiple patterns that have the
@@ -23,38 +24,72 @@ pub(crate) struct UsageCache {
     usages: Vec<(Definition, Vec<Reference>)>,
 }
 
+/// A rough indication of how expensive it is to search for a rule. Estimates can be compared, with
+/// greater estimates being more expensive. Needing type inference is considered to dominate, since
+/// it's by far the most expensive thing we do. After that, needing to scan every node in the
+/// workspace is more expensive than being able to find candidates via the usages of a path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CostEstimate {
+    /// Whether matching candidates requires type inference. e.g. to resolve method calls.
+    pub requires_type_inference: bool,
+    /// Whether the rule contains no paths that can be used to find candidates, meaning that we
+    /// need to attempt to match every node in the workspace.
+    pub requires_full_scan: bool,
+    /// The number of paths in the pattern that need to be resolved for each candidate.
+    pub resolved_paths: usize,
+}
+
 impl<'db> MatchFinder<'db> {
     /// Adds all matches for `rule` to `matches_out`. Matches may overlap in ways that make
     /// replacement impossible, so further processing is required in order to properly nest matches
-    /// and remove overlapping matches. This is done in the `nesting` module.
+    /// and remove overlapping matches. This is done in the `nesting` module. If `files` is set,
+    /// only those files are searched.
     pub(crate) fn find_matches_for_rule(
         &self,
         rule: &ResolvedRule,
+        files: Option<&FxHashSet<FileId>>,
         usage_cache: &mut UsageCache,
         matches_out: &mut Vec<Match>,
     ) {
-        if pick_path_for_usages(&rule.pattern).is_none() {
-            self.slow_scan(rule, matches_out);
+        // Code that only matches by heuristic has paths that don't resolve, so won't be found by
+        // searching for usages. Nor are references to the root module of a crate, which use
+        // declarations often start with.
+        let path_for_usages = pick_path_for_usages(&rule.pattern);
+        let is_crate_root = |path: &ResolvedPath| {
+            matches!(path.resolution, hir::PathResolution::Def(hir::ModuleDef::Module(module))
+                if module.parent(self.sema.db).is_none())
+        };
+        if rule.heuristic_fallback || path_for_usages.map_or(true, is_crate_root) {
+            self.slow_scan(rule, files, matches_out);
             return;
         }
-        self.find_matches_for_pattern_tree(rule, &rule.pattern, usage_cache, matches_out);
+        self.find_matches_for_pattern_tree(rule, &rule.pattern, files, usage_cache, matches_out);
     }
 
     fn find_matches_for_pattern_tree(
         &self,
         rule: &ResolvedRule,
         pattern: &ResolvedPattern,
+        files: Option<&FxHashSet<FileId>>,
         usage_cache: &mut UsageCache,
         matches_out: &mut Vec<Match>,
     ) {
         if let Some(resolved_path) = pick_path_for_usages(pattern) {
             let definition: Definition = resolved_path.resolution.clone().into();
-            for reference in self.find_usages(usage_cache, definition) {
+            for reference in self.find_usages(usage_cache, definition, files) {
+                if !self.edition_permits_search(rule, reference.file_range.file_id) {
+                    continue;
+                }
                 if let Some(node_to_match) = self.find_node_to_match(resolved_path, reference) {
-                    if !is_search_permitted_ancestors(&node_to_match) {
+                    if !is_search_permitted_ancestors(&node_to_match)
+                        && !is_whole_use_match(rule, &node_to_match)
+                    {
                         mark::hit!(use_declaration_with_braces);
                         continue;
                     }
+                    if self.expansion_depth(&node_to_match) > self.expansion_depth_limit {
+                        continue;
+                    }
                     self.try_add_match(rule, &node_to_match, &None, matches_out);
                 }
             }
@@ -99,13 +134,14 @@ impl<'db> MatchFinder<'db> {
         &self,
         usage_cache: &'a mut UsageCache,
         definition: Definition,
+        files: Option<&FxHashSet<FileId>>,
     ) -> &'a [Reference] {
         // Logically if a lookup succeeds we should just return it. Unfortunately returning it would
         // extend the lifetime of the borrow, then we wouldn't be able to do the insertion on a
         // cache miss. This is a limitation of NLL and is fixed with Polonius. For now we do two
         // lookups in the case of a cache hit.
         if usage_cache.find(&definition).is_none() {
-            let usages = definition.find_usages(&self.sema, Some(self.search_scope()));
+            let usages = definition.find_usages(&self.sema, Some(self.search_scope(files)));
             usage_cache.usages.push((definition, usages));
             return &usage_cache.usages.last().unwrap().1;
         }
@@ -114,26 +150,66 @@ impl<'db> MatchFinder<'db> {
 
     /// Returns the scope within which we want to search. We don't want un unrestricted search
     /// scope, since we don't want to find references in external dependencies.
-    fn search_scope(&self) -> SearchScope {
+    fn search_scope(&self, files: Option<&FxHashSet<FileId>>) -> SearchScope {
         // FIXME: We should ideally have a test that checks that we edit local roots and not library
         // roots. This probably would require some changes to fixtures, since currently everything
         // seems to get put into a single source root.
-        let mut files = Vec::new();
-        self.search_files_do(|file_id| {
-            files.push(file_id);
+        let mut scope_files = Vec::new();
+        self.search_files_in_do(files, |file_id| {
+            scope_files.push(file_id);
         });
-        SearchScope::files(&files)
+        SearchScope::files(&scope_files)
     }
 
-    fn slow_scan(&self, rule: &ResolvedRule, matches_out: &mut Vec<Match>) {
-        self.search_files_do(|file_id| {
+    fn slow_scan(
+        &self,
+        rule: &ResolvedRule,
+        files: Option<&FxHashSet<FileId>>,
+        matches_out: &mut Vec<Match>,
+    ) {
+        self.search_files_in_do(files, |file_id| {
+            if !self.edition_permits_search(rule, file_id) {
+                return;
+            }
             let file = self.sema.parse(file_id);
             let code = file.syntax();
-            self.slow_scan_node(code, rule, &None, matches_out);
+            self.slow_scan_node(code, rule, &None, 0, 0, matches_out);
         })
     }
 
-    fn search_files_do(&self, mut callback: impl FnMut(FileId)) {
+    /// Returns how many macro expansions `node` is nested within.
+    fn expansion_depth(&self, node: &SyntaxNode) -> usize {
+        self.sema
+            .ancestors_with_macros(node.clone())
+            .skip(1)
+            .filter(|ancestor| ancestor.kind() == SyntaxKind::MACRO_CALL)
+            .count()
+    }
+
+    /// Returns whether `rule` can be used to search `file_id`, given the edition of its crate.
+    pub(crate) fn edition_permits_search(&self, rule: &ResolvedRule, file_id: FileId) -> bool {
+        if !rule.requires_edition_2018 {
+            return true;
+        }
+        let edition =
+            self.sema.to_module_def(file_id).map(|module| module.krate().edition(self.sema.db));
+        edition != Some(Edition::Edition2015)
+    }
+
+    /// Like `search_files_do`, but if `files` is set, skips files that aren't in it.
+    pub(crate) fn search_files_in_do(
+        &self,
+        files: Option<&FxHashSet<FileId>>,
+        mut callback: impl FnMut(FileId),
+    ) {
+        self.search_files_do(|file_id| {
+            if files.map_or(true, |files| files.contains(&file_id)) {
+                callback(file_id);
+            }
+        });
+    }
+
+    pub(crate) fn search_files_do(&self, mut callback: impl FnMut(FileId)) {
         if self.restrict_ranges.is_empty() {
             // Unrestricted search.
             use ra_db::SourceDatabaseExt;
@@ -155,20 +231,39 @@ impl<'db> MatchFinder<'db> {
         }
     }
 
+    /// Adds matches for `rule` in `code` and its descendants to `matches_out`. `nesting` is how
+    /// many matches `code` is within.
     fn slow_scan_node(
         &self,
         code: &SyntaxNode,
         rule: &ResolvedRule,
         restrict_range: &Option<FileRange>,
+        expansion_depth: usize,
+        nesting: usize,
         matches_out: &mut Vec<Match>,
     ) {
         if !is_search_permitted(code) {
+            if is_whole_use_match(rule, code) {
+                self.try_add_match(rule, code, restrict_range, matches_out);
+            }
             return;
         }
+        let num_matches = matches_out.len();
         self.try_add_match(rule, &code, restrict_range, matches_out);
+        // Any match within one that would be nested more deeply than `nested_search_depth` allows
+        // would be dropped, so there's no need to look for them.
+        let nesting = nesting + (matches_out.len() > num_matches) as usize;
+        if self.nested_search_depth.map_or(false, |max_nesting| nesting > max_nesting) {
+            mark::hit!(nested_search_depth_reached);
+            return;
+        }
         // If we've got a macro call, we already tried matching it pre-expansion, which is the only
-        // way to match the whole macro, now try expanding it and matching the expansion.
-        if let Some(macro_call) = ast::MacroCall::cast(code.clone()) {
+        // way to match the whole macro, now try expanding it and matching the expansion. Macros
+        // that expand to calls of themselves would have us recurse forever, so we stop at the
+        // limit.
+        let macro_call = ast::MacroCall::cast(code.clone())
+            .filter(|_| expansion_depth < self.expansion_depth_limit);
+        if let Some(macro_call) = macro_call {
             if let Some(expanded) = self.sema.expand(&macro_call) {
                 if let Some(tt) = macro_call.token_tree() {
                     // When matching within a macro expansion, we only want to allow matches of
@@ -178,17 +273,26 @@ impl<'db> MatchFinder<'db> {
                         &expanded,
                         rule,
                         &Some(self.sema.original_range(tt.syntax())),
+                        expansion_depth + 1,
+                        nesting,
                         matches_out,
                     );
                 }
             }
         }
         for child in code.children() {
-            self.slow_scan_node(&child, rule, restrict_range, matches_out);
+            self.slow_scan_node(
+                &child,
+                rule,
+                restrict_range,
+                expansion_depth,
+                nesting,
+                matches_out,
+            );
         }
     }
 
-    fn try_add_match(
+    pub(crate) fn try_add_match(
         &self,
         rule: &ResolvedRule,
         code: &SyntaxNode,
@@ -199,11 +303,110 @@ impl<'db> MatchFinder<'db> {
             mark::hit!(replace_nonpath_within_selection);
             return;
         }
-        if let Ok(m) = matching::get_match(false, rule, code, restrict_range, &self.sema) {
+        if self.test_modules_only && !self.is_in_test_module(code) {
+            return;
+        }
+        let matched = match &self.fail_reasons {
+            Some(fail_reasons) => {
+                self.get_match_recording_failure(rule, code, restrict_range, fail_reasons)
+            }
+            None => matching::get_match(
+                false,
+                rule,
+                code,
+                restrict_range,
+                self.match_options,
+                &self.sema,
+            )
+            .ok(),
+        };
+        if let Some(mut m) = matched {
+            if !self.search_generated_code {
+                m.in_generated_code = self.is_generated_file(m.range.file_id)
+                    || self
+                        .sema
+                        .ancestors_with_macros(code.clone())
+                        .any(|n| is_automatically_derived(&n));
+            }
             matches_out.push(m);
         }
     }
 
+    /// Attempts to match `code`, recording why it didn't match if it's the kind of code that `rule`
+    /// could match and we haven't yet recorded as many reasons as we keep.
+    fn get_match_recording_failure(
+        &self,
+        rule: &ResolvedRule,
+        code: &SyntaxNode,
+        restrict_range: &Option<FileRange>,
+        fail_reasons: &RefCell<Vec<(FileRange, MatchFailureReason)>>,
+    ) -> Option<Match> {
+        if fail_reasons.borrow().len() >= crate::MAX_FAIL_REASONS
+            || !crate::is_debug_candidate(rule, code)
+        {
+            return matching::get_match(
+                false,
+                rule,
+                code,
+                restrict_range,
+                self.match_options,
+                &self.sema,
+            )
+            .ok();
+        }
+        let (result, trace) = matching::get_match_with_trace(
+            rule,
+            code,
+            restrict_range,
+            self.match_options,
+            &self.sema,
+        );
+        match result {
+            Ok(m) => Some(m),
+            Err(e) => {
+                let reason = crate::failure_reason(e, trace);
+                fail_reasons.borrow_mut().push((self.sema.original_range(code), reason));
+                None
+            }
+        }
+    }
+
+    /// Returns whether `code` is within a module marked `#[cfg(test)]`. The module may be inline,
+    /// or may be the module of the file containing `code`, or one of its parents.
+    fn is_in_test_module(&self, code: &SyntaxNode) -> bool {
+        if self.sema.ancestors_with_macros(code.clone()).any(|n| is_cfg_test(&n)) {
+            return true;
+        }
+        let file_id = self.sema.original_range(code).file_id;
+        let mut module = self.sema.to_module_def(file_id);
+        while let Some(m) = module {
+            if let Some(declaration) = m.declaration_source(self.sema.db) {
+                if declaration.value.syntax().ancestors().any(|n| is_cfg_test(&n)) {
+                    return true;
+                }
+            }
+            module = m.parent(self.sema.db);
+        }
+        false
+    }
+
+    /// Returns whether `file_id` has a generated file marker in a comment within its first few
+    /// lines. We only check each file once.
+    pub(crate) fn is_generated_file(&self, file_id: FileId) -> bool {
+        use ra_db::SourceDatabaseExt;
+        if let Some(&is_generated) = self.generated_files.borrow().get(&file_id) {
+            return is_generated;
+        }
+        let file_text = self.sema.db.file_text(file_id);
+        let is_generated = file_text.lines().take(GENERATED_FILE_MARKER_LINES).any(|line| {
+            let line = line.trim_start();
+            (line.starts_with("//") || line.starts_with("/*"))
+                && self.generated_file_markers.iter().any(|marker| line.contains(marker.as_str()))
+        });
+        self.generated_files.borrow_mut().insert(file_id, is_generated);
+        is_generated
+    }
+
     /// Returns whether `code` is within one of our range restrictions if we have any. No range
     /// restrictions is considered unrestricted and always returns true.
     fn within_range_restrictions(&self, code: &SyntaxNode) -> bool {
@@ -221,6 +424,132 @@ impl<'db> MatchFinder<'db> {
     }
 }
 
+pub(crate) fn estimate_cost(rule: &ResolvedRule) -> CostEstimate {
+    CostEstimate {
+        requires_type_inference: !rule.pattern.ufcs_function_calls.is_empty()
+            || rule
+                .pattern
+                .placeholders_by_stand_in
+                .values()
+                .any(|p| p.constraints.iter().any(|c| c.is_semantic())),
+        requires_full_scan: pick_path_for_usages(&rule.pattern).is_none(),
+        resolved_paths: rule.pattern.resolved_paths.len(),
+    }
+}
+
+impl CostEstimate {
+    /// Returns an estimate for doing the work of both `self` and `other`.
+    pub(crate) fn combine(self, other: CostEstimate) -> CostEstimate {
+        CostEstimate {
+            requires_type_inference: self.requires_type_inference || other.requires_type_inference,
+            requires_full_scan: self.requires_full_scan || other.requires_full_scan,
+            resolved_paths: self.resolved_paths.max(other.resolved_paths),
+        }
+    }
+}
+
+/// Returns whether `rule` could possibly match somewhere in `text`. We check that every token that
+/// the code must contain in order to match is present somewhere in `text`, unless matches may come
+/// from macro definitions.
+pub(crate) fn could_match_in_text(
+    rule: &ResolvedRule,
+    text: &str,
+    options: matching::MatchOptions,
+) -> bool {
+    // Code that came from a macro's definition needn't appear in the text at all.
+    if options.match_inside_macro_expansions {
+        return true;
+    }
+    let mut required_tokens = FxHashSet::default();
+    collect_required_tokens(rule, &rule.pattern.node, options, &mut required_tokens);
+    required_tokens.iter().all(|token| text.contains(token.as_str()))
+}
+
+/// Adds to `out` the text of tokens within `pattern` that must be present verbatim in any code
+/// that it matches. This must be conservative. Leaving out tokens only makes the check less
+/// effective, whereas including a token that isn't actually required would cause us to skip files
+/// containing matches.
+fn collect_required_tokens(
+    rule: &ResolvedRule,
+    pattern: &SyntaxNode,
+    options: matching::MatchOptions,
+    out: &mut FxHashSet<String>,
+) {
+    // Paths that we resolved match based on what they resolve to, not what they're called. e.g.
+    // they may be referenced via an alias. UFCS calls can match method calls, which are written
+    // differently, so only the arguments are required. A placeholder for an else branch may match
+    // an `if` expression, which needn't have braces. Paths with wildcards are compared with what
+    // the code resolves to, so none of their segments need be written.
+    if rule.pattern.resolved_paths.contains_key(pattern)
+        || rule.pattern.wildcard_paths.contains_key(pattern)
+        || rule.else_branch_placeholder_token(pattern).is_some()
+    {
+        return;
+    }
+    // Type arguments of method calls may be inferred rather than written.
+    if pattern.kind() == SyntaxKind::TYPE_ARG_LIST
+        && pattern.parent().map(|parent| parent.kind()) == Some(SyntaxKind::METHOD_CALL_EXPR)
+    {
+        return;
+    }
+    if rule.pattern.ufcs_function_calls.contains_key(pattern) {
+        use ast::ArgListOwner;
+        if let Some(arg_list) = ast::CallExpr::cast(pattern.clone()).and_then(|c| c.arg_list()) {
+            for arg in arg_list.args() {
+                collect_required_tokens(rule, arg.syntax(), options, out);
+            }
+        }
+        return;
+    }
+    for element in pattern.children_with_tokens() {
+        match element {
+            SyntaxElement::Node(node) => collect_required_tokens(rule, &node, options, out),
+            SyntaxElement::Token(token) => {
+                // Commas are optional in some places, such as before closing brackets. Identifiers
+                // may be written with a different case convention if that's enabled.
+                if token.kind().is_trivia()
+                    || token.kind() == SyntaxKind::COMMA
+                    || rule.get_placeholder(&token).is_some()
+                    || (options.case_convention_insensitive && token.kind() == SyntaxKind::IDENT)
+                {
+                    continue;
+                }
+                out.insert(token.text().to_string());
+            }
+        }
+    }
+}
+
+/// The number of lines at the start of a file that we check for generated file markers.
+const GENERATED_FILE_MARKER_LINES: usize = 5;
+
+/// Returns whether `node` is an item marked `#[automatically_derived]`.
+fn is_automatically_derived(node: &SyntaxNode) -> bool {
+    use ast::AttrsOwner;
+    match ast::Item::cast(node.clone()) {
+        Some(item) => {
+            item.attrs().any(|attr| attr.simple_name().as_deref() == Some("automatically_derived"))
+        }
+        None => false,
+    }
+}
+
+/// Returns whether `node` is a module, or a file, with a `#[cfg(test)]` attribute.
+fn is_cfg_test(node: &SyntaxNode) -> bool {
+    use ast::AttrsOwner;
+    let mut attrs = match_ast! {
+        match node {
+            ast::Module(it) => it.attrs(),
+            ast::SourceFile(it) => it.attrs(),
+            _ => return false,
+        }
+    };
+    attrs.any(|attr| {
+        attr.simple_name().as_deref() == Some("cfg")
+            && matches!(attr.token_tree(), Some(tt) if tt.syntax().text() == "(test)")
+    })
+}
+
 /// Returns whether we support matching within `node` and all of its ancestors.
 fn is_search_permitted_ancestors(node: &SyntaxNode) -> bool {
     if let Some(parent) = node.parent() {
@@ -240,6 +569,13 @@ fn is_search_permitted(node: &SyntaxNode) -> bool {
     node.kind() != SyntaxKind::USE
 }
 
+/// Returns whether `node` is a use declaration and so is the pattern of `rule`. Such patterns, e.g.
+/// `use $m::*;`, match whole declarations, which avoids the problem above, even though nothing
+/// within use declarations is searched.
+fn is_whole_use_match(rule: &ResolvedRule, node: &SyntaxNode) -> bool {
+    node.kind() == SyntaxKind::USE && rule.pattern.node.kind() == SyntaxKind::USE
+}
+
 impl UsageCache {
     fn find(&mut self, definition: &Definition) -> Option<&[Reference]> {
         // We expect a very small number of cache entries (generally 1), so a linear scan should be
@@ -257,6 +593,17 @@ impl UsageCache {
 /// something that we can find references to. We then somewhat arbitrarily pick the path that is the
 /// longest as this is hopefully more likely to be less common, making it faster to find.
 fn pick_path_for_usages(pattern: &ResolvedPattern) -> Option<&ResolvedPath> {
+    // The path before a placeholder that ends a use tree, as in `use foo::$rest;`, may match a
+    // qualifier at any depth within the code's path, so we can't tell which node to match from a
+    // reference.
+    if pattern
+        .node
+        .descendants()
+        .filter_map(ast::UseTree::cast)
+        .any(|tree| matching::use_tree_rest_placeholder(pattern, &tree).is_some())
+    {
+        return None;
+    }
     // FIXME: Take the scope of the resolved path into account. e.g. if there are any paths that are
     // private to the current module, then we definitely would want to pick them over say a path
     // from std. Possibly we should go further than this and intersect the search scopes for all
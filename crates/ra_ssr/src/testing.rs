@@ -0,0 +1,33 @@
+//! Helpers for unit testing rules, so that crates that define their own rules don't need to set up
+//! a database themselves. Enabled by the `testing` feature.
+
+use crate::{database_for_file_content, MatchFinder, SsrRule};
+use ra_db::SourceDatabaseExt;
+
+/// Applies `rule` to a single file containing `before` and panics unless the result is exactly
+/// `after`. Paths in the rule are resolved at the start of the file. A rule that shouldn't match
+/// can be tested by passing the same text for `before` and `after`.
+pub fn test_rule(rule: &str, before: &str, after: &str) {
+    let parsed: SsrRule = match rule.parse() {
+        Ok(parsed) => parsed,
+        Err(error) => panic!("Failed to parse rule `{}`: {}", rule, error),
+    };
+    let (db, file_id) = database_for_file_content(before);
+    let mut match_finder = match MatchFinder::at_first_file(&db) {
+        Ok(match_finder) => match_finder,
+        Err(error) => panic!("Failed to search `before`: {}", error),
+    };
+    if let Err(error) = match_finder.add_rule(parsed) {
+        panic!("Failed to add rule `{}`: {}", rule, error);
+    }
+    let mut actual = db.file_text(file_id).to_string();
+    for edit in match_finder.edits() {
+        edit.edit.apply(&mut actual);
+    }
+    if actual != after {
+        panic!(
+            "Rule `{}` gave the wrong result\n--- before\n{}\n--- expected\n{}\n--- actual\n{}\n",
+            rule, before, after, actual
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{MatchFinder, SsrRule};
+use crate::{
+    ConflictKind, InsertPosition, MatchFinder, MatchOrigin, OncePerScope, RulePriority,
+    SsrErrorKind, SsrRule, TrailingSemicolon,
+};
 use expect::{expect, Expect};
 use ra_db::{salsa::Durability, FileId, FilePosition, FileRange, SourceDatabaseExt};
 use rustc_hash::FxHashSet;
@@ -30,8 +33,8 @@ fn parser_two_delimiters() {
 #[test]
 fn parser_repeated_name() {
     assert_eq!(
-        parse_error_text("foo($a, $a) ==>>"),
-        "Parse error: Name `a` repeats more than once"
+        parse_error_text("foo($a, ${a:kind(literal)}) ==>>"),
+        "Parse error: Constraints for `a` must be given where it first appears"
     );
 }
 
@@ -59,6 +62,31 @@ fn parser_undefined_placeholder_in_replacement() {
     );
 }
 
+#[test]
+fn parser_undefined_placeholder_suggestion() {
+    assert_eq!(
+        parse_error_text("foo($value, $b) ==>> bar($valeu, $bb, $xyz)"),
+        "Parse error: Replacement contains undefined placeholders: $valeu (did you mean \
+        `$value`?), $bb (did you mean `$b`?), $xyz"
+    );
+}
+
+#[test]
+fn parser_similar_placeholder_warning() {
+    let warnings = |rule: &str| rule.parse::<SsrRule>().unwrap().warnings();
+    assert_eq!(
+        warnings("$left + $lefft ==>> $left * 2"),
+        vec![
+            "Placeholders `$left` and `$lefft` each occur once and have similar names. If they \
+            should match the same code, give them the same name."
+        ]
+    );
+    // Placeholders that occur more than once are evidently spelled as intended.
+    assert!(warnings("$left + $lefft + $left ==>> $left").is_empty());
+    assert!(warnings("$a + $b ==>> $b + $a").is_empty());
+    assert!(warnings("foo($arg1, $arg2) ==>> bar($arg2, $arg1)").is_empty());
+}
+
 /// `code` may optionally contain a cursor marker `<|>`. If it doesn't, then the position will be
 /// the start of the file. If there's a second cursor marker, then we'll return a single range.
 pub(crate) fn single_file(code: &str) -> (ra_ide_db::RootDatabase, FilePosition, Vec<FileRange>) {
@@ -127,8 +155,8 @@ fn assert_matches(pattern: &str, code: &str, expected: &[&str]) {
     let (db, position, selections) = single_file(code);
     let mut match_finder = MatchFinder::in_context(&db, position, selections);
     match_finder.add_search_pattern(pattern.parse().unwrap()).unwrap();
-    let matched_strings: Vec<String> =
-        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect();
+    let matches = match_finder.matches().flattened().matches;
+    let matched_strings: Vec<String> = matches.iter().map(|m| m.matched_text()).collect();
     if matched_strings != expected && !expected.is_empty() {
         print_match_debug_info(&match_finder, position.file_id, &expected[0]);
     }
@@ -434,6 +462,150 @@ fn literal_constraint() {
     assert_matches("Some(${a:not(kind(literal))})", code, &["Some(x1)", "Some(40 + 2)"]);
 }
 
+#[test]
+fn field_access_constraint() {
+    let code = r#"
+        struct S { a: i32, b: [i32; 1] }
+        impl S { fn c(&self) -> i32 { 1 } }
+        fn foo(_: i32) {}
+        fn f(s: S) {
+            foo(s.a);
+            foo(s.b[0]);
+            foo(s.c());
+        }
+        "#;
+    assert_matches("foo(${x:kind(field)})", code, &["foo(s.a)"]);
+    assert_matches("foo(${x:not(kind(field))})", code, &["foo(s.b[0])", "foo(s.c())"]);
+}
+
+#[test]
+fn const_constraint() {
+    let code = r#"
+        const LIMIT: i32 = 10;
+        const fn double(x: i32) -> i32 { x * 2 }
+        fn runtime(x: i32) -> i32 { x }
+        fn foo(_: i32) {}
+        fn f1(v: i32) {
+            foo(42);
+            foo(-LIMIT + 1);
+            foo(double(LIMIT));
+            foo(v);
+            foo(runtime(1));
+            foo(double(v));
+        }
+        "#;
+    assert_matches(
+        "foo(${a:is_const})",
+        code,
+        &["foo(42)", "foo(-LIMIT + 1)", "foo(double(LIMIT))"],
+    );
+    assert_matches(
+        "foo(${a:not(is_const)})",
+        code,
+        &["foo(v)", "foo(runtime(1))", "foo(double(v))"],
+    );
+}
+
+#[test]
+fn redundant_cast_constraint() {
+    assert_ssr_transform(
+        "${e:redundant_cast} as $t ==>> $e",
+        r#"
+        fn f(a: i32, b: u8) {
+            let v1 = a as i32;
+            let v2 = b as i32;
+            let v3 = (a + 1) as i32 * 2;
+            let v4 = -a as i32;
+            let v5 = a as i64;
+        }
+        "#,
+        expect![[r#"
+            fn f(a: i32, b: u8) {
+                let v1 = a;
+                let v2 = b as i32;
+                let v3 = (a + 1) * 2;
+                let v4 = -a;
+                let v5 = a as i64;
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn in_loop_constraint() {
+    let code = r#"
+        fn foo(_: i32) -> i32 { 0 }
+        fn call(f: impl Fn()) { f() }
+        fn f(v: &[i32]) {
+            foo(1);
+            loop { foo(2); }
+            while foo(3) == 0 {}
+            for _ in &[foo(4)] { foo(5); }
+            loop { call(|| { foo(6); }); }
+        }"#;
+    assert_matches("foo(${a:in_loop})", code, &["foo(2)", "foo(3)", "foo(5)"]);
+    assert_matches(
+        "foo(${a:in_loop(through_closures)})",
+        code,
+        &["foo(2)", "foo(3)", "foo(5)", "foo(6)"],
+    );
+    assert_matches("foo(${a:not(in_loop)})", code, &["foo(1)", "foo(4)", "foo(6)"]);
+}
+
+#[test]
+fn zero_sized_constraint() {
+    assert_ssr_transform(
+        "drop(${x:zero_sized}) ==>> ()",
+        r#"
+        fn drop<T>(_: T) {}
+        struct Unit;
+        struct Wrapper(Unit, ());
+        struct Data(i32);
+        enum Single { A(Unit) }
+        enum Several { A, B }
+        fn f(u: Unit, w: Wrapper, d: Data, s: Single, e: Several, a: [Unit; 4]) {
+            drop(u);
+            drop(w);
+            drop(d);
+            drop(s);
+            drop(e);
+            drop(a);
+        }
+        "#,
+        expect![[r#"
+            fn drop<T>(_: T) {}
+            struct Unit;
+            struct Wrapper(Unit, ());
+            struct Data(i32);
+            enum Single { A(Unit) }
+            enum Several { A, B }
+            fn f(u: Unit, w: Wrapper, d: Data, s: Single, e: Several, a: [Unit; 4]) {
+                ();
+                ();
+                drop(d);
+                ();
+                drop(e);
+                ();
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn named_constraint() {
+    let code = r#"
+        struct Foo {}
+        impl Foo { fn foo(&self, _: i32) {} }
+        fn f(result: Foo, other: Foo) {
+            result.foo(1);
+            other.foo(2);
+        }
+        impl Foo { fn g(&self) { self.foo(3); } }"#;
+    assert_matches("${x:named(result)}.foo($a)", code, &["result.foo(1)"]);
+    assert_matches("${x:named(self)}.foo($a)", code, &["self.foo(3)"]);
+    assert_matches("${x:not(named(result))}.foo($a)", code, &["other.foo(2)", "self.foo(3)"]);
+}
+
 #[test]
 fn match_reordered_struct_instantiation() {
     assert_matches(
@@ -1044,3 +1216,3905 @@ fn replace_nonpath_within_selection() {
             }"#]],
     );
 }
+
+#[test]
+fn estimate_rule_costs() {
+    let (db, position, selections) = single_file(
+        r#"
+        struct Foo {}
+        impl Foo { fn do_stuff(&self, _: i32) {} }
+        fn foo() {}
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    let rules = [
+        "$a + $b ==>> $b + $a",
+        "foo() ==>> foo()",
+        "Foo::do_stuff($a, $b) ==>> $a",
+        "${a:is_const} + $b ==>> $b + $a",
+    ];
+    for rule in &rules {
+        match_finder.add_rule(rule.parse().unwrap()).unwrap();
+    }
+    let syntactic = match_finder.estimate_cost(0);
+    let path_based = match_finder.estimate_cost(1);
+    let needs_inference = match_finder.estimate_cost(2);
+    let semantic_constraint = match_finder.estimate_cost(3);
+    assert!(syntactic.requires_full_scan);
+    assert!(!path_based.requires_full_scan);
+    assert!(needs_inference.requires_type_inference);
+    assert!(semantic_constraint.requires_type_inference);
+    assert!(path_based < syntactic);
+    assert!(syntactic < needs_inference);
+    assert!(syntactic < semantic_constraint);
+}
+
+#[test]
+fn could_match_in_file() {
+    let (db, position, selections) = single_file(
+        r#"
+        mod foo { pub fn bar() {} }
+        use foo::bar as baz;
+        fn f1() { baz(); 1 + 2; }
+        "#,
+    );
+    let file_id = position.file_id;
+    let could_match = |pattern: &str| {
+        let mut match_finder = MatchFinder::in_context(&db, position, selections.clone());
+        match_finder.add_search_pattern(pattern.parse().unwrap()).unwrap();
+        match_finder.could_match_in_file(file_id)
+    };
+    // The path matches via an alias, so the text `bar()` isn't required to be present.
+    assert!(could_match("foo::bar()"));
+    assert!(could_match("$a + 2"));
+    assert!(!could_match("$a * 2"));
+    assert!(!could_match("$a + 3"));
+}
+
+#[test]
+fn edition_2015_keywords_are_identifiers() {
+    // In a 2015 edition crate, `try` is an ordinary identifier. Our parser always treats it as a
+    // keyword, so here the code needs to use a raw identifier, but the rule doesn't. The path in the
+    // template is rendered for the 2015 edition, so doesn't need to be raw.
+    assert_ssr_transform(
+        "try($a) ==>> try($a, 0)",
+        r#"
+        //- /lib.rs crate:old edition:2015
+        fn r#try(_: i32) {}
+        fn f() { r#try(1);<|> }
+        "#,
+        expect![[r#"
+            fn r#try(_: i32) {}
+            fn f() { try(1, 0); }
+        "#]],
+    );
+}
+
+#[test]
+fn edition_2018_keywords_skip_2015_crates() {
+    let code = r#"
+        //- /main.rs crate:main deps:old edition:2018
+        fn f() { foo().await;<|> }
+        //- /old.rs crate:old edition:2015
+        fn f() { foo().await; }
+        "#;
+    assert_matches("$a.await", code, &["foo().await"]);
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("$a.await".parse().unwrap()).unwrap();
+    let skipped = match_finder.files_skipped_due_to_edition();
+    assert_eq!(skipped.len(), 1);
+    assert_ne!(skipped[0], position.file_id);
+}
+
+#[test]
+fn matches_batch_keeps_rules_independent() {
+    let code = r#"
+        fn foo(_: i32) {}
+        fn bar(_: i32) {}
+        fn f() { foo(bar(1)); bar(2); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    let results = match_finder
+        .matches_batch(vec![
+            "bar($a) ==>> foo($a)".parse().unwrap(),
+            "foo($a) ==>> f($a)".parse().unwrap(),
+        ])
+        .unwrap();
+    let matched_text: Vec<Vec<String>> = results
+        .into_iter()
+        .map(|m| m.flattened().matches.iter().map(|m| m.matched_text()).collect())
+        .collect();
+    // The rule added to `match_finder` doesn't stop `bar(1)` from matching the first rule.
+    assert_eq!(matched_text, vec![vec!["bar(1)", "bar(2)"], vec!["foo(bar(1))"]]);
+}
+
+#[test]
+fn matches_batch_within_macro_calls() {
+    let code = r#"
+        macro_rules! id { ($a:expr) => {$a}; }
+        fn foo(_: i32) {}
+        fn f() { id!(foo(1 + 2)); id!(id!(foo(3 + 4))); }"#;
+    let (db, position, selections) = single_file(code);
+    let match_finder = MatchFinder::in_context(&db, position, selections);
+    // The first rule is found by scanning every node, the second via the usages of `foo`.
+    let results = match_finder
+        .matches_batch(vec![
+            "$a + $b ==>> $b + $a".parse().unwrap(),
+            "foo($a) ==>> foo($a * 2)".parse().unwrap(),
+        ])
+        .unwrap();
+    let file_text = db.file_text(position.file_id);
+    let matched_text: Vec<Vec<&str>> = results
+        .into_iter()
+        .map(|m| m.flattened().matches.iter().map(|m| &file_text[m.range.range]).collect())
+        .collect();
+    assert_eq!(matched_text, vec![vec!["1 + 2", "3 + 4"], vec!["foo(1 + 2)", "foo(3 + 4)"]]);
+}
+
+#[test]
+fn placeholder_types() {
+    let code = r#"
+        struct Foo {}
+        fn foo(_: Foo, _: i32) {}
+        fn f() { foo(Foo {}, 1 + 2); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a, $b)".parse().unwrap()).unwrap();
+    let matches = match_finder.matches().matches;
+    assert_eq!(matches.len(), 1);
+    let sema = hir::Semantics::new(&db);
+    let types = matches[0].placeholder_types(&sema);
+    assert_eq!(types.get("a").map(String::as_str), Some("Foo"));
+    assert_eq!(types.get("b").map(String::as_str), Some("i32"));
+}
+
+#[test]
+fn repeated_placeholders_match_equal_code() {
+    let code = r#"
+        fn foo(_: i32, _: i32) {}
+        fn f() { foo(1 + 2, 1+2); foo(1, 2); }"#;
+    assert_matches("foo($a, $a)", code, &["foo(1 + 2, 1+2)"]);
+}
+
+#[test]
+fn repeated_placeholders_in_binary_expr() {
+    let code = r#"
+        fn f(x: i32, y: i32) -> i32 { x + x + (x + y) + (y/* c */ + y) }"#;
+    assert_matches("$a + $a", code, &["x + x", "y/* c */ + y"]);
+    assert_match_failure_reason(
+        "$a + $a",
+        code,
+        "x + y",
+        "Paths bound to `$a` resolve to different things",
+    );
+}
+
+#[test]
+fn repeated_placeholders_compare_path_resolution() {
+    let code = r#"
+        mod m {
+            pub struct Foo {}
+            impl Foo {
+                pub fn new(_: i32, _: Foo) -> Foo { Foo {} }
+                pub fn default() -> Foo { Foo {} }
+            }
+            pub struct Bar {}
+            impl Bar {
+                pub fn default() -> Foo { Foo {} }
+            }
+        }
+        use m::Foo;
+        fn f() {
+            Foo::new(1, self::m::Foo::default());
+            Foo::new(2, m::Bar::default());
+        }"#;
+    assert_matches("$t::new($a, $t::default())", code, &["Foo::new(1, self::m::Foo::default())"]);
+}
+
+#[test]
+fn replace_impl_member() {
+    // Members of the impl that aren't in the pattern are left alone, whether they come before or
+    // after the member that matched.
+    assert_ssr_transform(
+        "impl $t { fn get(&self) -> $r { $body } } ==>> impl $t { fn get(&self) -> &$r { &$body } }",
+        r#"
+        struct Foo { x: i32 }
+        impl Foo {
+            fn new() -> Foo { Foo { x: 0 } }
+            fn get(&self) -> i32 { self.x }
+            fn set(&mut self, x: i32) { self.x = x; }
+        }
+        "#,
+        expect![[r#"
+            struct Foo { x: i32 }
+            impl Foo {
+                fn new() -> Foo { Foo { x: 0 } }
+                fn get(&self) -> &i32 { &self.x }
+                fn set(&mut self, x: i32) { self.x = x; }
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn impl_members_match_in_any_order() {
+    let code = r#"
+        struct Foo {}
+        impl Foo {
+            fn a() {}
+            fn b() {}
+            fn c() {}
+        }
+        impl Foo {
+            fn a() {}
+        }"#;
+    assert_matches(
+        "impl Foo { fn c() {} fn a() {} }",
+        code,
+        &["impl Foo {\n    fn a() {}\n    fn b() {}\n    fn c() {}\n}"],
+    );
+}
+
+#[test]
+fn impl_members_try_other_pairings() {
+    // `fn $f` can first be paired with `a`, binding `$v` to `1`, but then `g` doesn't match. Only
+    // pairing it with `b` works.
+    assert_ssr_transform(
+        "impl Foo { fn $f() -> i32 { $v } fn g() -> i32 { $v } } ==>> \
+         impl Foo { fn $f() -> i32 { $v + 1 } fn g() -> i32 { $v } }",
+        r#"
+        struct Foo {}
+        impl Foo {
+            fn a() -> i32 { 1 }
+            fn b() -> i32 { 2 }
+            fn g() -> i32 { 2 }
+        }
+        "#,
+        expect![[r#"
+            struct Foo {}
+            impl Foo {
+                fn a() -> i32 { 1 }
+                fn b() -> i32 { 2 + 1 }
+                fn g() -> i32 { 2 }
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn impl_member_rule_must_only_change_members() {
+    assert_eq!(
+        parse_error_text("impl $t { fn a() {} } ==>> impl Foo for $t { fn a() {} }"),
+        "Parse error: Only the members of an impl can be replaced, not the impl itself"
+    );
+    assert_eq!(
+        parse_error_text("impl $t { fn a() {} } ==>> impl $t { fn a() {} fn b() {} }"),
+        "Parse error: Search pattern has 1 impl member(s), but the replacement has 2"
+    );
+}
+
+#[test]
+fn matches_with_fix_suggestions() {
+    let code = r#"
+        fn foo(_: i32) {}
+        fn bar(_: i32) {}
+        fn f() { foo(1); foo(2 + 3); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    let fixes = match_finder.matches_with_fix_suggestions();
+    assert_eq!(fixes.len(), 2);
+    assert_eq!(fixes[1].matched.matched_text(), "foo(2 + 3)");
+    assert_eq!(fixes[1].title, "Replace with `bar($a)`");
+    let mut after = db.file_text(position.file_id).to_string();
+    fixes[1].edit.edit.apply(&mut after);
+    assert_eq!(after, "fn foo(_: i32) {}\nfn bar(_: i32) {}\nfn f() { foo(1); bar(2 + 3); }");
+
+    // The title describes the replacement that's used for the match, which may be an alternative.
+    let code = r#"
+        fn foo(_: [i32; 1]) {}
+        fn bar(_: i32) {}
+        fn f() { foo([1]); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder
+        .add_rule("foo([$a, $rest...]) ==>> foo([$a, $rest...]) ||| bar($a)".parse().unwrap())
+        .unwrap();
+    let fixes = match_finder.matches_with_fix_suggestions();
+    assert_eq!(fixes.len(), 1);
+    assert_eq!(fixes[0].title, "Replace with `bar($a)`");
+}
+
+#[test]
+fn async_blocks_and_closures_are_distinct() {
+    let code = r#"
+        fn f() {
+            let a = async { 1 };
+            let b = async || { 2 };
+            let c = async move { 3 };
+            let d = async move || 4;
+            let e = || 5;
+        }"#;
+    assert_matches("async { $a }", code, &["async { 1 }"]);
+    assert_matches("async move { $a }", code, &["async move { 3 }"]);
+    assert_matches("async || $body", code, &["async || { 2 }"]);
+    assert_matches("async move || $body", code, &["async move || 4"]);
+    assert_matches("|| $body", code, &["|| 5"]);
+}
+
+#[test]
+fn replace_async_block_body() {
+    assert_ssr_transform(
+        "async { $body } ==>> async move { $body }",
+        r#"
+        fn foo() {}
+        fn f() {
+            let a = async { foo(); 1 };
+            let b = async || { foo(); 2 };
+            let c = async {};
+        }
+        "#,
+        expect![[r#"
+            fn foo() {}
+            fn f() {
+                let a = async move { foo(); 1 };
+                let b = async || { foo(); 2 };
+                let c = async move {};
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn matches_within_block_body_are_nested() {
+    let code = "fn foo(_: i32) {} fn f() { let a = async { foo(1); async { foo(2); 3 } }; }";
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("async { $body }".parse().unwrap()).unwrap();
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    let matches = match_finder.matches().matches;
+    assert_eq!(matches.len(), 1);
+    let body = &matches[0].placeholder_values[&crate::matching::Var("body".to_owned())];
+    let inner: Vec<_> = body.inner_matches.matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(inner, vec!["foo(1)", "async { foo(2); 3 }"]);
+}
+
+#[test]
+fn block_body_in_expression_position() {
+    assert_ssr_transform(
+        "async { $body } ==>> run($body)",
+        r#"
+        fn foo() {}
+        fn run<T>(_: T) {}
+        fn f() {
+            let a = async { foo(); 1 };
+            let b = async { 2 };
+            let c = async {};
+            let d = async { foo(); };
+        }
+        "#,
+        expect![[r#"
+            fn foo() {}
+            fn run<T>(_: T) {}
+            fn f() {
+                let a = run({ foo(); 1 });
+                let b = run(2);
+                let c = run({});
+                let d = run({ foo(); });
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn most_specific_rule_wins() {
+    let code = r#"
+        fn foo(_: i32) {}
+        fn bar(_: i32) {}
+        fn baz() {}
+        fn f() { foo(1); foo(2); }"#;
+    let apply = |priority| {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        match_finder.set_rule_priority(priority);
+        match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+        match_finder.add_rule("foo(1) ==>> baz()".parse().unwrap()).unwrap();
+        let edits = match_finder.edits();
+        let mut after = db.file_text(position.file_id).to_string();
+        edits[0].edit.apply(&mut after);
+        after
+    };
+    assert!(apply(RulePriority::AddedOrder).ends_with("fn f() { bar(1); bar(2); }"));
+    assert!(apply(RulePriority::MostSpecific).ends_with("fn f() { baz(); bar(2); }"));
+}
+
+#[test]
+fn report_conflicts() {
+    let code = r#"
+        fn foo(_: i32) -> i32 { 1 }
+        fn bar(_: i32) -> i32 { 1 }
+        fn f() { foo(foo(foo(1))); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo(foo($a)) ==>> bar($a)".parse().unwrap()).unwrap();
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    let text = |range: FileRange| db.file_text(range.file_id)[range.range].to_string();
+    let conflicts: Vec<_> = match_finder
+        .conflicts()
+        .into_iter()
+        .map(|c| {
+            let kept = format!("{} (rule {})", text(c.kept.range), c.kept.rule_index);
+            let discarded =
+                format!("{} (rule {})", text(c.discarded.range), c.discarded.rule_index);
+            (c.kind, kept, discarded)
+        })
+        .collect();
+    // The innermost `foo(1)` is within the `$a` placeholder of the outer match, so doesn't
+    // conflict.
+    assert_eq!(
+        conflicts,
+        vec![
+            (
+                ConflictKind::SameNode,
+                "foo(foo(foo(1))) (rule 0)".to_owned(),
+                "foo(foo(foo(1))) (rule 1)".to_owned()
+            ),
+            (
+                ConflictKind::Nested,
+                "foo(foo(foo(1))) (rule 0)".to_owned(),
+                "foo(foo(1)) (rule 0)".to_owned()
+            ),
+            (
+                ConflictKind::Nested,
+                "foo(foo(foo(1))) (rule 0)".to_owned(),
+                "foo(foo(1)) (rule 1)".to_owned()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn search_file_content() {
+    let (db, file_id) =
+        crate::database_for_file_content("fn foo(_: i32) {}\nfn f() { foo(1); foo(2); }\n");
+    let mut match_finder = MatchFinder::at_first_file(&db).unwrap();
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    let matches = match_finder.matches().matches;
+    let matched_text: Vec<_> = matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched_text, vec!["foo(1)", "foo(2)"]);
+    assert!(matches.iter().all(|m| m.range.file_id == file_id));
+}
+
+#[test]
+fn replace_doc_links() {
+    let code = r#"
+        mod m {
+            pub struct Foo {}
+            pub struct Bar {}
+        }
+        /// Returns a [`m::Foo`], or [some foo](m::Foo), not a [`Baz`] or [`m::Bar`].
+        fn make() {}"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_search_doc_links(true);
+    match_finder.add_rule("m::Foo ==>> m::Bar".parse().unwrap()).unwrap();
+    let edits = match_finder.edits();
+    assert_eq!(edits.len(), 1);
+    let mut after = db.file_text(position.file_id).to_string();
+    edits[0].edit.apply(&mut after);
+    assert!(after.ends_with(
+        "/// Returns a [`m::Bar`], or [some foo](m::Bar), not a [`Baz`] or [`m::Bar`].\nfn make() {}"
+    ));
+}
+
+#[test]
+fn doc_links_not_searched_by_default() {
+    let code = r#"
+        struct Foo {}
+        /// Returns a [`Foo`].
+        fn make() -> Foo { Foo {} }"#;
+    assert_matches("Foo", code, &["Foo", "Foo"]);
+}
+
+#[test]
+fn match_inferred_type_args() {
+    let code = r#"
+        struct V {}
+        struct W<T>(T);
+        struct Foo {}
+        impl Foo {
+            fn make<T>(&self) -> T { loop {} }
+            fn take<T>(&self, _: T) {}
+        }
+        fn f(foo: Foo) {
+            let a: V = foo.make();
+            let b = foo.make::<V>();
+            foo.take(V {});
+        }"#;
+    let find = |pattern: &str, infer| {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        match_finder.set_infer_type_args(infer);
+        match_finder.add_rule(pattern.parse().unwrap()).unwrap();
+        let edits = match_finder.edits();
+        let mut after = db.file_text(position.file_id).to_string();
+        for edit in edits {
+            edit.edit.apply(&mut after);
+        }
+        after
+    };
+    let after = find("$a.make::<$t>() ==>> $a.make::<W<$t>>()", false);
+    assert!(after.contains("let a: V = foo.make();"));
+    assert!(after.contains("let b = foo.make::<W<V>>();"));
+    let after = find("$a.make::<$t>() ==>> $a.make::<W<$t>>()", true);
+    assert!(after.contains("let a: V = foo.make::<W<V>>();"));
+    assert!(after.contains("let b = foo.make::<W<V>>();"));
+    let after = find("$a.take::<$t>($x) ==>> $a.take::<$t>($x, 1)", true);
+    assert!(after.contains("foo.take::<V>(V {}, 1);"));
+}
+
+#[test]
+fn uninferable_type_args_dont_match() {
+    let code = r#"
+        struct Foo {}
+        impl Foo { fn make<T>(&self) -> Option<T> { None } }
+        fn f(foo: Foo) { let a: Option<i32> = foo.make(); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_infer_type_args(true);
+    match_finder.add_search_pattern("$a.make::<$t>()".parse().unwrap()).unwrap();
+    assert!(match_finder.matches().matches.is_empty());
+}
+
+#[test]
+fn match_macro_calls_in_statement_and_expression_position() {
+    let code = r#"
+        macro_rules! m { ($x:expr) => { $x } }
+        m!(0);
+        fn f() -> i32 {
+            m!(1);
+            let a = m!(2);
+            m!(3)
+        }"#;
+    assert_matches("m!($x)", code, &["m!(0);", "m!(1)", "m!(2)", "m!(3)"]);
+}
+
+#[test]
+fn replace_macro_call_in_item_position_keeps_semicolon() {
+    assert_ssr_transform(
+        "m!($x) ==>> n!($x)",
+        r#"
+            macro_rules! m { ($x:expr) => { $x } }
+            macro_rules! n { ($x:expr) => { $x } }
+            m!(0);
+            fn f() { m!(1); }"#,
+        expect![[r#"
+            macro_rules! m { ($x:expr) => { $x } }
+            macro_rules! n { ($x:expr) => { $x } }
+            n!(0);
+            fn f() { n!(1); }"#]],
+    )
+}
+
+#[test]
+fn skip_matches_in_generated_code() {
+    let code = r#"
+        //- /main.rs crate:main
+        mod generated;
+        struct S;
+        fn foo() {}
+        fn f() { foo(); }<|>
+        #[automatically_derived]
+        impl Clone for S {
+            fn clone(&self) -> S { foo(); S }
+        }
+        //- /generated.rs
+        // @generated by a build script.
+        fn g() { crate::foo(); }
+        "#;
+    assert_matches("foo()", code, &["foo()"]);
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo()".parse().unwrap()).unwrap();
+    assert_eq!(match_finder.matches().num_skipped_in_generated_code, 2);
+    match_finder.set_search_generated_code(true);
+    let matches = match_finder.matches();
+    assert_eq!(matches.matches.len(), 3);
+    assert_eq!(matches.num_skipped_in_generated_code, 0);
+}
+
+#[test]
+fn custom_generated_file_markers() {
+    let code = r#"
+        // Code generated by protoc. DO NOT EDIT.
+        fn foo() {}
+        fn f() { foo(); }"#;
+    assert_matches("foo()", code, &["foo()"]);
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo()".parse().unwrap()).unwrap();
+    match_finder.set_generated_file_markers(vec!["DO NOT EDIT".to_owned()]);
+    assert_eq!(match_finder.matches().matches.len(), 0);
+}
+
+#[test]
+fn impl_trait_replacement_only_where_type_is_not_required() {
+    assert_ssr_transform(
+        "make($a) ==>> make_iter($a)",
+        r#"
+            trait Tr {}
+            struct V(i32);
+            impl Tr for V {}
+            fn make(x: i32) -> V { V(x) }
+            fn make_iter(x: i32) -> impl Tr { V(x) }
+            fn takes_v(_: V) {}
+            fn takes_any<T: Tr>(_: T) {}
+            fn takes_impl(_: impl Tr) {}
+            fn f() -> V {
+                let a = make(1);
+                let b: V = make(2);
+                takes_v(make(3));
+                takes_any(make(4));
+                takes_impl(make(5));
+                make(6)
+            }"#,
+        expect![[r#"
+            trait Tr {}
+            struct V(i32);
+            impl Tr for V {}
+            fn make(x: i32) -> V { V(x) }
+            fn make_iter(x: i32) -> impl Tr { V(x) }
+            fn takes_v(_: V) {}
+            fn takes_any<T: Tr>(_: T) {}
+            fn takes_impl(_: impl Tr) {}
+            fn f() -> V {
+                let a = make_iter(1);
+                let b: V = make(2);
+                takes_v(make(3));
+                takes_any(make_iter(4));
+                takes_impl(make_iter(5));
+                make(6)
+            }"#]],
+    );
+}
+
+#[test]
+fn impl_trait_replacement_where_type_mentions_type_param() {
+    assert_ssr_transform(
+        "make($a) ==>> make_iter($a)",
+        r#"
+            trait Tr {}
+            struct V;
+            impl Tr for &'static V {}
+            fn make(_: i32) -> &'static V { &V }
+            fn make_iter(_: i32) -> impl Tr { &V }
+            fn takes_ref<T>(_: &T) {}
+            fn takes_v_ref(_: &V) {}
+            fn f() {
+                takes_ref(make(1));
+                takes_v_ref(make(2));
+            }"#,
+        expect![[r#"
+            trait Tr {}
+            struct V;
+            impl Tr for &'static V {}
+            fn make(_: i32) -> &'static V { &V }
+            fn make_iter(_: i32) -> impl Tr { &V }
+            fn takes_ref<T>(_: &T) {}
+            fn takes_v_ref(_: &V) {}
+            fn f() {
+                takes_ref(make_iter(1));
+                takes_v_ref(make(2));
+            }"#]],
+    );
+}
+
+#[test]
+fn impl_trait_replacement_failure_reason() {
+    let code = r#"
+        trait Tr {}
+        struct V;
+        fn make(_: i32) -> V { V }
+        fn make_iter(_: i32) -> impl Tr {}
+        fn takes_v(_: V) {}
+        fn f() { takes_v(make(1)); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("make($a) ==>> make_iter($a)".parse().unwrap()).unwrap();
+    let reasons: Vec<String> = match_finder
+        .debug_where_text_equal(position.file_id, "make(1)")
+        .iter()
+        .filter_map(|d| d.match_failure_reason().map(str::to_owned))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec!["Replacement calls `make_iter`, which returns `impl Trait`, but `V` is required here"]
+    );
+}
+
+#[test]
+#[cfg(feature = "persist")]
+fn saved_rules_give_the_same_matches() {
+    let rule_text = ["foo($a) ==>> bar($a)", "${x:kind(literal)} + 0 ==>> $x"];
+    let code = r#"
+        fn foo(_: i32) {}
+        fn bar(_: i32) {}
+        fn f() { foo(1 + 0); foo(2); }"#;
+    let rules: Vec<SsrRule> = rule_text.iter().map(|rule| rule.parse().unwrap()).collect();
+    let loaded = crate::load_rules(&crate::save_rules(&rules)).unwrap();
+    let (db, position, selections) = single_file(code);
+    let edits_for = |rules: Vec<SsrRule>| {
+        let mut match_finder = MatchFinder::in_context(&db, position, selections.clone());
+        for rule in rules {
+            match_finder.add_rule(rule).unwrap();
+        }
+        let mut after = db.file_text(position.file_id).to_string();
+        match_finder.edits()[0].edit.apply(&mut after);
+        after
+    };
+    let from_text = edits_for(rule_text.iter().map(|rule| rule.parse().unwrap()).collect());
+    assert_eq!(from_text, "fn foo(_: i32) {}\nfn bar(_: i32) {}\nfn f() { bar(1); bar(2); }");
+    assert_eq!(edits_for(loaded), from_text);
+}
+
+#[test]
+#[cfg(feature = "persist")]
+fn saved_rules_with_another_version() {
+    let error = crate::load_rules(r#"{"version":0,"rules":[]}"#).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parse error: Saved rules have format version 0, but only version 24 is supported"
+    );
+}
+
+#[test]
+fn case_convention_insensitive_identifiers() {
+    let code = r#"
+        struct S { my_field: i32, myField: i32, MyField: i32, my_other_field: i32 }
+        fn f(s: S) {
+            s.my_field;
+            s.myField;
+            s.MyField;
+            s.my_other_field;
+        }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_case_convention_insensitive(true);
+    match_finder.add_search_pattern("$s.my_field".parse().unwrap()).unwrap();
+    assert!(match_finder.could_match_in_file(position.file_id));
+    let matched: Vec<String> =
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["s.my_field", "s.myField", "s.MyField"]);
+    // Without the option, only the exact identifier matches.
+    assert_matches("$s.myField", code, &["s.myField"]);
+}
+
+#[test]
+fn placeholder_max_tokens() {
+    let code = r#"
+        macro_rules! foo {() => {}}
+        fn f() {
+            foo!(1, x, 3);
+            foo!(1, a + b, 3);
+            foo!(1, a + b + c, 3);
+            foo!(1, (a, b), 3);
+        }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_placeholder_max_tokens(3);
+    match_finder.add_search_pattern("foo!(1, $a, 3)".parse().unwrap()).unwrap();
+    let matched: Vec<String> =
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["foo!(1, x, 3)", "foo!(1, a + b, 3)"]);
+}
+
+#[test]
+fn normalize_case_conventions() {
+    use crate::matching::normalize_case_convention;
+    assert_eq!(normalize_case_convention("my_func"), "my_func");
+    assert_eq!(normalize_case_convention("myFunc"), "my_func");
+    assert_eq!(normalize_case_convention("MyFunc"), "my_func");
+    assert_eq!(normalize_case_convention("MY_FUNC"), "my_func");
+    assert_eq!(normalize_case_convention("HTTPServer"), "http_server");
+    assert_eq!(normalize_case_convention("utf8Decode"), "utf8_decode");
+    assert_eq!(normalize_case_convention("_unused"), "_unused");
+    assert_ne!(normalize_case_convention("_unused"), normalize_case_convention("unused"));
+}
+
+#[test]
+fn matches_with_source_text() {
+    let code = r#"
+        fn foo(_: i32, _: i32) {}
+        fn f() { foo(1 + 2, foo(3, 4)); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a, $b)".parse().unwrap()).unwrap();
+    let matches = match_finder.matches_with_source_text().matches;
+    let texts: Vec<(&str, &str, &str)> = matches
+        .iter()
+        .map(|m| {
+            (m.text.as_str(), m.placeholders["a"].text.as_str(), m.placeholders["b"].text.as_str())
+        })
+        .collect();
+    assert_eq!(
+        texts,
+        vec![("foo(3, 4)", "3", "4"), ("foo(1 + 2, foo(3, 4))", "1 + 2", "foo(3, 4)")]
+    );
+    let b = &matches[1].placeholders["b"];
+    assert_eq!(&db.file_text(position.file_id)[b.range.range], b.text);
+}
+
+#[test]
+fn match_positions() {
+    let code = r#"
+        fn foo(_: &str) {}
+        fn f() {
+            foo("é"); foo("x");
+        }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    let positions: Vec<_> = match_finder
+        .matches()
+        .with_positions(&db)
+        .iter()
+        .map(|m| {
+            let a = &m.placeholders["a"];
+            (
+                m.range.start.line,
+                m.range.start.col_utf8,
+                m.range.end.col_utf8,
+                m.range.end.col_utf16,
+                a.start.col_utf8,
+            )
+        })
+        .collect();
+    // `é` is two bytes in UTF-8, but one code unit in UTF-16.
+    assert_eq!(positions, vec![(2, 4, 13, 12, 8), (2, 15, 23, 22, 19)]);
+}
+
+#[test]
+fn match_if_else_chains() {
+    let code = r#"
+        fn f(a: bool, b: bool) -> i32 {
+            let x = if a { 1 } else { 2 };
+            if a { 1 } else if b { 2 } else { 3 }
+        }"#;
+    assert_matches(
+        "if $c1 { $b1 } else { $b2 }",
+        code,
+        &["if a { 1 } else { 2 }", "if b { 2 } else { 3 }"],
+    );
+    assert_matches(
+        "if $c1 { $b1 } else if $c2 { $b2 } else { $b3 }",
+        code,
+        &["if a { 1 } else if b { 2 } else { 3 }"],
+    );
+    // A placeholder directly after `else` matches the rest of the chain, whatever its length.
+    assert_matches(
+        "if $c { $b } else $rest",
+        code,
+        &[
+            "if a { 1 } else { 2 }",
+            "if b { 2 } else { 3 }",
+            "if a { 1 } else if b { 2 } else { 3 }",
+        ],
+    );
+}
+
+#[test]
+fn replace_if_else_chains() {
+    assert_ssr_transform(
+        "if $c1 { $b1 } else if $c2 { $b2 } else { $b3 } ==>> match () { _ if $c1 => $b1, _ if $c2 => $b2, _ => $b3 }",
+        r#"
+        fn f(a: bool, b: bool) -> i32 {
+            if a { 1 } else if b { 2 } else { 3 }
+        }"#,
+        expect![[r#"
+            fn f(a: bool, b: bool) -> i32 {
+                match () { _ if a => 1, _ if b => 2, _ => 3 }
+            }"#]],
+    );
+    assert_ssr_transform(
+        "if $c { $b } else $rest ==>> if check($c) { $b } else $rest",
+        r#"
+        fn check(c: bool) -> bool { c }
+        fn f(a: bool, b: bool) -> i32 {
+            if a { 1 } else if b { 2 } else { 3 }
+        }"#,
+        expect![[r#"
+            fn check(c: bool) -> bool { c }
+            fn f(a: bool, b: bool) -> i32 {
+                if check(a) { 1 } else if check(b) { 2 } else { 3 }
+            }"#]],
+    );
+}
+
+#[test]
+fn match_fn_pointer_types() {
+    let code = r#"
+        struct S {
+            a: fn(i32) -> u8,
+            b: fn(x: i32) -> u8,
+            c: fn(i32, i32) -> u8,
+            d: fn(i32),
+            e: unsafe fn(i32) -> u8,
+        }"#;
+    assert_matches("fn($x: $t) -> $r", code, &["fn(i32) -> u8", "fn(x: i32) -> u8"]);
+    assert_matches("fn($t) -> u8", code, &["fn(i32) -> u8", "fn(x: i32) -> u8"]);
+    assert_matches("fn(i32, $t) -> $r", code, &["fn(i32, i32) -> u8"]);
+    assert_matches("unsafe fn($t) -> $r", code, &["unsafe fn(i32) -> u8"]);
+    assert_no_match("fn(y: i32) -> u8", code);
+}
+
+#[test]
+fn replace_fn_pointer_types() {
+    assert_ssr_transform(
+        "fn($x: $t) -> $r ==>> fn($x: $t) -> W<$r>",
+        "struct W<T>(T); struct S { a: fn(i32) -> u8, b: fn(x: i32) -> u8 }",
+        expect![["struct W<T>(T); struct S { a: fn(_: i32) -> W<u8>, b: fn(x: i32) -> W<u8> }"]],
+    );
+}
+
+#[test]
+fn statement_boundary() {
+    let code = r#"
+        fn foo() -> i32 { 1 }
+        fn bar(x: i32) {}
+        fn f() {
+            foo();
+            bar(foo());
+            let x = foo();
+        }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    let mut rule: SsrRule = "foo() ==>> bar(1)".parse().unwrap();
+    rule.set_statement_boundary(true);
+    match_finder.add_rule(rule).unwrap();
+    let file_text = db.file_text(position.file_id);
+    let matched: Vec<&str> = match_finder
+        .matches()
+        .flattened()
+        .matches
+        .iter()
+        .map(|m| &file_text[m.range.range])
+        .collect();
+    // Only the call that makes up a whole statement matches and the match includes the `;`.
+    assert_eq!(matched, vec!["foo();"]);
+    let edits = match_finder.edits();
+    let mut actual = file_text.to_string();
+    edits[0].edit.apply(&mut actual);
+    expect![[r#"
+        fn foo() -> i32 { 1 }
+        fn bar(x: i32) {}
+        fn f() {
+            bar(1);
+            bar(foo());
+            let x = foo();
+        }"#]]
+    .assert_eq(&actual);
+}
+
+#[test]
+fn matches_and_apply_fails_on_conflicts() {
+    let code = r#"
+        fn foo(_: i32) -> i32 { 1 }
+        fn bar(_: i32) -> i32 { 1 }
+        fn f() { foo(foo(1)); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    let change = match_finder.matches_and_apply().unwrap();
+    let mut actual = db.file_text(position.file_id).to_string();
+    change.source_file_edits[0].edit.apply(&mut actual);
+    assert!(actual.contains("fn f() { bar(bar(1)); }"));
+
+    // The second rule matches the same code as the first, so applying both would conflict.
+    match_finder.add_rule("foo($a) ==>> bar(2)".parse().unwrap()).unwrap();
+    let error = match_finder.matches_and_apply().unwrap_err();
+    assert_eq!(error.to_string(), "2 conflicting matches found");
+    assert!(error.conflicts.iter().all(|c| c.kind == ConflictKind::SameNode));
+    assert!(!match_finder.edits().is_empty());
+}
+
+#[test]
+fn no_alloc_constraint() {
+    let code = r#"
+        //- /main.rs crate:main deps:std
+        use std::{boxed::Box, string::{String, ToString}};
+        fn consume<T>(_: T) {}
+        fn wrap(s: String) -> String { s }
+        fn f(n: i32) {
+            consume(n + 1);
+            consume(String::new());
+            consume(String::from("a"));
+            consume(wrap(String::with_capacity(1)));
+            consume(n.to_string());
+            consume(Box::new(n));
+            consume(std::vec![n]);
+            consume(std::format!("{}", n));
+        }<|>
+        //- /std.rs crate:std
+        pub mod string {
+            pub struct String;
+            impl String {
+                pub fn new() -> String { String }
+                pub fn from(_: &str) -> String { String }
+                pub fn with_capacity(_: usize) -> String { String }
+            }
+            pub trait ToString { fn to_string(&self) -> String; }
+            impl ToString for i32 { fn to_string(&self) -> String { String } }
+        }
+        pub mod boxed {
+            pub struct Box<T>(T);
+            impl<T> Box<T> { pub fn new(t: T) -> Box<T> { Box(t) } }
+        }
+        #[macro_export]
+        macro_rules! vec { ($($x:expr),*) => { 0 } }
+        #[macro_export]
+        macro_rules! format { ($($x:tt)*) => { 0 } }
+        "#;
+    assert_matches("consume(${x:no_alloc})", code, &["consume(n + 1)", "consume(String::new())"]);
+    assert_matches(
+        "consume(${x:not(no_alloc)})",
+        code,
+        &[
+            r#"consume(String::from("a"))"#,
+            "consume(wrap(String::with_capacity(1)))",
+            "consume(n.to_string())",
+            "consume(Box::new(n))",
+            "consume(std::vec![n])",
+            r#"consume(std::format!("{}", n))"#,
+        ],
+    );
+}
+
+#[test]
+fn no_alloc_with_added_allocating_paths() {
+    let code = r#"
+        struct Arena;
+        impl Arena { fn alloc(&self, n: i32) -> i32 { n } }
+        trait Intern { fn intern(&self) -> i32; }
+        impl Intern for i32 { fn intern(&self) -> i32 { *self } }
+        macro_rules! pooled { ($x:expr) => { $x } }
+        fn make(n: i32) -> i32 { n }
+        fn consume<T>(_: T) {}
+        fn f(arena: &Arena, n: i32) {
+            consume(make(n));
+            consume(arena.alloc(n));
+            consume(Arena::alloc(arena, n));
+            consume(n.intern());
+            consume(pooled!(n));
+        }<|>
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("consume(${x:no_alloc}) ==>> ()".parse().unwrap()).unwrap();
+    match_finder.add_allocating_path("Arena::alloc").unwrap();
+    match_finder.add_allocating_path("Intern").unwrap();
+    match_finder.add_allocating_path("pooled").unwrap();
+    let matched: Vec<String> =
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["consume(make(n))"]);
+    assert_eq!(
+        match_finder.add_allocating_path("missing").unwrap_err().to_string(),
+        "Parse error: Failed to resolve allocating path `missing`"
+    );
+    assert_eq!(
+        match_finder.add_allocating_path("Arena").unwrap_err().to_string(),
+        "Parse error: Allocating path `Arena` isn't a function, trait or macro"
+    );
+}
+
+#[test]
+fn literal_suffix_constraint() {
+    let code = r#"
+        fn consume<T>(_: T) {}
+        fn f() {
+            consume(1u8);
+            consume(2);
+            consume(3i64);
+            consume(4.0f32);
+            consume(5f64);
+            consume(6.5);
+            consume("7");
+        }"#;
+    assert_matches("consume(${n:suffix(u8)})", code, &["consume(1u8)"]);
+    assert_matches("consume(${n:suffix(f32)})", code, &["consume(4.0f32)"]);
+    assert_matches("consume(${n:suffix(f64)})", code, &["consume(5f64)"]);
+    assert_matches("consume(${n:suffix(none)})", code, &["consume(2)", "consume(6.5)"]);
+    assert_matches(
+        "consume(${n:not(suffix(none))})",
+        code,
+        &["consume(1u8)", "consume(3i64)", "consume(4.0f32)", "consume(5f64)", r#"consume("7")"#],
+    );
+    assert_eq!(
+        parse_error_text("consume(${n:suffix(u7)}) ==>> ()"),
+        "Parse error: Expected a numeric type or `none`, found 'u7' in suffix constraint"
+    );
+}
+
+#[test]
+fn assert_match_count() {
+    let code = r#"
+        fn foo(_: i32) -> i32 { 1 }
+        fn bar(_: i32) -> i32 { 1 }
+        fn f() { foo(foo(1)); bar(2); }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    match_finder.add_search_pattern("bar(3)".parse().unwrap()).unwrap();
+    assert_eq!(match_finder.assert_match_count(0, 1..=2), Ok(()));
+    assert_eq!(match_finder.assert_match_count(1, 0..=0), Ok(()));
+
+    let error = match_finder.assert_match_count(0, 0..=0).unwrap_err();
+    assert_eq!(error.to_string(), "Expected 0..=0 matches of rule 0, but found 2");
+    let text = |range: FileRange| db.file_text(range.file_id)[range.range].to_string();
+    let locations: Vec<String> = error.locations.into_iter().map(text).collect();
+    assert_eq!(locations, vec!["foo(1)", "foo(foo(1))"]);
+}
+
+#[test]
+fn in_const_constraint() {
+    let code = r#"
+        const fn f(a: usize) -> usize { a + 1 }
+        static S: usize = 2 + 1;
+        const C: usize = 3 + 1;
+        struct A([u8; 4 + 1]);
+        enum E { V = 5 + 1 }
+        fn g(b: usize) -> usize {
+            let _ = [0; 6 + 1];
+            b + 1
+        }"#;
+    assert_matches(
+        "${n:in_const} + 1",
+        code,
+        &["a + 1", "2 + 1", "3 + 1", "4 + 1", "5 + 1", "6 + 1"],
+    );
+    assert_matches("${n:not(in_const)} + 1", code, &["b + 1"]);
+}
+
+#[test]
+fn in_const_initializers() {
+    // Calls are found via references to the called function, so check that references within
+    // initializers are found.
+    let code = r#"
+        struct V;
+        impl V { const fn new() -> V { V } }
+        const C: V = V::new();
+        static S: V = V::new();
+        fn f() { let _ = V::new(); }"#;
+    assert_matches("V::new()", code, &["V::new()", "V::new()", "V::new()"]);
+    assert_matches("${f:in_const}()", code, &["V::new()", "V::new()"]);
+    assert_matches("${f:not(in_const)}()", code, &["V::new()"]);
+}
+
+#[test]
+fn int_in_range_constraint() {
+    let code = r#"
+        fn sleep(_: i64) {}
+        fn f(n: i64) {
+            sleep(500);
+            sleep(1_000);
+            sleep(1001);
+            sleep(0x1000);
+            sleep(-5);
+            sleep(n);
+        }"#;
+    assert_matches("sleep(${ms:int_in(1001..)})", code, &["sleep(1001)", "sleep(0x1000)"]);
+    assert_matches("sleep(${ms:int_in(..1000)})", code, &["sleep(500)", "sleep(-5)"]);
+    assert_matches("sleep(${ms:int_in(500..=1000)})", code, &["sleep(500)", "sleep(1_000)"]);
+    assert_matches("sleep(${ms:int_in(-10..0)})", code, &["sleep(-5)"]);
+    assert_matches(
+        "sleep(${ms:not(int_in(0..=1000))})",
+        code,
+        &["sleep(1001)", "sleep(0x1000)", "sleep(-5)", "sleep(n)"],
+    );
+    assert_match_failure_reason(
+        "sleep(${ms:int_in(1001..)})",
+        code,
+        "sleep(n)",
+        "Code 'n' isn't an integer literal. Only literals, possibly negated, are evaluated",
+    );
+    assert_eq!(
+        parse_error_text("sleep(${ms:int_in(1..=)}) ==>> ()"),
+        "Parse error: Invalid range '1..=' in int_in constraint"
+    );
+    assert_eq!(
+        parse_error_text("sleep(${ms:int_in(x)}) ==>> ()"),
+        "Parse error: Invalid range 'x' in int_in constraint"
+    );
+}
+
+#[test]
+fn matches_in_test_modules() {
+    let code = r#"
+        //- /main.rs crate:main cfg:test
+        mod helpers;
+        #[cfg(test)]
+        mod tests;
+        fn foo() {}
+        fn f() { foo(); }<|>
+        #[cfg(test)]
+        mod inline_tests {
+            fn t() { crate::foo(); }
+        }
+        //- /helpers.rs
+        fn h() { crate::foo(); }
+        //- /tests.rs
+        mod nested;
+        fn t() { crate::foo(); }
+        //- /tests/nested.rs
+        fn n() { crate::foo(); }
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder =
+        MatchFinder::in_context(&db, position, selections).matches_in_test_modules();
+    match_finder.add_search_pattern("foo()".parse().unwrap()).unwrap();
+    let mut matched: Vec<String> = match_finder
+        .matches()
+        .flattened()
+        .matches
+        .iter()
+        .map(|m| format!("{:?} {}", m.range.file_id, m.matched_text()))
+        .collect();
+    matched.sort();
+    assert_eq!(
+        matched,
+        vec!["FileId(0) crate::foo()", "FileId(2) crate::foo()", "FileId(3) crate::foo()"]
+    );
+}
+
+#[test]
+fn no_locals_constraint() {
+    let code = r#"
+        const C: i32 = 1;
+        fn g() -> i32 { 2 }
+        fn consume(_: i32) {}
+        struct S { a: i32 }
+        impl S {
+            fn f(&self, p: i32) {
+                let l = 3;
+                consume(C + g());
+                consume(p + 1);
+                consume(l);
+                consume(self.a);
+                consume({ let x = C; x + 1 });
+            }
+        }"#;
+    assert_matches(
+        "consume(${x:no_locals})",
+        code,
+        &["consume(C + g())", "consume({ let x = C; x + 1 })"],
+    );
+    assert_match_failure_reason(
+        "consume(${x:no_locals})",
+        code,
+        "consume(p + 1)",
+        "Code 'p + 1' refers to the local `p`",
+    );
+    assert_match_failure_reason(
+        "consume(${x:no_locals})",
+        code,
+        "consume(self.a)",
+        "Code 'self.a' refers to the local `self`",
+    );
+}
+
+#[test]
+fn one_of_constraint() {
+    let code = r#"
+        mod boxed { pub struct Box<T>(T); impl<T> Box<T> { pub fn new(x: T) -> Self { Box(x) } } }
+        mod rc { pub struct Rc<T>(T); impl<T> Rc<T> { pub fn new(x: T) -> Self { Rc(x) } } }
+        mod sync { pub struct Arc<T>(T); impl<T> Arc<T> { pub fn new(x: T) -> Self { Arc(x) } } }
+        mod cell { pub struct Cell<T>(T); impl<T> Cell<T> { pub fn new(x: T) -> Self { Cell(x) } } }
+        use boxed::Box; use cell::Cell; use rc::Rc; use sync::Arc;
+        fn f() {
+            let a = Box::new(1);
+            let b = Rc::new(2);
+            let c = sync::Arc::new(3);
+            let d = Cell::new(4);
+        }"#;
+    let pattern = "${ctor:one_of(Box::new, Rc::new, Arc::new)}($x)";
+    assert_matches(pattern, code, &["Box::new(1)", "Rc::new(2)", "sync::Arc::new(3)"]);
+    assert_match_failure_reason(
+        pattern,
+        code,
+        "Cell::new(4)",
+        "Code 'Cell::new' isn't one of Box::new, Rc::new, Arc::new",
+    );
+    assert_ssr_transform(
+        "${ctor:one_of(Box::new, Rc::new)}($x) ==>> Arc::new($x)",
+        code,
+        expect![[r#"
+            mod boxed { pub struct Box<T>(T); impl<T> Box<T> { pub fn new(x: T) -> Self { Box(x) } } }
+            mod rc { pub struct Rc<T>(T); impl<T> Rc<T> { pub fn new(x: T) -> Self { Rc(x) } } }
+            mod sync { pub struct Arc<T>(T); impl<T> Arc<T> { pub fn new(x: T) -> Self { Arc(x) } } }
+            mod cell { pub struct Cell<T>(T); impl<T> Cell<T> { pub fn new(x: T) -> Self { Cell(x) } } }
+            use boxed::Box; use cell::Cell; use rc::Rc; use sync::Arc;
+            fn f() {
+                let a = Arc::new(1);
+                let b = Arc::new(2);
+                let c = sync::Arc::new(3);
+                let d = Cell::new(4);
+            }"#]],
+    );
+}
+
+#[test]
+fn one_of_constraint_with_unresolved_path() {
+    let (db, position, selections) = single_file("fn f() {}<|>");
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    let error = match_finder
+        .add_search_pattern("${ctor:one_of(Box::new)}($x)".parse().unwrap())
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parse error: Failed to resolve path `Box::new` in one_of constraint"
+    );
+    assert_eq!(
+        parse_error_text("${ctor:one_of(Box::new, )}($x) ==>> $x"),
+        "Parse error: Invalid path '' in one_of constraint"
+    );
+}
+
+#[test]
+#[cfg(feature = "lsp")]
+fn lsp_locations() {
+    let code = r#"
+        //- /main.rs crate:main
+        mod other;
+        fn foo(_: &str) {}
+        fn f() { foo("é"); foo(""); }<|>
+        //- /other.rs
+        fn g() { crate::foo("a"); }
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    // Files other than main.rs have no URI, so their matches are skipped.
+    let locations = match_finder.lsp_locations(|file_id| {
+        if file_id == position.file_id {
+            lsp_types::Url::parse("file:///src/main.rs").ok()
+        } else {
+            None
+        }
+    });
+    let locations: Vec<String> = locations
+        .iter()
+        .map(|l| {
+            let (start, end) = (l.range.start, l.range.end);
+            format!("{} {}:{}-{}:{}", l.uri, start.line, start.character, end.line, end.character)
+        })
+        .collect();
+    // `é` is two bytes in UTF-8, but one UTF-16 code unit.
+    assert_eq!(locations, vec!["file:///src/main.rs 2:9-2:17", "file:///src/main.rs 2:19-2:26"]);
+}
+
+#[test]
+#[cfg(feature = "lsp")]
+fn lsp_code_actions() {
+    let code = r#"
+        fn foo(_: &str) {}
+        fn bar(_: &str) {}
+        fn f() { foo("é"); foo("a"); }<|>
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    let uri = lsp_types::Url::parse("file:///src/main.rs").unwrap();
+    let actions = match_finder.lsp_code_actions(|_| Some(uri.clone()));
+    let actions: Vec<String> = actions
+        .iter()
+        .map(|action| {
+            let changes = action.edit.as_ref().unwrap().changes.as_ref().unwrap();
+            let edits: Vec<String> = changes[&uri]
+                .iter()
+                .map(|edit| {
+                    let (start, end) = (edit.range.start, edit.range.end);
+                    let range = (start.line, start.character, end.line, end.character);
+                    format!("{:?} {}", range, edit.new_text)
+                })
+                .collect();
+            format!("{}: {}", action.title, edits.join(", "))
+        })
+        .collect();
+    // `é` is two bytes in UTF-8, but one UTF-16 code unit.
+    assert_eq!(
+        actions,
+        vec![
+            "Replace with `bar($a)`: (2, 9, 2, 17) bar(\"é\")",
+            "Replace with `bar($a)`: (2, 19, 2, 27) bar(\"a\")",
+        ]
+    );
+    assert!(match_finder.lsp_code_actions(|_| None).is_empty());
+}
+
+#[test]
+#[cfg(feature = "lsp")]
+fn match_finder_for_lsp_position() {
+    let code = r#"
+        mod m {
+            pub struct Foo {}
+            fn f() -> Foo { Foo {} }
+        }
+        fn g() -> m::Foo { m::Foo {} }<|>
+        "#;
+    let (db, position, _) = single_file(code);
+    let main_uri = lsp_types::Url::parse("file:///src/main.rs").unwrap();
+    let file_resolver = |uri: &lsp_types::Url| Some(position.file_id).filter(|_| uri == &main_uri);
+    let finder_at = |line, character| {
+        MatchFinder::for_lsp_position(
+            &db,
+            &main_uri,
+            lsp_types::Position::new(line, character),
+            file_resolver,
+        )
+    };
+    // `Foo` is only in scope within `m`.
+    let mut match_finder = finder_at(2, 4).unwrap();
+    match_finder.add_search_pattern("Foo".parse().unwrap()).unwrap();
+    assert_eq!(match_finder.matches().flattened().matches.len(), 4);
+    let mut match_finder = finder_at(4, 0).unwrap();
+    assert!(match_finder.add_search_pattern("Foo".parse().unwrap()).is_err());
+
+    assert!(finder_at(10, 0).is_none());
+    let other_uri = lsp_types::Url::parse("file:///src/other.rs").unwrap();
+    assert!(MatchFinder::for_lsp_position(
+        &db,
+        &other_uri,
+        lsp_types::Position::new(0, 0),
+        file_resolver
+    )
+    .is_none());
+}
+
+#[test]
+fn canonicalize_rules() {
+    let canonical = |rule: &str| rule.parse::<SsrRule>().unwrap().canonicalize();
+    assert_eq!(canonical("foo($a, $b) ==>> bar($b, $a)"), "foo($p0,$p1) ==>> bar($p1,$p0)");
+    assert_eq!(canonical("foo($x,$y)==>>bar( $y , $x )"), "foo($p0,$p1) ==>> bar($p1,$p0)");
+    assert_eq!(canonical("$a+/* one */1 ==>> $a . add(1)"), "$p0+1 ==>> $p0.add(1)");
+    assert_eq!(canonical("foo(- -$a) ==>> foo(&mut  $a)"), "foo(- -$p0) ==>> foo(&mut $p0)");
+    assert_eq!(
+        canonical("foo(${v:kind(literal):not(int_in(..10))}, ${w:suffix(none)}) ==>> bar($v, $w)"),
+        "foo(${p0:kind(literal):not(int_in(..=9))},${p1:suffix(none)}) ==>> bar($p0,$p1)"
+    );
+    // The canonical form is itself a rule, which is its own canonical form.
+    let rule = "foo(${a:in_loop(through_closures)}) ==>> std::mem::drop($a)";
+    assert_eq!(canonical(&canonical(rule)), canonical(rule));
+}
+
+#[test]
+fn match_destructured_params() {
+    let code = r#"
+        struct P { x: i32, y: i32 }
+        fn f((a, b): (i32, i32)) {}
+        fn g(P { x, y }: P) {}
+        fn h(P { y: b, x: ref a }: P) {}
+        fn k(P { x, .. }: P) {}
+        fn l(p: P) {}"#;
+    assert_matches("fn $f(($a, $b): $t) {}", code, &["fn f((a, b): (i32, i32)) {}"]);
+    assert_matches(
+        "fn $f(P { x: $a, y: $b }: P) {}",
+        code,
+        &["fn g(P { x, y }: P) {}", "fn h(P { y: b, x: ref a }: P) {}"],
+    );
+    assert_matches("fn $f(P { y, x }: P) {}", code, &["fn g(P { x, y }: P) {}"]);
+    assert_matches("fn $f(P { x: $a, .. }: $t) {}", code, &["fn k(P { x, .. }: P) {}"]);
+    assert_matches(
+        "fn $f($p: P) {}",
+        code,
+        &[
+            "fn g(P { x, y }: P) {}",
+            "fn h(P { y: b, x: ref a }: P) {}",
+            "fn k(P { x, .. }: P) {}",
+            "fn l(p: P) {}",
+        ],
+    );
+}
+
+#[test]
+fn replace_destructured_params() {
+    assert_ssr_transform(
+        "fn $f(P { x: $a, y: $b }: P) {} ==>> fn $f(($a, $b): (i32, i32)) {}",
+        "struct P { x: i32, y: i32 } fn f(P { x, y }: P) {} fn g(P { y: d, x: c }: P) {}",
+        expect![[
+            "struct P { x: i32, y: i32 } fn f((x, y): (i32, i32)) {} fn g((c, d): (i32, i32)) {}"
+        ]],
+    );
+}
+
+#[test]
+fn replace_with_fresh_identifier() {
+    assert_ssr_transform(
+        "foo($a) ==>> { let ${fresh(tmp)} = $a; bar(${fresh(tmp)}, ${fresh(tmp)}) }",
+        r#"
+        fn foo(a: i32) -> i32 { a }
+        fn bar(a: i32, b: i32) -> i32 { a }
+        fn f1() { let tmp = 1; foo(tmp); }
+        fn f2() { foo(2); }
+        "#,
+        expect![[r#"
+            fn foo(a: i32) -> i32 { a }
+            fn bar(a: i32, b: i32) -> i32 { a }
+            fn f1() { let tmp = 1; { let tmp1 = tmp; bar(tmp1, tmp1) }; }
+            fn f2() { { let tmp = 2; bar(tmp, tmp) }; }
+        "#]],
+    );
+}
+
+#[test]
+fn fresh_identifier_only_in_replacement() {
+    assert_eq!(
+        parse_error_text("foo(${fresh(tmp)}) ==>> bar()"),
+        "Parse error: Fresh identifiers can only be used in the replacement"
+    );
+}
+
+#[test]
+fn rebind_to_another_snapshot() {
+    let (db1, position1, selections1) = single_file(
+        "fn foo(a: i32) -> i32 { a } fn bar(a: i32) -> i32 { a } fn f() { foo(1); }<|>",
+    );
+    let (db2, position2, selections2) = single_file(
+        "fn foo(a: i32) -> i32 { a } fn bar(a: i32) -> i32 { a } fn g() { foo(2); foo(3); }<|>",
+    );
+    let (db3, position3, selections3) =
+        single_file("fn foo(a: i32) -> i32 { a } fn g() { foo(4); }<|>");
+    let mut match_finder = MatchFinder::in_context(&db1, position1, selections1);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    let matched = |match_finder: &MatchFinder| -> Vec<String> {
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect()
+    };
+    assert_eq!(matched(&match_finder), vec!["foo(1)"]);
+
+    match_finder.rebind(&db2, position2, selections2).unwrap();
+    assert_eq!(matched(&match_finder), vec!["foo(2)", "foo(3)"]);
+
+    // `bar` doesn't exist in the third snapshot, so only the search pattern is used.
+    assert_eq!(
+        match_finder.rebind(&db3, position3, selections3).unwrap_err().to_string(),
+        "Parse error: Rules no longer resolve: rule 0: Failed to resolve path `bar`"
+    );
+    assert_eq!(matched(&match_finder), vec!["foo(4)"]);
+}
+
+#[test]
+fn match_inside_macro_expansion() {
+    let code = r#"
+        macro_rules! my_write { ($w:expr, $e:expr) => { $w.write_fmt($e) } }
+        struct W {}
+        impl W { fn write_fmt(&self, x: i32) {} }
+        fn f() {
+            let w = W {};
+            my_write!(w, 1);
+            w.write_fmt(2);
+        }"#;
+    // By default, code that came partly from the macro's definition can't match.
+    assert_matches("$w.write_fmt($e)", code, &["w.write_fmt(2)"]);
+
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_match_inside_macro_expansions(true);
+    match_finder.add_rule("$w.write_fmt($e) ==>> $w.write_fmt($e + 1)".parse().unwrap()).unwrap();
+    let matches = match_finder.matches().flattened().matches;
+    let file_text = db.file_text(position.file_id);
+    let reported: Vec<(&str, bool)> =
+        matches.iter().map(|m| (&file_text[m.range.range], m.inside_macro_expansion())).collect();
+    assert_eq!(reported, vec![("my_write!(w, 1)", true), ("w.write_fmt(2)", false)]);
+    // Only the match that's entirely in the file is edited.
+    let edits = match_finder.edits();
+    let mut after = file_text.to_string();
+    edits[0].edit.apply(&mut after);
+    assert!(after.contains("my_write!(w, 1);"));
+    assert!(after.contains("w.write_fmt(2 + 1);"));
+}
+
+/// Builds a match of the rule with index `rule_index` with the range `start..end` in `file_id`. If
+/// given, `placeholder` is the range of a placeholder `$a` and the matches nested within it.
+fn hand_built_match(
+    file_id: u32,
+    rule_index: usize,
+    (start, end): (u32, u32),
+    placeholder: Option<((u32, u32), crate::SsrMatches)>,
+) -> crate::Match {
+    use crate::matching::{PlaceholderMatch, Var};
+    use ra_syntax::{AstNode, TextRange};
+    let range = |start: u32, end: u32| FileRange {
+        file_id: FileId(file_id),
+        range: TextRange::new(start.into(), end.into()),
+    };
+    let mut placeholder_values = rustc_hash::FxHashMap::default();
+    if let Some(((start, end), inner_matches)) = placeholder {
+        let placeholder_value =
+            PlaceholderMatch { inner_matches, ..PlaceholderMatch::from_range(range(start, end)) };
+        placeholder_values.insert(Var("a".to_owned()), placeholder_value);
+    }
+    crate::Match {
+        range: range(start, end),
+        matched_node: ra_syntax::SourceFile::parse("").tree().syntax().clone(),
+        placeholder_values,
+        ignored_comments: Vec::new(),
+        rule_index,
+        added_rule_index: rule_index,
+        depth: 0,
+        rendered_template_paths: rustc_hash::FxHashMap::default(),
+        matched_impl_members: Vec::new(),
+        in_generated_code: false,
+        fresh_names: rustc_hash::FxHashMap::default(),
+        match_variables: rustc_hash::FxHashMap::default(),
+        inside_macro_expansion: false,
+        origin: crate::MatchOrigin::Direct,
+        constraint_checks: Vec::new(),
+        is_heuristic: false,
+        restrict_range: None,
+    }
+}
+
+fn hand_built_matches(matches: Vec<crate::Match>) -> crate::SsrMatches {
+    crate::SsrMatches { matches, ..crate::SsrMatches::default() }
+}
+
+/// The file, rule and start of each match, in the order returned by `flatten`.
+fn flattened_summary(matches: &crate::SsrMatches) -> Vec<(u32, usize, u32)> {
+    matches
+        .flatten()
+        .iter()
+        .map(|m| (m.range.file_id.0, m.added_rule_index, u32::from(m.range.range.start())))
+        .collect()
+}
+
+#[test]
+fn ssr_matches_flatten_and_filter() {
+    let build = || {
+        let c = hand_built_match(0, 0, (5, 8), None);
+        let b = hand_built_match(0, 1, (4, 10), Some(((5, 9), hand_built_matches(vec![c]))));
+        let a = hand_built_match(0, 0, (0, 20), Some(((4, 19), hand_built_matches(vec![b]))));
+        let d = hand_built_match(0, 1, (30, 40), None);
+        let e = hand_built_match(1, 0, (0, 5), None);
+        hand_built_matches(vec![e, d, a])
+    };
+    assert_eq!(
+        flattened_summary(&build()),
+        vec![(0, 0, 0), (0, 1, 4), (0, 0, 5), (0, 1, 30), (1, 0, 0)]
+    );
+
+    // The match of rule 0 that was nested in a match of rule 1 is now nested directly in the outer
+    // match of rule 0.
+    let rule_0 = build().filter_rule(0);
+    assert_eq!(rule_0.matches.len(), 2);
+    assert_eq!(flattened_summary(&rule_0), vec![(0, 0, 0), (0, 0, 5), (1, 0, 0)]);
+    let rule_1 = build().filter_rule(1);
+    assert_eq!(rule_1.matches.len(), 2);
+    assert_eq!(flattened_summary(&rule_1), vec![(0, 1, 4), (0, 1, 30)]);
+
+    assert_eq!(flattened_summary(&build().filter_file(FileId(1))), vec![(1, 0, 0)]);
+
+    // `flattened` returns the same matches, just in the order in which they were found.
+    let mut flattened = flattened_summary(&build().flattened());
+    let mut flatten = flattened_summary(&build());
+    flattened.sort();
+    flatten.sort();
+    assert_eq!(flattened, flatten);
+}
+
+#[test]
+fn ssr_matches_merge() {
+    let first = hand_built_matches(vec![
+        hand_built_match(0, 0, (0, 20), Some(((4, 19), hand_built_matches(vec![])))),
+        hand_built_match(0, 1, (30, 40), None),
+    ]);
+    let c = hand_built_match(0, 0, (5, 8), None);
+    let second = hand_built_matches(vec![
+        hand_built_match(0, 1, (4, 10), Some(((5, 9), hand_built_matches(vec![c])))),
+        hand_built_match(0, 1, (30, 40), None),
+        hand_built_match(0, 2, (0, 30), Some(((0, 25), hand_built_matches(vec![])))),
+    ]);
+    let merged = first.merge(second);
+    // The match of rule 2 contains the first match of rule 0, which contains the match of rule 1.
+    // The duplicate match of rule 1 at 30 is dropped.
+    assert_eq!(merged.matches.len(), 2);
+    assert_eq!(
+        flattened_summary(&merged),
+        vec![(0, 2, 0), (0, 0, 0), (0, 1, 4), (0, 0, 5), (0, 1, 30)]
+    );
+}
+
+#[test]
+fn match_impl_trait_type() {
+    let code = r#"
+        mod m { pub trait Tr {} }
+        trait Send {}
+        use m::Tr;
+        fn f(x: impl Tr) -> impl Tr + Send { x }
+        fn g(x: impl 'static + Tr) {}
+        fn h(x: &dyn Tr) {}
+        fn same(a: impl m::Tr, b: impl Tr) {}
+        fn different(a: impl Tr, b: impl Send) {}
+        "#;
+    assert_matches(
+        "impl $T",
+        code,
+        &[
+            "impl Tr",
+            "impl Tr + Send",
+            "impl 'static + Tr",
+            "impl m::Tr",
+            "impl Tr",
+            "impl Tr",
+            "impl Send",
+        ],
+    );
+    // `$T` is bound to the trait's path, so repeated occurrences match if they refer to the same
+    // trait, however it's written.
+    assert_matches(
+        "fn $f($a: impl $T, $b: impl $T) {}",
+        code,
+        &["fn same(a: impl m::Tr, b: impl Tr) {}"],
+    );
+    assert_ssr_transform(
+        "impl $T ==>> Box<dyn $T>",
+        "trait Tr {} struct Box<T>(T); fn f(x: impl Tr) {}",
+        expect![["trait Tr {} struct Box<T>(T); fn f(x: Box<dyn Tr>) {}"]],
+    );
+}
+
+#[test]
+fn match_loop_labels() {
+    let code = r#"
+        fn f(a: bool, b: bool) {
+            'outer: loop { 'inner: loop { if b { break 'outer; } } }
+            'x: loop { if a { break 'x; } }
+            while a { if b { break; } }
+            'y: while a { if b { break; } }
+        }"#;
+    // The label placeholder must bind the same label at each use.
+    assert_matches("$l: loop { if $c { break $l; } }", code, &["'x: loop { if a { break 'x; } }"]);
+    assert_match_failure_reason(
+        "$l: loop { if $c { break $l; } }",
+        code,
+        "'inner: loop { if b { break 'outer; } }",
+        "Code bound to `$l` differs between occurrences",
+    );
+    // A pattern without a label matches labeled loops, but only if the label isn't used.
+    assert_matches("loop { $body }", code, &["'inner: loop { if b { break 'outer; } }"]);
+    assert_matches(
+        "while $c { if $d { break; } }",
+        code,
+        &["while a { if b { break; } }", "'y: while a { if b { break; } }"],
+    );
+}
+
+#[test]
+fn replace_loop_with_label() {
+    assert_ssr_transform(
+        "$l: loop { if $c { break $l; } } ==>> $l: while !$c {}",
+        "fn f(a: bool) { 'outer: loop { 'inner: loop { if a { break 'inner; } } } }",
+        expect![["fn f(a: bool) { 'outer: loop { 'inner: while !a {} } }"]],
+    );
+}
+
+#[test]
+fn replace_loop_with_label_in_macro_call() {
+    assert_ssr_transform(
+        "$l: loop { if $c { break $l; } } ==>> $l: while !$c {}",
+        r#"
+            macro_rules! m1 { ($a:expr) => {$a}; }
+            fn f(a: bool) { m1!('inner: loop { if a { break 'inner; } }); }"#,
+        expect![[r#"
+            macro_rules! m1 { ($a:expr) => {$a}; }
+            fn f(a: bool) { m1!('inner: while !a {}); }"#]],
+    );
+}
+
+#[test]
+fn node_rewrite_rule() {
+    use ra_syntax::{ast, ast::make, AstNode};
+    let (db, position, selections) = single_file(
+        r#"
+        struct S {}
+        impl S { fn foo(&self, x: i32) {} }
+        fn foo(s: &S, x: i32) {}
+        fn f(s: &S) { foo(s, 1); foo(s, 0); }<|>
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder
+        .add_node_rewrite_rule("foo($s, $x)".parse().unwrap(), |m| {
+            let receiver = ast::Expr::cast(m.placeholder_node("s")?.clone())?;
+            let arg = ast::Expr::cast(m.placeholder_node("x")?.clone())?;
+            // Returning `None` leaves the match alone.
+            if arg.syntax().text() == "0" {
+                return None;
+            }
+            Some(
+                make::expr_method_call(receiver, make::name_ref("foo"), vec![arg]).syntax().clone(),
+            )
+        })
+        .unwrap();
+    let edits = match_finder.edits();
+    let mut actual = db.file_text(position.file_id).to_string();
+    edits[0].edit.apply(&mut actual);
+    expect![[r#"
+        struct S {}
+        impl S { fn foo(&self, x: i32) {} }
+        fn foo(s: &S, x: i32) {}
+        fn f(s: &S) { s.foo(1); foo(s, 0); }
+    "#]]
+    .assert_eq(&actual);
+    let titles: Vec<String> =
+        match_finder.matches_with_fix_suggestions().into_iter().map(|fix| fix.title).collect();
+    assert_eq!(titles, vec!["Replace with `s.foo(1)`"]);
+}
+
+#[test]
+fn unmatched_rules() {
+    let (db, position, selections) = single_file(
+        "fn foo(a: i32) -> i32 { a } fn bar(a: i32) -> i32 { a } fn f() { foo(foo(1)); }",
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("bar($a) ==>> foo($a)".parse().unwrap()).unwrap();
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    match_finder.add_search_pattern("foo(1)".parse().unwrap()).unwrap();
+    match_finder.add_search_pattern("bar(1)".parse().unwrap()).unwrap();
+    // Both calls to `foo` match rule 1, the inner one nested within the outer, so rule 2 matches
+    // nothing, since rules added earlier take precedence.
+    let matches = match_finder.matches();
+    assert_eq!(match_finder.unmatched_rules(&matches), vec![0, 2, 3]);
+}
+
+#[test]
+fn parent_constraint() {
+    let code = r#"
+        fn foo(_: i32) -> bool { true }
+        fn bar(_: bool) {}
+        fn f() {
+            if foo(1) {}
+            while foo(2) {}
+            bar(foo(3));
+            foo(4);
+            let _ = foo(5);
+        }"#;
+    assert_matches("${e:parent(condition)}", code, &["foo(1)", "foo(2)"]);
+    assert_matches("${e:parent(argument)}", code, &["1", "2", "3", "foo(3)", "4", "5"]);
+    assert_matches(
+        "${e:parent(statement)}",
+        code,
+        &["if foo(1) {}", "while foo(2) {}", "bar(foo(3))", "foo(4)"],
+    );
+    assert_eq!(
+        parse_error_text("${a:parent(body)} ==>> ()"),
+        "Parse error: Unknown parent kind 'body'"
+    );
+}
+
+#[test]
+fn has_default_constraint() {
+    let code = "struct A<T = i32>; struct B<T>; struct C<U, T = ()>;";
+    assert_matches("struct $s<${t:has_default}>;", code, &["struct A<T = i32>;"]);
+    assert_matches("struct $s<${t:not(has_default)}>;", code, &["struct B<T>;"]);
+    assert_matches("struct $s<$u, ${t:has_default}>;", code, &["struct C<U, T = ()>;"]);
+}
+
+#[test]
+fn near_misses() {
+    let (db, position, selections) = single_file(
+        r#"
+        fn foo(_: i32, _: i32, _: i32) {}
+        fn bar(_: i32, _: i32, _: i32) {}
+        fn f(x: i32) {
+            foo(x, 2, 3);
+            bar(x, 1, 3);
+            foo(x, 1, 4);
+            x;
+        }
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a, 1, 3)".parse().unwrap()).unwrap();
+    let misses: Vec<(String, String)> = match_finder
+        .near_misses(0, 2)
+        .into_iter()
+        .map(|(range, reason)| (db.file_text(range.file_id)[range.range].to_owned(), reason.reason))
+        .collect();
+    // `bar(x, 1, 3)` is syntactically the same as the pattern, so only fails once we resolve paths.
+    assert_eq!(
+        misses,
+        vec![
+            ("bar(x, 1, 3)".to_owned(), "Pattern had path `foo` code had `bar`".to_owned()),
+            (
+                "foo(x, 1, 4)".to_owned(),
+                "Pattern wanted token '3' (INT_NUMBER), but code had token '4' (INT_NUMBER)"
+                    .to_owned()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn explain_constraints() {
+    use crate::{ConstraintCheck, ConstraintOutcome};
+    let (db, position, selections) = single_file(
+        r#"
+        struct Z;
+        fn drop<T>(_: T) {}
+        fn f(u: Unknown) { drop(Z); drop(1); drop(u); }
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_explain_constraints(true);
+    match_finder
+        .add_search_pattern("drop(${x:not(kind(literal)):not(zero_sized)})".parse().unwrap())
+        .unwrap();
+    let check = |constraint: &str, outcome: ConstraintOutcome| ConstraintCheck {
+        placeholder: "x".to_owned(),
+        constraint: constraint.to_owned(),
+        outcome,
+    };
+    // The type of `u` is unknown, so `zero_sized` doesn't hold, which lets `not(zero_sized)` pass.
+    let matches = match_finder.matches().matches;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].matched_text(), "drop(u)");
+    assert_eq!(
+        matches[0].constraint_checks(),
+        &[
+            check("not(kind(literal))", ConstraintOutcome::Passed),
+            check(
+                "not(zero_sized)",
+                ConstraintOutcome::NotEvaluated("The type of 'u' couldn't be inferred".to_owned())
+            ),
+        ]
+    );
+    let misses = match_finder.near_misses(0, 10);
+    let checks: Vec<(String, Vec<ConstraintCheck>)> = misses
+        .into_iter()
+        .map(|(range, reason)| {
+            (db.file_text(range.file_id)[range.range].to_owned(), reason.constraint_checks)
+        })
+        .collect();
+    assert_eq!(
+        checks,
+        vec![
+            (
+                "drop(Z)".to_owned(),
+                vec![
+                    check("not(kind(literal))", ConstraintOutcome::Passed),
+                    check(
+                        "not(zero_sized)",
+                        ConstraintOutcome::Failed(
+                            "Constraint Not(IsZeroSized) failed for 'Z'".to_owned()
+                        )
+                    ),
+                ]
+            ),
+            (
+                "drop(1)".to_owned(),
+                vec![check(
+                    "not(kind(literal))",
+                    ConstraintOutcome::Failed(
+                        "Constraint Not(Kind(Literal)) failed for '1'".to_owned()
+                    )
+                )]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn expansion_depth_limit() {
+    let code = r#"
+        macro_rules! id { ($e:expr) => { $e } }
+        macro_rules! again { ($e:expr) => { again!($e) } }
+        fn foo(x: i32) -> i32 { x }
+        fn f() {
+            foo(1+1);
+            id!(foo(2+2));
+            id!(id!(foo(3+3)));
+            again!(4+4);
+        }"#;
+    // Code from macro expansions is rendered without whitespace.
+    let matches = |pattern: &str, limit: Option<usize>| -> Vec<String> {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        if let Some(limit) = limit {
+            match_finder.with_expansion_depth_limit(limit);
+        }
+        match_finder.add_search_pattern(pattern.parse().unwrap()).unwrap();
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect()
+    };
+    assert_eq!(matches("foo($a)", None), vec!["foo(1+1)", "foo(2+2)", "foo(3+3)"]);
+    assert_eq!(matches("foo($a)", Some(1)), vec!["foo(1+1)", "foo(2+2)"]);
+    assert_eq!(matches("foo($a)", Some(0)), vec!["foo(1+1)"]);
+    // `again!` expands to a call of itself, which we only expand as far as the limit.
+    assert_eq!(matches("$a + $b", None), vec!["1+1", "2+2", "3+3"]);
+    assert_eq!(matches("$a + $b", Some(1)), vec!["1+1", "2+2"]);
+}
+
+#[test]
+fn one_tuple_is_not_parenthesized_expr() {
+    assert_matches("($a,)", "fn f() { let t = (1,); let p = (2); }", &["(1,)"]);
+    assert_matches("($a)", "fn f() { let t = (1,); let p = (2); }", &["(2)"]);
+    assert_no_match("($a,)", "fn f() { let p = (2); }");
+    assert_no_match("($a)", "fn f() { let t = (1,); }");
+}
+
+#[test]
+fn variadic_array_and_tuple_elements() {
+    let code = r#"
+        fn f() {
+            let a = [1];
+            let b = [1, 2, 3];
+            let c = [2, 3];
+            let d = [1; 4];
+            let t = (1, true, "x");
+        }"#;
+    assert_matches("[1, $rest...]", code, &["[1]", "[1, 2, 3]"]);
+    assert_matches("[$first, $second, $rest...]", code, &["[1, 2, 3]", "[2, 3]"]);
+    assert_matches("($a, $rest...)", code, &["(1, true, \"x\")"]);
+    assert_ssr_transform(
+        "($first, $rest...) ==>> ($rest..., $first)",
+        "fn f() { let t = (1, 2, 3); let u = (4,); }",
+        expect![["fn f() { let t = (2, 3, 1); let u = (4,); }"]],
+    );
+    assert_ssr_transform(
+        "[$first, $rest...] ==>> [$rest..., $first]",
+        "fn f() { let a = [1]; }",
+        expect![["fn f() { let a = [1]; }"]],
+    );
+    assert_ssr_transform(
+        "[$first, $rest...] ==>> [$first, 0, $rest...]",
+        "fn f() { let a = [1]; let b = [1, 2]; }",
+        expect![["fn f() { let a = [1, 0]; let b = [1, 0, 2]; }"]],
+    );
+    assert_eq!(
+        parse_error_text("foo($a, $rest...) ==>> foo($a)"),
+        "Parse error: Variadic placeholders, such as `$rest...`, can only be the last element of \
+         an array or tuple expression, or the stages of a `?` chain, as in `$e.$stages...?`"
+    );
+    assert_eq!(
+        parse_error_text("[$rest..., $a] ==>> [$a]"),
+        "Parse error: Variadic placeholders, such as `$rest...`, can only be the last element of \
+         an array or tuple expression, or the stages of a `?` chain, as in `$e.$stages...?`"
+    );
+}
+
+#[test]
+fn arity_constraint() {
+    let code = r#"
+        fn foo<T>(t: T) {}
+        fn f() {
+            foo([1, 2]);
+            foo([1, 2, 3]);
+            foo([0; 2]);
+            foo((1, 2));
+            foo(());
+            foo([]);
+            foo((1));
+        }"#;
+    assert_matches("foo(${a:arity(2)})", code, &["foo([1, 2])", "foo((1, 2))"]);
+    assert_matches("foo(${a:arity(0)})", code, &["foo(())", "foo([])"]);
+    assert_matches("foo(${a:arity(1)})", code, &[]);
+    assert_eq!(parse_error_text("${a:arity(x)} ==>> ()"), "Parse error: Invalid arity `x`");
+}
+
+#[test]
+fn position_prefix_selects_expressions_or_patterns() {
+    let code = r#"
+        mod m { pub enum Opt { Some(i32), Nothing } }
+        mod other { pub enum Other { Some(i32) } }
+        use m::Opt::{self, Some};
+        fn f(o: Opt, p: other::Other) {
+            let a = Some(1);
+            let b = other::Other::Some(2);
+            if let Some(y) = o {}
+            if let other::Other::Some(z) = p {}
+        }
+        mod n {
+            use super::other::Other::Some;
+            fn g(q: super::other::Other) {
+                let c = Some(3);
+                if let Some(w) = q {}
+            }
+        }"#;
+    // Paths are compared by what they resolve to, whether they're in an expression or a pattern.
+    assert_matches("Some($x)", code, &["Some(1)", "Some(y)"]);
+    assert_matches("expr: Some($x)", code, &["Some(1)"]);
+    assert_matches("pat: Some($x)", code, &["Some(y)"]);
+    assert_matches("expr: other::Other::Some($x)", code, &["other::Other::Some(2)", "Some(3)"]);
+    assert_matches("pat:other::Other::Some($x)", code, &["other::Other::Some(z)", "Some(w)"]);
+    assert_ssr_transform(
+        "pat: Some($x) ==>> Some(ref $x)",
+        "enum E { Some(i32) } use E::Some; fn f(e: E) { let a = Some(1); if let Some(y) = e {} }",
+        expect![["enum E { Some(i32) } use E::Some; fn f(e: E) { let a = Some(1); if let Some(ref y) = e {} }"]],
+    );
+    let canonical = |rule: &str| rule.parse::<SsrRule>().unwrap().canonicalize();
+    assert_eq!(canonical("pat:Some($a) ==>> $a"), "pat: Some($p0) ==>> $p0");
+    assert_eq!(parse_error_text("pat: $a + 1 ==>> 1"), "Parse error: Not a valid Rust pattern");
+    assert_eq!(
+        parse_error_text("expr: Some($a) ==>> pat: $a"),
+        "Parse error: The replacement can only have the same position prefix as the search pattern"
+    );
+}
+
+#[test]
+fn match_arm_guards() {
+    let code = r#"
+        fn f(x: i32) -> i32 { match x { 1 if x > 0 => 10, _ => 20 } }
+        fn g(x: i32) -> i32 { match x { 1 => 10, _ => 20 } }"#;
+    let with_guard = "match x { 1 if x > 0 => 10, _ => 20 }";
+    let without_guard = "match x { 1 => 10, _ => 20 }";
+    assert_matches("match $e { $p if $g => $a, _ => $b }", code, &[with_guard]);
+    assert_matches("match $e { $p if $e > 0 => $a, _ => $b }", code, &[with_guard]);
+    assert_no_match("match $e { $p if $e < 0 => $a, _ => $b }", code);
+    assert_matches("match $e { $p => $a, _ => $b }", code, &[with_guard, without_guard]);
+    assert_ssr_transform(
+        "match $e { $p if $g => $a, _ => $b } ==>> match $e { $p if !($g) => $b, _ => $a }",
+        "fn f(x: i32) -> i32 { match x { 1 if x > 0 => 10, _ => 20 } }",
+        expect![["fn f(x: i32) -> i32 { match x { 1 if !(x > 0) => 20, _ => 10 } }"]],
+    );
+    // Replacing would drop the guard, so an arm with one only matches a pattern with one.
+    assert_ssr_transform(
+        "match $e { $p => $a, _ => $b } ==>> match $e { $p => $b, _ => $a }",
+        "fn f(x: i32) { match x { 1 if x > 0 => 10, _ => 20 }; match x { 1 => 10, _ => 20 }; }",
+        expect![[
+            "fn f(x: i32) { match x { 1 if x > 0 => 10, _ => 20 }; match x { 1 => 20, _ => 10 }; }"
+        ]],
+    );
+}
+
+#[test]
+fn nested_search_depth() {
+    let code = r#"
+        fn foo(x: i32) -> i32 { x }
+        fn f() { foo(foo(foo(1))); }"#;
+    let matches = |depth: Option<usize>| -> Vec<String> {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        if let Some(depth) = depth {
+            match_finder.nested_search_depth(depth);
+        }
+        match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect()
+    };
+    assert_eq!(matches(None), vec!["foo(1)", "foo(foo(1))", "foo(foo(foo(1)))"]);
+    assert_eq!(matches(Some(1)), vec!["foo(foo(1))", "foo(foo(foo(1)))"]);
+    assert_eq!(matches(Some(0)), vec!["foo(foo(foo(1)))"]);
+
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.nested_search_depth(1);
+    match_finder.add_rule("foo($a) ==>> $a".parse().unwrap()).unwrap();
+    let mut after = db.file_text(position.file_id).to_string();
+    match_finder.edits()[0].edit.apply(&mut after);
+    assert_eq!(after, "fn foo(x: i32) -> i32 { x }\nfn f() { foo(1); }");
+    assert!(match_finder.conflicts().is_empty());
+}
+
+#[test]
+fn nested_search_depth_stops_search() {
+    mark::check!(nested_search_depth_reached);
+    let code = "fn f() -> i32 { 1 + 2 + 3 + 4 }";
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.nested_search_depth(1);
+    match_finder.add_search_pattern("$a + $b".parse().unwrap()).unwrap();
+    let matches = match_finder.matches().flattened().matches;
+    let matched: Vec<String> = matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["1 + 2 + 3", "1 + 2 + 3 + 4"]);
+}
+
+#[test]
+fn range_forms() {
+    let code = r#"
+        fn f(a: i32, b: i32) {
+            let r1 = a..b;
+            let r2 = a..=b;
+            let r3 = ..b;
+            let r4 = a..;
+            let r5 = ..;
+            let r6 = ..=b;
+        }"#;
+    assert_matches("$x..$y", code, &["a..b"]);
+    assert_matches("$x..=$y", code, &["a..=b"]);
+    assert_matches("..$y", code, &["..b"]);
+    assert_matches("..=$y", code, &["..=b"]);
+    assert_matches("$x..", code, &["a.."]);
+    assert_matches("..", code, &[".."]);
+    // A placeholder doesn't match a missing operand.
+    assert_no_match("$x..$y", "fn f(b: i32) { let r = ..b; }");
+    assert_no_match("$x..$y", "fn f(a: i32) { let r = a..; }");
+    assert_no_match("$x..", "fn f(b: i32) { let r = ..b; }");
+    assert_no_match("..$y", "fn f(a: i32) { let r = a..; }");
+    assert_no_match("..", "fn f(a: i32, b: i32) { let r = a..b; }");
+    assert_match_failure_reason(
+        "$x..$y",
+        "fn f(a: i32) { let r = a..; }",
+        "a..",
+        "Code `a..` has no end to match",
+    );
+    // Inclusive and exclusive ranges are only treated as equivalent by an explicit rule.
+    assert_ssr_transform(
+        "$a..=$b ==>> $a..$b + 1",
+        "fn f(a: i32, b: i32) { let r = a..=b - 1; let s = ..=b; }",
+        expect![["fn f(a: i32, b: i32) { let r = a..b - 1 + 1; let s = ..=b; }"]],
+    );
+    assert_ssr_transform(
+        "$a..$b + 1 ==>> $a..=$b",
+        "fn f(a: i32, b: i32) { let r = a..b + 1; let s = a..b; }",
+        expect![["fn f(a: i32, b: i32) { let r = a..=b; let s = a..b; }"]],
+    );
+}
+
+#[test]
+fn inclusive_range_library_entry() {
+    let (db, position, selections) = single_file(
+        "fn f(a: i32, b: i32) { let r = a..b + 1; let s = a..(b + 1); let t = ..b + 1; \
+         let u = ..(b + 1); let v = a..b; let w = a..b + 2; }",
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    for rule in crate::library_entry("inclusive_range").unwrap().rules() {
+        match_finder.add_rule(rule).unwrap();
+    }
+    let edits = match_finder.edits();
+    let mut actual = db.file_text(position.file_id).to_string();
+    edits[0].edit.apply(&mut actual);
+    expect![["fn f(a: i32, b: i32) { let r = a..=b; let s = a..=b; let t = ..=b; let u = ..=b; \
+         let v = a..b; let w = a..b + 2; }"]]
+    .assert_eq(&actual);
+}
+
+#[test]
+fn library_rules_are_valid() {
+    for entry in crate::library() {
+        assert!(!entry.rules().is_empty(), "{} has no rules", entry.name);
+    }
+}
+
+#[test]
+fn heuristic_fallback_for_unresolved_paths() {
+    let code = r#"
+        fn foo(x: i32) -> i32 { x }
+        fn bar(x: i32) -> i32 { x }
+        fn f() {
+            foo(1);
+            unknown::foo(2);
+            missing::thing(3);
+        }"#;
+    let (db, position, selections) = single_file(code);
+    let finder_with = |rule: &str, heuristic_fallback: bool| {
+        let mut rule: SsrRule = rule.parse().unwrap();
+        rule.with_heuristic_fallback(heuristic_fallback);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections.clone());
+        match_finder.add_rule(rule).map(|()| match_finder)
+    };
+    let texts = |matches: crate::SsrMatches| -> Vec<String> {
+        matches.matches.iter().map(|m| m.matched_text()).collect()
+    };
+
+    let exact = finder_with("foo($a) ==>> bar($a)", false).unwrap();
+    assert_eq!(texts(exact.matches()), vec!["foo(1)"]);
+    let approximate = finder_with("foo($a) ==>> bar($a)", true).unwrap();
+    assert_eq!(texts(approximate.matches()), vec!["foo(1)", "unknown::foo(2)"]);
+    assert_eq!(texts(approximate.matches_by_heuristic()), vec!["unknown::foo(2)"]);
+
+    // A path in the rule that doesn't resolve is an error, unless we fall back to comparing text.
+    assert_eq!(
+        finder_with("missing::thing($a) ==>> bar($a)", false).err().unwrap().to_string(),
+        "Parse error: Failed to resolve path `missing::thing`"
+    );
+    let approximate = finder_with("missing::thing($a) ==>> other::thing($a)", true).unwrap();
+    assert_eq!(texts(approximate.matches_by_heuristic()), vec!["missing::thing(3)"]);
+    let mut after = db.file_text(position.file_id).to_string();
+    approximate.edits()[0].edit.apply(&mut after);
+    assert!(after.contains("    other::thing(3);"));
+}
+
+#[test]
+fn rules_compose() {
+    use crate::{composition_warnings, Compatibility};
+    let rule = |text: &str| text.parse::<SsrRule>().unwrap();
+    let compose = |a: &str, b: &str| crate::rules_compose(&rule(a), &rule(b));
+    // A self-feeding rule.
+    assert_eq!(
+        compose("foo($a) ==>> foo(foo($a))", "foo($a) ==>> foo(foo($a))"),
+        Compatibility::MayFeed
+    );
+    // Code that the replacement only copies from a placeholder was already there to be matched.
+    assert_eq!(compose("foo($a) ==>> bar($a)", "foo($a) ==>> bar($a)"), Compatibility::Independent);
+    assert_eq!(compose("foo($a) ==>> bar($a)", "bar($a) ==>> baz($a)"), Compatibility::MayFeed);
+    assert_eq!(compose("bar($a) ==>> baz($a)", "foo($a) ==>> bar($a)"), Compatibility::Independent);
+    assert_eq!(
+        compose("foo($a) ==>> bar($a, 1)", "bar($a) ==>> baz($a)"),
+        Compatibility::Independent
+    );
+    assert_eq!(compose("foo($a) ==>> $a.len()", "$x.len() ==>> len($x)"), Compatibility::MayFeed);
+    // Alternative replacements can feed other rules too.
+    assert_eq!(
+        compose("foo([$a, $r...]) ==>> baz($a, $r...) ||| bar($a)", "bar($a) ==>> baz($a)"),
+        Compatibility::MayFeed
+    );
+
+    let rules = vec![
+        rule("foo($a) ==>> bar($a)"),
+        rule("x.y() ==>> z"),
+        rule("bar($a) ==>> foo($a)"),
+        rule("$a + 0 ==>> $a + 0 + 0"),
+    ];
+    assert_eq!(
+        composition_warnings(&rules),
+        vec![
+            "The replacement of `$p0+0 ==>> $p0+0+0` may be matched by its own pattern, so \
+             applying it repeatedly might not finish",
+            "The rules `foo($p0) ==>> bar($p0)` then `bar($p0) ==>> foo($p0)` may each produce \
+             code that the next matches, so applying them repeatedly might not finish",
+        ]
+    );
+}
+
+#[test]
+fn ignore_explicit_self() {
+    let code = r#"
+        struct S;
+        impl S {
+            fn foo(&self, x: i32) -> i32 { x }
+            fn bar(&self, x: i32) -> i32 { x }
+            fn f(&self, other: &S) {
+                self.foo(1);
+                Self::foo(self, 2);
+                S::foo(self, 3);
+                other.foo(4);
+                self.bar(5);<|>
+            }
+        }"#;
+    let matches = |ignore_explicit_self: bool| -> Vec<String> {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        match_finder.ignore_explicit_self(ignore_explicit_self);
+        match_finder.add_search_pattern("S::foo($x)".parse().unwrap()).unwrap();
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect()
+    };
+    assert_eq!(matches(false), Vec::<String>::new());
+    assert_eq!(matches(true), vec!["self.foo(1)", "Self::foo(self, 2)", "S::foo(self, 3)"]);
+
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.ignore_explicit_self(true);
+    match_finder.add_rule("S::foo($x) ==>> self.bar($x)".parse().unwrap()).unwrap();
+    let mut after = db.file_text(position.file_id).to_string();
+    match_finder.edits()[0].edit.apply(&mut after);
+    assert!(after.contains(
+        "self.bar(1);\n        self.bar(2);\n        self.bar(3);\n        other.foo(4);"
+    ));
+}
+
+#[test]
+fn numeric_and_string_literal_kinds() {
+    let code = r#"
+        fn foo<T>(t: T) {}
+        fn f() {
+            foo(1);
+            foo(2.5f32);
+            foo("s");
+            foo(r"raw");
+            foo(b"bytes");
+            foo('c');
+            foo(true);
+        }"#;
+    assert_matches("foo(${a:kind(numeric_literal)})", code, &["foo(1)", "foo(2.5f32)"]);
+    assert_matches("foo(${a:kind(string_literal)})", code, &["foo(\"s\")", "foo(r\"raw\")"]);
+    assert_matches(
+        "foo(${a:kind(literal):not(kind(numeric_literal)):not(kind(string_literal))})",
+        code,
+        &["foo(b\"bytes\")", "foo('c')", "foo(true)"],
+    );
+}
+
+#[test]
+fn has_attr_constraint() {
+    let code = r#"
+        #[must_use]
+        fn a() -> i32 { 1 }
+        #[inline]
+        fn b() -> i32 { 2 }
+        #[cfg_attr(test, inline)]
+        fn c() -> i32 { 5 }
+        #[derive(Clone)]
+        struct S;
+        struct T;
+        impl T {
+            #[must_use]
+            fn m(&self) -> i32 { 3 }
+            fn n(&self) -> i32 { 4 }
+        }
+        fn consume<X>(x: X) {}
+        fn f() {
+            consume(a());
+            consume(b());
+            consume(c());
+            consume(S);
+            consume(T);
+            consume(T.m());
+            consume(T.n());
+        }"#;
+    assert_matches("consume(${x:has_attr(must_use)})", code, &["consume(a())", "consume(T.m())"]);
+    assert_matches("consume(${x:has_attr(derive)})", code, &["consume(S)"]);
+    assert_matches("consume(${x:has_attr(cfg_attr(test, inline))})", code, &["consume(c())"]);
+    assert_matches(
+        "consume(${x:not(has_attr(must_use))})",
+        code,
+        &["consume(b())", "consume(c())", "consume(S)", "consume(T)", "consume(T.n())"],
+    );
+}
+
+#[test]
+fn placeholder_text_is_copied_verbatim() {
+    // Spacing and comments within the placeholder's value are kept exactly as they were written,
+    // even where the template's own formatting differs.
+    assert_ssr_transform(
+        "foo($x) ==>> bar($x)",
+        "fn foo(x: i32) {} fn bar(x: i32) {} fn f() { foo( 1+   2 /* two */ *3 ); }",
+        expect![["fn foo(x: i32) {} fn bar(x: i32) {} fn f() { bar(1+   2 /* two */ *3); }"]],
+    );
+    assert_ssr_transform(
+        "foo($x, $y) ==>> foo($y, $x)",
+        "fn foo(x: i32, y: i32) {} fn f() { foo(( 1 ) ,-  /* minus */ 2); }",
+        expect![["fn foo(x: i32, y: i32) {} fn f() { foo(-  /* minus */ 2, ( 1 )); }"]],
+    );
+}
+
+#[test]
+fn idempotency_check() {
+    let (db, position, selections) = single_file("fn x() {} fn foo() {} fn bar() {}");
+    let add = |rule: &str, check: bool| {
+        let mut rule: SsrRule = rule.parse().unwrap();
+        rule.with_idempotency_check(check);
+        MatchFinder::in_context(&db, position, selections.clone()).add_rule(rule)
+    };
+    let error = add("x ==>> x + 0", true).unwrap_err();
+    assert_eq!(error.kind(), SsrErrorKind::NonIdempotentRule);
+    assert_eq!(
+        error.to_string(),
+        "The replacement of `x ==>> x+0` is matched by its own pattern, so the rule isn't \
+         idempotent"
+    );
+    assert_eq!(
+        add("foo($a) ==>> foo(bar($a))", true).unwrap_err().kind(),
+        SsrErrorKind::NonIdempotentRule
+    );
+    assert!(add("foo($a) ==>> bar($a)", true).is_ok());
+    // Without the check, such rules are accepted.
+    assert!(add("x ==>> x + 0", false).is_ok());
+    // Other errors are still parse errors.
+    assert_eq!(add("missing($a) ==>> $a", true).unwrap_err().kind(), SsrErrorKind::Parse);
+}
+
+#[test]
+fn idempotency_check_accepts_rule_that_only_looks_self_feeding() {
+    let (db, position, selections) =
+        single_file("fn foo(_: i32) {} fn bar(_: i32) -> i32 { 0 } fn f() { foo(1); }");
+    // Ignoring constraints, `foo(bar($a))` has the shape of the pattern, but `bar($a)` isn't a
+    // literal, so the rule doesn't match its own replacement.
+    let mut rule: SsrRule = "foo(${a:kind(literal)}) ==>> foo(bar($a))".parse().unwrap();
+    assert_eq!(crate::rules_compose(&rule, &rule), crate::Compatibility::MayFeed);
+    rule.with_idempotency_check(true);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule(rule).unwrap();
+    assert_eq!(
+        match_finder.edits()[0].edit.iter().map(|indel| indel.insert.as_str()).collect::<Vec<_>>(),
+        vec!["foo(bar(1))"]
+    );
+}
+
+#[test]
+fn idempotency_check_predicts_repeated_application() {
+    // The check is meant for rules that are applied over and over until nothing matches, e.g. by
+    // a tool that keeps a codebase tidy. Those that it rejects would match their own output.
+    let code = "fn x() {} fn foo(_: ()) {} fn bar(_: ()) {} fn f() { foo(x()); }";
+    let matches_own_output = |rule: &str| {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        match_finder.add_rule(rule.parse().unwrap()).unwrap();
+        let mut after = db.file_text(position.file_id).to_string();
+        match_finder.edits()[0].edit.apply(&mut after);
+        let (db, position, selections) = single_file(&after);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        match_finder.add_rule(rule.parse().unwrap()).unwrap();
+        !match_finder.matches().matches.is_empty()
+    };
+    let (db, position, selections) = single_file(code);
+    for rule in &["x() ==>> bar(x())", "foo($a) ==>> foo(bar($a))", "foo($a) ==>> bar($a)"] {
+        let mut checked: SsrRule = rule.parse().unwrap();
+        checked.with_idempotency_check(true);
+        let rejected =
+            MatchFinder::in_context(&db, position, selections.clone()).add_rule(checked).is_err();
+        assert_eq!(rejected, matches_own_output(rule), "{}", rule);
+    }
+}
+
+#[test]
+fn multiline_chains_keep_their_layout() {
+    let code = r#"
+        struct V;
+        impl V {
+            fn a(self) -> V { self }
+            fn b(self) -> V { self }
+            fn iter(self) -> V { self }
+            fn map(self, f: i32) -> V { self }
+            fn collect(self) -> V { self }
+            fn into_vec(self) -> V { self }
+        }
+        fn consume(v: V, f: i32) {}
+        fn f(v: V) {
+            let r = v
+                .a()
+                .b()
+                .iter().map(1).collect();
+        }
+    "#;
+    // The receiver keeps its line breaks when spliced into a single-line template.
+    assert_ssr_transform(
+        "$x.iter().map($f).collect() ==>> $x.into_vec()",
+        code,
+        expect![[r#"
+            struct V;
+            impl V {
+                fn a(self) -> V { self }
+                fn b(self) -> V { self }
+                fn iter(self) -> V { self }
+                fn map(self, f: i32) -> V { self }
+                fn collect(self) -> V { self }
+                fn into_vec(self) -> V { self }
+            }
+            fn consume(v: V, f: i32) {}
+            fn f(v: V) {
+                let r = v
+                    .a()
+                    .b().into_vec();
+            }
+        "#]],
+    );
+    // A template written with each call on its own line keeps that structure, indented to match
+    // the surrounding code.
+    assert_ssr_transform(
+        "$x.iter().map($f).collect() ==>> $x\n    .iter()\n    .into_vec()",
+        code,
+        expect![[r#"
+            struct V;
+            impl V {
+                fn a(self) -> V { self }
+                fn b(self) -> V { self }
+                fn iter(self) -> V { self }
+                fn map(self, f: i32) -> V { self }
+                fn collect(self) -> V { self }
+                fn into_vec(self) -> V { self }
+            }
+            fn consume(v: V, f: i32) {}
+            fn f(v: V) {
+                let r = v
+                    .a()
+                    .b()
+                    .iter()
+                    .into_vec();
+            }
+        "#]],
+    );
+    // When the receiver moves to a line that's indented further, its lines move with it.
+    assert_ssr_transform(
+        "$x.iter().map($f).collect() ==>> consume(\n    $x,\n    $f,\n)",
+        code,
+        expect![[r#"
+            struct V;
+            impl V {
+                fn a(self) -> V { self }
+                fn b(self) -> V { self }
+                fn iter(self) -> V { self }
+                fn map(self, f: i32) -> V { self }
+                fn collect(self) -> V { self }
+                fn into_vec(self) -> V { self }
+            }
+            fn consume(v: V, f: i32) {}
+            fn f(v: V) {
+                let r = consume(
+                    v
+                        .a()
+                        .b(),
+                    1,
+                );
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn rewrite_unwrap_by_resolution() {
+    let code = r#"
+        enum Option<T> { Some(T), None }
+        impl<T> Option<T> {
+            fn unwrap(self) -> T { loop {} }
+            fn expect(self, msg: &str) -> T { loop {} }
+        }
+        enum Result<T, E> { Ok(T), Err(E) }
+        impl<T, E> Result<T, E> {
+            fn unwrap(self) -> T { loop {} }
+            fn expect(self, msg: &str) -> T { loop {} }
+        }
+        struct Wrapper;
+        impl Wrapper {
+            fn unwrap(self) -> i32 { 0 }
+        }
+        fn f(o: Option<i32>, r: Result<i32, ()>, w: Wrapper) -> Option<i32> {
+            let a = o.unwrap();
+            let b = r.unwrap();
+            let c = w.unwrap();
+            None
+        }
+    "#;
+    assert_matches("$e.unwrap()", code, &["o.unwrap()", "r.unwrap()", "w.unwrap()"]);
+    assert_matches("Option::unwrap($e)", code, &["o.unwrap()"]);
+    assert_matches("Result::unwrap($e)", code, &["r.unwrap()"]);
+    assert_ssr_transforms(
+        &["Option::unwrap($e) ==>> $e?", r#"Result::unwrap($e) ==>> $e.expect("r failed")"#],
+        code,
+        expect![[r#"
+            enum Option<T> { Some(T), None }
+            impl<T> Option<T> {
+                fn unwrap(self) -> T { loop {} }
+                fn expect(self, msg: &str) -> T { loop {} }
+            }
+            enum Result<T, E> { Ok(T), Err(E) }
+            impl<T, E> Result<T, E> {
+                fn unwrap(self) -> T { loop {} }
+                fn expect(self, msg: &str) -> T { loop {} }
+            }
+            struct Wrapper;
+            impl Wrapper {
+                fn unwrap(self) -> i32 { 0 }
+            }
+            fn f(o: Option<i32>, r: Result<i32, ()>, w: Wrapper) -> Option<i32> {
+                let a = o?;
+                let b = r.expect("r failed");
+                let c = w.unwrap();
+                None
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn optional_binding_modifiers() {
+    let code = r#"
+        enum Option<T> { Some(T), None }
+        use Option::Some;
+        fn f(o: Option<i32>) {
+            if let Some(x) = o {}
+            if let Some(mut x) = o {}
+            if let Some(ref x) = o {}
+        }
+    "#;
+    assert_matches("pat: Some(x)", code, &["Some(x)", "Some(mut x)", "Some(ref x)"]);
+    assert_matches("pat: Some(mut x)", code, &["Some(mut x)"]);
+    assert_matches("pat: Some(ref mut x)", code, &[]);
+    // When replacing, the modifiers would be lost, so only bindings without them match.
+    assert_ssr_transform(
+        "pat: Some(x) ==>> Some(y)",
+        code,
+        expect![[r#"
+            enum Option<T> { Some(T), None }
+            use Option::Some;
+            fn f(o: Option<i32>) {
+                if let Some(y) = o {}
+                if let Some(mut x) = o {}
+                if let Some(ref x) = o {}
+            }
+        "#]],
+    );
+}
+
+#[test]
+fn resolution_scope_selection() {
+    let code = r#"
+        mod a { pub fn foo() {} }
+        mod b { pub fn foo() {} }
+        fn foo() {}
+        fn f() { a::foo(); b::foo(); foo(); }
+    "#;
+    let (db, position, _) = single_file(code);
+    let sema = hir::Semantics::new(&db);
+    let root = sema.to_module_def(position.file_id).unwrap();
+    let module_named = |name: &str| {
+        root.children(&db).find(|module| {
+            module.name(&db).map(|module_name| module_name.to_string()) == Some(name.to_owned())
+        })
+    };
+    let matched_text = |mut match_finder: MatchFinder| -> Vec<String> {
+        match_finder.add_search_pattern("foo()".parse().unwrap()).unwrap();
+        match_finder.matches().flattened().matches.iter().map(|m| m.matched_text()).collect()
+    };
+    let module_a = module_named("a").unwrap();
+    let in_a = MatchFinder::in_module(&db, module_a);
+    assert_eq!(in_a.resolution_module(), Some(module_a));
+    assert_eq!(matched_text(in_a), vec!["a::foo()"]);
+    let in_b = MatchFinder::in_module(&db, module_named("b").unwrap());
+    assert_eq!(matched_text(in_b), vec!["b::foo()"]);
+    let in_root = MatchFinder::in_crate_root(&db, root.krate().into()).unwrap();
+    assert_eq!(in_root.resolution_module(), Some(root));
+    assert_eq!(matched_text(in_root), vec!["foo()"]);
+}
+
+#[test]
+fn collect_all_fail_reasons() {
+    let code = r#"
+        fn foo(a: i32, b: i32) {}
+        fn f() { foo(1, 1); foo(2, 3); }
+    "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a, $a)".parse().unwrap()).unwrap();
+    assert!(match_finder.matches().fail_reasons.is_empty());
+    match_finder.collect_all_fail_reasons(true);
+    let matches = match_finder.matches();
+    let matched: Vec<String> = matches.matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["foo(1, 1)"]);
+    let file_text = db.file_text(position.file_id);
+    let failed: Vec<(&str, &str)> = matches
+        .fail_reasons
+        .iter()
+        .map(|(range, reason)| (&file_text[range.range], reason.reason.as_str()))
+        .collect();
+    assert!(failed.contains(&("foo(2, 3)", "Code bound to `$a` differs between occurrences")));
+    assert!(failed.iter().all(|(text, _)| *text != "foo(1, 1)"));
+}
+
+#[test]
+fn ssr_matches_merge_overlapping() {
+    // Overlapping matches that aren't within one another's placeholders can't both be applied, so
+    // the match of the earlier rule is kept, whichever side it came from.
+    let first = hand_built_matches(vec![
+        hand_built_match(0, 1, (0, 10), None),
+        hand_built_match(0, 0, (20, 30), None),
+        hand_built_match(0, 2, (40, 50), None),
+    ]);
+    let second = hand_built_matches(vec![
+        hand_built_match(0, 0, (5, 15), None),
+        hand_built_match(0, 1, (25, 35), None),
+        hand_built_match(0, 2, (45, 55), None),
+        hand_built_match(0, 3, (60, 70), None),
+    ]);
+    let merged = first.merge(second);
+    assert_eq!(flattened_summary(&merged), vec![(0, 0, 5), (0, 0, 20), (0, 2, 40), (0, 3, 60)]);
+}
+
+#[test]
+fn impl_assoc_type_bindings() {
+    let code = r#"
+        trait Add { type Output; fn add(self) -> Self::Output; }
+        trait Pair { type A; type B; }
+        struct S;
+        struct T;
+        struct W<X>(X);
+        impl Add for S { type Output = i32; fn add(self) -> i32 { 1 } }
+        impl Add for T { type Output = u8; fn add(self) -> u8 { 2 } }
+        impl Pair for S { type A = i32; type B = u8; }
+        "#;
+    assert_matches(
+        "impl $tr for $ty { type $assoc = $val; }",
+        code,
+        &[
+            "impl Add for S { type Output = i32; fn add(self) -> i32 { 1 } }",
+            "impl Add for T { type Output = u8; fn add(self) -> u8 { 2 } }",
+            "impl Pair for S { type A = i32; type B = u8; }",
+        ],
+    );
+    assert_matches(
+        "impl $tr for $ty { type Output = u8; }",
+        code,
+        &["impl Add for T { type Output = u8; fn add(self) -> u8 { 2 } }"],
+    );
+    assert_matches(
+        "impl Pair for $ty { type A = $a; type B = $b; }",
+        code,
+        &["impl Pair for S { type A = i32; type B = u8; }"],
+    );
+    assert_ssr_transforms(
+        &[
+            "impl Add for $ty { type Output = $v; } ==>> impl Add for $ty { type Output = W<$v>; }",
+            "impl Pair for $ty { type A = $a; type B = $b; } ==>> impl Pair for $ty { type A = $b; type B = $a; }",
+        ],
+        code,
+        expect![[r#"
+            trait Add { type Output; fn add(self) -> Self::Output; }
+            trait Pair { type A; type B; }
+            struct S;
+            struct T;
+            struct W<X>(X);
+            impl Add for S { type Output = W<i32>; fn add(self) -> i32 { 1 } }
+            impl Add for T { type Output = W<u8>; fn add(self) -> u8 { 2 } }
+            impl Pair for S { type A = u8; type B = i32; }
+        "#]],
+    );
+}
+
+#[test]
+fn is_async_constraint() {
+    let code = r#"
+        async fn fetch(x: i32) -> i32 { x }
+        fn compute(x: i32) -> i32 { x }
+        struct S;
+        impl S {
+            async fn load(&self, x: i32) -> i32 { x }
+            fn get(&self, x: i32) -> i32 { x }
+        }
+        fn spawn<T>(t: T) {}
+        fn f(s: S) {
+            spawn(fetch(1));
+            spawn(compute(2));
+            spawn(s.load(3));
+            spawn(s.get(4));
+            spawn(S::load(&s, 5));
+        }
+        "#;
+    assert_matches(
+        "spawn(${c:is_async})",
+        code,
+        &["spawn(fetch(1))", "spawn(s.load(3))", "spawn(S::load(&s, 5))"],
+    );
+    assert_matches("spawn(${c:not(is_async)})", code, &["spawn(compute(2))", "spawn(s.get(4))"]);
+    assert_matches("${f:is_async}($x)", code, &["fetch(1)"]);
+}
+
+#[test]
+fn try_chain_stages() {
+    let code = r#"
+        enum Result<T, E> { Ok(T), Err(E) }
+        struct S;
+        impl S {
+            fn foo(&self) -> Result<S, ()> { Result::Ok(S) }
+            fn bar(&self) -> Result<S, ()> { Result::Ok(S) }
+            fn baz(&self, x: i32) -> Result<S, ()> { Result::Ok(S) }
+        }
+        fn f(a: S) -> Result<S, ()> {
+            let x = a.foo()?.bar()?;
+            let y = a.foo()?.bar()?.baz(1)?;
+            let z = a.foo()?;
+            let w = a.foo().bar;
+            Result::Ok(x)
+        }
+        "#;
+    let stage_texts = |rule: &str| -> Vec<(String, Vec<String>)> {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        match_finder.add_search_pattern(rule.parse().unwrap()).unwrap();
+        let text = db.file_text(position.file_id);
+        match_finder
+            .matches()
+            .flattened()
+            .matches
+            .iter()
+            .map(|m| {
+                let stages = m
+                    .placeholder_stages("stages")
+                    .iter()
+                    .map(|stage| text[stage.range].to_string())
+                    .collect();
+                (m.matched_text(), stages)
+            })
+            .collect()
+    };
+    assert_eq!(
+        stage_texts("$e.$stages...?"),
+        vec![
+            ("a.foo()?.bar()?".to_string(), vec!["foo()".to_string(), "bar()".to_string()]),
+            (
+                "a.foo()?.bar()?.baz(1)?".to_string(),
+                vec!["foo()".to_string(), "bar()".to_string(), "baz(1)".to_string()]
+            ),
+            ("a.foo()?".to_string(), vec!["foo()".to_string()]),
+        ]
+    );
+    // A base that's more than a placeholder limits how many stages the variadic takes.
+    assert_matches("$a.foo()?.$stages...?", code, &["a.foo()?.bar()?", "a.foo()?.bar()?.baz(1)?"]);
+    assert_no_match("$e.foo()?.bar()?.$stages...?", "fn f() { a.foo()?.bar()?; }");
+    assert_ssr_transform(
+        "$e.$stages...? ==>> audit($e.$stages...)?",
+        "fn audit<T>(t: T) -> T { t } fn f() { let x = a.foo()?.bar(1)?; }",
+        expect![[r#"fn audit<T>(t: T) -> T { t } fn f() { let x = audit(a.foo()?.bar(1))?; }"#]],
+    );
+}
+
+#[test]
+fn once_per_scope() {
+    let code = r#"
+        struct Api;
+        impl Api { fn use_api(&self, x: i32) -> i32 { x } }
+        fn init() {}
+        fn f(a: Api) {
+            a.use_api(1);
+            a.use_api(a.use_api(2));
+        }
+        fn g(a: Api) {
+            a.use_api(3);
+        }
+        "#;
+    let matched_texts = |set: &dyn Fn(&mut SsrRule)| {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        let mut rule: SsrRule = "$a.use_api($x) ==>> $a.use_api($x)".parse().unwrap();
+        set(&mut rule);
+        match_finder.add_rule(rule).unwrap();
+        match_finder
+            .matches()
+            .flattened()
+            .matches
+            .iter()
+            .map(|m| m.matched_text())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        matched_texts(&|rule| rule.set_once_per(Some(OncePerScope::Fn))),
+        vec!["a.use_api(1)", "a.use_api(3)"]
+    );
+    // The nested match is within the kept match, so is kept too.
+    assert_eq!(
+        matched_texts(&|rule| rule.set_last_per(Some(OncePerScope::Fn))),
+        vec!["a.use_api(2)", "a.use_api(a.use_api(2))", "a.use_api(3)"]
+    );
+    assert_eq!(
+        matched_texts(&|rule| rule.set_once_per(Some(OncePerScope::File))),
+        vec!["a.use_api(1)"]
+    );
+    assert_eq!(
+        matched_texts(&|rule| rule.set_last_per(Some(OncePerScope::Module))),
+        vec!["a.use_api(3)"]
+    );
+    assert_eq!(
+        matched_texts(&|rule| rule.set_once_per(None)),
+        vec!["a.use_api(1)", "a.use_api(2)", "a.use_api(a.use_api(2))", "a.use_api(3)"]
+    );
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    let mut rule: SsrRule = "$a.use_api($x) ==>> { init(); $a.use_api($x) }".parse().unwrap();
+    rule.set_once_per(Some(OncePerScope::Fn));
+    match_finder.add_rule(rule).unwrap();
+    let mut after = db.file_text(position.file_id).to_string();
+    match_finder.edits()[0].edit.apply(&mut after);
+    expect![[r#"
+        struct Api;
+        impl Api { fn use_api(&self, x: i32) -> i32 { x } }
+        fn init() {}
+        fn f(a: Api) {
+            { init(); a.use_api(1) };
+            a.use_api(a.use_api(2));
+        }
+        fn g(a: Api) {
+            { init(); a.use_api(3) };
+        }
+    "#]]
+    .assert_eq(&after);
+}
+
+#[test]
+fn export_as_clippy_lint_stubs() {
+    let (db, position, selections) = single_file("fn foo() {} fn bar() {}");
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    match_finder.add_search_pattern(r#"foo("\"")"#.parse().unwrap()).unwrap();
+    match_finder.add_rule("$a.clone() ==>> $a".parse().unwrap()).unwrap();
+    match_finder
+        .add_rule("bar([$a, $rest...]) ==>> bar($a, $rest...) ||| bar()".parse().unwrap())
+        .unwrap();
+    expect![[r#"
+        // Generated from structured search replace rules.
+
+        declare_clippy_lint! {
+            /// **What it does:** Finds code matching `foo($a)`.
+            ///
+            /// **Suggestion:** Replace with `bar($a)`.
+            pub SSR_RULE_0,
+            style,
+            "matches `foo($a)`"
+        }
+
+        declare_clippy_lint! {
+            /// **What it does:** Finds code matching `foo("\"")`.
+            pub SSR_RULE_1,
+            style,
+            "matches `foo(\"\\\"\")`"
+        }
+
+        declare_clippy_lint! {
+            /// **What it does:** Finds code matching `$a.clone()`.
+            ///
+            /// **Suggestion:** Replace with `$a`.
+            pub SSR_RULE_2,
+            style,
+            "matches `$a.clone()`"
+        }
+
+        declare_clippy_lint! {
+            /// **What it does:** Finds code matching `bar([$a, $rest...])`.
+            ///
+            /// **Suggestion:** Replace with `bar($a, $rest...)`, or else `bar()`.
+            pub SSR_RULE_3,
+            style,
+            "matches `bar([$a, $rest...])`"
+        }
+    "#]]
+    .assert_eq(&match_finder.export_as_clippy_lint_stubs());
+}
+
+#[test]
+fn match_snapshots_outlive_edits() {
+    let (mut db, position, selections) =
+        single_file("fn foo() {} fn bar() {} fn f() { foo(1 + 2); }");
+    let (snapshots, edits) = {
+        let mut match_finder = MatchFinder::in_context(&db, position, selections.clone());
+        match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+        let snapshots: Vec<crate::MatchSnapshot> =
+            match_finder.matches().matches.iter().map(|m| m.snapshot(&db)).collect();
+        (snapshots, match_finder.edits())
+    };
+    let mut after = db.file_text(position.file_id).to_string();
+    edits[0].edit.apply(&mut after);
+    db.set_file_text(position.file_id, Arc::new(after));
+    assert_eq!(snapshots.len(), 1);
+    let snapshot = snapshots[0].clone();
+    assert_eq!(snapshot.path.as_deref(), Some("/main.rs"));
+    assert_eq!(snapshot.text, "foo(1 + 2)");
+    assert_eq!(snapshot.rule_index, 0);
+    assert_eq!((snapshot.start, snapshot.end), (33, 43));
+    assert_eq!(snapshot.placeholders["a"].text, "1 + 2");
+    assert_eq!((snapshot.placeholders["a"].start, snapshot.placeholders["a"].end), (37, 42));
+    // The snapshot still describes the code that was searched, even though the file now differs.
+    assert_eq!(&db.file_text(position.file_id)[33..43], "bar(1 + 2)");
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo($a)".parse().unwrap()).unwrap();
+    assert!(match_finder.matches().matches.is_empty());
+}
+
+#[test]
+fn debug_tree() {
+    let rule: SsrRule = "expr: $a.clone() ==>> $a".parse().unwrap();
+    expect![[r#"
+        Parsed as METHOD_CALL_EXPR
+        pattern:
+          METHOD_CALL_EXPR@1..24
+            PATH_EXPR@1..16
+              PATH@1..16
+                PATH_SEGMENT@1..16
+                  NAME_REF@1..16
+                    IDENT@1..16 "__placeholder_a" $a
+            DOT@16..17 "."
+            NAME_REF@17..22
+              IDENT@17..22 "clone"
+            ARG_LIST@22..24
+              L_PAREN@22..23 "("
+              R_PAREN@23..24 ")"
+        template:
+          PATH_EXPR@0..15
+            PATH@0..15
+              PATH_SEGMENT@0..15
+                NAME_REF@0..15
+                  IDENT@0..15 "__placeholder_a" $a
+    "#]]
+    .assert_eq(&rule.debug_tree());
+    let rule: SsrRule = "Some($a) ==>> Some($a)".parse().unwrap();
+    let tree = rule.debug_tree();
+    assert!(tree.contains("Parsed as CALL_EXPR\n"));
+    assert!(tree.contains("Parsed as TUPLE_STRUCT_PAT\n"));
+}
+
+#[test]
+fn preview_as_diff() {
+    let code = r#"
+        fn foo(x: i32) {}
+        fn bar(x: i32) {}
+        fn f() {
+            foo(1);
+            let a = 1;
+            let b = 2;
+            let c = 3;
+            let d = 4;
+            foo(2);
+            let e = 5;
+            let g = 6;
+            let h = 7;
+            let i = 8;
+            let j = 9;
+            let k = 10;
+            let l = 11;
+            foo(3);
+        }
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> bar($a)".parse().unwrap()).unwrap();
+    expect![[r#"
+        --- a/main.rs
+        +++ b/main.rs
+        @@ -1,12 +1,12 @@
+         fn foo(x: i32) {}
+         fn bar(x: i32) {}
+         fn f() {
+        -    foo(1);
+        +    bar(1);
+             let a = 1;
+             let b = 2;
+             let c = 3;
+             let d = 4;
+        -    foo(2);
+        +    bar(2);
+             let e = 5;
+             let g = 6;
+             let h = 7;
+        @@ -14,5 +14,5 @@
+             let j = 9;
+             let k = 10;
+             let l = 11;
+        -    foo(3);
+        +    bar(3);
+         }
+    "#]]
+    .assert_eq(&match_finder.preview_as_diff());
+    // The last line has no newline.
+    let code = "fn foo() {}\nfn f() {\n    foo();\n}\nfn g() { foo() }";
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo() ==>> 1".parse().unwrap()).unwrap();
+    expect![[r#"
+        --- a/main.rs
+        +++ b/main.rs
+        @@ -1,5 +1,5 @@
+         fn foo() {}
+         fn f() {
+        -    foo();
+        +    1;
+         }
+        -fn g() { foo() }
+        \ No newline at end of file
+        +fn g() { 1 }
+        \ No newline at end of file
+    "#]]
+    .assert_eq(&match_finder.preview_as_diff());
+}
+
+#[test]
+fn alternative_templates() {
+    assert_ssr_transform(
+        "run([$cmd, $args...]) ==>> spawn($cmd, &[$args...]) ||| spawn_bare($cmd)",
+        r#"
+        fn run<T>(t: T) {}
+        fn spawn<T, A>(t: T, args: A) {}
+        fn spawn_bare<T>(t: T) {}
+        fn f() {
+            run(["ls"]);
+            run(["ls", "-l", "-a"]);
+        }
+        "#,
+        expect![[r#"
+            fn run<T>(t: T) {}
+            fn spawn<T, A>(t: T, args: A) {}
+            fn spawn_bare<T>(t: T) {}
+            fn f() {
+                spawn_bare("ls");
+                spawn("ls", &["-l", "-a"]);
+            }
+        "#]],
+    );
+    // With no template usable, the last is used.
+    assert_ssr_transform(
+        "f(($a, $b...)) ==>> g($b...) ||| h($b...)",
+        "fn f() {} fn g() {} fn h() {} fn main() { f((1,)); }",
+        expect![["fn f() {} fn g() {} fn h() {} fn main() { h(); }"]],
+    );
+    let rule: SsrRule = "f([$a, $rest...]) ==>>  g($a,$rest...)|||h( $a )".parse().unwrap();
+    assert_eq!(rule.canonicalize(), "f([$p0,$p1...]) ==>> g($p0,$p1...) ||| h($p0)");
+    #[cfg(feature = "persist")]
+    assert_eq!(
+        crate::load_rules(&crate::save_rules(&[rule])).unwrap()[0].canonicalize(),
+        "f([$p0,$p1...]) ==>> g($p0,$p1...) ||| h($p0)"
+    );
+    assert_eq!(
+        parse_error_text("f($a) ==>> g($a) ||| h($b)"),
+        "Parse error: Replacement contains undefined placeholders: $b"
+    );
+}
+
+#[test]
+fn wildcards_in_paths() {
+    let code = r#"
+        //- /main.rs crate:main deps:std
+        use std::thread;
+        fn f() {
+            thread::spawn(1);
+            std::thread::spawn(2);
+            std::process::spawn(3);
+            std::sys::unix::thread::spawn(4);
+            std::thread::other(5);
+            spawn(6);<|>
+        }
+        fn spawn(_: i32) {}
+        //- /std.rs crate:std
+        pub mod thread { pub fn spawn(_: i32) {} pub fn other(_: i32) {} }
+        pub mod process { pub fn spawn(_: i32) {} }
+        pub mod sys { pub mod unix { pub mod thread { pub fn spawn(_: i32) {} } } }
+        "#;
+    assert_matches(
+        "std::*::spawn($a)",
+        code,
+        &["thread::spawn(1)", "std::thread::spawn(2)", "std::process::spawn(3)"],
+    );
+    assert_matches(
+        "std::**::spawn($a)",
+        code,
+        &[
+            "thread::spawn(1)",
+            "std::thread::spawn(2)",
+            "std::process::spawn(3)",
+            "std::sys::unix::thread::spawn(4)",
+        ],
+    );
+    assert_matches(
+        "std::**::thread::spawn($a)",
+        code,
+        &["thread::spawn(1)", "std::thread::spawn(2)", "std::sys::unix::thread::spawn(4)"],
+    );
+    // `**` can match no segments at all, but `*` must match exactly one.
+    assert_matches("main::**::spawn($a)", code, &["spawn(6)"]);
+    assert_no_match("main::*::spawn($a)", code);
+    let rule: SsrRule = "std::**::spawn($a) ==>> std::thread::spawn($a)".parse().unwrap();
+    assert_eq!(rule.canonicalize(), "std::**::spawn($p0) ==>> std::thread::spawn($p0)");
+    assert_eq!(
+        parse_error_text("spawn($a) ==>> std::*::spawn($a)"),
+        "Parse error: Path wildcards can only be used in the search pattern"
+    );
+}
+
+#[test]
+fn insertion_rule() {
+    let (db, position, selections) = single_file(
+        r#"
+        fn foo() {}
+        mod m {
+            fn bar() {}
+            pub fn baz(x: i32) {}
+        }
+        fn f() { foo(); }<|>
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder
+        .add_insertion_rule(
+            "fn $f() {}".parse().unwrap(),
+            InsertPosition::BeforeEnclosingItem,
+            |m| {
+                let name =
+                    m.placeholder_node("f").map(|n| n.text().to_string()).unwrap_or_default();
+                format!("// Does nothing, like `{}`.", name)
+            },
+        )
+        .unwrap();
+    // Insertions keep their places alongside replacements, even of the same code.
+    match_finder.add_rule("foo() ==>> m::baz(1)".parse().unwrap()).unwrap();
+    match_finder
+        .add_insertion_rule("foo()".parse().unwrap(), InsertPosition::AfterMatch, |_| {
+            "/* was foo */".to_owned()
+        })
+        .unwrap();
+    let edits = match_finder.edits();
+    let mut actual = db.file_text(position.file_id).to_string();
+    edits[0].edit.apply(&mut actual);
+    expect![[r#"
+        // Does nothing, like `foo`.
+        fn foo() {}
+        mod m {
+            // Does nothing, like `bar`.
+            fn bar() {}
+            pub fn baz(x: i32) {}
+        }
+        fn f() { m::baz(1)/* was foo */; }
+    "#]]
+    .assert_eq(&actual);
+}
+
+#[test]
+fn use_glob() {
+    let code = r#"
+        mod foo { pub struct A; pub struct B; }
+        mod bar { pub mod baz { pub struct C; } }
+        use foo::*;
+        use bar::baz::*;
+        use bar::baz::C;
+        fn f() {}<|>
+        "#;
+    assert_matches("use $mod::*;", code, &["use foo::*;", "use bar::baz::*;"]);
+    // Paths within use declarations are matched by what they resolve to.
+    assert_matches("use crate::bar::baz::*;", code, &["use bar::baz::*;"]);
+    assert_no_match("use $m::C;", "mod m { pub struct C; } use m::*; fn f() {}");
+    assert_ssr_transform(
+        "use $mod::*; ==>> use $mod::{A, B};",
+        r#"
+        mod foo { pub struct A; pub struct B; }
+        use foo::*;
+        fn f() { A; B; }
+        "#,
+        expect![[r#"
+            mod foo { pub struct A; pub struct B; }
+            use foo::{A, B};
+            fn f() { A; B; }
+        "#]],
+    );
+}
+
+#[test]
+fn operator_placeholders() {
+    assert_ssr_transform(
+        "$a ${op:one_of(==, !=)} $b ==>> ($b) $op ($a)",
+        "fn f(x: i32, y: i32) { x == y; x != y + 1; x < y; x+y == 0; }",
+        expect![[r#"fn f(x: i32, y: i32) { (y) == (x); (y + 1) != (x); x < y; (0) == (x+y); }"#]],
+    );
+    assert_matches("${op:one_of(!, -)}$a", "fn f(x: i32, y: bool) { -x; !y; *&x; }", &["-x", "!y"]);
+    // Every occurrence of the placeholder must be the same operator.
+    assert_matches(
+        "($a ${op:one_of(+, -)} $b) $op $c",
+        "fn f(x: i32) { (x + 1) + 2; (x + 1) - 2; }",
+        &["(x + 1) + 2"],
+    );
+    let rule: SsrRule = "$a ${op:one_of(==, !=)} $b ==>> $b $op $a".parse().unwrap();
+    assert_eq!(rule.canonicalize(), "$p0 ${p1:one_of(==, !=)} $p2 ==>> $p2 $p1 $p0");
+    #[cfg(feature = "persist")]
+    assert_eq!(
+        crate::load_rules(&crate::save_rules(&[rule])).unwrap()[0].canonicalize(),
+        "$p0 ${p1:one_of(==, !=)} $p2 ==>> $p2 $p1 $p0"
+    );
+    assert_eq!(
+        parse_error_text("${x:one_of(==, Foo)}($a) ==>> $a"),
+        "Parse error: A one_of constraint can list either operators or paths, but not both"
+    );
+}
+
+#[test]
+fn matches_in_changed_files() {
+    let code = r#"
+        //- /main.rs crate:main
+        mod changed;
+        fn foo() {}
+        fn f() { foo(); }<|>
+        //- /changed.rs
+        fn g() { crate::foo(); }
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("foo()".parse().unwrap()).unwrap();
+    let matches = match_finder
+        .matches_in_files_at_paths(std::path::Path::new("/"), &["changed.rs", "deleted.rs"]);
+    let matched: Vec<String> = matches.matches.iter().map(|m| m.matched_text()).collect();
+    assert_eq!(matched, vec!["crate::foo()"]);
+    assert!(match_finder
+        .matches_in_files_at_paths(std::path::Path::new("/"), &[])
+        .matches
+        .is_empty());
+    // Restricting a search doesn't affect later searches.
+    assert_eq!(match_finder.matches().matches.len(), 2);
+
+    // Paths that aren't of files in the workspace are ignored.
+    let paths: rustc_hash::FxHashSet<std::path::PathBuf> =
+        ["/changed.rs", "/deleted.rs", "/main.rs/changed.rs"].iter().map(Into::into).collect();
+    let files: Vec<FileId> = match_finder.files_at_paths(&paths).into_iter().collect();
+    assert_eq!(files.len(), 1);
+    assert_ne!(files[0], position.file_id);
+}
+
+#[test]
+fn const_generics() {
+    let code = r#"
+        struct Foo<const N: usize>;
+        impl<const N: usize> Foo<N> { fn new() -> Self { Foo } }
+        fn f() { Foo::<5>::new(); Foo::<{ 2 + 3 }>::new(); }
+        "#;
+    assert_matches("Foo::<$n>::new()", code, &["Foo::<5>::new()", "Foo::<{ 2 + 3 }>::new()"]);
+    assert_matches("Foo::<${n:kind(literal)}>::new()", code, &["Foo::<5>::new()"]);
+    assert_matches("Foo::<${n:int_in(1..=9)}>::new()", code, &["Foo::<5>::new()"]);
+    assert_matches(
+        "impl<const $n: $t> $s {}",
+        "struct Foo<const N: usize>; impl<const N: usize> Foo<N> {} impl Foo<1> {}",
+        &["impl<const N: usize> Foo<N> {}"],
+    );
+    assert_matches("struct $s<$p>;", code, &["struct Foo<const N: usize>;"]);
+    assert_ssr_transform(
+        "struct $s<const $n: usize>; ==>> struct $s<const $n: u32>;",
+        code,
+        expect![[r#"
+            struct Foo<const N: u32>;
+            impl<const N: usize> Foo<N> { fn new() -> Self { Foo } }
+            fn f() { Foo::<5>::new(); Foo::<{ 2 + 3 }>::new(); }
+        "#]],
+    );
+    // Expressions other than literals, blocks and paths need braces to be const generic arguments.
+    assert_ssr_transform(
+        "make($a, $b) ==>> Foo::<$a>::new($b)",
+        r#"
+        struct Foo<const N: usize>;
+        impl<const N: usize> Foo<N> { fn new(_: usize) -> Self { Foo } }
+        fn make(_: usize, _: usize) {}
+        const M: usize = 1;
+        fn f() { make(2 + 3, 2 + 3); make(5, 5); make(M, M); }
+        "#,
+        expect![[r#"
+            struct Foo<const N: usize>;
+            impl<const N: usize> Foo<N> { fn new(_: usize) -> Self { Foo } }
+            fn make(_: usize, _: usize) {}
+            const M: usize = 1;
+            fn f() { Foo::<{ 2 + 3 }>::new(2 + 3); Foo::<5>::new(5); Foo::<M>::new(M); }
+        "#]],
+    );
+}
+
+#[test]
+fn match_variables_in_template_strings() {
+    assert_ssr_transform(
+        r#"mark() ==>> log("${match.module}", "${match.file}:${match.fn}")"#,
+        r#"
+        //- /main.rs crate:main
+        fn mark() {}
+        fn log(_: &str, _: &str) {}
+        fn f() { mark(); }
+        mod foo {
+            pub mod bar {
+                pub fn g() { crate::mark(); }
+            }
+        }
+        const C: () = mark();
+        "#,
+        expect![[r#"
+            fn mark() {}
+            fn log(_: &str, _: &str) {}
+            fn f() { log("main", "main:f"); }
+            mod foo {
+                pub mod bar {
+                    pub fn g() { crate::log("main::foo::bar", "main:g"); }
+                }
+            }
+            const C: () = log("main", "main:");
+        "#]],
+    );
+    // Strings within macro calls and raw strings work too.
+    assert_ssr_transform(
+        r#"mark() ==>> trace!(r"${match.fn}", "${match.fn} done")"#,
+        r#"
+        macro_rules! trace { ($($t:tt)*) => {} }
+        fn mark() {}
+        fn f() { mark(); }
+        "#,
+        expect![[r#"
+            macro_rules! trace { ($($t:tt)*) => {} }
+            fn mark() {}
+            fn f() { trace!(r"f", "f done"); }
+        "#]],
+    );
+    assert_eq!(
+        parse_error_text(r#"mark() ==>> log("${match.line}")"#),
+        "Parse error: Unknown variable `${match.line}`, expected one of: module, file, fn"
+    );
+}
+
+#[test]
+fn returns_constraint() {
+    let code = r#"
+        mod m { pub struct Wrapper<T>(pub T); }
+        struct S;
+        impl S { fn ready(&self) -> bool { true } }
+        fn is_ok() -> bool { true }
+        fn count() -> usize { 0 }
+        fn wrap() -> m::Wrapper<i32> { m::Wrapper(0) }
+        fn take<T>(_: T) {}
+        fn f(s: S) { take(is_ok()); take(count()); take(s.ready()); take(wrap()); take(is_ok); }
+        "#;
+    assert_matches("${f:returns(bool)}", code, &["is_ok()", "s.ready()"]);
+    // A path to a function isn't a call, so doesn't return anything.
+    assert_matches(
+        "take(${f:not(returns(bool))})",
+        code,
+        &["take(count())", "take(wrap())", "take(is_ok)"],
+    );
+    // Tuple struct constructors are called like functions.
+    assert_matches("${f:returns(crate::m::Wrapper< i32 >)}", code, &["m::Wrapper(0)", "wrap()"]);
+    assert_ssr_transform(
+        "${f:returns(bool)} ==>> $f.then_some(())",
+        "fn ready() -> bool { true } fn g() -> i32 { 1 } fn f() { ready(); g(); }",
+        expect![[
+            r#"fn ready() -> bool { true } fn g() -> i32 { 1 } fn f() { ready().then_some(()); g(); }"#
+        ]],
+    );
+    let rule: SsrRule = "${f:returns(Option<&str>)} ==>> $f.unwrap()".parse().unwrap();
+    assert_eq!(rule.canonicalize(), "${p0:returns(Option<&str>)} ==>> $p0.unwrap()");
+    assert_eq!(
+        parse_error_text("${f:returns(1 + 2)} ==>> $f"),
+        "Parse error: Expected a type in returns constraint, found `1 + 2`"
+    );
+}
+
+#[test]
+fn explain_returns_constraint_without_type() {
+    use crate::{ConstraintCheck, ConstraintOutcome};
+    // Array lengths in types aren't inferred, so the call there has no type.
+    let (db, position, selections) = single_file(
+        r#"
+        const fn len() -> usize { 0 }
+        fn f() { let a: [u8; len()] = []; }
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.set_explain_constraints(true);
+    match_finder.add_search_pattern("[u8; ${f:not(returns(usize))}]".parse().unwrap()).unwrap();
+    let matches = match_finder.matches().matches;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].matched_text(), "[u8; len()]");
+    assert_eq!(
+        matches[0].constraint_checks(),
+        &[ConstraintCheck {
+            placeholder: "f".to_owned(),
+            constraint: "not(returns(usize))".to_owned(),
+            outcome: ConstraintOutcome::NotEvaluated(
+                "The type of 'len()' couldn't be inferred".to_owned()
+            ),
+        }]
+    );
+}
+
+#[test]
+fn returns_constraint_resolves_type_in_rule_scope() {
+    let code = r#"
+        mod a { pub struct Id; }
+        mod b { pub struct Id; }
+        use a::Id;
+        fn from_a() -> a::Id { a::Id }
+        fn from_b() -> b::Id { b::Id }
+        type Alias = b::Id;
+        fn from_alias() -> Alias { b::Id }
+        fn f() { from_a(); from_b(); from_alias(); }<|>
+        "#;
+    // Both types are named `Id`, but only `a::Id` is in scope under that name.
+    assert_matches("${f:returns(Id)}", code, &["from_a()"]);
+    assert_matches("${f:returns(b::Id)}", code, &["from_b()", "from_alias()"]);
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    let error =
+        match_finder.add_search_pattern("${f:returns(c::Id)}".parse().unwrap()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Parse error: Failed to resolve type `c::Id` in returns constraint"
+    );
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn test_rule_helper() {
+    crate::test_rule(
+        "foo($x) ==>> bar($x)",
+        "fn foo(_: i32) {}\nfn bar(_: i32) {}\nfn f() { foo(1 + 2); }",
+        "fn foo(_: i32) {}\nfn bar(_: i32) {}\nfn f() { bar(1 + 2); }",
+    );
+    let wrong_result = std::panic::catch_unwind(|| {
+        crate::test_rule("foo($x) ==>> bar($x)", "fn foo() {}\nfn bar() {}\n", "fn bar() {}\n")
+    });
+    assert!(wrong_result.is_err());
+}
+
+#[test]
+fn use_and_extern_crate_items() {
+    let code = r#"
+        //- /main.rs crate:main deps:old_name,new_name
+        extern crate old_name;
+        use old_name::a;
+        use old_name::{a as x, b::c};
+        use old_name::b::*;
+        use old_name::a as y;
+        use other::a;<|>
+        //- /old.rs crate:old_name
+        pub fn a() {}
+        pub mod b { pub fn c() {} }
+        //- /new.rs crate:new_name
+        pub fn a() {}
+        pub mod b { pub fn c() {} }
+        "#;
+    assert_ssr_transform(
+        "use old_name::$rest; ==>> use new_name::$rest;",
+        code,
+        expect![[r#"
+            extern crate old_name;
+            use new_name::a;
+            use new_name::{a as x, b::c};
+            use new_name::b::*;
+            use new_name::a as y;
+            use other::a;
+        "#]],
+    );
+    assert_ssr_transform(
+        "extern crate $n; ==>> ",
+        code,
+        expect![[r#"
+            use old_name::a;
+            use old_name::{a as x, b::c};
+            use old_name::b::*;
+            use old_name::a as y;
+            use other::a;
+        "#]],
+    );
+    // Trees within braces may be in any order.
+    assert_matches("use old_name::{b::c, a as x};", code, &["use old_name::{a as x, b::c};"]);
+    assert_no_match("use old_name::{b::c, a};", code);
+    assert_eq!(
+        parse_error_text("foo() ==>> "),
+        "Parse error: Not a valid Rust expression, type, item, path or pattern"
+    );
+}
+
+#[test]
+fn use_trees_try_other_pairings() {
+    let code = r#"
+        mod m { pub mod p { pub struct A; pub struct B; } pub mod q { pub struct A; } }
+        use m::{q::A, p::B, p::A};
+        "#;
+    // `$x::A` can first be paired with `q::A`, but then `$x::B` doesn't match `p::B`. Only pairing
+    // it with `p::A` works.
+    assert_matches("use m::{$x::A, $x::B, $y::A};", code, &["use m::{q::A, p::B, p::A};"]);
+    assert_no_match("use m::{$x::A, $x::B, $x::A};", code);
+}
+
+#[test]
+fn matches_with_origin() {
+    let code = r#"
+        macro_rules! my_write { ($w:expr, $e:expr) => { $w.write_fmt($e) } }
+        macro_rules! id { ($e:expr) => { $e } }
+        struct W {}
+        impl W { fn write_fmt(&self, x: i32) {} }
+        fn f() {
+            let w = W {};
+            my_write!(w, 1);
+            w.write_fmt(2);
+            id!(w.write_fmt(3));
+        }"#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("$w.write_fmt($e)".parse().unwrap()).unwrap();
+    let file_text = db.file_text(position.file_id);
+    let origins: Vec<(&str, String, bool)> = match_finder
+        .matches_with_origin()
+        .flattened()
+        .matches
+        .iter()
+        .map(|m| {
+            let origin = match m.origin() {
+                MatchOrigin::Direct => "direct".to_owned(),
+                MatchOrigin::MacroExpansion { call_site } => {
+                    assert_eq!(call_site.file_id, position.file_id);
+                    format!("via {}", &file_text[call_site.range])
+                }
+            };
+            (&file_text[m.range.range], origin, m.inside_macro_expansion())
+        })
+        .collect();
+    assert_eq!(
+        origins,
+        vec![
+            ("my_write!(w, 1)", "via my_write!(w, 1)".to_owned(), true),
+            ("w.write_fmt(2)", "direct".to_owned(), false),
+            ("w.write_fmt(3)", "via id!(w.write_fmt(3))".to_owned(), false),
+        ]
+    );
+    // Matching inside macro expansions is only enabled while finding the matches.
+    assert_eq!(match_finder.matches().flattened().matches.len(), 2);
+}
+
+#[test]
+fn apply_guarded_edits() {
+    let code = r#"
+        //- /main.rs crate:main
+        mod other;
+        fn foo() {}
+        fn bar() {}
+        fn f() { foo(); }<|>
+        //- /other.rs
+        fn g() { crate::foo(); }
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo() ==>> bar()".parse().unwrap()).unwrap();
+    let edits = match_finder.guarded_edits();
+    assert_eq!(edits.len(), 2);
+
+    let dir = std::env::temp_dir().join(format!("ra_ssr_apply_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path_for_file = |file_id: FileId| {
+        let source_root = db.source_root(db.file_source_root(file_id));
+        let path = source_root.path_for_file(&file_id)?.to_string();
+        Some(dir.join(path.trim_start_matches('/')))
+    };
+    let write_originals = || {
+        for edit in &edits {
+            std::fs::write(path_for_file(edit.file_id).unwrap(), &*db.file_text(edit.file_id))
+                .unwrap();
+        }
+    };
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+
+    // If one file has changed since the search, neither is written.
+    write_originals();
+    let stale = read("other.rs").replace("crate::foo()", "crate::foo ()");
+    std::fs::write(dir.join("other.rs"), &stale).unwrap();
+    let error = crate::apply_guarded_edits(&edits, path_for_file).unwrap_err();
+    match &error {
+        crate::ApplyError::StaleFiles(paths) => assert_eq!(paths, &[dir.join("other.rs")]),
+        crate::ApplyError::Io(err) => panic!("Unexpected IO error: {}", err),
+    }
+    assert!(error.to_string().contains("other.rs"));
+    assert_eq!(read("main.rs"), db.file_text(position.file_id).to_string());
+    assert_eq!(read("other.rs"), stale);
+
+    // Otherwise both are.
+    write_originals();
+    crate::apply_guarded_edits(&edits, path_for_file).unwrap();
+    assert!(read("main.rs").contains("fn f() { bar(); }"));
+    assert!(read("other.rs").contains("fn g() { crate::bar(); }"));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn apply_guarded_edits_checks_line_of_insertion() {
+    let code = r#"
+        //- /main.rs crate:main
+        fn foo() {}
+        fn f() { foo(); }
+        fn g() {}<|>
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder
+        .add_insertion_rule("foo()".parse().unwrap(), InsertPosition::AfterMatch, |_| {
+            "/* was foo */".to_owned()
+        })
+        .unwrap();
+    let edits = match_finder.guarded_edits();
+
+    let dir = std::env::temp_dir().join(format!("ra_ssr_apply_insertion_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("main.rs");
+    let original = db.file_text(position.file_id).to_string();
+    // A change to the line that the insertion goes in makes the file stale.
+    std::fs::write(&path, original.replace("foo(); }", "foo(); foo(); }")).unwrap();
+    let error = crate::apply_guarded_edits(&edits, |_| Some(path.clone())).unwrap_err();
+    assert!(matches!(error, crate::ApplyError::StaleFiles(_)));
+    // A change elsewhere doesn't.
+    std::fs::write(&path, original.replace("fn g() {}", "fn h() {}")).unwrap();
+    crate::apply_guarded_edits(&edits, |_| Some(path.clone())).unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(text.contains("fn f() { foo()/* was foo */; }"));
+    assert!(text.contains("fn h() {}"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn trailing_semicolons() {
+    let code = r#"
+        fn foo(_: bool) {}
+        fn f(x: bool) -> i32 {
+            if x {}
+            foo(x);
+            let _ = { foo(x) };
+            if x {}
+        }"#;
+    // A call replacing an `if` that wasn't the last thing in its block needs a `;`, but one that
+    // ends up as the block's tail doesn't.
+    assert_ssr_transform(
+        "if $a {} ==>> foo($a)",
+        code,
+        expect![[r#"
+            fn foo(_: bool) {}
+            fn f(x: bool) -> i32 {
+                foo(x);
+                foo(x);
+                let _ = { foo(x) };
+                foo(x)
+            }"#]],
+    );
+    // An `if` of type `()` doesn't need the `;` of the statement it replaces, but one with a value
+    // does, as does any expression that isn't a whole statement.
+    assert_ssr_transform(
+        "foo($a) ==>> if $a {}",
+        code,
+        expect![[r#"
+            fn foo(_: bool) {}
+            fn f(x: bool) -> i32 {
+                if x {}
+                if x {}
+                let _ = { if x {} };
+                if x {}
+            }"#]],
+    );
+    assert_ssr_transform(
+        "foo($a) ==>> if $a { 1 } else { 2 }",
+        code,
+        expect![[r#"
+            fn foo(_: bool) {}
+            fn f(x: bool) -> i32 {
+                if x {}
+                if x { 1 } else { 2 };
+                let _ = { if x { 1 } else { 2 } };
+                if x {}
+            }"#]],
+    );
+
+    let transform = |rule: &str, trailing_semicolon: TrailingSemicolon| {
+        let (db, position, selections) = single_file(code);
+        let mut match_finder = MatchFinder::in_context(&db, position, selections);
+        let mut rule: SsrRule = rule.parse().unwrap();
+        rule.set_trailing_semicolon(trailing_semicolon);
+        match_finder.add_rule(rule).unwrap();
+        let mut after = db.file_text(position.file_id).to_string();
+        match_finder.edits()[0].edit.apply(&mut after);
+        after
+    };
+    let after = transform("foo($a) ==>> if $a {}", TrailingSemicolon::Add);
+    assert!(after.contains("    if x {};\n"));
+    let after = transform("if $a {} ==>> foo($a)", TrailingSemicolon::Keep);
+    assert!(after.contains("    foo(x)\n    foo(x);\n"));
+}
+
+#[test]
+fn could_match_in_file_with_macro_generated_code() {
+    let code = r#"
+        //- /main.rs crate:main
+        #[macro_use]
+        mod macros;
+        mod other;
+        struct W {}
+        impl W { fn write_fmt(&self, x: i32) {} }
+        //- /other.rs
+        fn f(w: crate::W) { my_write!(w, 1); }<|>
+        //- /macros.rs
+        macro_rules! my_write { ($w:expr, $e:expr) => { $w.write_fmt($e) } }
+        "#;
+    let (db, position, selections) = single_file(code);
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_search_pattern("$w.write_fmt($e)".parse().unwrap()).unwrap();
+    // The file doesn't contain `write_fmt`, so can only match via the macro's definition.
+    assert!(!match_finder.could_match_in_file(position.file_id));
+    let matches = match_finder.matches_with_origin().flattened().matches;
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].range.file_id, position.file_id);
+    match_finder.set_match_inside_macro_expansions(true);
+    assert!(match_finder.could_match_in_file(position.file_id));
+}
+
+#[test]
+fn insertion_after_match_whose_semicolon_is_dropped() {
+    let (db, position, selections) = single_file(
+        r#"
+        fn foo(_: bool) {}
+        fn f(x: bool) {
+            foo(x);
+        }<|>
+        "#,
+    );
+    let mut match_finder = MatchFinder::in_context(&db, position, selections);
+    match_finder.add_rule("foo($a) ==>> if $a {}".parse().unwrap()).unwrap();
+    match_finder
+        .add_insertion_rule("foo($a)".parse().unwrap(), InsertPosition::AfterMatch, |_| {
+            " // was foo".to_owned()
+        })
+        .unwrap();
+    let mut actual = db.file_text(position.file_id).to_string();
+    match_finder.edits()[0].edit.apply(&mut actual);
+    expect![[r#"
+        fn foo(_: bool) {}
+        fn f(x: bool) {
+            if x {} // was foo
+        }
+    "#]]
+    .assert_eq(&actual);
+}
@@ -131,6 +131,14 @@ pub fn expr_prefix(op: SyntaxKind, expr: ast::Expr) -> ast::Expr {
     let token = token(op);
     expr_from_text(&format!("{}{}", token, expr))
 }
+pub fn expr_method_call(
+    receiver: ast::Expr,
+    method: ast::NameRef,
+    args: impl IntoIterator<Item = ast::Expr>,
+) -> ast::Expr {
+    let args = args.into_iter().map(|arg| arg.to_string()).join(", ");
+    expr_from_text(&format!("{}.{}({})", receiver, method, args))
+}
 fn expr_from_text(text: &str) -> ast::Expr {
     ast_from_text(&format!("const C: () = {};", text))
 }
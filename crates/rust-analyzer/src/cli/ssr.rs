@@ -4,21 +4,24 @@ use crate::cli::{load_cargo::load_cargo, Result};
 use ra_ssr::{MatchFinder, SsrPattern, SsrRule};
 
 pub fn apply_ssr_rules(rules: Vec<SsrRule>) -> Result<()> {
-    use ra_db::SourceDatabaseExt;
     let (host, vfs) = load_cargo(&std::env::current_dir()?, true, true)?;
     let db = host.raw_database();
     let mut match_finder = MatchFinder::at_first_file(db)?;
-    for rule in rules {
-        match_finder.add_rule(rule)?;
+    for warning in ra_ssr::composition_warnings(&rules) {
+        eprintln!("Warning: {}", warning);
     }
-    let edits = match_finder.edits();
-    for edit in edits {
-        if let Some(path) = vfs.file_path(edit.file_id).as_path() {
-            let mut contents = db.file_text(edit.file_id).to_string();
-            edit.edit.apply(&mut contents);
-            std::fs::write(path, contents)?;
+    for rule in rules {
+        for warning in rule.warnings() {
+            eprintln!("Warning: {}", warning);
         }
+        match_finder.add_rule(rule)?;
     }
+    // Nothing is written unless every file can be edited, so that the workspace is never left
+    // partly rewritten.
+    let edits = match_finder.guarded_edits();
+    ra_ssr::apply_guarded_edits(&edits, |file_id| {
+        vfs.file_path(file_id).as_path().map(|path| path.to_path_buf().into())
+    })?;
     Ok(())
 }
 
@@ -32,6 +35,9 @@ pub fn search_for_patterns(patterns: Vec<SsrPattern>, debug_snippet: Option<Stri
     let db = host.raw_database();
     let mut match_finder = MatchFinder::at_first_file(db)?;
     for pattern in patterns {
+        for warning in pattern.warnings() {
+            eprintln!("Warning: {}", warning);
+        }
         match_finder.add_search_pattern(pattern)?;
     }
     if let Some(debug_snippet) = &debug_snippet {